@@ -0,0 +1,157 @@
+use domain::{AckError, AppCommand};
+use matrix_sdk::ruma::api::client::error::Error as ClientApiError;
+use std::future::Future;
+use std::time::Duration;
+use storage::Db;
+use tracing::{error, warn};
+
+/// Pause before the second try at a command; it doubles for each later one.
+pub const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A command the relay refuses for good, e.g. a comment too large to send.
+/// It isn't retried, and its message is passed on to the client.
+#[derive(Debug)]
+pub(crate) struct Rejected(pub String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// The homeserver's own refusal of a request: a 4xx other than rate
+/// limiting or a timeout, which a later try could get past.
+fn homeserver_refusal(e: &anyhow::Error) -> Option<&ClientApiError> {
+    let api = e.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<matrix_sdk::Error>() {
+            e.as_client_api_error()
+        } else if let Some(e) = cause.downcast_ref::<matrix_sdk::HttpError>() {
+            e.as_client_api_error()
+        } else {
+            None
+        }
+    })?;
+    let status = api.status_code.as_u16();
+    ((400..500).contains(&status) && status != 408 && status != 429).then_some(api)
+}
+
+fn is_permanent(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<Rejected>()) || homeserver_refusal(e).is_some()
+}
+
+/// How a driver error is reported to whoever waits on the command. Only
+/// refusals carry a message meant for the client; anything else is an
+/// upstream failure whose detail belongs in the log.
+pub fn ack_error(e: &anyhow::Error) -> AckError {
+    if let Some(rejected) = e.chain().find_map(|cause| cause.downcast_ref::<Rejected>()) {
+        return AckError::Rejected(rejected.0.clone());
+    }
+    if let Some(api) = homeserver_refusal(e) {
+        return AckError::Rejected(format!(
+            "The Matrix homeserver refused the request ({})",
+            api.status_code
+        ));
+    }
+    AckError::Failed(format!("{:#}", e))
+}
+
+/// Runs `op` up to `attempts` times before giving up with its last error.
+/// Each homeserver request already retries transient failures for
+/// `request_retry`; this covers a command failing as a whole, e.g. while the
+/// homeserver restarts, so it isn't dead-lettered on the first failure.
+/// A refusal is final and returned at once.
+pub async fn with_retries<T, F, Fut>(
+    attempts: u32,
+    first_delay: Duration,
+    what: &str,
+    mut op: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut delay = first_delay;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts && !is_permanent(&e) => {
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                    what, attempt, attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Records a command the driver gave up on so an admin can inspect or
+/// re-enqueue it later.
+pub async fn dead_letter(db: &Db, cmd: &AppCommand, err: &anyhow::Error) {
+    let command = match serde_json::to_string(cmd) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to serialize dead letter: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = db
+        .insert_dead_letter(cmd.site_id().as_str(), &command, &format!("{:#}", err))
+        .await
+    {
+        error!("Failed to store dead letter: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_retries_are_bounded() {
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = with_retries(3, Duration::ZERO, "Send", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            anyhow::bail!("homeserver unavailable")
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let calls = AtomicU32::new(0);
+        let result = with_retries(3, Duration::ZERO, "Send", || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => anyhow::bail!("homeserver unavailable"),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refusals_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = with_retries(3, Duration::ZERO, "Send", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Rejected("Comment too large".to_string()).into())
+        })
+        .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let err = result.unwrap_err().context("Send failed");
+        assert_eq!(
+            ack_error(&err),
+            AckError::Rejected("Comment too large".to_string())
+        );
+
+        let err = anyhow::anyhow!("connection refused");
+        assert!(matches!(ack_error(&err), AckError::Failed(_)));
+    }
+}
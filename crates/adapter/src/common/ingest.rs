@@ -0,0 +1,515 @@
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use domain::{protocol, Comment, DeletionKind, IngestEvent, NewAuditEntry, OriginKind, SiteId};
+use matrix_sdk::{
+    ruma::{
+        api::client::room::get_room_event::v3::Request as GetRoomEventRequest,
+        events::room::message::{Relation, RoomMessageEventContent},
+        EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
+    },
+    Client,
+};
+use storage::Db;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::common::matrix_utils::reply_target;
+use crate::{RelayConfig, RuleAction, ThreadReplyTo};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IngestOutcome {
+    Saved,
+    Deleted,
+    Skipped,
+}
+
+/// Builds a `Comment` from a room message. An edit (`m.replace`) yields the
+/// original event's ID with the new content and `updated_at` set.
+#[allow(clippy::too_many_arguments)]
+pub fn comment_from_message(
+    event_id: &EventId,
+    sender: &UserId,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    content: &RoomMessageEventContent,
+    site_id: SiteId,
+    post_slug: String,
+    bot_id: &str,
+    native_name: &str,
+    threads: ThreadReplyTo,
+) -> Result<Comment> {
+    let content_json = serde_json::to_value(content)?;
+
+    let ts_millis: i64 = origin_server_ts.get().into();
+    let current_time = chrono::DateTime::from_timestamp_millis(ts_millis)
+        .unwrap_or_default()
+        .naive_utc();
+
+    let (target_id, final_content_json, updated_at) = match content.relates_to {
+        Some(Relation::Replacement(ref re)) => (
+            re.event_id.to_string(),
+            serde_json::to_value(&re.new_content).unwrap_or(content_json),
+            Some(current_time),
+        ),
+        _ => (event_id.to_string(), content_json, None),
+    };
+
+    let sender_id = sender.to_string();
+    let data = protocol::extract_comment_data(&final_content_json, &sender_id, bot_id, native_name);
+    let origin = data.origin;
+    let origin_kind = protocol::classify_sender(&sender_id, bot_id);
+    let is_federated = bot_id
+        .split_once(':')
+        .is_some_and(|(_, server)| sender.server_name().as_str() != server);
+
+    Ok(Comment {
+        id: target_id,
+        site_id,
+        post_slug,
+        author_id: sender_id.clone(),
+        author_name: data.author_name,
+        is_guest: data.is_guest,
+        origin,
+        origin_kind,
+        verified: origin.is_verified() || data.verified,
+        is_federated,
+        author_server: protocol::author_server(&sender_id, data.is_guest),
+        flagged: false,
+        is_redacted: false,
+        author_fingerprint: data.author_fingerprint,
+        content: data.content,
+        created_at: current_time,
+        updated_at,
+        reply_to: reply_target(content.relates_to.as_ref(), threads),
+        lang: data.lang,
+    })
+}
+
+/// An edit of a comment we never stored only knows its own timestamp, which
+/// `comment_from_message` uses as `created_at`. Look up the original event's
+/// time instead, keeping the edit's time if that fails for any reason: a
+/// comment with a slightly late timestamp beats a lost one.
+pub async fn backfill_created_at(
+    client: &Client,
+    db: &Db,
+    room_id: &RoomId,
+    comment: &mut Comment,
+) {
+    if comment.updated_at.is_none() {
+        return;
+    }
+    match original_created_at(client, db, room_id, &comment.id).await {
+        Ok(Some(created_at)) => comment.created_at = created_at,
+        Ok(None) => {}
+        Err(e) => warn!(
+            "Could not fetch original of edited {}, keeping the edit's time: {:#}",
+            comment.id, e
+        ),
+    }
+}
+
+/// `None` if the original is already stored, so its time is known.
+async fn original_created_at(
+    client: &Client,
+    db: &Db,
+    room_id: &RoomId,
+    event_id: &str,
+) -> Result<Option<NaiveDateTime>> {
+    if db.get_comment_origin(event_id).await?.is_some() {
+        return Ok(None);
+    }
+    let req = GetRoomEventRequest::new(room_id.to_owned(), EventId::parse(event_id)?);
+    let ts = client
+        .send(req, None)
+        .await?
+        .event
+        .deserialize()?
+        .origin_server_ts();
+    Ok(chrono::DateTime::from_timestamp_millis(ts.get().into()).map(|t| t.naive_utc()))
+}
+
+/// Applies the relay's moderation policy to a freshly built comment.
+/// Returns `false` if the comment must be dropped instead of stored.
+pub fn apply_moderation(comment: &mut Comment, relay: &RelayConfig) -> bool {
+    comment.flagged = relay.flag_federated && comment.is_federated;
+    // Blanked edits are deletions; `ingest_comment` handles those.
+    if comment.content.trim().is_empty() {
+        return true;
+    }
+
+    for (rule, action) in relay.moderation.violations(comment) {
+        match action {
+            RuleAction::Drop => {
+                info!("Dropped comment {} ({})", comment.id, rule);
+                return false;
+            }
+            RuleAction::Flag => {
+                info!("Flagged comment {} ({})", comment.id, rule);
+                comment.flagged = true;
+            }
+            RuleAction::Off => {}
+        }
+    }
+    true
+}
+
+pub async fn ingest_comment(
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    room_id: &str,
+    comment: Comment,
+) -> Result<IngestOutcome> {
+    // Only the original sender may replace a comment, as Matrix clients
+    // enforce when rendering edits. Checked before the blank-edit path so a
+    // foreign edit can't delete either.
+    if comment.updated_at.is_some() {
+        if let Some((_, _, author_id)) = db.get_comment_origin(&comment.id).await? {
+            if author_id != comment.author_id {
+                warn!(
+                    "Dropped edit of {} by {}: the comment belongs to {}",
+                    comment.id, comment.author_id, author_id
+                );
+                return Ok(IngestOutcome::Skipped);
+            }
+        }
+    }
+
+    if comment.content.trim().is_empty() {
+        // An edit that blanks the content is treated as a soft delete, so the
+        // stored row doesn't keep showing text the author has since removed.
+        if comment.updated_at.is_some()
+            && ingest_deletion(db, tx, &comment.id, DeletionKind::Redacted).await?
+        {
+            return Ok(IngestOutcome::Deleted);
+        }
+        return Ok(IngestOutcome::Skipped);
+    }
+
+    db.upsert_comment(
+        room_id,
+        comment.site_id.as_str(),
+        &comment.post_slug,
+        &comment,
+    )
+    .await?;
+    info!("Comment synced: {} -> {}", comment.id, comment.content);
+
+    let _ = tx.send(IngestEvent::CommentSaved {
+        site_id: comment.site_id.clone(),
+        post_slug: comment.post_slug.clone(),
+        comment: Box::new(comment),
+        reply_context: None,
+    });
+
+    Ok(IngestOutcome::Saved)
+}
+
+/// The relay only redacts on the admin API's behalf, whether as the bot or,
+/// in appservice mode, as the author's ghost.
+pub fn deletion_kind(redactor: &str, bot_id: &str) -> DeletionKind {
+    match protocol::classify_sender(redactor, bot_id) {
+        OriginKind::Native => DeletionKind::Redacted,
+        _ => DeletionKind::AdminRemoved,
+    }
+}
+
+pub async fn ingest_deletion(
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    comment_id: &str,
+    kind: DeletionKind,
+) -> Result<bool> {
+    match db.delete_comment(comment_id).await? {
+        Some((site_id, post_slug)) => {
+            info!("Broadcasting deletion for {}/{}", site_id, post_slug);
+            let _ = tx.send(IngestEvent::CommentDeleted {
+                site_id,
+                post_slug,
+                comment_id: comment_id.to_string(),
+                kind,
+            });
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Marks a comment the relay has just redacted on the homeserver as removed,
+/// rather than waiting for the redaction to come back through sync, which
+/// may never arrive. When it does, `ingest_redaction` finds nothing left to
+/// do.
+pub async fn ingest_admin_redaction(
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    comment_id: &str,
+) -> Result<bool> {
+    ingest_deletion(db, tx, comment_id, DeletionKind::AdminRemoved).await
+}
+
+/// Applies a redaction seen in a room. A room member removing someone
+/// else's comment is moderation done from a Matrix client, so it goes to the
+/// audit log; the relay's own redactions are logged by the admin API.
+pub async fn ingest_redaction(
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    comment_id: &str,
+    redactor: &str,
+    reason: Option<&str>,
+    bot_id: &str,
+) -> Result<bool> {
+    let kind = deletion_kind(redactor, bot_id);
+    if !ingest_deletion(db, tx, comment_id, kind).await? {
+        return Ok(false);
+    }
+    if kind == DeletionKind::Redacted {
+        if let Some(comment) = db.get_comment(comment_id).await? {
+            if comment.author_id != redactor {
+                let mut entry = NewAuditEntry::new(redactor, "remove_comment", comment.site_id);
+                entry.post_slug = Some(comment.post_slug);
+                entry.comment_id = Some(comment_id.to_string());
+                entry.reason = reason.map(str::to_string);
+                db.insert_audit(&entry).await?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{CommentOrigin, CommentScope, OriginKind};
+
+    fn comment(id: &str, content: &str, edited: bool) -> Comment {
+        let comment = Comment::fixture(id);
+        Comment {
+            author_id: "@cumments_bot:example.com".to_string(),
+            author_name: "Alice".to_string(),
+            is_guest: true,
+            origin: CommentOrigin::Web,
+            origin_kind: OriginKind::Bot,
+            verified: false,
+            content: content.to_string(),
+            updated_at: edited.then_some(comment.created_at),
+            ..comment
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_edit_soft_deletes() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, mut rx) = broadcast::channel(8);
+
+        let outcome = ingest_comment(&db, &tx, "!room:example.com", comment("$a", "hi", false))
+            .await
+            .unwrap();
+        assert_eq!(outcome, IngestOutcome::Saved);
+        let _ = rx.recv().await.unwrap();
+
+        let outcome = ingest_comment(&db, &tx, "!room:example.com", comment("$a", "  ", true))
+            .await
+            .unwrap();
+        assert_eq!(outcome, IngestOutcome::Deleted);
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            IngestEvent::CommentDeleted { ref comment_id, .. } if comment_id == "$a"
+        ));
+
+        let stored = db
+            .list_comments("blog", "hello", CommentScope::All, 10, 0)
+            .await
+            .unwrap();
+        assert!(stored[0].comment.is_redacted);
+        assert!(stored[0].comment.content.is_empty());
+
+        let outcome = ingest_comment(&db, &tx, "!room:example.com", comment("$b", "", false))
+            .await
+            .unwrap();
+        assert_eq!(outcome, IngestOutcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn test_echoed_redaction_is_ignored() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, mut rx) = broadcast::channel(8);
+        ingest_comment(&db, &tx, "!room:example.com", comment("$a", "hi", false))
+            .await
+            .unwrap();
+        let _ = rx.recv().await.unwrap();
+
+        // The guest's delete, then the homeserver echoing our own redaction.
+        let kind = deletion_kind("@cumments:example.com", "@cumments:example.com");
+        assert!(ingest_deletion(&db, &tx, "$a", kind).await.unwrap());
+        assert!(!ingest_deletion(&db, &tx, "$a", kind).await.unwrap());
+
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            IngestEvent::CommentDeleted {
+                kind: DeletionKind::AdminRemoved,
+                ..
+            }
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_edit_keeps_created_at() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, _rx) = broadcast::channel(8);
+        let original = comment("$a", "hi", false);
+        ingest_comment(&db, &tx, "!room:example.com", original.clone())
+            .await
+            .unwrap();
+
+        let mut edit = comment("$a", "hello", true);
+        edit.created_at = original.created_at + chrono::Duration::minutes(5);
+        ingest_comment(&db, &tx, "!room:example.com", edit)
+            .await
+            .unwrap();
+
+        let stored = db
+            .list_comments("blog", "hello", CommentScope::All, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(stored[0].comment.content, "hello");
+        assert_eq!(stored[0].comment.created_at, original.created_at);
+    }
+
+    #[tokio::test]
+    async fn test_edit_by_other_sender_is_dropped() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, _rx) = broadcast::channel(8);
+        ingest_comment(&db, &tx, "!room:example.com", comment("$a", "hi", false))
+            .await
+            .unwrap();
+
+        for content in ["hijacked", ""] {
+            let mut edit = comment("$a", content, true);
+            edit.author_id = "@cumments_bot_other:example.com".to_string();
+            let outcome = ingest_comment(&db, &tx, "!room:example.com", edit)
+                .await
+                .unwrap();
+            assert_eq!(outcome, IngestOutcome::Skipped);
+        }
+
+        let stored = db.get_comment("$a").await.unwrap().unwrap();
+        assert_eq!(stored.content, "hi");
+        assert!(!stored.is_redacted);
+    }
+
+    #[test]
+    fn test_moderation_rules() {
+        let rules = crate::ModerationRules {
+            empty_nickname: RuleAction::Drop,
+            banned_words: vec!["spam".to_string()],
+            banned_words_action: RuleAction::Flag,
+        };
+
+        let mut named = comment("$a", "Buy SPAM now", false);
+        assert_eq!(
+            rules.violations(&named),
+            vec![("banned word", RuleAction::Flag)]
+        );
+        named.content = "hello".to_string();
+        assert!(rules.violations(&named).is_empty());
+
+        let mut blank = comment("$b", "hello", false);
+        blank.author_name = "  ".to_string();
+        assert_eq!(
+            rules.violations(&blank),
+            vec![("empty nickname", RuleAction::Drop)]
+        );
+
+        let off = crate::ModerationRules {
+            banned_words: vec!["spam".to_string()],
+            ..Default::default()
+        };
+        assert!(off.violations(&comment("$c", "spam", false)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_moderation_is_audited() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, _rx) = broadcast::channel(8);
+        let bot = "@cumments:example.com";
+        for id in ["$a", "$b", "$c"] {
+            let mut c = comment(id, "hi", false);
+            c.author_id = "@alice:example.com".to_string();
+            ingest_comment(&db, &tx, "!room:example.com", c)
+                .await
+                .unwrap();
+        }
+
+        // The author's own delete and the relay's redaction aren't moderation.
+        assert!(
+            ingest_redaction(&db, &tx, "$a", "@alice:example.com", None, bot)
+                .await
+                .unwrap()
+        );
+        assert!(ingest_redaction(&db, &tx, "$b", bot, None, bot)
+            .await
+            .unwrap());
+        assert!(
+            ingest_redaction(&db, &tx, "$c", "@mod:example.com", Some("spam"), bot)
+                .await
+                .unwrap()
+        );
+
+        let log = db.list_audit(None, 10, 0).await.unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].actor, "@mod:example.com");
+        assert_eq!(log[0].comment_id.as_deref(), Some("$c"));
+        assert_eq!(log[0].reason.as_deref(), Some("spam"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_redaction_applies_without_echo() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx, mut rx) = broadcast::channel(8);
+        let bot = "@cumments:example.com";
+        ingest_comment(&db, &tx, "!room:example.com", comment("$a", "hi", false))
+            .await
+            .unwrap();
+        let _ = rx.try_recv();
+
+        assert!(ingest_admin_redaction(&db, &tx, "$a").await.unwrap());
+        assert!(db.get_comment("$a").await.unwrap().unwrap().is_redacted);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(IngestEvent::CommentDeleted {
+                kind: DeletionKind::AdminRemoved,
+                ..
+            })
+        ));
+
+        // The echo, if it comes, changes nothing and isn't broadcast again.
+        assert!(!ingest_redaction(&db, &tx, "$a", bot, None, bot)
+            .await
+            .unwrap());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backfill_failure_keeps_the_edit() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let client = Client::builder()
+            .homeserver_url("http://127.0.0.1:9")
+            .request_config(matrix_sdk::config::RequestConfig::new().disable_retry())
+            .build()
+            .await
+            .unwrap();
+        let room_id = RoomId::parse("!room:example.com").unwrap();
+
+        for id in ["not-an-event-id", "$unreachable"] {
+            let mut edit = comment(id, "fixed typo", true);
+            let edited_at = edit.created_at;
+            backfill_created_at(&client, &db, &room_id, &mut edit).await;
+            assert_eq!(edit.created_at, edited_at);
+
+            let (tx, _rx) = broadcast::channel(8);
+            let outcome = ingest_comment(&db, &tx, room_id.as_str(), edit)
+                .await
+                .unwrap();
+            assert_eq!(outcome, IngestOutcome::Saved);
+        }
+    }
+}
@@ -1,36 +1,248 @@
 use anyhow::Result;
-use domain::SiteId;
+use async_trait::async_trait;
+use domain::{
+    protocol::{self, AliasScheme},
+    SiteId, SpaceLinkReport,
+};
+use hmac::{Hmac, Mac};
+use matrix_sdk::reqwest::Url;
 use matrix_sdk::{
+    config::RequestConfig,
     deserialized_responses::SyncOrStrippedState,
     ruma::{
+        api::client::discovery::get_supported_versions::Request as VersionsRequest,
+        api::client::error::ErrorKind,
+        api::client::redact::redact_event::v3::Request as RedactRequest,
         api::client::room::create_room::v3::Request as CreateRoomRequest,
         api::client::room::create_room::v3::RoomPreset,
         api::client::state::get_state_events_for_key::v3::Request as GetStateRequest,
+        api::MatrixVersion,
         events::{
             room::canonical_alias::RoomCanonicalAliasEventContent,
-            space::child::SpaceChildEventContent, StateEventType, SyncStateEvent,
+            room::message::{Relation, RoomMessageEventContentWithoutRelation},
+            space::child::SpaceChildEventContent,
+            AnyMessageLikeEventContent, StateEventType, SyncStateEvent,
         },
         room::RoomType,
         serde::Raw,
-        OwnedRoomId, RoomAliasId, ServerName,
+        EventId, OwnedRoomId, RoomAliasId, RoomId, ServerName, TransactionId,
     },
-    Client, Room,
+    Client, ClientBuilder, Room, RoomState,
 };
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::future::Future;
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use storage::Db;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tracing::{error, info, warn};
+
+use crate::common::dead_letter::Rejected;
+use crate::traits::EventSink;
+use crate::{RelayConfig, ThreadReplyTo};
+
+/// Each key's mutex and how many holders and waiters it has.
+type LockMap = Arc<std::sync::Mutex<HashMap<String, (Arc<Mutex<()>>, usize)>>>;
+
+/// One async mutex per key, created on first use. Holders of the same key
+/// run one at a time; different keys never wait on each other. A key's
+/// entry goes away with its last holder or waiter, so the map only holds
+/// keys in use.
+#[derive(Clone, Default)]
+pub struct KeyedLocks(LockMap);
+
+impl KeyedLocks {
+    pub async fn lock(&self, key: &str) -> KeyedGuard {
+        let lock = {
+            let mut map = self.0.lock().unwrap();
+            let (lock, users) = map.entry(key.to_string()).or_default();
+            *users += 1;
+            lock.clone()
+        };
+        // Registered before waiting, so a waiter that is cancelled still
+        // gives its place up.
+        let user = KeyUser {
+            key: key.to_string(),
+            map: self.0.clone(),
+        };
+        KeyedGuard {
+            _guard: lock.lock_owned().await,
+            _user: user,
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// Held lock on one key of `KeyedLocks`.
+pub struct KeyedGuard {
+    _guard: OwnedMutexGuard<()>,
+    _user: KeyUser,
+}
 
+struct KeyUser {
+    key: String,
+    map: LockMap,
+}
+
+impl Drop for KeyUser {
+    fn drop(&mut self) {
+        let mut map = self.map.lock().unwrap();
+        if let Some((_, users)) = map.get_mut(&self.key) {
+            *users -= 1;
+            if *users == 0 {
+                map.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct SpaceCache {
     inner: Arc<RwLock<HashMap<String, OwnedRoomId>>>,
+    alias_locks: KeyedLocks,
 }
 
 impl SpaceCache {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(HashMap::new())),
+            alias_locks: KeyedLocks::default(),
+        }
+    }
+
+    /// Serializes room creation per alias within this process.
+    pub async fn lock_alias(&self, alias: &str) -> KeyedGuard {
+        self.alias_locks.lock(alias).await
+    }
+}
+
+/// Check-then-create for a room alias. Creation runs under the alias lock
+/// after resolving again, so concurrent first comments on a new slug create
+/// one room and the rest pick it up.
+pub async fn resolve_or_create<T, R, RF, C, CF>(
+    cache: &SpaceCache,
+    alias: &str,
+    resolve: R,
+    create: C,
+) -> Result<T>
+where
+    R: Fn() -> RF,
+    RF: Future<Output = Option<T>>,
+    C: FnOnce() -> CF,
+    CF: Future<Output = Result<T>>,
+{
+    if let Some(found) = resolve().await {
+        return Ok(found);
+    }
+    let _guard = cache.lock_alias(alias).await;
+    if let Some(found) = resolve().await {
+        return Ok(found);
+    }
+    create().await
+}
+
+impl Default for SpaceCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A client builder with the relay's request timeouts, so a slow homeserver
+/// fails a command instead of stalling it.
+pub fn client_builder(homeserver_url: &str, relay: &RelayConfig) -> ClientBuilder {
+    let mut request = RequestConfig::new().timeout(relay.request_timeout);
+    if let Some(retry) = relay.request_retry {
+        request = request.retry_timeout(retry);
+    }
+    Client::builder()
+        .homeserver_url(homeserver_url)
+        .request_config(request)
+}
+
+/// Fails fast on a malformed or unreachable homeserver URL, before the SDK
+/// turns it into a confusing error on the first real request.
+pub async fn probe_homeserver(homeserver_url: &str, timeout: Duration) -> Result<()> {
+    let url = Url::parse(homeserver_url)
+        .map_err(|e| anyhow::anyhow!("Invalid homeserver URL {:?}: {}", homeserver_url, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!(
+            "Homeserver URL {:?} must use http:// or https://",
+            homeserver_url
+        );
+    }
+
+    let versions_url = url.join("_matrix/client/versions")?;
+    let http = matrix_sdk::reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?;
+    let resp = http.get(versions_url.clone()).send().await.map_err(|e| {
+        anyhow::anyhow!(
+            "Homeserver {} is unreachable (timeout {:?}): {}",
+            url,
+            timeout,
+            e
+        )
+    })?;
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "Homeserver probe {} returned {}; is this a Matrix homeserver?",
+            versions_url,
+            resp.status()
+        );
+    }
+    Ok(())
+}
+
+/// Probes `/versions` and `/capabilities` so an old or restricted homeserver
+/// shows up as a startup diagnostic instead of confusing runtime failures.
+pub async fn check_homeserver_support(client: &Client, relay: &RelayConfig) -> Result<()> {
+    let versions = match client.send(VersionsRequest::new(), None).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Could not probe homeserver versions: {:?}", e);
+            return Ok(());
+        }
+    };
+    let known: Vec<MatrixVersion> = versions.known_versions().collect();
+
+    let missing = missing_features(&known, relay);
+    if !known.contains(&MatrixVersion::V1_4) {
+        warn!("Homeserver does not advertise Matrix v1.4: edits (m.replace) and threads may misbehave");
+    }
+
+    match client.get_capabilities().await {
+        Ok(caps) if !caps.set_displayname.enabled => {
+            warn!("Homeserver disallows display name changes: guest nicknames won't show on ghost users")
         }
+        Ok(_) => {}
+        Err(e) => warn!("Could not fetch homeserver capabilities: {:?}", e),
+    }
+
+    if missing.is_empty() {
+        info!("Homeserver supports all required features");
+        return Ok(());
+    }
+
+    let msg = format!("Homeserver lacks required features: {}", missing.join(", "));
+    if relay.strict_capabilities {
+        anyhow::bail!(msg);
+    }
+    warn!("{}", msg);
+    Ok(())
+}
+
+/// Required features absent from `known`; spaces only matter when enabled.
+fn missing_features(known: &[MatrixVersion], relay: &RelayConfig) -> Vec<&'static str> {
+    let mut missing = Vec::new();
+    if relay.use_spaces && !known.contains(&MatrixVersion::V1_2) {
+        missing.push("spaces (Matrix v1.2)");
     }
+    missing
 }
 
 pub async fn resolve_room_alias_chain(room: &Room, client: &Client) -> Option<String> {
@@ -80,41 +292,373 @@ pub async fn resolve_room_alias_chain(room: &Room, client: &Client) -> Option<St
     None
 }
 
+/// The comment a message replies to. Relations other than replies and
+/// threads (edits, annotations, unknown types) leave it top-level.
+pub fn reply_target(
+    relation: Option<&Relation<RoomMessageEventContentWithoutRelation>>,
+    threads: ThreadReplyTo,
+) -> Option<String> {
+    match relation? {
+        Relation::Reply { in_reply_to } => Some(in_reply_to.event_id.to_string()),
+        Relation::Thread(thread) => match (&thread.in_reply_to, thread.is_falling_back, threads) {
+            (Some(parent), false, ThreadReplyTo::Parent) => Some(parent.event_id.to_string()),
+            _ => Some(thread.event_id.to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// Makes the event a reply to `parent_id`. The relation is dropped, and the
+/// comment sent top-level, unless the parent is a known comment in `room_id`:
+/// a reply pointing into another room would confuse Matrix clients. A
+/// thread's root is looked for at most `max_depth` comments up.
+pub async fn attach_reply_relation(
+    db: &Db,
+    event_json: &mut serde_json::Value,
+    room_id: &str,
+    parent_id: &str,
+    use_threads: bool,
+    max_depth: u32,
+) -> Result<()> {
+    if EventId::parse(parent_id).is_err() {
+        error!("Invalid reply_to ID: {}", parent_id);
+        return Ok(());
+    }
+    match db.get_comment_origin(parent_id).await? {
+        Some((parent_room, _, _)) if parent_room == room_id => {}
+        Some((parent_room, _, _)) => {
+            warn!(
+                "Dropping reply_to {}: it is in {}, not {}",
+                parent_id, parent_room, room_id
+            );
+            return Ok(());
+        }
+        None => {
+            warn!("Dropping reply_to {}: no such comment", parent_id);
+            return Ok(());
+        }
+    }
+
+    let thread_root = if use_threads {
+        // Past the limit the farthest ancestor found stands in for the root;
+        // the API refuses such replies, so only Matrix clients get here.
+        let mut ancestors = db.get_ancestors(parent_id, max_depth).await?;
+        ancestors.truncate(max_depth as usize);
+        Some(ancestors.pop().unwrap_or_else(|| parent_id.to_string()))
+    } else {
+        None
+    };
+
+    if let Some(obj) = event_json.as_object_mut() {
+        obj.insert(
+            "m.relates_to".to_string(),
+            protocol::build_reply_relation(parent_id, thread_root.as_deref()),
+        );
+    }
+    Ok(())
+}
+
+/// The relay reads and writes plaintext only; an encrypted event can't be
+/// stored, and a plaintext one sent into an encrypted room would be refused
+/// by members' clients.
+pub const E2EE_UNSUPPORTED: &str = "comment rooms must be unencrypted";
+
+/// Refuses to send into `room` if it has encryption on. Pass the room as the
+/// long-lived bot client sees it: that client caches the room's encryption
+/// state after the first lookup, while a freshly built ghost client would
+/// ask the homeserver again on every send.
+pub async fn ensure_unencrypted(room: &Room) -> Result<()> {
+    refuse_encrypted(room.room_id(), room.is_encrypted().await?)
+}
+
+fn refuse_encrypted(room_id: &RoomId, encrypted: bool) -> Result<()> {
+    if encrypted {
+        return Err(Rejected(format!(
+            "Room {} is encrypted: {}",
+            room_id, E2EE_UNSUPPORTED
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl EventSink for Room {
+    async fn send_event(&self, content: serde_json::Value, txn_id: &TransactionId) -> Result<()> {
+        let raw: Raw<AnyMessageLikeEventContent> = serde_json::from_value(content)?;
+        self.send_raw("m.room.message", raw)
+            .with_transaction_id(txn_id)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Last steps shared by every driver before an event leaves: the reply
+/// relation, the size check, then the sink.
+pub async fn execute_send(
+    sink: &impl EventSink,
+    db: &Db,
+    room_id: &str,
+    mut event_json: serde_json::Value,
+    reply_to: Option<&str>,
+    relay: &RelayConfig,
+    txn_id: &TransactionId,
+) -> Result<()> {
+    if let Some(parent_id) = reply_to {
+        attach_reply_relation(
+            db,
+            &mut event_json,
+            room_id,
+            parent_id,
+            relay.use_threads,
+            relay.max_reply_depth,
+        )
+        .await?;
+    }
+    ensure_event_size(&event_json, relay.max_event_bytes)?;
+    sink.send_event(event_json, txn_id).await
+}
+
+/// Homeservers reject events over 65536 bytes with an opaque error; checking
+/// the fully expanded event (metadata, relations) lets us fail clearly first.
+pub fn ensure_event_size(event_json: &serde_json::Value, max_bytes: usize) -> Result<()> {
+    let size = serde_json::to_vec(event_json)?.len();
+    if size > max_bytes {
+        return Err(Rejected(format!(
+            "Comment too large: event is {} bytes, limit is {}",
+            size, max_bytes
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+pub enum RedactOutcome {
+    Redacted,
+    /// The homeserver refused because the client lacks redact power in the
+    /// room, e.g. a bot redacting a native user's event in a `PublicChat` room.
+    Forbidden,
+}
+
+/// Redacts an event by sending the request directly, so it works for clients
+/// that have not synced the room. A refusal from a client that is no longer
+/// joined (e.g. the bot was kicked) triggers one rejoin and retry; if the
+/// rejoin itself fails, that is the error reported.
+pub async fn redact_event(
+    client: &Client,
+    room_id: &str,
+    event_id: &str,
+    reason: Option<&str>,
+) -> Result<RedactOutcome> {
+    let room_id = RoomId::parse(room_id)?;
+    let event_id = EventId::parse(event_id)?;
+
+    let outcome = send_redaction(client, &room_id, &event_id, reason).await?;
+    let joined = client
+        .get_room(&room_id)
+        .is_some_and(|r| r.state() == RoomState::Joined);
+    if matches!(outcome, RedactOutcome::Redacted) || joined {
+        return Ok(outcome);
+    }
+
+    if let Err(e) = client.join_room_by_id(&room_id).await {
+        anyhow::bail!(
+            "{} is not in {} and could not rejoin to redact {}: {}",
+            client.user_id().map(|u| u.as_str()).unwrap_or("client"),
+            room_id,
+            event_id,
+            e
+        );
+    }
+    info!("Rejoined {} to redact {}", room_id, event_id);
+    send_redaction(client, &room_id, &event_id, reason).await
+}
+
+async fn send_redaction(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    reason: Option<&str>,
+) -> Result<RedactOutcome> {
+    let mut req = RedactRequest::new(
+        room_id.to_owned(),
+        event_id.to_owned(),
+        TransactionId::new(),
+    );
+    req.reason = reason.map(str::to_string);
+
+    match client.send(req, None).await {
+        Ok(_) => Ok(RedactOutcome::Redacted),
+        Err(e) if e.client_api_error_kind() == Some(&ErrorKind::Forbidden) => {
+            Ok(RedactOutcome::Forbidden)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `create_room` failed because the alias is already taken, typically by
+/// another instance creating the same room concurrently.
+pub fn is_alias_conflict(err: &matrix_sdk::Error) -> bool {
+    err.client_api_error_kind() == Some(&ErrorKind::RoomInUse)
+}
+
+pub async fn resolve_conflicting_alias(client: &Client, alias: &str) -> Result<OwnedRoomId> {
+    let room_alias = RoomAliasId::parse(alias)?;
+    let resp = client.resolve_room_alias(&room_alias).await?;
+    info!(
+        "Alias {} was taken concurrently, using {}",
+        alias, resp.room_id
+    );
+    Ok(resp.room_id)
+}
+
+/// Creates the room for a thread and, if `space_id` is given, links it into
+/// the site's space.
 pub async fn create_and_link_room(
     client: &Client,
     server_name: &ServerName,
-    space_id: &OwnedRoomId,
+    aliases: &AliasScheme,
+    space_id: Option<&OwnedRoomId>,
     site_id: &SiteId,
     slug: &str,
 ) -> Result<Room> {
-    let alias_local = format!("{}_{}", site_id.as_str(), slug);
+    let alias_local = aliases.localpart(site_id, slug);
     let mut req = CreateRoomRequest::new();
     req.room_alias_name = Some(alias_local);
     req.name = Some(format!("Comments for {}", slug));
     req.preset = Some(RoomPreset::PublicChat);
 
     info!("Creating new room for slug: {}", slug);
-    let room = client.create_room(req).await?;
+    let room = match client.create_room(req).await {
+        Ok(room) => room,
+        Err(e) if is_alias_conflict(&e) => {
+            // Another instance won the race; it also links the room.
+            let alias = aliases.format(site_id, slug, server_name.as_str());
+            let room_id = resolve_conflicting_alias(client, &alias).await?;
+            return match client.get_room(&room_id) {
+                Some(room) => Ok(room),
+                None => Ok(client.join_room_by_id(&room_id).await?),
+            };
+        }
+        Err(e) => return Err(e.into()),
+    };
 
-    let space_room_opt = if let Some(r) = client.get_room(space_id) {
-        Some(r)
-    } else {
-        client.join_room_by_id(space_id).await.ok()
+    let Some(space_id) = space_id else {
+        return Ok(room);
     };
+    // A failed link leaves the room usable but outside the space, which
+    // `ensure_space_links` repairs later.
+    match link_to_space(client, server_name, space_id, room.room_id()).await {
+        Ok(()) => info!("Linked new room {} to space", room.room_id()),
+        Err(e) => warn!("Failed to link room to space: {:?}", e),
+    }
+    Ok(room)
+}
 
-    if let Some(space_room) = space_room_opt {
-        let server_name_owned = server_name.to_owned();
-        let child = SpaceChildEventContent::new(vec![server_name_owned]);
-        if let Err(e) = space_room
-            .send_state_event_for_key(room.room_id(), child)
-            .await
-        {
-            warn!("Failed to link room to space: {:?}", e);
-        } else {
-            info!("Linked new room {} to space", room.room_id());
+/// Adds `room_id` to the space as a child, joining the space first if needed.
+async fn link_to_space(
+    client: &Client,
+    server_name: &ServerName,
+    space_id: &RoomId,
+    room_id: &RoomId,
+) -> Result<()> {
+    let space_room = match client.get_room(space_id) {
+        Some(r) => r,
+        None => client.join_room_by_id(space_id).await?,
+    };
+    let child = SpaceChildEventContent::new(vec![server_name.to_owned()]);
+    space_room.send_state_event_for_key(room_id, child).await?;
+    Ok(())
+}
+
+/// Whether the space lists `room_id` as a child. A child event without
+/// `via` servers counts as removed, per the spec.
+async fn is_space_child(client: &Client, space_id: &RoomId, room_id: &RoomId) -> Result<bool> {
+    let req = GetStateRequest::new(
+        space_id.to_owned(),
+        StateEventType::SpaceChild,
+        room_id.to_string(),
+    );
+    match client.send(req, None).await {
+        Ok(response) => Ok(response
+            .content
+            .deserialize_as::<SpaceChildEventContent>()
+            .is_ok_and(|c| !c.via.is_empty())),
+        Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Re-sends the space child event for each of the site's rooms its space
+/// doesn't list, e.g. because linking failed when the room was created.
+pub async fn ensure_space_links(
+    client: &Client,
+    server_name: &ServerName,
+    cache: &SpaceCache,
+    db: &Db,
+    site_id: &SiteId,
+    relay: &RelayConfig,
+) -> Result<SpaceLinkReport> {
+    let Some(space_id) = site_space(client, server_name, cache, site_id, relay).await? else {
+        return Err(Rejected("Spaces are disabled (relay.use_spaces)".to_string()).into());
+    };
+
+    let rooms = db.site_rooms(site_id.as_str()).await?;
+    let space_id = &space_id;
+    Ok(link_missing(rooms, site_id, |room_id| async move {
+        if is_space_child(client, space_id, &room_id).await? {
+            return Ok(false);
+        }
+        link_to_space(client, server_name, space_id, &room_id).await?;
+        Ok(true)
+    })
+    .await)
+}
+
+/// Runs `link` on every room, which returns whether it had to link it. A
+/// room that fails, or whose stored ID doesn't parse, counts as `failed`
+/// and the rest still get their turn.
+async fn link_missing<F, Fut>(rooms: Vec<String>, site_id: &SiteId, link: F) -> SpaceLinkReport
+where
+    F: Fn(OwnedRoomId) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let mut report = SpaceLinkReport::default();
+    for room in rooms {
+        report.checked += 1;
+        let result = match RoomId::parse(&room) {
+            Ok(room_id) => link(room_id).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                info!("Re-linked room {} to the {} space", room, site_id);
+                report.linked += 1;
+            }
+            Err(e) => {
+                warn!("Failed to re-link room {} to space: {:?}", room, e);
+                report.failed += 1;
+            }
         }
     }
-    Ok(room)
+    report
+}
+
+/// The site's space, or `None` when `relay.use_spaces` is off.
+pub async fn site_space(
+    client: &Client,
+    server_name: &ServerName,
+    cache: &SpaceCache,
+    site_id: &SiteId,
+    relay: &RelayConfig,
+) -> Result<Option<OwnedRoomId>> {
+    if !relay.use_spaces {
+        return Ok(None);
+    }
+    Ok(Some(
+        ensure_site_space(client, server_name, cache, site_id).await?,
+    ))
 }
 
 pub async fn ensure_site_space(
@@ -161,7 +705,45 @@ pub async fn ensure_site_space(
     Ok(room_id)
 }
 
-pub fn compute_user_fingerprint(email: Option<&str>, guest_token: &str, salt: &str) -> String {
+/// Upper bound for `fingerprint_rounds`. Every comment and report pays this
+/// cost, so it stays in the tens of milliseconds.
+pub const MAX_FINGERPRINT_ROUNDS: u32 = 200_000;
+
+/// Salt for guest fingerprints. With `per_site` each site gets its own,
+/// `hmac_sha256(global, site_id)`, so one person's fingerprints can't be
+/// linked across the sites an instance hosts.
+#[derive(Clone)]
+pub struct IdentitySalt {
+    global: String,
+    per_site: bool,
+}
+
+impl IdentitySalt {
+    pub fn new(global: String, per_site: bool) -> Self {
+        Self { global, per_site }
+    }
+
+    pub fn for_site(&self, site_id: &SiteId) -> Cow<'_, str> {
+        if !self.per_site {
+            return Cow::Borrowed(&self.global);
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.global.as_bytes())
+            .expect("HMAC accepts any key length");
+        mac.update(site_id.as_str().as_bytes());
+        Cow::Owned(hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+/// Stable guest identity derived from the email, or else the guest token.
+/// One round gives the original 12 hex digits. More rounds iterate SHA-256
+/// to slow down guessing low-entropy tokens, and yield `k{rounds}x{hex}`, so
+/// the cost travels with the fingerprint. Both forms are valid localparts.
+pub fn compute_user_fingerprint(
+    email: Option<&str>,
+    guest_token: &str,
+    salt: &str,
+    rounds: u32,
+) -> String {
     let seed = if let Some(e) = email {
         format!("email:{}", e.trim().to_lowercase())
     } else {
@@ -171,7 +753,373 @@ pub fn compute_user_fingerprint(email: Option<&str>, guest_token: &str, salt: &s
     let mut hasher = Sha256::new();
     hasher.update(seed.as_bytes());
     hasher.update(salt.as_bytes());
-    let result = hasher.finalize();
+    let mut result = hasher.finalize();
+    if rounds <= 1 {
+        return hex::encode(&result[..6]);
+    }
 
-    hex::encode(&result[..6])
+    // The label must name the work actually done, or two settings would
+    // label the same hash differently.
+    let rounds = rounds.min(MAX_FINGERPRINT_ROUNDS);
+    for _ in 1..rounds {
+        let mut hasher = Sha256::new();
+        hasher.update(result);
+        hasher.update(salt.as_bytes());
+        result = hasher.finalize();
+    }
+    format!("k{}x{}", rounds, hex::encode(&result[..8]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Keeps sent events instead of talking to a homeserver.
+    #[derive(Default)]
+    struct CapturedEvents(std::sync::Mutex<Vec<serde_json::Value>>);
+
+    #[async_trait]
+    impl EventSink for CapturedEvents {
+        async fn send_event(&self, content: serde_json::Value, _: &TransactionId) -> Result<()> {
+            self.0.lock().unwrap().push(content);
+            Ok(())
+        }
+    }
+
+    fn relay() -> RelayConfig {
+        RelayConfig {
+            use_threads: false,
+            max_event_bytes: 60000,
+            strict_capabilities: true,
+            native_name_fallback: domain::protocol::NativeNameFallback::Mxid,
+            probe_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            request_retry: None,
+            flag_federated: false,
+            use_spaces: true,
+            body_prefix: None,
+            thread_reply_to: ThreadReplyTo::Parent,
+            room_alias: AliasScheme::default(),
+            moderation: Default::default(),
+            max_reply_depth: 64,
+            command_attempts: 1,
+        }
+    }
+
+    /// Stores a native comment in room `!a:x`.
+    async fn store_comment(db: &Db, id: &str, reply_to: Option<&str>) {
+        let comment = domain::Comment {
+            post_slug: "a".to_string(),
+            reply_to: reply_to.map(str::to_string),
+            ..domain::Comment::fixture(id)
+        };
+        db.upsert_comment("!a:x", "blog", "a", &comment)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_first_posts_create_one_room() {
+        let cache = Arc::new(SpaceCache::new());
+        let room: Arc<std::sync::Mutex<Option<String>>> = Arc::default();
+        let created = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let (cache, room, created) = (cache.clone(), room.clone(), created.clone());
+                tokio::spawn(async move {
+                    resolve_or_create(
+                        &cache,
+                        "#blog_new:example.org",
+                        || {
+                            let found = room.lock().unwrap().clone();
+                            async move { found }
+                        },
+                        || async {
+                            created.fetch_add(1, Ordering::SeqCst);
+                            // Widen the race window, as a real create_room would.
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            let id = "!room:example.org".to_string();
+                            *room.lock().unwrap() = Some(id.clone());
+                            Ok(id)
+                        },
+                    )
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "!room:example.org");
+        }
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+        // Every poster has let go of the alias, so its lock is gone too.
+        assert_eq!(cache.alias_locks.len(), 0);
+    }
+
+    #[test]
+    fn test_spaces_required_only_when_enabled() {
+        let mut relay = relay();
+        let old = [MatrixVersion::V1_1];
+        assert_eq!(missing_features(&old, &relay), ["spaces (Matrix v1.2)"]);
+        assert!(missing_features(&[MatrixVersion::V1_2], &relay).is_empty());
+
+        relay.use_spaces = false;
+        assert!(missing_features(&old, &relay).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_other_room_is_dropped() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        store_comment(&db, "$parent:x", None).await;
+
+        for (room, parent_id, attached) in [
+            ("!a:x", "$parent:x", true),
+            ("!b:x", "$parent:x", false),
+            ("!a:x", "$unknown:x", false),
+        ] {
+            let mut event = serde_json::json!({ "body": "re" });
+            attach_reply_relation(&db, &mut event, room, parent_id, false, 64)
+                .await
+                .unwrap();
+            assert_eq!(
+                event.get("m.relates_to").is_some(),
+                attached,
+                "{room} {parent_id}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_thread_reply_target_modes() {
+        use matrix_sdk::ruma::{events::relation::Thread, owned_event_id};
+
+        let reply = Relation::Thread(Thread::reply(
+            owned_event_id!("$root:x"),
+            owned_event_id!("$parent:x"),
+        ));
+        let plain = Relation::Thread(Thread::plain(
+            owned_event_id!("$root:x"),
+            owned_event_id!("$latest:x"),
+        ));
+
+        let target = |r, mode| reply_target(Some(r), mode).unwrap();
+        assert_eq!(target(&reply, ThreadReplyTo::Parent), "$parent:x");
+        assert_eq!(target(&reply, ThreadReplyTo::Root), "$root:x");
+        assert_eq!(target(&plain, ThreadReplyTo::Parent), "$root:x");
+        assert_eq!(target(&plain, ThreadReplyTo::Root), "$root:x");
+    }
+
+    #[tokio::test]
+    async fn test_outbound_event_wire_format() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        store_comment(&db, "$root:x", None).await;
+        store_comment(&db, "$parent:x", Some("$root:x")).await;
+        let sink = CapturedEvents::default();
+        let event = || {
+            protocol::build_outbound_event(
+                "Eve <3",
+                "*hi*",
+                Some("fp".to_string()),
+                Some("en".to_string()),
+                true,
+                Some("[c] "),
+            )
+        };
+
+        let mut relay = relay();
+        execute_send(
+            &sink,
+            &db,
+            "!a:x",
+            event(),
+            None,
+            &relay,
+            &TransactionId::new(),
+        )
+        .await
+        .unwrap();
+        execute_send(
+            &sink,
+            &db,
+            "!a:x",
+            event(),
+            Some("$parent:x"),
+            &relay,
+            &TransactionId::new(),
+        )
+        .await
+        .unwrap();
+        relay.use_threads = true;
+        execute_send(
+            &sink,
+            &db,
+            "!a:x",
+            event(),
+            Some("$parent:x"),
+            &relay,
+            &TransactionId::new(),
+        )
+        .await
+        .unwrap();
+        relay.max_event_bytes = 100;
+        assert!(execute_send(
+            &sink,
+            &db,
+            "!a:x",
+            event(),
+            None,
+            &relay,
+            &TransactionId::new()
+        )
+        .await
+        .is_err());
+
+        let sent = sink.0.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0],
+            serde_json::json!({
+                "msgtype": "m.text",
+                "body": "[c] **Eve <3** (Guest): *hi*",
+                "com.cumments.v1": {
+                    "version": 1,
+                    "author_name": "Eve <3",
+                    "is_guest": true,
+                    "origin_content": "*hi*",
+                    "author_fingerprint": "fp",
+                    "lang": "en",
+                    "verified": true
+                }
+            })
+        );
+        assert_eq!(
+            sent[1]["m.relates_to"],
+            serde_json::json!({ "m.in_reply_to": { "event_id": "$parent:x" } })
+        );
+        assert_eq!(
+            sent[2]["m.relates_to"],
+            serde_json::json!({
+                "rel_type": "m.thread",
+                "event_id": "$root:x",
+                "is_falling_back": false,
+                "m.in_reply_to": { "event_id": "$parent:x" }
+            })
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_rounds() {
+        let legacy = compute_user_fingerprint(None, "tok", "salt", 1);
+        assert_eq!(legacy.len(), 12);
+        assert_eq!(legacy, compute_user_fingerprint(None, "tok", "salt", 0));
+
+        let slow = compute_user_fingerprint(None, "tok", "salt", 1000);
+        assert!(slow.starts_with("k1000x"));
+        assert_eq!(slow.len(), "k1000x".len() + 16);
+        assert_ne!(slow, compute_user_fingerprint(None, "tok", "salt", 1001));
+        assert!(slow
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+        assert_eq!(
+            compute_user_fingerprint(Some(" A@x.org"), "tok", "salt", 50),
+            compute_user_fingerprint(Some("a@x.org"), "ignored", "salt", 50)
+        );
+
+        let max = compute_user_fingerprint(None, "tok", "salt", MAX_FINGERPRINT_ROUNDS);
+        assert!(max.starts_with(&format!("k{}x", MAX_FINGERPRINT_ROUNDS)));
+        assert_eq!(
+            compute_user_fingerprint(None, "tok", "salt", MAX_FINGERPRINT_ROUNDS + 1),
+            max
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keyed_locks_serialize_per_key() {
+        let locks = KeyedLocks::default();
+        let held = locks.lock("!a:x").await;
+
+        let other = tokio::time::timeout(Duration::from_millis(50), locks.lock("!b:x")).await;
+        assert!(other.is_ok(), "a different key must not wait");
+        drop(other);
+
+        let same = tokio::time::timeout(Duration::from_millis(50), locks.lock("!a:x")).await;
+        assert!(same.is_err(), "the same key must wait for the holder");
+
+        drop(held);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), locks.lock("!a:x"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(locks.len(), 0);
+
+        // Released while another task waits: the entry stays for the waiter.
+        let held = locks.lock("!a:x").await;
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move { drop(locks.lock("!a:x").await) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(held);
+        waiter.await.unwrap();
+        assert_eq!(locks.len(), 0);
+
+        // A waiter that gives up doesn't leave its key behind either.
+        let held = locks.lock("!a:x").await;
+        let gave_up = tokio::time::timeout(Duration::from_millis(20), locks.lock("!a:x")).await;
+        assert!(gave_up.is_err());
+        drop(held);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[test]
+    fn test_per_site_salt() {
+        let blog = SiteId::new_unchecked("blog".to_string());
+        let docs = SiteId::new_unchecked("docs".to_string());
+
+        let shared = IdentitySalt::new("salt".to_string(), false);
+        assert_eq!(shared.for_site(&blog), "salt");
+        assert_eq!(shared.for_site(&docs), "salt");
+
+        let scoped = IdentitySalt::new("salt".to_string(), true);
+        assert_ne!(scoped.for_site(&blog), scoped.for_site(&docs));
+        assert_eq!(scoped.for_site(&blog), scoped.for_site(&blog));
+        assert_ne!(
+            compute_user_fingerprint(None, "tok", &scoped.for_site(&blog), 1),
+            compute_user_fingerprint(None, "tok", &scoped.for_site(&docs), 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_space_links_survive_bad_rooms() {
+        let site = SiteId::new_unchecked("blog".to_string());
+        let rooms = ["!linked:x", "not a room id", "!missing:x", "!broken:x"]
+            .map(String::from)
+            .to_vec();
+        let report = link_missing(rooms, &site, |room_id| async move {
+            match room_id.as_str() {
+                "!linked:x" => Ok(false),
+                "!missing:x" => Ok(true),
+                _ => anyhow::bail!("forbidden"),
+            }
+        })
+        .await;
+        assert_eq!((report.checked, report.linked, report.failed), (4, 1, 2));
+    }
+
+    #[test]
+    fn test_encrypted_rooms_are_refused() {
+        let room_id = RoomId::parse("!a:x").unwrap();
+        assert!(refuse_encrypted(&room_id, false).is_ok());
+
+        let err = refuse_encrypted(&room_id, true).unwrap_err();
+        assert!(matches!(
+            crate::common::dead_letter::ack_error(&err),
+            domain::AckError::Rejected(msg) if msg.contains(E2EE_UNSUPPORTED)
+        ));
+    }
 }
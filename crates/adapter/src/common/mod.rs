@@ -1 +1,4 @@
+pub mod dead_letter;
+pub mod ingest;
 pub mod matrix_utils;
+pub mod resync;
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use domain::{
+    protocol::{self, AliasScheme},
+    IngestEvent, ResyncReport, SiteId,
+};
+use matrix_sdk::{
+    room::{MessagesOptions, Room},
+    ruma::{
+        events::{
+            room::message::RoomMessageEvent, room::redaction::RoomRedactionEvent,
+            AnyMessageLikeEvent, AnyTimelineEvent,
+        },
+        uint, OwnedRoomId, RoomAliasId,
+    },
+    Client,
+};
+use storage::Db;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::common::ingest::{
+    apply_moderation, backfill_created_at, comment_from_message, ingest_comment, ingest_redaction,
+    IngestOutcome,
+};
+use crate::RelayConfig;
+
+/// Upper bound on events fetched per resync, so a huge room can't stall the
+/// command worker.
+const MAX_RESYNC_EVENTS: usize = 1000;
+
+/// Finds the room for a thread, preferring the local mapping and falling back
+/// to the room alias on the homeserver.
+pub async fn resolve_thread_room(
+    client: &Client,
+    db: &Db,
+    aliases: &AliasScheme,
+    server_name: &str,
+    site_id: &SiteId,
+    slug: &str,
+) -> Result<Room> {
+    let room_id = match db.get_room_id(site_id.as_str(), slug).await? {
+        Some(id) => OwnedRoomId::try_from(id)?,
+        None => {
+            let alias = aliases.format(site_id, slug, server_name);
+            let resp = client
+                .resolve_room_alias(&RoomAliasId::parse(&alias)?)
+                .await?;
+            db.ensure_room(resp.room_id.as_str(), site_id.as_str(), slug)
+                .await?;
+            resp.room_id
+        }
+    };
+
+    match client.get_room(&room_id) {
+        Some(room) => Ok(room),
+        None => Ok(client.join_room_by_id(&room_id).await?),
+    }
+}
+
+/// Re-reads the room's recent timeline and re-ingests every comment in it,
+/// repairing rows missed while the relay was down.
+pub async fn resync_room(
+    room: &Room,
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    site_id: &SiteId,
+    slug: &str,
+    bot_id: &str,
+    relay: &RelayConfig,
+) -> Result<ResyncReport> {
+    let mut events = Vec::new();
+    let mut from: Option<String> = None;
+    while events.len() < MAX_RESYNC_EVENTS {
+        let mut options = MessagesOptions::backward().from(from.as_deref());
+        options.limit = uint!(100);
+        let page = room.messages(options).await?;
+        let done = page.chunk.is_empty() || page.end.is_none();
+        events.extend(page.chunk);
+        if done {
+            break;
+        }
+        from = page.end;
+    }
+
+    let room_id = room.room_id().as_str();
+    let mut report = ResyncReport::default();
+
+    // Pages come newest first; replay oldest first so edits land on top of
+    // their originals.
+    for timeline_event in events.into_iter().rev() {
+        let event = match timeline_event.event.deserialize() {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Skipping undecodable event in {}: {}", room_id, e);
+                continue;
+            }
+        };
+        report.scanned += 1;
+
+        let AnyTimelineEvent::MessageLike(event) = event else {
+            continue;
+        };
+        match event {
+            AnyMessageLikeEvent::RoomMessage(RoomMessageEvent::Original(ev)) => {
+                let sender_id = ev.sender.to_string();
+                let display_name = match room.get_member_no_sync(&ev.sender).await {
+                    Ok(Some(member)) => member.display_name().map(str::to_string),
+                    _ => None,
+                };
+                let native_name = protocol::native_author_name(
+                    &sender_id,
+                    display_name.as_deref(),
+                    &relay.native_name_fallback,
+                );
+                let mut comment = comment_from_message(
+                    &ev.event_id,
+                    &ev.sender,
+                    ev.origin_server_ts,
+                    &ev.content,
+                    site_id.clone(),
+                    slug.to_string(),
+                    bot_id,
+                    &native_name,
+                    relay.thread_reply_to,
+                )?;
+                if !apply_moderation(&mut comment, relay) {
+                    continue;
+                }
+                backfill_created_at(&room.client(), db, room.room_id(), &mut comment).await;
+
+                let existed = db.get_comment_origin(&comment.id).await?.is_some();
+                match ingest_comment(db, tx, room_id, comment).await? {
+                    IngestOutcome::Saved if existed => report.updated += 1,
+                    IngestOutcome::Saved => report.added += 1,
+                    IngestOutcome::Deleted => report.deleted += 1,
+                    IngestOutcome::Skipped => {}
+                }
+            }
+            AnyMessageLikeEvent::RoomMessage(RoomMessageEvent::Redacted(ev)) => {
+                let because = &ev.unsigned.redacted_because;
+                let redacted = ingest_redaction(
+                    db,
+                    tx,
+                    ev.event_id.as_str(),
+                    because.sender.as_str(),
+                    because.content.reason.as_deref(),
+                    bot_id,
+                )
+                .await?;
+                if redacted {
+                    report.deleted += 1;
+                }
+            }
+            AnyMessageLikeEvent::RoomRedaction(RoomRedactionEvent::Original(ev)) => {
+                if let Some(redacts) = ev.redacts {
+                    let redacted = ingest_redaction(
+                        db,
+                        tx,
+                        redacts.as_str(),
+                        ev.sender.as_str(),
+                        ev.content.reason.as_deref(),
+                        bot_id,
+                    )
+                    .await?;
+                    if redacted {
+                        report.deleted += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    info!(
+        "Resynced {}/{}: {} scanned, {} added, {} updated, {} deleted",
+        site_id, slug, report.scanned, report.added, report.updated, report.deleted
+    );
+    Ok(report)
+}
@@ -6,64 +6,93 @@ use axum::{
     routing::put,
     Json, Router,
 };
-use domain::{protocol, AppCommand, Comment, IngestEvent, SiteId};
+use domain::{protocol, AppCommand, IngestEvent, OriginKind, ProvisionedRoom, SiteId};
 use matrix_sdk::{
     matrix_auth::{MatrixSession, MatrixSessionTokens},
     ruma::{
+        api::client::account::register,
+        api::client::error::ErrorKind,
         api::client::room::create_room::v3::Request as CreateRoomRequest,
         api::client::room::create_room::v3::RoomPreset,
         events::{
-            room::message::{OriginalRoomMessageEvent, Relation, RoomMessageEvent},
+            room::message::{OriginalRoomMessageEvent, RoomMessageEvent},
             room::redaction::{OriginalRoomRedactionEvent, RoomRedactionEvent},
             AnyMessageLikeEvent, AnyTimelineEvent,
         },
         serde::Raw,
-        EventId, OwnedRoomId, RoomAliasId, ServerName, UserId,
+        OwnedRoomId, OwnedUserId, RoomAliasId, ServerName, TransactionId, UserId,
     },
     Client, SessionMeta,
 };
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use storage::Db;
 use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 
-use crate::common::matrix_utils::{compute_user_fingerprint, SpaceCache};
-use crate::traits::MatrixDriver;
+use crate::common::dead_letter::{
+    ack_error, dead_letter, with_retries, Rejected, FIRST_RETRY_DELAY,
+};
+use crate::common::ingest::{
+    apply_moderation, backfill_created_at, comment_from_message, ingest_admin_redaction,
+    ingest_comment, ingest_redaction,
+};
+use crate::common::matrix_utils::{
+    check_homeserver_support, client_builder, ensure_space_links, ensure_unencrypted, execute_send,
+    is_alias_conflict, probe_homeserver, redact_event, resolve_conflicting_alias,
+    resolve_or_create, KeyedLocks, RedactOutcome, SpaceCache, E2EE_UNSUPPORTED,
+};
+use crate::common::resync::{resolve_thread_room, resync_room};
+use crate::drivers::AbortOnDrop;
+use crate::readiness::Readiness;
+use crate::traits::CommentTransport;
 use crate::AppServiceConfig;
 
 #[derive(Clone)]
 struct AsContext {
+    client: Client,
     db: Db,
     tx_ingest: broadcast::Sender<IngestEvent>,
     config: AppServiceConfig,
+    /// Serializes ingest per room across overlapping transactions.
+    room_locks: KeyedLocks,
 }
 
 pub struct AppServiceDriver {
     config: AppServiceConfig,
+    ready: Readiness,
 }
 
 impl AppServiceDriver {
-    pub fn new(config: AppServiceConfig) -> Self {
-        Self { config }
+    pub fn new(config: AppServiceConfig, ready: Readiness) -> Self {
+        Self { config, ready }
     }
 }
 
 #[async_trait]
-impl MatrixDriver for AppServiceDriver {
+impl CommentTransport for AppServiceDriver {
+    fn name(&self) -> &'static str {
+        "matrix-appservice"
+    }
+
     async fn run(
         &self,
         db: Db,
         mut rx_cmd: mpsc::Receiver<AppCommand>,
         tx_ingest: broadcast::Sender<IngestEvent>,
     ) -> Result<()> {
+        let ready = self.ready.track();
         info!(
             "Starting AppService Driver on port {}",
             self.config.listen_port
         );
 
-        let main_client = Client::builder()
-            .homeserver_url(&self.config.homeserver_url)
+        probe_homeserver(&self.config.homeserver_url, self.config.relay.probe_timeout).await?;
+
+        let main_client = client_builder(&self.config.homeserver_url, &self.config.relay)
             .build()
             .await?;
 
@@ -85,12 +114,21 @@ impl MatrixDriver for AppServiceDriver {
         main_client.matrix_auth().restore_session(session).await?;
         info!("AS Main Bot logged in as {}", main_user_id);
 
+        check_homeserver_support(&main_client, &self.config.relay).await?;
+        info!(
+            "End-to-end encryption is not supported: {}",
+            E2EE_UNSUPPORTED
+        );
+
         let space_cache = SpaceCache::new();
+        let ghosts = GhostRegistry::default();
 
         let state = AsContext {
+            client: main_client.clone(),
             db: db.clone(),
             tx_ingest: tx_ingest.clone(),
             config: self.config.clone(),
+            room_locks: KeyedLocks::default(),
         };
 
         let app = Router::new()
@@ -100,72 +138,228 @@ impl MatrixDriver for AppServiceDriver {
         let addr = SocketAddr::from(([0, 0, 0, 0], self.config.listen_port));
         let listener = tokio::net::TcpListener::bind(addr).await?;
 
-        tokio::spawn(async move {
+        let server = tokio::spawn(async move {
             if let Err(e) = axum::serve(listener, app).await {
                 error!("AppService WebServer error: {}", e);
             }
         });
+        // Free the port when this run ends, even by panic, so a restart can
+        // bind it again.
+        let _server_guard = AbortOnDrop(server.abort_handle());
 
         info!("AppService listening for transactions on {}", addr);
+        ready.mark_ready();
 
+        // Space links run beside the queue but belong to this run, so they
+        // are aborted with it and drained before it returns.
+        let mut links = JoinSet::new();
         while let Some(cmd) = rx_cmd.recv().await {
-            match cmd {
+            while links.try_join_next().is_some() {}
+            match cmd.clone() {
                 AppCommand::SendComment {
                     site_id,
                     post_slug,
                     content,
                     nickname,
                     reply_to,
-                    email,
-                    guest_token,
+                    author_fingerprint,
+                    lang,
+                    verified,
+                    ack,
                 } => {
-                    if let Err(e) = handle_as_send(
-                        &main_client,
-                        &self.config,
-                        &db,
-                        &space_cache,
-                        &site_id,
-                        &post_slug,
-                        &nickname,
-                        email.as_deref(),
-                        &guest_token,
-                        &content,
-                        reply_to,
+                    let txn_id = TransactionId::new();
+                    let result = with_retries(
+                        self.config.relay.command_attempts,
+                        FIRST_RETRY_DELAY,
+                        "AS Send",
+                        || {
+                            handle_as_send(
+                                &main_client,
+                                &self.config,
+                                &db,
+                                &space_cache,
+                                &ghosts,
+                                &site_id,
+                                &post_slug,
+                                &nickname,
+                                &author_fingerprint,
+                                &content,
+                                reply_to.as_deref(),
+                                lang.clone(),
+                                verified,
+                                &txn_id,
+                            )
+                        },
                     )
-                    .await
-                    {
+                    .await;
+                    if let Err(ref e) = result {
                         error!("AS Send failed: {:?}", e);
+                        dead_letter(&db, &cmd, e).await;
+                    }
+                    ack.resolve(result.map_err(|e| ack_error(&e)));
+                }
+                AppCommand::RedactComment {
+                    site_id,
+                    comment_id,
+                    reason,
+                    ack,
+                } => {
+                    let result = with_retries(
+                        self.config.relay.command_attempts,
+                        FIRST_RETRY_DELAY,
+                        "AS Redact",
+                        || {
+                            handle_as_redact(
+                                &main_client,
+                                &self.config,
+                                &db,
+                                &tx_ingest,
+                                &site_id,
+                                &comment_id,
+                                reason.as_deref(),
+                            )
+                        },
+                    )
+                    .await;
+                    if let Err(ref e) = result {
+                        error!("AS Redact failed: {:?}", e);
+                        dead_letter(&db, &cmd, e).await;
+                    }
+                    ack.resolve(result.map_err(|e| ack_error(&e)));
+                }
+                AppCommand::ResyncRoom {
+                    site_id,
+                    post_slug,
+                    ack,
+                } => {
+                    let bot_id =
+                        format!("@{}:{}", self.config.bot_localpart, self.config.server_name);
+                    let result = async {
+                        let room = resolve_thread_room(
+                            &main_client,
+                            &db,
+                            &self.config.relay.room_alias,
+                            &self.config.server_name,
+                            &site_id,
+                            &post_slug,
+                        )
+                        .await?;
+                        resync_room(
+                            &room,
+                            &db,
+                            &tx_ingest,
+                            &site_id,
+                            &post_slug,
+                            &bot_id,
+                            &self.config.relay,
+                        )
+                        .await
                     }
+                    .await;
+                    if let Err(ref e) = result {
+                        error!("AS Resync failed: {:?}", e);
+                    }
+                    ack.resolve(result.map_err(|e| ack_error(&e)));
+                }
+                AppCommand::EnsureRoom {
+                    site_id,
+                    post_slug,
+                    ack,
+                } => {
+                    let result = async {
+                        let room_id = ensure_room_for_as(
+                            &main_client,
+                            &self.config,
+                            &space_cache,
+                            &site_id,
+                            &post_slug,
+                        )
+                        .await?;
+                        db.ensure_room(room_id.as_str(), site_id.as_str(), &post_slug)
+                            .await?;
+                        Ok::<_, anyhow::Error>(ProvisionedRoom {
+                            room_id: room_id.to_string(),
+                            room_alias: self.config.relay.room_alias.format(
+                                &site_id,
+                                &post_slug,
+                                &self.config.server_name,
+                            ),
+                        })
+                    }
+                    .await;
+                    if let Err(ref e) = result {
+                        error!("AS room provisioning failed: {:?}", e);
+                    }
+                    ack.resolve(result.map_err(|e| ack_error(&e)));
+                }
+                // Checks every room of the site, so it runs beside the queue
+                // instead of holding up comments behind it.
+                AppCommand::LinkSpace { site_id, ack } => {
+                    let (client, space_cache, db, config) = (
+                        main_client.clone(),
+                        space_cache.clone(),
+                        db.clone(),
+                        self.config.clone(),
+                    );
+                    links.spawn(async move {
+                        let result = async {
+                            ensure_space_links(
+                                &client,
+                                &ServerName::parse(&config.server_name)?,
+                                &space_cache,
+                                &db,
+                                &site_id,
+                                &config.relay,
+                            )
+                            .await
+                        }
+                        .await;
+                        if let Err(ref e) = result {
+                            error!("AS space re-linking failed: {:?}", e);
+                        }
+                        ack.resolve(result.map_err(|e| ack_error(&e)));
+                    });
                 }
             }
         }
+        while links.join_next().await.is_some() {}
 
         Ok(())
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_as_send(
     main_client: &Client,
     config: &AppServiceConfig,
     db: &Db,
     cache: &SpaceCache,
+    ghosts: &GhostRegistry,
     site_id: &SiteId,
     slug: &str,
     nickname: &str,
-    email: Option<&str>,
-    guest_token: &str,
+    fingerprint: &str,
     content: &str,
-    reply_to: Option<String>,
+    reply_to: Option<&str>,
+    lang: Option<String>,
+    verified: bool,
+    txn_id: &TransactionId,
 ) -> Result<()> {
     let room_id = ensure_room_for_as(main_client, config, cache, site_id, slug).await?;
     db.ensure_room(room_id.as_str(), site_id.as_str(), slug)
         .await?;
-
-    let fingerprint = compute_user_fingerprint(email, guest_token, &config.identity_salt);
+    // The main bot is in every thread room; joining again only makes its
+    // client track a room it resolved by alias, so the check below is cached.
+    let main_room = match main_client.get_room(&room_id) {
+        Some(room) => room,
+        None => main_client.join_room_by_id(&room_id).await?,
+    };
+    ensure_unencrypted(&main_room).await?;
 
     let ghost_localpart = format!("{}_{}", config.bot_localpart, fingerprint);
     let ghost_user_id = UserId::parse(format!("@{}:{}", ghost_localpart, config.server_name))?;
 
+    ensure_ghost_registered(main_client, ghosts, &ghost_user_id).await?;
     let ghost_client = get_ghost_client(config, &ghost_user_id).await?;
 
     if ghost_client.get_room(&room_id).is_none() {
@@ -177,24 +371,26 @@ async fn handle_as_send(
         .set_display_name(Some(nickname))
         .await;
 
-    let event_json = protocol::build_outbound_event(nickname, content, Some(fingerprint));
-    let mut final_json = event_json;
-
-    if let Some(parent_id_str) = reply_to {
-        if let Ok(_) = EventId::parse(&parent_id_str) {
-            if let Some(obj) = final_json.as_object_mut() {
-                obj.insert(
-                    "m.relates_to".to_string(),
-                    serde_json::json!({ "m.in_reply_to": { "event_id": parent_id_str } }),
-                );
-            }
-        }
-    }
+    let event_json = protocol::build_outbound_event(
+        nickname,
+        content,
+        Some(fingerprint.to_string()),
+        lang,
+        verified,
+        config.relay.body_prefix.as_deref(),
+    );
 
     if let Some(room) = ghost_client.get_room(&room_id) {
-        use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
-        let raw_content: Raw<AnyMessageLikeEventContent> = serde_json::from_value(final_json)?;
-        room.send_raw("m.room.message", raw_content).await?;
+        execute_send(
+            &room,
+            db,
+            room_id.as_str(),
+            event_json,
+            reply_to,
+            &config.relay,
+            txn_id,
+        )
+        .await?;
         info!("Sent AS message as {} ({})", ghost_user_id, nickname);
     } else {
         warn!("Ghost client joined room but get_room failed immediately.");
@@ -203,9 +399,95 @@ async fn handle_as_send(
     Ok(())
 }
 
+/// Redacts as the main bot first. If that is refused and the author is one of
+/// our ghosts, retries as the ghost, which can always redact its own events.
+/// Native authors are outside the AS namespace, so that case is an error.
+async fn handle_as_redact(
+    main_client: &Client,
+    config: &AppServiceConfig,
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    site_id: &SiteId,
+    comment_id: &str,
+    reason: Option<&str>,
+) -> Result<()> {
+    let Some((room_id, owner, author_id)) = db.get_comment_origin(comment_id).await? else {
+        return Err(Rejected(format!("Comment {} not found", comment_id)).into());
+    };
+    if &owner != site_id {
+        return Err(Rejected(format!(
+            "Comment {} does not belong to site {}",
+            comment_id, site_id
+        ))
+        .into());
+    }
+
+    if let RedactOutcome::Redacted = redact_event(main_client, &room_id, comment_id, reason).await?
+    {
+        info!("AS redacted {} in {}", comment_id, room_id);
+        ingest_admin_redaction(db, tx, comment_id).await?;
+        return Ok(());
+    }
+
+    let ghost_prefix = format!("@{}_", config.bot_localpart);
+    let ghost_suffix = format!(":{}", config.server_name);
+    if !(author_id.starts_with(&ghost_prefix) && author_id.ends_with(&ghost_suffix)) {
+        return Err(Rejected(format!(
+            "AS bot lacks permission to redact {} by native user {} in {}; raise the bot's power level to the room's redact level",
+            comment_id, author_id, room_id
+        ))
+        .into());
+    }
+
+    let ghost_client = get_ghost_client(config, &UserId::parse(&author_id)?).await?;
+    match redact_event(&ghost_client, &room_id, comment_id, reason).await? {
+        RedactOutcome::Redacted => {
+            info!("AS redacted {} as its author {}", comment_id, author_id);
+            ingest_admin_redaction(db, tx, comment_id).await?;
+            Ok(())
+        }
+        RedactOutcome::Forbidden => Err(Rejected(format!(
+            "Neither the AS bot nor author {} may redact {} in {}",
+            author_id, comment_id, room_id
+        ))
+        .into()),
+    }
+}
+
+/// Ghosts known to exist on the homeserver, so each is registered at most
+/// once per run.
+#[derive(Clone, Default)]
+struct GhostRegistry(Arc<Mutex<HashSet<OwnedUserId>>>);
+
+/// Registers a ghost in the appservice namespace on first use. Strict
+/// homeservers reject requests masquerading as users that don't exist yet;
+/// `M_USER_IN_USE` means an earlier run already registered it.
+async fn ensure_ghost_registered(
+    main_client: &Client,
+    ghosts: &GhostRegistry,
+    user_id: &UserId,
+) -> Result<()> {
+    if ghosts.0.lock().unwrap().contains(user_id) {
+        return Ok(());
+    }
+
+    let mut request = register::v3::Request::new();
+    request.username = Some(user_id.localpart().to_owned());
+    request.login_type = Some(register::LoginType::ApplicationService);
+    request.inhibit_login = true;
+
+    match main_client.send(request, None).await {
+        Ok(_) => info!("Registered ghost {}", user_id),
+        Err(e) if e.client_api_error_kind() == Some(&ErrorKind::UserInUse) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    ghosts.0.lock().unwrap().insert(user_id.to_owned());
+    Ok(())
+}
+
 async fn get_ghost_client(config: &AppServiceConfig, user_id: &UserId) -> Result<Client> {
-    let client = Client::builder()
-        .homeserver_url(&config.homeserver_url)
+    let client = client_builder(&config.homeserver_url, &config.relay)
         .build()
         .await?;
 
@@ -231,32 +513,62 @@ async fn ensure_room_for_as(
     site_id: &SiteId,
     slug: &str,
 ) -> Result<OwnedRoomId> {
-    let full_alias = format!("#{}_{}:{}", site_id.as_str(), slug, config.server_name);
+    let full_alias = config
+        .relay
+        .room_alias
+        .format(site_id, slug, &config.server_name);
     let room_alias = RoomAliasId::parse(&full_alias)?;
+    let alias_ref = &room_alias;
 
-    if let Ok(resp) = client.resolve_room_alias(&room_alias).await {
-        return Ok(resp.room_id);
-    }
+    resolve_or_create(
+        cache,
+        &full_alias,
+        || async move {
+            client
+                .resolve_room_alias(alias_ref)
+                .await
+                .ok()
+                .map(|r| r.room_id)
+        },
+        || create_room_for_as(client, config, cache, site_id, slug, &full_alias),
+    )
+    .await
+}
 
-    let space_id = crate::common::matrix_utils::ensure_site_space(
+async fn create_room_for_as(
+    client: &Client,
+    config: &AppServiceConfig,
+    cache: &SpaceCache,
+    site_id: &SiteId,
+    slug: &str,
+    full_alias: &str,
+) -> Result<OwnedRoomId> {
+    let space_id = crate::common::matrix_utils::site_space(
         client,
         &ServerName::parse(&config.server_name)?,
         cache,
         site_id,
+        &config.relay,
     )
     .await?;
 
-    let alias_local = format!("{}_{}", site_id.as_str(), slug);
+    let alias_local = config.relay.room_alias.localpart(site_id, slug);
     let mut req = CreateRoomRequest::new();
     req.room_alias_name = Some(alias_local);
     req.name = Some(format!("Comments for {}", slug));
     req.preset = Some(RoomPreset::PublicChat);
 
     info!("AS creating new room: {}", full_alias);
-    let room = client.create_room(req).await?;
+    let room = match client.create_room(req).await {
+        Ok(room) => room,
+        Err(e) if is_alias_conflict(&e) => {
+            return resolve_conflicting_alias(client, full_alias).await;
+        }
+        Err(e) => return Err(e.into()),
+    };
     let room_id = room.room_id().to_owned();
 
-    if let Some(space_room) = client.get_room(&space_id) {
+    if let Some(space_room) = space_id.and_then(|id| client.get_room(&id)) {
         use matrix_sdk::ruma::events::space::child::SpaceChildEventContent;
         let server_name = ServerName::parse(&config.server_name)?;
         let child = SpaceChildEventContent::new(vec![server_name.to_owned()]);
@@ -287,14 +599,16 @@ async fn handle_transaction(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    // Events are handled one by one, in transaction order, before the
+    // homeserver gets its reply, so a redaction never overtakes the comment
+    // it removes. A retried transaction can overlap the original, hence the
+    // per-room lock.
     for raw_event in body.events {
         if let Ok(event) = raw_event.deserialize() {
-            let ctx_clone = ctx.clone();
-            tokio::spawn(async move {
-                if let Err(e) = process_as_event(event, ctx_clone).await {
-                    error!("Error processing AS event: {:?}", e);
-                }
-            });
+            let _guard = ctx.room_locks.lock(event.room_id().as_str()).await;
+            if let Err(e) = process_as_event(event, ctx.clone()).await {
+                error!("Error processing AS event: {:?}", e);
+            }
         }
     }
 
@@ -310,6 +624,15 @@ async fn process_as_event(event: AnyTimelineEvent, ctx: AsContext) -> Result<()>
             AnyMessageLikeEvent::RoomRedaction(RoomRedactionEvent::Original(ev)) => {
                 handle_as_redaction(ev, &ctx).await
             }
+            AnyMessageLikeEvent::RoomEncrypted(ev) => {
+                warn!(
+                    "Skipped encrypted event {} in room {}: {}",
+                    ev.event_id(),
+                    ev.room_id(),
+                    E2EE_UNSUPPORTED
+                );
+                Ok(())
+            }
             _ => Ok(()),
         },
         _ => Ok(()),
@@ -321,9 +644,8 @@ async fn handle_as_message(event: OriginalRoomMessageEvent, ctx: &AsContext) ->
     let sender_id = event.sender.to_string();
 
     let bot_exact = format!("@{}:{}", ctx.config.bot_localpart, ctx.config.server_name);
-    let bot_prefix = format!("@{}_", ctx.config.bot_localpart);
 
-    if sender_id == bot_exact || sender_id.starts_with(&bot_prefix) {
+    if protocol::classify_sender(&sender_id, &bot_exact) != OriginKind::Native {
         return Ok(());
     }
 
@@ -335,80 +657,121 @@ async fn handle_as_message(event: OriginalRoomMessageEvent, ctx: &AsContext) ->
         }
     };
 
-    let content_json = serde_json::to_value(&event.content)?;
-
-    let current_ts_millis: i64 = event.origin_server_ts.get().into();
-    let current_time = chrono::DateTime::from_timestamp_millis(current_ts_millis)
-        .unwrap_or_default()
-        .naive_utc();
-
-    let (target_id, final_content_json, updated_at) = if let Some(Relation::Replacement(ref re)) =
-        event.content.relates_to
-    {
-        let original_id = re.event_id.to_string();
-        let new_content_val = serde_json::to_value(&re.new_content).unwrap_or(content_json.clone());
-        (original_id, new_content_val, Some(current_time))
-    } else {
-        (event.event_id.to_string(), content_json, None)
-    };
-
-    let (author_name, is_guest, content, author_fingerprint) =
-        protocol::extract_comment_data(&final_content_json, &sender_id, &bot_exact);
-
-    if content.trim().is_empty() {
-        return Ok(());
-    }
-
-    let reply_to = if let Some(Relation::Reply { in_reply_to }) = event.content.relates_to {
-        Some(in_reply_to.event_id.to_string())
-    } else {
-        None
-    };
-
-    let comment = Comment {
-        id: target_id,
-        site_id: site_id.clone(),
-        post_slug: post_slug.clone(),
-        author_id: sender_id,
-        author_name,
-        is_guest,
-        is_redacted: false,
-        author_fingerprint,
-        content,
-        created_at: current_time,
-        updated_at,
-        reply_to,
-    };
-
-    ctx.db
-        .upsert_comment(&room_id_str, site_id.as_str(), &post_slug, &comment)
-        .await?;
-    info!("AS Comment received: {} -> {}", comment.id, comment.content);
-
-    let _ = ctx.tx_ingest.send(IngestEvent::CommentSaved {
+    // Transactions carry no member state, so native senders always get the
+    // configured fallback name here.
+    let native_name =
+        protocol::native_author_name(&sender_id, None, &ctx.config.relay.native_name_fallback);
+    let mut comment = comment_from_message(
+        &event.event_id,
+        &event.sender,
+        event.origin_server_ts,
+        &event.content,
         site_id,
         post_slug,
-        comment,
-    });
+        &bot_exact,
+        &native_name,
+        ctx.config.relay.thread_reply_to,
+    )?;
+    if !apply_moderation(&mut comment, &ctx.config.relay) {
+        return Ok(());
+    }
+    backfill_created_at(&ctx.client, &ctx.db, &event.room_id, &mut comment).await;
 
+    ingest_comment(&ctx.db, &ctx.tx_ingest, &room_id_str, comment).await?;
     Ok(())
 }
 
 async fn handle_as_redaction(event: OriginalRoomRedactionEvent, ctx: &AsContext) -> Result<()> {
     if let Some(redacts_id) = event.redacts {
         let id_str = redacts_id.to_string();
-        match ctx.db.delete_comment(&id_str).await {
-            Ok(Some((site_id, post_slug))) => {
-                info!("AS Redaction detected: {}", id_str);
-                let _ = ctx.tx_ingest.send(IngestEvent::CommentDeleted {
-                    site_id,
-                    post_slug,
-                    comment_id: id_str,
-                });
-            }
-            Ok(None) => {}
+        let bot_exact = format!("@{}:{}", ctx.config.bot_localpart, ctx.config.server_name);
+        let redacted = ingest_redaction(
+            &ctx.db,
+            &ctx.tx_ingest,
+            &id_str,
+            event.sender.as_str(),
+            event.content.reason.as_deref(),
+            &bot_exact,
+        )
+        .await;
+        match redacted {
+            Ok(true) => info!("AS Redaction detected: {}", id_str),
+            Ok(false) => {}
             Err(e) => error!("Failed to delete comment: {:?}", e),
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::protocol::{AliasScheme, NativeNameFallback};
+    use matrix_sdk::config::RequestConfig;
+    use std::time::Duration;
+
+    async fn context() -> AsContext {
+        let client = Client::builder()
+            .homeserver_url("http://127.0.0.1:9")
+            .request_config(RequestConfig::new().disable_retry())
+            .build()
+            .await
+            .unwrap();
+        let relay = crate::RelayConfig {
+            use_threads: false,
+            max_event_bytes: 60000,
+            strict_capabilities: true,
+            native_name_fallback: NativeNameFallback::Mxid,
+            probe_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            request_retry: None,
+            flag_federated: false,
+            use_spaces: false,
+            body_prefix: None,
+            thread_reply_to: crate::ThreadReplyTo::Parent,
+            room_alias: AliasScheme::default(),
+            moderation: Default::default(),
+            max_reply_depth: 64,
+            command_attempts: 1,
+        };
+        AsContext {
+            client,
+            db: Db::new("sqlite::memory:").await.unwrap(),
+            tx_ingest: broadcast::channel(8).0,
+            config: AppServiceConfig {
+                homeserver_url: "http://127.0.0.1:9".to_string(),
+                server_name: "x".to_string(),
+                as_token: "as".to_string(),
+                hs_token: "hs".to_string(),
+                bot_localpart: "cumments".to_string(),
+                listen_port: 0,
+                relay,
+            },
+            room_locks: KeyedLocks::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_events_are_skipped() {
+        let ctx = context().await;
+        let mut rx = ctx.tx_ingest.subscribe();
+        let event: AnyTimelineEvent = serde_json::from_value(serde_json::json!({
+            "type": "m.room.encrypted",
+            "event_id": "$e:x",
+            "room_id": "!a:x",
+            "sender": "@alice:x",
+            "origin_server_ts": 1,
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "AwgAEn",
+                "sender_key": "key",
+                "device_id": "DEVICE",
+                "session_id": "session"
+            }
+        }))
+        .unwrap();
+
+        process_as_event(event, ctx).await.unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
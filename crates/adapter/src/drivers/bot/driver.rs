@@ -1,69 +1,84 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use domain::{protocol, AppCommand, IngestEvent};
+use domain::{protocol, AppCommand, IngestEvent, ProvisionedRoom};
 use matrix_sdk::{
     config::SyncSettings,
-    matrix_auth::{MatrixSession, MatrixSessionTokens},
     ruma::{
         events::{
+            room::encrypted::OriginalSyncRoomEncryptedEvent,
             room::message::OriginalSyncRoomMessageEvent,
             room::redaction::OriginalSyncRoomRedactionEvent,
         },
-        OwnedUserId,
+        OwnedUserId, TransactionId,
     },
-    Client, Room, SessionMeta,
+    Client, Room,
 };
 use std::time::Duration;
 use storage::Db;
 use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
 
-use super::handlers::{handle_multitenant_send, handle_sync_event};
-use crate::common::matrix_utils::{compute_user_fingerprint, SpaceCache};
-use crate::traits::MatrixDriver;
+use super::handlers::{
+    ensure_thread_room, handle_multitenant_send, handle_redact, handle_sync_event,
+};
+use super::session::{load_session, watch_session};
+use crate::common::dead_letter::{ack_error, dead_letter, with_retries, FIRST_RETRY_DELAY};
+use crate::common::ingest::{ingest_redaction, IngestOutcome};
+use crate::common::matrix_utils::{
+    check_homeserver_support, client_builder, ensure_space_links, probe_homeserver, SpaceCache,
+    E2EE_UNSUPPORTED,
+};
+use crate::common::resync::{resolve_thread_room, resync_room};
+use crate::drivers::AbortOnDrop;
+use crate::readiness::Readiness;
+use crate::traits::CommentTransport;
+use crate::RelayConfig;
 
 #[derive(Clone)]
 pub struct BotConfig {
     pub homeserver_url: String,
     pub user_id: OwnedUserId,
     pub access_token: String,
+    /// Enables token refresh, for homeservers that expire access tokens.
+    pub refresh_token: Option<String>,
 
-    pub identity_salt: String,
+    pub relay: RelayConfig,
 }
 
 pub struct BotDriver {
     config: BotConfig,
+    ready: Readiness,
 }
 
 impl BotDriver {
-    pub fn new(config: BotConfig) -> Self {
-        Self { config }
+    pub fn new(config: BotConfig, ready: Readiness) -> Self {
+        Self { config, ready }
     }
 }
 
 #[async_trait]
-impl MatrixDriver for BotDriver {
+impl CommentTransport for BotDriver {
+    fn name(&self) -> &'static str {
+        "matrix-bot"
+    }
+
     async fn run(
         &self,
         db: Db,
         mut rx_cmd: mpsc::Receiver<AppCommand>,
         tx_ingest: broadcast::Sender<IngestEvent>,
     ) -> Result<()> {
-        let client = Client::builder()
-            .homeserver_url(&self.config.homeserver_url)
-            .build()
-            .await?;
-
-        let session = MatrixSession {
-            meta: SessionMeta {
-                user_id: self.config.user_id.clone(),
-                device_id: "CUMMENTS_BOT_V4".into(),
-            },
-            tokens: MatrixSessionTokens {
-                access_token: self.config.access_token.clone(),
-                refresh_token: None,
-            },
-        };
+        let ready = self.ready.track();
+        probe_homeserver(&self.config.homeserver_url, self.config.relay.probe_timeout).await?;
+
+        let session = load_session(&db, &self.config).await;
+
+        let mut builder = client_builder(&self.config.homeserver_url, &self.config.relay);
+        if session.tokens.refresh_token.is_some() {
+            builder = builder.handle_refresh_tokens();
+        }
+        let client = builder.build().await?;
 
         client.matrix_auth().restore_session(session).await?;
         info!(
@@ -71,124 +86,298 @@ impl MatrixDriver for BotDriver {
             self.config.user_id
         );
 
+        check_homeserver_support(&client, &self.config.relay).await?;
+        info!(
+            "End-to-end encryption is not supported: {}",
+            E2EE_UNSUPPORTED
+        );
+
         let my_bot_id = client.user_id().unwrap().to_string();
         let space_cache = SpaceCache::new();
 
         let sender_client = client.clone();
         let server_name_task = self.config.user_id.server_name().to_owned();
         let db_write = db.clone();
+        let tx_resync = tx_ingest.clone();
+        let bot_id_task = my_bot_id.clone();
 
-        let salt = self.config.identity_salt.clone();
+        let relay = self.config.relay.clone();
 
-        tokio::spawn(async move {
+        let mut cmd_task = tokio::spawn(async move {
+            // Space links run beside the queue but belong to this loop, so
+            // they are aborted with it and drained before it returns.
+            let mut links = JoinSet::new();
             while let Some(cmd) = rx_cmd.recv().await {
-                match cmd {
+                while links.try_join_next().is_some() {}
+                match cmd.clone() {
                     AppCommand::SendComment {
                         site_id,
                         post_slug,
                         content,
                         nickname,
                         reply_to,
-                        email,
-                        guest_token,
+                        author_fingerprint,
+                        lang,
+                        verified,
+                        ack,
                     } => {
-                        let fingerprint =
-                            compute_user_fingerprint(email.as_deref(), &guest_token, &salt);
-
-                        let event_json =
-                            protocol::build_outbound_event(&nickname, &content, Some(fingerprint));
+                        let event_json = protocol::build_outbound_event(
+                            &nickname,
+                            &content,
+                            Some(author_fingerprint),
+                            lang,
+                            verified,
+                            relay.body_prefix.as_deref(),
+                        );
 
-                        if let Err(e) = handle_multitenant_send(
+                        let txn_id = TransactionId::new();
+                        let result =
+                            with_retries(relay.command_attempts, FIRST_RETRY_DELAY, "Send", || {
+                                handle_multitenant_send(
+                                    &sender_client,
+                                    &server_name_task,
+                                    &db_write,
+                                    &space_cache,
+                                    &site_id,
+                                    &post_slug,
+                                    event_json.clone(),
+                                    reply_to.as_deref(),
+                                    &relay,
+                                    &txn_id,
+                                )
+                            })
+                            .await;
+                        if let Err(ref e) = result {
+                            error!("Send failed: {:?}", e);
+                            dead_letter(&db_write, &cmd, e).await;
+                        }
+                        ack.resolve(result.map_err(|e| ack_error(&e)));
+                    }
+                    AppCommand::RedactComment {
+                        site_id,
+                        comment_id,
+                        reason,
+                        ack,
+                    } => {
+                        let result = with_retries(
+                            relay.command_attempts,
+                            FIRST_RETRY_DELAY,
+                            "Redact",
+                            || {
+                                handle_redact(
+                                    &sender_client,
+                                    &db_write,
+                                    &tx_resync,
+                                    &site_id,
+                                    &comment_id,
+                                    reason.as_deref(),
+                                )
+                            },
+                        )
+                        .await;
+                        if let Err(ref e) = result {
+                            error!("Redact failed: {:?}", e);
+                            dead_letter(&db_write, &cmd, e).await;
+                        }
+                        ack.resolve(result.map_err(|e| ack_error(&e)));
+                    }
+                    AppCommand::ResyncRoom {
+                        site_id,
+                        post_slug,
+                        ack,
+                    } => {
+                        let result = async {
+                            let room = resolve_thread_room(
+                                &sender_client,
+                                &db_write,
+                                &relay.room_alias,
+                                server_name_task.as_str(),
+                                &site_id,
+                                &post_slug,
+                            )
+                            .await?;
+                            resync_room(
+                                &room,
+                                &db_write,
+                                &tx_resync,
+                                &site_id,
+                                &post_slug,
+                                &bot_id_task,
+                                &relay,
+                            )
+                            .await
+                        }
+                        .await;
+                        if let Err(ref e) = result {
+                            error!("Resync failed: {:?}", e);
+                        }
+                        ack.resolve(result.map_err(|e| ack_error(&e)));
+                    }
+                    AppCommand::EnsureRoom {
+                        site_id,
+                        post_slug,
+                        ack,
+                    } => {
+                        let result = ensure_thread_room(
                             &sender_client,
                             &server_name_task,
                             &db_write,
                             &space_cache,
                             &site_id,
                             &post_slug,
-                            event_json,
-                            reply_to,
+                            &relay,
                         )
                         .await
-                        {
-                            error!("Send failed: {:?}", e);
+                        .map(|room| ProvisionedRoom {
+                            room_id: room.room_id().to_string(),
+                            room_alias: relay.room_alias.format(
+                                &site_id,
+                                &post_slug,
+                                server_name_task.as_str(),
+                            ),
+                        });
+                        if let Err(ref e) = result {
+                            error!("Room provisioning failed: {:?}", e);
                         }
+                        ack.resolve(result.map_err(|e| ack_error(&e)));
+                    }
+                    // Checks every room of the site, so it runs beside the
+                    // queue instead of holding up comments behind it.
+                    AppCommand::LinkSpace { site_id, ack } => {
+                        let (client, server_name, space_cache, db, relay) = (
+                            sender_client.clone(),
+                            server_name_task.clone(),
+                            space_cache.clone(),
+                            db_write.clone(),
+                            relay.clone(),
+                        );
+                        links.spawn(async move {
+                            let result = ensure_space_links(
+                                &client,
+                                &server_name,
+                                &space_cache,
+                                &db,
+                                &site_id,
+                                &relay,
+                            )
+                            .await;
+                            if let Err(ref e) = result {
+                                error!("Space re-linking failed: {:?}", e);
+                            }
+                            ack.resolve(result.map_err(|e| ack_error(&e)));
+                        });
                     }
                 }
             }
+            while links.join_next().await.is_some() {}
         });
 
         let db_sync = db.clone();
         let bot_id_sync = my_bot_id.clone();
         let tx_sync = tx_ingest.clone();
+        let relay_sync = self.config.relay.clone();
+        let ready_sync = self.ready.clone();
 
         client.add_event_handler(
             move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
                 let db = db_sync.clone();
                 let bot_id = bot_id_sync.clone();
                 let tx = tx_sync.clone();
+                let relay = relay_sync.clone();
+                let ready = ready_sync.clone();
                 async move {
-                    if let Err(e) = handle_sync_event(event, room, client, db, bot_id, tx).await {
-                        error!("Sync error: {:?}", e);
+                    let sent_at = event.origin_server_ts.get().into();
+                    match handle_sync_event(event, room, client, db, bot_id, tx, &relay).await {
+                        // Only stored comments count; ignored or dropped
+                        // events would skew the lag.
+                        Ok(IngestOutcome::Saved) => ready.record_ingest_lag(sent_at),
+                        Ok(_) => {}
+                        Err(e) => error!("Sync error: {:?}", e),
                     }
                 }
             },
         );
 
+        client.add_event_handler(
+            |event: OriginalSyncRoomEncryptedEvent, room: Room| async move {
+                warn!(
+                    "Skipped encrypted event {} in room {}: {}",
+                    event.event_id,
+                    room.room_id(),
+                    E2EE_UNSUPPORTED
+                );
+            },
+        );
+
         let db_redact = db.clone();
         let tx_redact = tx_ingest.clone();
+        let bot_id_redact = my_bot_id.clone();
 
         client.add_event_handler(move |event: OriginalSyncRoomRedactionEvent, _: Client| {
             let db = db_redact.clone();
             let tx = tx_redact.clone();
+            let bot_id = bot_id_redact.clone();
             async move {
                 if let Some(redacts_id) = event.redacts {
                     let id_str = redacts_id.to_string();
                     info!("Redaction detected, soft deleting: {}", id_str);
 
-                    match db.delete_comment(&id_str).await {
-                        Ok(Some((site_id, post_slug))) => {
-                            info!("Broadcasting deletion for {}/{}", site_id, post_slug);
-                            let _ = tx.send(IngestEvent::CommentDeleted {
-                                site_id,
-                                post_slug,
-                                comment_id: id_str,
-                            });
-                        }
-                        Ok(None) => {}
-                        Err(e) => error!("Failed to delete comment: {:?}", e),
+                    let reason = event.content.reason.as_deref();
+                    let sender = event.sender.as_str();
+                    if let Err(e) =
+                        ingest_redaction(&db, &tx, &id_str, sender, reason, &bot_id).await
+                    {
+                        error!("Failed to delete comment: {:?}", e);
                     }
                 }
             }
         });
 
+        let _cmd_guard = AbortOnDrop(cmd_task.abort_handle());
+        let session_watch = watch_session(client.clone(), db.clone(), self.config.clone());
+
         info!("Starting Matrix Sync Loop...");
         let mut sync_token = db.get_sync_token().await?;
         if let Some(ref t) = sync_token {
             info!("Resuming sync from token: {}", t);
         }
 
-        loop {
-            let mut settings = SyncSettings::default().timeout(Duration::from_secs(30));
-            if let Some(ref token) = sync_token {
-                settings = settings.token(token);
-            }
+        let sync_loop = async move {
+            loop {
+                let mut settings = SyncSettings::default().timeout(Duration::from_secs(30));
+                if let Some(ref token) = sync_token {
+                    settings = settings.token(token);
+                }
 
-            match client.sync_once(settings).await {
-                Ok(response) => {
-                    let next_batch = response.next_batch;
-                    if Some(&next_batch) != sync_token.as_ref() {
-                        if let Err(e) = db.save_sync_token(&next_batch).await {
-                            error!("CRITICAL: Failed to save sync token: {:?}", e);
-                        } else {
-                            sync_token = Some(next_batch);
+                match client.sync_once(settings).await {
+                    Ok(response) => {
+                        let next_batch = response.next_batch;
+                        if Some(&next_batch) != sync_token.as_ref() {
+                            if let Err(e) = db.save_sync_token(&next_batch).await {
+                                error!("CRITICAL: Failed to save sync token: {:?}", e);
+                            } else {
+                                sync_token = Some(next_batch);
+                            }
                         }
+                        ready.mark_ready();
+                    }
+                    Err(e) => {
+                        error!("Matrix sync failed: {:?}. Retrying in 5s...", e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
                     }
-                }
-                Err(e) => {
-                    error!("Matrix sync failed: {:?}. Retrying in 5s...", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
                 }
             }
+        };
+
+        // The sync loop never ends on its own, so the driver's lifetime is
+        // tied to the command loop: it returns when the channel closes, and
+        // fails if the loop panics so the supervisor can restart it. If the
+        // driver stops for any other reason the guard aborts the loop, so a
+        // restart never leaves two of them sending.
+        tokio::select! {
+            res = &mut cmd_task => res.map_err(|e| anyhow::anyhow!("Command loop died: {}", e)),
+            res = session_watch => res,
+            _ = sync_loop => Ok(()),
         }
     }
 }
@@ -1,53 +1,30 @@
 use anyhow::Result;
-use domain::{protocol, Comment, IngestEvent, SiteId};
+use domain::{protocol, IngestEvent, SiteId};
 use matrix_sdk::{
     ruma::{
         api::client::alias::delete_alias::v3::Request as DeleteAliasRequest,
-        events::{
-            room::message::{OriginalSyncRoomMessageEvent, Relation},
-            AnyMessageLikeEventContent,
-        },
-        serde::Raw,
-        EventId, RoomAliasId, ServerName,
+        events::room::message::OriginalSyncRoomMessageEvent, RoomAliasId, ServerName,
+        TransactionId,
     },
     Client, Room,
 };
 use storage::Db;
 use tokio::sync::broadcast;
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 
+use crate::common::dead_letter::Rejected;
+use crate::common::ingest::{
+    apply_moderation, backfill_created_at, comment_from_message, ingest_admin_redaction,
+    ingest_comment, IngestOutcome,
+};
 use crate::common::matrix_utils::{
-    create_and_link_room, ensure_site_space, resolve_room_alias_chain, SpaceCache,
+    create_and_link_room, ensure_unencrypted, execute_send, redact_event, resolve_or_create,
+    resolve_room_alias_chain, site_space, RedactOutcome, SpaceCache,
 };
+use crate::RelayConfig;
 
-fn resolve_event_details(
-    event: &OriginalSyncRoomMessageEvent,
-    content_json: serde_json::Value,
-) -> (
-    String,
-    serde_json::Value,
-    chrono::NaiveDateTime,
-    Option<chrono::NaiveDateTime>,
-) {
-    let current_ts_millis: i64 = event.origin_server_ts.get().into();
-    let current_time = chrono::DateTime::from_timestamp_millis(current_ts_millis)
-        .unwrap_or_default()
-        .naive_utc();
-
-    if let Some(Relation::Replacement(ref re)) = event.content.relates_to {
-        let original_id = re.event_id.to_string();
-        let new_content_val = serde_json::to_value(&re.new_content).unwrap_or(content_json);
-        (
-            original_id,
-            new_content_val,
-            current_time,
-            Some(current_time),
-        )
-    } else {
-        (event.event_id.to_string(), content_json, current_time, None)
-    }
-}
-
+/// `Skipped` for events outside a comment room and comments moderation
+/// dropped, as well as those `ingest_comment` skips.
 pub async fn handle_sync_event(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
@@ -55,12 +32,13 @@ pub async fn handle_sync_event(
     db: Db,
     bot_id: String,
     tx: broadcast::Sender<IngestEvent>,
-) -> Result<()> {
+    relay: &RelayConfig,
+) -> Result<IngestOutcome> {
     let alias_str = match resolve_room_alias_chain(&room, &client).await {
         Some(a) => a,
         None => {
             warn!("Ignored event in room {} (No alias found)", room.room_id());
-            return Ok(());
+            return Ok(IngestOutcome::Skipped);
         }
     };
 
@@ -69,7 +47,7 @@ pub async fn handle_sync_event(
         .next()
         .unwrap_or("")
         .trim_start_matches('#');
-    let (site_id, post_slug) = match protocol::parse_room_alias(localpart) {
+    let (site_id, post_slug) = match relay.room_alias.parse(localpart) {
         Some(res) => res,
         None => {
             warn!(
@@ -77,58 +55,42 @@ pub async fn handle_sync_event(
                 room.room_id(),
                 localpart
             );
-            return Ok(());
+            return Ok(IngestOutcome::Skipped);
         }
     };
 
-    let content_json = serde_json::to_value(&event.content)?;
-    let (target_id, final_content_json, current_time, updated_at) =
-        resolve_event_details(&event, content_json);
-
     let sender_id = event.sender.to_string();
 
-    let (author_name, is_guest, content, author_fingerprint) =
-        protocol::extract_comment_data(&final_content_json, &sender_id, &bot_id);
-
-    if content.trim().is_empty() {
-        return Ok(());
-    }
-
-    let reply_to = if let Some(Relation::Reply { in_reply_to }) = event.content.relates_to {
-        Some(in_reply_to.event_id.to_string())
-    } else {
-        None
+    let display_name = match room.get_member_no_sync(&event.sender).await {
+        Ok(Some(member)) => member.display_name().map(str::to_string),
+        _ => None,
     };
-
-    let comment = Comment {
-        id: target_id,
-        site_id: site_id.clone(),
-        post_slug: post_slug.clone(),
-        author_id: sender_id,
-        author_name,
-        is_guest,
-        is_redacted: false,
-        author_fingerprint,
-        content,
-        created_at: current_time,
-        updated_at,
-        reply_to,
-    };
-
-    let room_id = room.room_id().as_str();
-    db.upsert_comment(room_id, site_id.as_str(), &post_slug, &comment)
-        .await?;
-    info!("Comment synced: {} -> {}", comment.id, comment.content);
-
-    let _ = tx.send(IngestEvent::CommentSaved {
+    let native_name = protocol::native_author_name(
+        &sender_id,
+        display_name.as_deref(),
+        &relay.native_name_fallback,
+    );
+
+    let mut comment = comment_from_message(
+        &event.event_id,
+        &event.sender,
+        event.origin_server_ts,
+        &event.content,
         site_id,
         post_slug,
-        comment,
-    });
+        &bot_id,
+        &native_name,
+        relay.thread_reply_to,
+    )?;
+    if !apply_moderation(&mut comment, relay) {
+        return Ok(IngestOutcome::Skipped);
+    }
+    backfill_created_at(&client, &db, room.room_id(), &mut comment).await;
 
-    Ok(())
+    ingest_comment(&db, &tx, room.room_id().as_str(), comment).await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_multitenant_send(
     client: &Client,
     server_name: &ServerName,
@@ -137,50 +99,144 @@ pub async fn handle_multitenant_send(
     site_id: &SiteId,
     slug: &str,
     event_json: serde_json::Value,
-    reply_to: Option<String>,
+    reply_to: Option<&str>,
+    relay: &RelayConfig,
+    txn_id: &TransactionId,
 ) -> Result<()> {
-    let space_id = ensure_site_space(client, server_name, cache, site_id).await?;
+    let room = ensure_thread_room(client, server_name, db, cache, site_id, slug, relay).await?;
+    ensure_unencrypted(&room).await?;
+    execute_send(
+        &room,
+        db,
+        room.room_id().as_str(),
+        event_json,
+        reply_to,
+        relay,
+        txn_id,
+    )
+    .await
+}
+
+/// The thread's room, joined, created under the site space if needed.
+pub async fn ensure_thread_room(
+    client: &Client,
+    server_name: &ServerName,
+    db: &Db,
+    cache: &SpaceCache,
+    site_id: &SiteId,
+    slug: &str,
+    relay: &RelayConfig,
+) -> Result<Room> {
+    let space_id = site_space(client, server_name, cache, site_id, relay).await?;
 
-    let full_alias = format!("#{}_{}:{}", site_id.as_str(), slug, server_name);
+    let full_alias = relay.room_alias.format(site_id, slug, server_name.as_str());
     let room_alias = RoomAliasId::parse(&full_alias)?;
 
-    let room = match client.resolve_room_alias(&room_alias).await {
-        Ok(resp) => match client.get_room(&resp.room_id) {
-            Some(r) => r,
-            None => match client.join_room_by_id(&resp.room_id).await {
-                Ok(r) => r,
-                Err(e) => {
-                    warn!(
-                        "Alias {} exists but join failed: {:?}. Recreating.",
-                        room_alias, e
-                    );
-                    let req = DeleteAliasRequest::new(room_alias.clone());
-                    client.send(req, None).await?;
-                    create_and_link_room(client, server_name, &space_id, site_id, slug).await?
+    let alias_ref = &room_alias;
+    let room_id = resolve_or_create(
+        cache,
+        &full_alias,
+        || async move {
+            client
+                .resolve_room_alias(alias_ref)
+                .await
+                .ok()
+                .map(|r| r.room_id)
+        },
+        || async {
+            let room = create_and_link_room(
+                client,
+                server_name,
+                &relay.room_alias,
+                space_id.as_ref(),
+                site_id,
+                slug,
+            )
+            .await?;
+            Ok(room.room_id().to_owned())
+        },
+    )
+    .await?;
+
+    let room = match client.get_room(&room_id) {
+        Some(r) => r,
+        None => match client.join_room_by_id(&room_id).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(
+                    "Alias {} exists but join failed: {:?}. Recreating.",
+                    room_alias, e
+                );
+                let _guard = cache.lock_alias(&full_alias).await;
+                // Another comment may have recreated the room while this one
+                // waited for the lock; join that rather than replacing it.
+                let current = client
+                    .resolve_room_alias(&room_alias)
+                    .await
+                    .ok()
+                    .map(|r| r.room_id)
+                    .filter(|id| *id != room_id);
+                let rejoined = match current {
+                    Some(id) => client.join_room_by_id(&id).await.ok(),
+                    None => None,
+                };
+                match rejoined {
+                    Some(room) => room,
+                    None => {
+                        let req = DeleteAliasRequest::new(room_alias.clone());
+                        client.send(req, None).await?;
+                        create_and_link_room(
+                            client,
+                            server_name,
+                            &relay.room_alias,
+                            space_id.as_ref(),
+                            site_id,
+                            slug,
+                        )
+                        .await?
+                    }
                 }
-            },
+            }
         },
-        Err(_) => create_and_link_room(client, server_name, &space_id, site_id, slug).await?,
     };
 
     db.ensure_room(room.room_id().as_str(), site_id.as_str(), slug)
         .await?;
+    Ok(room)
+}
 
-    let mut final_json = event_json;
-    if let Some(parent_id_str) = reply_to {
-        if let Ok(_) = EventId::parse(&parent_id_str) {
-            if let Some(obj) = final_json.as_object_mut() {
-                obj.insert(
-                    "m.relates_to".to_string(),
-                    serde_json::json!({ "m.in_reply_to": { "event_id": parent_id_str } }),
-                );
-            }
-        } else {
-            error!("Invalid reply_to ID: {}", parent_id_str);
-        }
+/// Redacts a comment as the bot. Unlike the appservice driver the bot cannot
+/// act as the author, so a permission failure is reported as such instead of
+/// leaking the raw upstream error.
+pub async fn handle_redact(
+    client: &Client,
+    db: &Db,
+    tx: &broadcast::Sender<IngestEvent>,
+    site_id: &SiteId,
+    comment_id: &str,
+    reason: Option<&str>,
+) -> Result<()> {
+    let Some((room_id, owner, author_id)) = db.get_comment_origin(comment_id).await? else {
+        return Err(Rejected(format!("Comment {} not found", comment_id)).into());
+    };
+    if &owner != site_id {
+        return Err(Rejected(format!(
+            "Comment {} does not belong to site {}",
+            comment_id, site_id
+        ))
+        .into());
     }
 
-    let raw_content: Raw<AnyMessageLikeEventContent> = serde_json::from_value(final_json)?;
-    room.send_raw("m.room.message", raw_content).await?;
-    Ok(())
+    match redact_event(client, &room_id, comment_id, reason).await? {
+        RedactOutcome::Redacted => {
+            info!("Redacted {} in {}", comment_id, room_id);
+            ingest_admin_redaction(db, tx, comment_id).await?;
+            Ok(())
+        }
+        RedactOutcome::Forbidden => Err(Rejected(format!(
+            "Bot lacks permission to redact {} by {} in {}; raise the bot's power level to the room's redact level",
+            comment_id, author_id, room_id
+        ))
+        .into()),
+    }
 }
@@ -1,4 +1,5 @@
 mod driver;
 pub(crate) mod handlers;
+mod session;
 
 pub use driver::{BotConfig, BotDriver};
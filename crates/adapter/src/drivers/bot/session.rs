@@ -0,0 +1,118 @@
+use anyhow::Result;
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    Client, SessionChange, SessionMeta,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use storage::Db;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info};
+
+use super::BotConfig;
+use crate::supervisor::Fatal;
+
+const SESSION_KEY: &str = "bot_session";
+const DEVICE_ID: &str = "CUMMENTS_BOT_V4";
+
+/// Tokens saved after a refresh, so a restart doesn't fall back to the
+/// configured access token the homeserver has since expired.
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    /// Hash of the configured credentials this session was refreshed from.
+    seed: String,
+    device_id: String,
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+/// Changes whenever the operator configures new credentials, which then win
+/// over any session refreshed from the old ones.
+fn seed(config: &BotConfig) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(config.user_id.as_bytes());
+    hasher.update(config.access_token.as_bytes());
+    hasher.update(config.refresh_token.as_deref().unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+/// The saved session if it descends from the current config, otherwise the
+/// configured tokens.
+pub(super) async fn load_session(db: &Db, config: &BotConfig) -> MatrixSession {
+    let saved = match db.get_meta(SESSION_KEY).await {
+        Ok(Some(json)) => serde_json::from_str::<SavedSession>(&json).ok(),
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to load saved Matrix session: {:?}", e);
+            None
+        }
+    };
+
+    match saved.filter(|s| s.seed == seed(config)) {
+        Some(saved) => {
+            info!("Restoring refreshed Matrix session");
+            MatrixSession {
+                meta: SessionMeta {
+                    user_id: config.user_id.clone(),
+                    device_id: saved.device_id.into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: saved.access_token,
+                    refresh_token: saved.refresh_token,
+                },
+            }
+        }
+        None => MatrixSession {
+            meta: SessionMeta {
+                user_id: config.user_id.clone(),
+                device_id: DEVICE_ID.into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: config.access_token.clone(),
+                refresh_token: config.refresh_token.clone(),
+            },
+        },
+    }
+}
+
+async fn save_session(db: &Db, config: &BotConfig, session: MatrixSession) -> Result<()> {
+    let saved = SavedSession {
+        seed: seed(config),
+        device_id: session.meta.device_id.to_string(),
+        access_token: session.tokens.access_token,
+        refresh_token: session.tokens.refresh_token,
+    };
+    db.set_meta(SESSION_KEY, &serde_json::to_string(&saved)?)
+        .await
+}
+
+/// Persists refreshed tokens as they arrive. Returns a `Fatal` error once the
+/// homeserver rejects the session for good, since retrying with the same
+/// credentials would only loop on 401s.
+pub(super) async fn watch_session(client: Client, db: Db, config: BotConfig) -> Result<()> {
+    let mut changes = client.subscribe_to_session_changes();
+    loop {
+        match changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) => {
+                let Some(session) = client.matrix_auth().session() else {
+                    continue;
+                };
+                match save_session(&db, &config, session).await {
+                    Ok(()) => info!("Matrix access token refreshed"),
+                    Err(e) => error!("Failed to persist refreshed Matrix session: {:?}", e),
+                }
+            }
+            Ok(SessionChange::UnknownToken { soft_logout }) => {
+                return Err(Fatal(format!(
+                    "Matrix access token for {} was rejected (soft_logout={}); \
+                     re-authenticate and update CUMMENTS_MATRIX__TOKEN \
+                     (and CUMMENTS_MATRIX__REFRESH_TOKEN), then restart",
+                    config.user_id, soft_logout
+                ))
+                .into());
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
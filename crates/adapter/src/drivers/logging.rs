@@ -0,0 +1,63 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use domain::{AppCommand, IngestEvent};
+use storage::Db;
+use tokio::sync::{broadcast, mpsc};
+use tracing::info;
+
+use crate::traits::CommentTransport;
+
+/// Write-only mirror that records every command in the log. Handy for
+/// testing the fan-out without a second real backend.
+pub struct LoggingTransport;
+
+#[async_trait]
+impl CommentTransport for LoggingTransport {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn run(
+        &self,
+        _db: Db,
+        mut rx_cmd: mpsc::Receiver<AppCommand>,
+        _tx_ingest: broadcast::Sender<IngestEvent>,
+    ) -> Result<()> {
+        while let Some(cmd) = rx_cmd.recv().await {
+            match cmd {
+                AppCommand::SendComment {
+                    site_id,
+                    post_slug,
+                    nickname,
+                    content,
+                    reply_to,
+                    ..
+                } => info!(
+                    "[mirror:log] {}/{} {} (reply_to={:?}): {}",
+                    site_id, post_slug, nickname, reply_to, content
+                ),
+                AppCommand::RedactComment {
+                    site_id,
+                    comment_id,
+                    reason,
+                    ..
+                } => info!(
+                    "[mirror:log] {} redact {} (reason={:?})",
+                    site_id, comment_id, reason
+                ),
+                // Acks belong to the primary transport; a mirror resolving
+                // one would report a resync that never happened.
+                AppCommand::ResyncRoom {
+                    site_id, post_slug, ..
+                } => info!("[mirror:log] {}/{} resync", site_id, post_slug),
+                AppCommand::EnsureRoom {
+                    site_id, post_slug, ..
+                } => info!("[mirror:log] {}/{} ensure room", site_id, post_slug),
+                AppCommand::LinkSpace { site_id, .. } => {
+                    info!("[mirror:log] {} link space", site_id)
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,13 @@
 pub mod appservice;
 pub mod bot;
+pub mod logging;
+
+/// Aborts a spawned task when the driver that owns it stops, including when
+/// the supervisor aborts the driver itself.
+pub(crate) struct AbortOnDrop(pub(crate) tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
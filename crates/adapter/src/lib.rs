@@ -1,17 +1,114 @@
 mod common;
 mod drivers;
+mod readiness;
+mod supervisor;
 mod traits;
 
-pub use common::matrix_utils::SpaceCache;
+pub use common::matrix_utils::{
+    compute_user_fingerprint, IdentitySalt, SpaceCache, MAX_FINGERPRINT_ROUNDS,
+};
 pub use drivers::bot::BotConfig;
-pub use traits::MatrixDriver;
+pub use readiness::{LagSample, Readiness};
+pub use traits::CommentTransport;
 
-use domain::{AppCommand, IngestEvent};
+use domain::{
+    protocol::{AliasScheme, NativeNameFallback},
+    AppCommand, IngestEvent,
+};
 use drivers::appservice::AppServiceDriver;
 use drivers::bot::BotDriver;
+use drivers::logging::LoggingTransport;
+use std::sync::Arc;
+use std::time::Duration;
 use storage::Db;
+use supervisor::supervise;
 use tokio::sync::{broadcast, mpsc};
-use tracing::info;
+use tracing::{info, warn};
+
+/// Which event an incoming `m.thread` message is filed under as `reply_to`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThreadReplyTo {
+    /// The message it quotes, falling back to the root when the client only
+    /// set a fallback reply.
+    #[default]
+    Parent,
+    /// Always the thread root, so each thread shows as one level of replies.
+    Root,
+}
+
+/// What happens to an incoming comment that breaks a moderation rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RuleAction {
+    /// The rule is not enforced.
+    #[default]
+    Off,
+    /// Stored and shown as usual, but marked `flagged` so it turns up in
+    /// the admin API's flagged filter. An edit can flag a comment, never
+    /// clear its flag.
+    Flag,
+    /// Not stored at all.
+    Drop,
+}
+
+/// Ingest-time rules, applied to every comment whether it came through the
+/// web API or straight from a Matrix client.
+#[derive(Clone, Debug, Default)]
+pub struct ModerationRules {
+    pub empty_nickname: RuleAction,
+    /// Lowercase words matched anywhere in the content or author name.
+    pub banned_words: Vec<String>,
+    pub banned_words_action: RuleAction,
+}
+
+impl ModerationRules {
+    /// Each broken rule with its configured action, skipping disabled ones.
+    pub fn violations(&self, comment: &domain::Comment) -> Vec<(&'static str, RuleAction)> {
+        let mut broken = Vec::new();
+        if comment.author_name.trim().is_empty() {
+            broken.push(("empty nickname", self.empty_nickname));
+        }
+        let content = comment.content.to_lowercase();
+        let name = comment.author_name.to_lowercase();
+        if self
+            .banned_words
+            .iter()
+            .any(|w| content.contains(w.as_str()) || name.contains(w.as_str()))
+        {
+            broken.push(("banned word", self.banned_words_action));
+        }
+        broken.retain(|(_, action)| *action != RuleAction::Off);
+        broken
+    }
+}
+
+#[derive(Clone)]
+pub struct RelayConfig {
+    pub use_threads: bool,
+    pub max_event_bytes: usize,
+    pub strict_capabilities: bool,
+    pub native_name_fallback: NativeNameFallback,
+    pub probe_timeout: Duration,
+    /// Per attempt of each homeserver request; the sync long-poll gets its
+    /// own timeout on top.
+    pub request_timeout: Duration,
+    /// How long a failing request keeps being retried; `None` retries
+    /// forever, as the SDK does by default.
+    pub request_retry: Option<Duration>,
+    /// Flag comments from other homeservers for moderator review; they
+    /// stay visible.
+    pub flag_federated: bool,
+    /// Group each site's rooms under a `#cumments_{site_id}` space.
+    pub use_spaces: bool,
+    /// Prepended to relayed message bodies so bridges can recognise them.
+    pub body_prefix: Option<String>,
+    pub thread_reply_to: ThreadReplyTo,
+    pub room_alias: AliasScheme,
+    pub moderation: ModerationRules,
+    /// Longest `reply_to` chain walked, and allowed, above a new reply.
+    pub max_reply_depth: u32,
+    /// Tries at a send or redaction before it is dead-lettered.
+    pub command_attempts: u32,
+}
 
 #[derive(Clone)]
 pub struct AppServiceConfig {
@@ -22,7 +119,7 @@ pub struct AppServiceConfig {
     pub bot_localpart: String,
     pub listen_port: u16,
 
-    pub identity_salt: String,
+    pub relay: RelayConfig,
 }
 
 #[derive(Clone)]
@@ -31,22 +128,79 @@ pub enum MatrixConfig {
     AppService(AppServiceConfig),
 }
 
+#[derive(Clone)]
+pub enum MirrorConfig {
+    Log,
+}
+
+/// Runs the Matrix driver and, if configured, write-only mirror transports.
+/// Every command is fanned out to each mirror without blocking the primary:
+/// a mirror that falls behind drops commands rather than stalling Matrix.
+/// Each transport is supervised and restarted if it crashes; they all share
+/// the caller's `tx_ingest`, so live subscribers are unaffected by restarts.
+/// Returns once `rx` closes and the queue has drained, or `drain_timeout`
+/// has passed and the rest has been dead-lettered. `ready` tracks whether
+/// the Matrix driver is currently able to take commands.
 pub async fn start(
     config: MatrixConfig,
+    mirrors: Vec<MirrorConfig>,
     db: Db,
-    rx: mpsc::Receiver<AppCommand>,
+    mut rx: mpsc::Receiver<AppCommand>,
     tx_ingest: broadcast::Sender<IngestEvent>,
+    drain_timeout: Duration,
+    ready: Readiness,
 ) -> anyhow::Result<()> {
-    let driver: Box<dyn MatrixDriver> = match config {
+    let driver: Arc<dyn CommentTransport> = match config {
         MatrixConfig::Bot(bot_conf) => {
             info!("Initializing Adapter in BOT mode...");
-            Box::new(BotDriver::new(bot_conf))
+            Arc::new(BotDriver::new(bot_conf, ready))
         }
         MatrixConfig::AppService(as_conf) => {
             info!("Initializing Adapter in APP_SERVICE mode...");
-            Box::new(AppServiceDriver::new(as_conf))
+            Arc::new(AppServiceDriver::new(as_conf, ready))
         }
     };
 
-    driver.run(db, rx, tx_ingest).await
+    if mirrors.is_empty() {
+        supervise(driver, db, rx, tx_ingest, Some(drain_timeout)).await;
+        return Ok(());
+    }
+
+    let mut mirror_txs = Vec::new();
+    for mirror in mirrors {
+        let transport: Arc<dyn CommentTransport> = match mirror {
+            MirrorConfig::Log => Arc::new(LoggingTransport),
+        };
+        info!("Starting mirror transport: {}", transport.name());
+
+        let (tx_mirror, rx_mirror) = mpsc::channel(100);
+        tokio::spawn(supervise(
+            transport,
+            db.clone(),
+            rx_mirror,
+            tx_ingest.clone(),
+            None,
+        ));
+        mirror_txs.push(tx_mirror);
+    }
+
+    // A single slot, filled only once it's free, so the backlog stays in
+    // `rx` and the caller's bound on it holds.
+    let (tx_primary, rx_primary) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while let Ok(permit) = tx_primary.reserve().await {
+            let Some(cmd) = rx.recv().await else {
+                break;
+            };
+            for tx in &mirror_txs {
+                if tx.try_send(cmd.clone()).is_err() {
+                    warn!("Mirror transport is lagging or closed, dropping command");
+                }
+            }
+            permit.send(cmd);
+        }
+    });
+
+    supervise(driver, db, rx_primary, tx_ingest, Some(drain_timeout)).await;
+    Ok(())
 }
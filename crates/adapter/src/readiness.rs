@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Whether the Matrix driver can take commands: logged in and, in bot mode,
+/// through its first sync. Cleared whenever a driver run ends, so commands
+/// aren't dispatched into a driver that is still (re)starting.
+///
+/// Also carries the latest ingest lag, a sign of how far sync is behind.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+    lag: Arc<Mutex<Option<LagSample>>>,
+}
+
+/// How long after its `origin_server_ts` a synced comment was ingested.
+#[derive(Debug, Clone, Copy)]
+pub struct LagSample {
+    pub lag: Duration,
+    /// When the comment was ingested.
+    pub at: Instant,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// The most recent ingest lag, if any comment has come in through sync.
+    pub fn ingest_lag(&self) -> Option<LagSample> {
+        *self.lag.lock().unwrap()
+    }
+
+    /// Records the lag of a comment sent at `origin_ts_millis`. Clock skew
+    /// that puts it in the future counts as no lag.
+    pub(crate) fn record_ingest_lag(&self, origin_ts_millis: i64) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let lag = Duration::from_millis(now.saturating_sub(origin_ts_millis).max(0) as u64);
+        *self.lag.lock().unwrap() = Some(LagSample {
+            lag,
+            at: Instant::now(),
+        });
+    }
+
+    /// Starts tracking one driver run. It counts as not ready until
+    /// `mark_ready`, and again once the guard drops.
+    pub(crate) fn track(&self) -> ReadyGuard {
+        self.ready.store(false, Ordering::Release);
+        ReadyGuard(self.clone())
+    }
+}
+
+pub(crate) struct ReadyGuard(Readiness);
+
+impl ReadyGuard {
+    pub(crate) fn mark_ready(&self) {
+        self.0.ready.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for ReadyGuard {
+    fn drop(&mut self) {
+        self.0.ready.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_only_while_run_lives() {
+        let ready = Readiness::new();
+        assert!(!ready.is_ready());
+
+        let guard = ready.track();
+        assert!(!ready.is_ready());
+        guard.mark_ready();
+        assert!(ready.is_ready());
+
+        drop(guard);
+        assert!(!ready.is_ready());
+    }
+
+    #[test]
+    fn test_ingest_lag() {
+        let ready = Readiness::new();
+        assert!(ready.ingest_lag().is_none());
+
+        let sent = chrono::Utc::now().timestamp_millis() - 30_000;
+        ready.record_ingest_lag(sent);
+        let lag = ready.ingest_lag().unwrap().lag;
+        assert!(lag >= Duration::from_secs(30) && lag < Duration::from_secs(40));
+
+        ready.record_ingest_lag(chrono::Utc::now().timestamp_millis() + 5_000);
+        assert_eq!(ready.ingest_lag().unwrap().lag, Duration::ZERO);
+    }
+}
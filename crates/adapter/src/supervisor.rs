@@ -0,0 +1,367 @@
+use domain::{AckError, AppCommand, IngestEvent};
+use std::{sync::Arc, time::Duration};
+use storage::Db;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::common::dead_letter::dead_letter;
+use crate::traits::CommentTransport;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often a supervisor whose run is busy checks whether its queue closed.
+const CLOSE_POLL: Duration = Duration::from_millis(100);
+
+/// A driver error that restarting can't fix, such as revoked credentials.
+/// The supervisor gives up instead of retrying.
+#[derive(Debug)]
+pub(crate) struct Fatal(pub String);
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// Runs a transport until the command channel closes, restarting it whenever
+/// it returns or panics. Each run gets a fresh command receiver fed from `rx`,
+/// while `tx_ingest` is the caller's sender, so SSE subscribers holding
+/// receivers from it keep getting events across restarts.
+///
+/// A command is only taken from `rx` once the run has a free slot for it, so
+/// a backlog waits in `rx` and the caller's bound on that queue holds.
+///
+/// Once `rx` closes the transport drains what is already queued. With a
+/// `drain_timeout`, it is stopped when that runs out. Whenever a run ends,
+/// by crash or shutdown, the commands it was handed but hadn't acked are
+/// abandoned. Mirrors pass `None`: they never ack, and their commands share
+/// acks with the primary's, so theirs are not tracked.
+pub(crate) async fn supervise(
+    transport: Arc<dyn CommentTransport>,
+    db: Db,
+    mut rx: mpsc::Receiver<AppCommand>,
+    tx_ingest: broadcast::Sender<IngestEvent>,
+    drain_timeout: Option<Duration>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let acks = drain_timeout.is_some();
+
+    loop {
+        let (tx_run, rx_run) = mpsc::channel(1);
+        let mut tx_run = Some(tx_run);
+        let mut pending: Vec<AppCommand> = Vec::new();
+        let mut deadline: Option<Instant> = None;
+        let mut handle = tokio::spawn({
+            let transport = transport.clone();
+            let db = db.clone();
+            let tx_ingest = tx_ingest.clone();
+            async move { transport.run(db, rx_run, tx_ingest).await }
+        });
+
+        let mut permit: Option<mpsc::OwnedPermit<AppCommand>> = None;
+        let result = loop {
+            tokio::select! {
+                res = &mut handle => break res,
+                p = reserve(tx_run.clone()), if tx_run.is_some() && permit.is_none() => {
+                    permit = Some(p);
+                }
+                cmd = rx.recv(), if permit.is_some() => match (cmd, permit.take()) {
+                    (Some(cmd), Some(permit)) => {
+                        if acks {
+                            pending.retain(|c| !c.is_acked());
+                            pending.push(cmd.clone());
+                        }
+                        permit.send(cmd);
+                    }
+                    // Closing the run's channel lets the transport drain and exit.
+                    _ => {
+                        tx_run = None;
+                        deadline = deadline.or(drain_timeout.map(|d| Instant::now() + d));
+                    }
+                },
+                // `rx` isn't read while the run's slot is full, so it has to
+                // be checked for closing to start the drain clock on time.
+                _ = tokio::time::sleep(CLOSE_POLL), if deadline.is_none() && permit.is_none() => {
+                    if rx.is_closed() {
+                        deadline = drain_timeout.map(|d| Instant::now() + d);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    handle.abort();
+                    warn!("{} did not drain in time", transport.name());
+                    // Whatever the run never got to is abandoned with the rest.
+                    rx.close();
+                    while let Ok(cmd) = rx.try_recv() {
+                        if acks {
+                            pending.push(cmd);
+                        }
+                    }
+                    tx_run = None;
+                    break (&mut handle).await;
+                }
+            }
+        };
+        let closed = tx_run.is_none();
+
+        let unacked: Vec<_> = pending.drain(..).filter(|c| !c.is_acked()).collect();
+        if !unacked.is_empty() {
+            warn!(
+                "{} left {} command(s) unacked, dead-lettering them",
+                transport.name(),
+                unacked.len()
+            );
+        }
+        let reason = if closed {
+            "Server shut down before the command was relayed"
+        } else {
+            "The Matrix driver restarted before the command was relayed"
+        };
+        for cmd in unacked {
+            abandon(&db, cmd, reason).await;
+        }
+
+        match result {
+            Ok(Err(e)) if e.is::<Fatal>() => {
+                error!("{} stopped for good: {}", transport.name(), e);
+                return;
+            }
+            Ok(Ok(())) => info!("{} stopped", transport.name()),
+            Ok(Err(e)) => error!("{} failed: {:?}", transport.name(), e),
+            Err(e) if e.is_panic() => error!("{} panicked", transport.name()),
+            Err(e) => error!("{} was cancelled: {:?}", transport.name(), e),
+        }
+
+        if closed {
+            return;
+        }
+
+        warn!("Restarting {} in {:?}", transport.name(), backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Waits for a free slot in the run's channel. A run that dropped its
+/// receiver is ending, so this waits for the supervisor to notice that.
+async fn reserve(tx: Option<mpsc::Sender<AppCommand>>) -> mpsc::OwnedPermit<AppCommand> {
+    match tx.map(mpsc::Sender::reserve_owned) {
+        Some(reserve) => match reserve.await {
+            Ok(permit) => permit,
+            Err(_) => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Settles a command the transport never finished. Sends and redactions go
+/// to the dead-letter table, where they stay until an admin retries them
+/// through the dead-letter API; nothing replays them on restart. It's
+/// possible one was already on its way to the homeserver, so a retry may
+/// duplicate it.
+async fn abandon(db: &Db, cmd: AppCommand, reason: &str) {
+    let err = || AckError::Unavailable(reason.to_string());
+    match &cmd {
+        AppCommand::ResyncRoom { ack, .. } => ack.resolve(Err(err())),
+        AppCommand::EnsureRoom { ack, .. } => ack.resolve(Err(err())),
+        AppCommand::LinkSpace { ack, .. } => ack.resolve(Err(err())),
+        AppCommand::SendComment { ack, .. } | AppCommand::RedactComment { ack, .. } => {
+            dead_letter(db, &cmd, &anyhow::anyhow!(reason.to_string())).await;
+            ack.resolve(Err(err()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use domain::{Ack, DeletionKind, SiteId};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Panics on its first command; later runs report each command as a
+    /// deletion so the test can observe them.
+    struct FlakyTransport {
+        runs: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl CommentTransport for FlakyTransport {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn run(
+            &self,
+            _db: Db,
+            mut rx_cmd: mpsc::Receiver<AppCommand>,
+            tx_ingest: broadcast::Sender<IngestEvent>,
+        ) -> anyhow::Result<()> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            while let Some(cmd) = rx_cmd.recv().await {
+                if run == 0 {
+                    panic!("simulated driver crash");
+                }
+                if let AppCommand::RedactComment {
+                    site_id,
+                    comment_id,
+                    ..
+                } = cmd
+                {
+                    let _ = tx_ingest.send(IngestEvent::CommentDeleted {
+                        site_id,
+                        post_slug: "hello".to_string(),
+                        comment_id,
+                        kind: DeletionKind::AdminRemoved,
+                    });
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn redact(id: &str) -> AppCommand {
+        AppCommand::RedactComment {
+            site_id: SiteId::new_unchecked("blog".to_string()),
+            comment_id: id.to_string(),
+            reason: None,
+            ack: Ack::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_survive_driver_restart() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx_cmd, rx_cmd) = mpsc::channel(8);
+        let (tx_ingest, mut rx_sse) = broadcast::channel(8);
+        let transport = Arc::new(FlakyTransport {
+            runs: AtomicUsize::new(0),
+        });
+
+        let task = tokio::spawn(supervise(
+            transport.clone(),
+            db.clone(),
+            rx_cmd,
+            tx_ingest,
+            Some(Duration::from_secs(5)),
+        ));
+
+        let (ack, ack_rx) = Ack::new();
+        tx_cmd.send(redact("$lost").with_ack(ack)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.runs.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("driver was not restarted");
+        tx_cmd.send(redact("$after")).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), rx_sse.recv())
+            .await
+            .expect("no event after restart")
+            .unwrap();
+        assert!(matches!(
+            event,
+            IngestEvent::CommentDeleted { ref comment_id, .. } if comment_id == "$after"
+        ));
+        assert_eq!(transport.runs.load(Ordering::SeqCst), 2);
+
+        // The command the crashed run took is settled, not silently dropped.
+        assert!(matches!(
+            ack_rx.await.unwrap(),
+            Err(AckError::Unavailable(_))
+        ));
+        let letters = db.list_dead_letters("blog").await.unwrap();
+        assert_eq!(letters.len(), 1);
+
+        drop(tx_cmd);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Never reads its commands, like a driver stuck on the homeserver.
+    struct StuckTransport;
+
+    #[async_trait]
+    impl CommentTransport for StuckTransport {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        async fn run(
+            &self,
+            _db: Db,
+            _rx_cmd: mpsc::Receiver<AppCommand>,
+            _tx_ingest: broadcast::Sender<IngestEvent>,
+        ) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backlog_stays_in_the_bounded_queue() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx_cmd, rx_cmd) = mpsc::channel(4);
+        let (tx_ingest, _) = broadcast::channel(8);
+        tokio::spawn(supervise(
+            Arc::new(StuckTransport),
+            db,
+            rx_cmd,
+            tx_ingest,
+            Some(Duration::from_secs(5)),
+        ));
+
+        // The first command moves on to the run's single slot...
+        tx_cmd.try_send(redact("$0")).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tx_cmd.capacity() < 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("supervisor did not take the first command");
+
+        // ...and the rest fill exactly the configured capacity, after which
+        // the API's `try_send` sees a full queue and answers Busy.
+        for i in 1..=4 {
+            tx_cmd.try_send(redact(&i.to_string())).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(
+            tx_cmd.try_send(redact("$5")),
+            Err(mpsc::error::TrySendError::Full(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_undrained_commands_are_dead_lettered() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (tx_cmd, rx_cmd) = mpsc::channel(8);
+        let (tx_ingest, _) = broadcast::channel(8);
+        let (ack, ack_rx) = Ack::new();
+        let cmd = redact("$queued").with_ack(ack);
+
+        let task = tokio::spawn(supervise(
+            Arc::new(StuckTransport),
+            db.clone(),
+            rx_cmd,
+            tx_ingest,
+            Some(Duration::from_millis(50)),
+        ));
+        tx_cmd.send(cmd).await.unwrap();
+        drop(tx_cmd);
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("supervisor ignored the drain deadline")
+            .unwrap();
+        assert!(ack_rx.await.unwrap().is_err());
+        let letters = db.list_dead_letters("blog").await.unwrap();
+        assert_eq!(letters.len(), 1);
+    }
+}
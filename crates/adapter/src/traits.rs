@@ -1,11 +1,16 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use domain::{AppCommand, IngestEvent};
+use matrix_sdk::ruma::TransactionId;
 use storage::Db;
 use tokio::sync::{broadcast, mpsc};
 
+/// Consumes `AppCommand`s and relays them somewhere. The Matrix drivers are
+/// the canonical store; other transports only mirror what is written.
 #[async_trait]
-pub trait MatrixDriver: Send + Sync {
+pub trait CommentTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+
     async fn run(
         &self,
         db: Db,
@@ -13,3 +18,11 @@ pub trait MatrixDriver: Send + Sync {
         tx_ingest: broadcast::Sender<IngestEvent>,
     ) -> Result<()>;
 }
+
+/// Where a finished `m.room.message` goes. Rooms send it to the homeserver;
+/// tests capture it to check the wire format. Sends sharing a `txn_id` are
+/// one event to the homeserver, so a retried send can't post it twice.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn send_event(&self, content: serde_json::Value, txn_id: &TransactionId) -> Result<()>;
+}
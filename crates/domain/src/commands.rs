@@ -1,6 +1,73 @@
 use crate::models::SiteId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
 
+pub type AckResult<T = ()> = Result<T, AckError>;
+
+/// Why the driver didn't carry out a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckError {
+    /// Refused for good, by the relay or the homeserver, e.g. a comment too
+    /// large to send or a room the bot may not redact in. The message is
+    /// meant for the client; retrying the same command won't help.
+    Rejected(String),
+    /// The relay is shutting down or restarting; try again later.
+    Unavailable(String),
+    /// The homeserver failed or couldn't be reached. The detail is for the
+    /// server log, not the client.
+    Failed(String),
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::Rejected(msg) | AckError::Unavailable(msg) | AckError::Failed(msg) => {
+                f.write_str(msg)
+            }
+        }
+    }
+}
+
+/// Completion handle for a command. Clones share one slot, so only the first
+/// `resolve` is delivered; mirror transports get clones but never resolve.
 #[derive(Debug)]
+pub struct Ack<T = ()>(Arc<Mutex<Option<oneshot::Sender<AckResult<T>>>>>);
+
+impl<T> Clone for Ack<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Default for Ack<T> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<T> Ack<T> {
+    pub fn new() -> (Self, oneshot::Receiver<AckResult<T>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(Arc::new(Mutex::new(Some(tx)))), rx)
+    }
+
+    pub fn resolve(&self, result: AckResult<T>) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            let _ = tx.send(result);
+        }
+    }
+
+    /// True once some clone has resolved, or if nobody was waiting.
+    pub fn is_resolved(&self) -> bool {
+        self.0.lock().unwrap().is_none()
+    }
+}
+
+/// Serializes without its `ack`, for the dead-letter table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AppCommand {
     SendComment {
         site_id: SiteId,
@@ -8,7 +75,105 @@ pub enum AppCommand {
         content: String,
         nickname: String,
         reply_to: Option<String>,
-        email: Option<String>,
-        guest_token: String,
+        /// Derived by the API from the guest's email or token, so neither
+        /// secret ever reaches the driver or the dead-letter table.
+        author_fingerprint: String,
+        #[serde(default)]
+        lang: Option<String>,
+        /// Set when the posting guest token confirmed the email address.
+        #[serde(default)]
+        verified: bool,
+        #[serde(skip)]
+        ack: Ack,
+    },
+    RedactComment {
+        site_id: SiteId,
+        comment_id: String,
+        reason: Option<String>,
+        #[serde(skip)]
+        ack: Ack,
+    },
+    /// Re-reads a thread's recent Matrix timeline into the database.
+    ResyncRoom {
+        site_id: SiteId,
+        post_slug: String,
+        #[serde(skip)]
+        ack: Ack<ResyncReport>,
+    },
+    /// Finds or creates a thread's room without posting to it.
+    EnsureRoom {
+        site_id: SiteId,
+        post_slug: String,
+        #[serde(skip)]
+        ack: Ack<ProvisionedRoom>,
     },
+    /// Re-links a site's thread rooms that are missing from its space.
+    LinkSpace {
+        site_id: SiteId,
+        #[serde(skip)]
+        ack: Ack<SpaceLinkReport>,
+    },
+}
+
+/// Row counts from a `ResyncRoom` run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ResyncReport {
+    pub scanned: u32,
+    pub added: u32,
+    pub updated: u32,
+    pub deleted: u32,
+}
+
+/// Room counts from a `LinkSpace` run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct SpaceLinkReport {
+    pub checked: u32,
+    pub linked: u32,
+    /// Rooms that couldn't be checked or linked; see the server log.
+    pub failed: u32,
+}
+
+/// A thread's room, as returned by `EnsureRoom`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProvisionedRoom {
+    pub room_id: String,
+    pub room_alias: String,
+}
+
+impl AppCommand {
+    pub fn site_id(&self) -> &SiteId {
+        match self {
+            AppCommand::SendComment { site_id, .. }
+            | AppCommand::RedactComment { site_id, .. }
+            | AppCommand::ResyncRoom { site_id, .. }
+            | AppCommand::EnsureRoom { site_id, .. }
+            | AppCommand::LinkSpace { site_id, .. } => site_id,
+        }
+    }
+
+    pub fn is_acked(&self) -> bool {
+        match self {
+            AppCommand::SendComment { ack, .. } | AppCommand::RedactComment { ack, .. } => {
+                ack.is_resolved()
+            }
+            AppCommand::ResyncRoom { ack, .. } => ack.is_resolved(),
+            AppCommand::EnsureRoom { ack, .. } => ack.is_resolved(),
+            AppCommand::LinkSpace { ack, .. } => ack.is_resolved(),
+        }
+    }
+
+    /// Swaps in a fresh ack, e.g. when re-enqueueing a deserialized command.
+    /// Resyncs, provisioning and space links are never dead-lettered, so
+    /// they keep their own ack.
+    pub fn with_ack(mut self, new_ack: Ack) -> Self {
+        match &mut self {
+            AppCommand::SendComment { ack, .. } | AppCommand::RedactComment { ack, .. } => {
+                *ack = new_ack
+            }
+            AppCommand::ResyncRoom { .. }
+            | AppCommand::EnsureRoom { .. }
+            | AppCommand::LinkSpace { .. } => {}
+        }
+        self
+    }
 }
@@ -1,16 +1,65 @@
 use crate::models::{Comment, SiteId};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IngestEvent {
     CommentSaved {
         site_id: SiteId,
         post_slug: String,
-        comment: Comment,
+        comment: Box<Comment>,
+        /// The parent of a reply, when the SSE layer is set to look it up.
+        #[serde(default)]
+        reply_context: Option<ReplyContext>,
     },
     CommentDeleted {
         site_id: SiteId,
         post_slug: String,
         comment_id: String,
+        #[serde(default)]
+        kind: DeletionKind,
     },
+    /// A comment was flagged for moderation, e.g. by reader reports. Only
+    /// sent to admin streams.
+    CommentFlagged {
+        site_id: SiteId,
+        post_slug: String,
+        comment_id: String,
+        reports: i64,
+    },
+}
+
+/// Why a comment went away, so clients can pick a placeholder or drop it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionKind {
+    /// Redacted on Matrix by its author or a room moderator; show a
+    /// placeholder.
+    #[default]
+    Redacted,
+    /// Removed outright, e.g. purged by an admin; drop it from the page.
+    /// Its replies were kept, attached to an ancestor or made top-level.
+    Erased,
+    /// Removed through the admin API, i.e. redacted by the relay itself.
+    AdminRemoved,
+}
+
+/// Enough of a reply's parent to show "replying to X" without a refetch.
+/// A redacted parent keeps only its ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyContext {
+    pub id: String,
+    pub author_name: Option<String>,
+    pub snippet: Option<String>,
+    pub is_redacted: bool,
+}
+
+impl IngestEvent {
+    pub fn site_id(&self) -> &SiteId {
+        match self {
+            IngestEvent::CommentSaved { site_id, .. }
+            | IngestEvent::CommentDeleted { site_id, .. }
+            | IngestEvent::CommentFlagged { site_id, .. } => site_id,
+        }
+    }
 }
@@ -2,7 +2,13 @@ mod commands;
 mod events;
 mod models;
 pub mod protocol;
+pub mod render;
 
-pub use commands::AppCommand;
-pub use events::IngestEvent;
-pub use models::{Comment, SiteId};
+pub use commands::{
+    Ack, AckError, AckResult, AppCommand, ProvisionedRoom, ResyncReport, SpaceLinkReport,
+};
+pub use events::{DeletionKind, IngestEvent, ReplyContext};
+pub use models::{
+    AuditEntry, Comment, CommentEntry, CommentOrigin, CommentScope, DeadLetter, LinkPreview,
+    NewAuditEntry, OriginKind, PurgeReplies, ReplySubscription, SiteId, ThreadOrder, ThreadSummary,
+};
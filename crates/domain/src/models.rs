@@ -1,8 +1,9 @@
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
 #[serde(transparent)]
 pub struct SiteId(String);
 
@@ -39,7 +40,82 @@ impl fmt::Display for SiteId {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where a comment's author identity comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentOrigin {
+    /// Submitted through the web API and relayed by the bot or a ghost.
+    Web,
+    /// Sent directly from a Matrix account.
+    Native,
+    /// Relayed by the bot, but a bridge stripped the metadata block, so the
+    /// author name was parsed from the message body and can't be trusted.
+    Fallback,
+}
+
+impl CommentOrigin {
+    pub fn from_guest(is_guest: bool) -> Self {
+        if is_guest {
+            CommentOrigin::Web
+        } else {
+            CommentOrigin::Native
+        }
+    }
+
+    /// Native senders are authenticated by their homeserver; web identities
+    /// are self-asserted (nickname plus optional email), so never verified.
+    pub fn is_verified(self) -> bool {
+        self == CommentOrigin::Native
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommentOrigin::Web => "web",
+            CommentOrigin::Native => "native",
+            CommentOrigin::Fallback => "fallback",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "web" => CommentOrigin::Web,
+            "fallback" => CommentOrigin::Fallback,
+            _ => CommentOrigin::Native,
+        }
+    }
+}
+
+/// Which Matrix account actually sent a comment's event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginKind {
+    /// The relay's own account (bot mode, or the appservice sender).
+    Bot,
+    /// A per-guest appservice puppet.
+    Ghost,
+    /// Any other Matrix user.
+    Native,
+}
+
+impl OriginKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OriginKind::Bot => "bot",
+            OriginKind::Ghost => "ghost",
+            OriginKind::Native => "native",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "bot" => OriginKind::Bot,
+            "ghost" => OriginKind::Ghost,
+            _ => OriginKind::Native,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Comment {
     pub id: String,
     pub site_id: SiteId,
@@ -47,10 +123,197 @@ pub struct Comment {
     pub author_id: String,
     pub author_name: String,
     pub is_guest: bool,
+    pub origin: CommentOrigin,
+    pub origin_kind: OriginKind,
+    pub verified: bool,
+    /// Sent from a homeserver other than the relay's own.
+    pub is_federated: bool,
+    /// Homeserver of a native author, e.g. `matrix.org`; `None` for guests,
+    /// whose events come from the relay's accounts.
+    pub author_server: Option<String>,
+    /// Held for moderator review, e.g. by the `relay.flag_federated` policy.
+    pub flagged: bool,
     pub is_redacted: bool,
     pub author_fingerprint: Option<String>,
     pub content: String,
     pub created_at: NaiveDateTime,
     pub reply_to: Option<String>,
     pub updated_at: Option<NaiveDateTime>,
+    /// BCP-47 tag supplied by the web client, if any.
+    pub lang: Option<String>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Comment {
+    /// A native comment `id` by `@a:x` on `blog`/`hello`, posted now. Tests
+    /// override what they care about with struct update syntax.
+    pub fn fixture(id: &str) -> Self {
+        Comment {
+            id: id.to_string(),
+            site_id: SiteId::new_unchecked("blog".to_string()),
+            post_slug: "hello".to_string(),
+            author_id: "@a:x".to_string(),
+            author_name: "A".to_string(),
+            is_guest: false,
+            origin: CommentOrigin::Native,
+            origin_kind: OriginKind::Native,
+            verified: true,
+            is_federated: false,
+            author_server: None,
+            flagged: false,
+            is_redacted: false,
+            author_fingerprint: None,
+            content: "hi".to_string(),
+            created_at: chrono::Utc::now().naive_utc(),
+            reply_to: None,
+            updated_at: None,
+            lang: None,
+        }
+    }
+}
+
+/// A comment as returned by list endpoints, with its direct-reply stats.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CommentEntry {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub reply_count: i64,
+    pub latest_reply_at: Option<NaiveDateTime>,
+    /// Filled in by the API layer, e.g. with a guest's identicon URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// Card for the first link in the content, once it has been fetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_preview: Option<LinkPreview>,
+}
+
+/// OpenGraph metadata of a page linked from a comment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Absolute URL of the page's preview image.
+    pub image: Option<String>,
+}
+
+/// A comment thread (one Matrix room) with its activity, for admin listings.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ThreadSummary {
+    pub room_id: String,
+    pub post_slug: String,
+    pub comment_count: i64,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_comment_at: Option<NaiveDateTime>,
+}
+
+/// Sort order for `Db::list_threads`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadOrder {
+    /// Most recent comment first; threads without comments last.
+    #[default]
+    Recent,
+    /// Newest thread first.
+    Created,
+    /// Most comments first.
+    Comments,
+}
+
+impl ThreadOrder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThreadOrder::Recent => "recent",
+            ThreadOrder::Created => "created",
+            ThreadOrder::Comments => "comments",
+        }
+    }
+}
+
+/// Which comments of a thread `Db::list_comments` returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommentScope<'a> {
+    #[default]
+    All,
+    /// Only comments that are not replies, plus replies whose parent isn't
+    /// stored, which would otherwise be listed nowhere.
+    TopLevel,
+    /// Direct replies to the given comment.
+    RepliesTo(&'a str),
+}
+
+/// What becomes of the replies to a purged comment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PurgeReplies {
+    /// Attach them to the purged comment's own parent, keeping the tree.
+    #[default]
+    Reparent,
+    /// Make them top-level comments.
+    Orphan,
+}
+
+/// A command the Matrix driver failed to carry out, kept for retry or audit.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub site_id: SiteId,
+    /// The `AppCommand` as JSON.
+    pub command: String,
+    pub error: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A moderation or admin action, recorded for accountability.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// `admin` for the admin API, or the Matrix user who acted in the room.
+    pub actor: String,
+    /// e.g. `redact_comment`, `update_settings`, `remove_comment`.
+    pub action: String,
+    pub site_id: SiteId,
+    pub post_slug: Option<String>,
+    pub comment_id: Option<String>,
+    pub reason: Option<String>,
+    /// Action-specific parameters, as JSON.
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// An `AuditEntry` before it's stored.
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+    pub actor: String,
+    pub action: &'static str,
+    pub site_id: SiteId,
+    pub post_slug: Option<String>,
+    pub comment_id: Option<String>,
+    pub reason: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl NewAuditEntry {
+    pub fn new(actor: impl Into<String>, action: &'static str, site_id: SiteId) -> Self {
+        Self {
+            actor: actor.into(),
+            action,
+            site_id,
+            post_slug: None,
+            comment_id: None,
+            reason: None,
+            detail: None,
+        }
+    }
+}
+
+/// A guest's reply-email choice. Emails only go out once `confirmed_owner`
+/// is set, by following the link mailed to `email`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplySubscription {
+    pub email: String,
+    pub opted_out: bool,
+    /// Key of the guest token that confirmed the address.
+    pub confirmed_owner: Option<String>,
+    pub unsubscribe_token: Option<String>,
 }
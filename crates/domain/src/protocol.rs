@@ -1,70 +1,567 @@
-use crate::models::SiteId;
+use crate::models::{CommentOrigin, OriginKind, SiteId};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Version of the metadata block written by `build_outbound_event`, stored
+/// under the `com.cumments.v{N}` key and repeated in its `version` field.
+///
+/// Compatibility contract: new fields must be optional so older readers,
+/// which ignore unknown fields, keep parsing newer events. Readers take the
+/// highest `com.cumments.vN` block they can deserialize, so a writer only
+/// bumps N for a change older readers cannot safely ignore.
+pub const METADATA_VERSION: u32 = 1;
+const METADATA_PREFIX: &str = "com.cumments.v";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CummentsMetadata {
+    #[serde(default = "current_version")]
+    pub version: u32,
     pub author_name: String,
     pub is_guest: bool,
     pub origin_content: String,
     pub author_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// The guest proved control of their email address.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verified: bool,
+}
+
+fn current_version() -> u32 {
+    METADATA_VERSION
+}
+
+pub fn metadata_key() -> String {
+    format!("{}{}", METADATA_PREFIX, METADATA_VERSION)
+}
+
+/// Finds the newest `com.cumments.vN` block that deserializes.
+pub fn find_metadata(content_json: &Value) -> Option<CummentsMetadata> {
+    let mut versioned: Vec<(u32, &Value)> = content_json
+        .as_object()?
+        .iter()
+        .filter_map(|(k, v)| Some((k.strip_prefix(METADATA_PREFIX)?.parse().ok()?, v)))
+        .collect();
+    versioned.sort_by_key(|(version, _)| std::cmp::Reverse(*version));
+
+    versioned
+        .into_iter()
+        .find_map(|(_, v)| serde_json::from_value(v.clone()).ok())
+}
+
+pub const DEFAULT_ALIAS_TEMPLATE: &str = "{site_id}_{slug}";
+
+/// How thread rooms are aliased: a localpart template holding `{site_id}`
+/// and `{slug}` once each, separated by a literal, e.g.
+/// `comments.{slug}__{site_id}`.
+///
+/// Slugs may contain anything, including the separator. The separator must
+/// hold a character site IDs can't (anything but `[a-z0-9.-]`), so the
+/// site ID's end is never ambiguous and aliases parse back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasScheme {
+    prefix: String,
+    separator: String,
+    suffix: String,
+    site_first: bool,
+}
+
+impl AliasScheme {
+    pub fn new(template: &str) -> Result<Self, String> {
+        let (site_at, slug_at) = match (template.find("{site_id}"), template.find("{slug}")) {
+            (Some(site_at), Some(slug_at)) => (site_at, slug_at),
+            _ => {
+                return Err(format!(
+                    "Alias template '{}' needs both {{site_id}} and {{slug}}",
+                    template
+                ))
+            }
+        };
+        if template.matches("{site_id}").count() > 1 || template.matches("{slug}").count() > 1 {
+            return Err(format!(
+                "Alias template '{}' repeats a placeholder",
+                template
+            ));
+        }
+
+        let site_first = site_at < slug_at;
+        let (first, second) = if site_first {
+            ("{site_id}", "{slug}")
+        } else {
+            ("{slug}", "{site_id}")
+        };
+        let (prefix, rest) = template.split_once(first).unwrap_or_default();
+        let (separator, suffix) = rest.split_once(second).unwrap_or_default();
+        let site_id_char =
+            |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-';
+        if separator.chars().all(site_id_char) {
+            return Err(format!(
+                "Alias template '{}' needs a separator between {{site_id}} and {{slug}} with a character site IDs can't contain, e.g. '_'",
+                template
+            ));
+        }
+
+        Ok(Self {
+            prefix: prefix.to_string(),
+            separator: separator.to_string(),
+            suffix: suffix.to_string(),
+            site_first,
+        })
+    }
+
+    /// The alias localpart, without `#` or server name.
+    pub fn localpart(&self, site_id: &SiteId, slug: &str) -> String {
+        let (first, second) = if self.site_first {
+            (site_id.as_str(), slug)
+        } else {
+            (slug, site_id.as_str())
+        };
+        format!(
+            "{}{}{}{}{}",
+            self.prefix, first, self.separator, second, self.suffix
+        )
+    }
+
+    pub fn format(&self, site_id: &SiteId, slug: &str, server_name: &str) -> String {
+        format!("#{}:{}", self.localpart(site_id, slug), server_name)
+    }
+
+    /// Inverse of `localpart`; a leading `#` is ignored.
+    pub fn parse(&self, localpart: &str) -> Option<(SiteId, String)> {
+        let inner = localpart
+            .trim_start_matches('#')
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)?;
+        // The site ID can't hold the separator, so the one next to it wins.
+        let (site_id_str, slug) = if self.site_first {
+            inner.split_once(&self.separator)?
+        } else {
+            let (slug, site_id_str) = inner.rsplit_once(&self.separator)?;
+            (site_id_str, slug)
+        };
+        let site_id = SiteId::new(site_id_str).ok()?;
+        Some((site_id, slug.to_string()))
+    }
 }
 
-pub fn parse_room_alias(localpart: &str) -> Option<(SiteId, String)> {
-    let localpart = localpart.trim_start_matches('#');
-    let (site_id_str, slug) = localpart.split_once('_')?;
-    let site_id = SiteId::new(site_id_str).ok()?;
-    Some((site_id, slug.to_string()))
+impl Default for AliasScheme {
+    fn default() -> Self {
+        Self::new(DEFAULT_ALIAS_TEMPLATE).expect("default alias template is valid")
+    }
 }
 
-pub fn build_outbound_event(nickname: &str, content: &str, fingerprint: Option<String>) -> Value {
-    let body_fallback = format!("**{}** (Guest): {}", nickname, content);
+/// `target` is a room ID or alias; the leading `#` of an alias is escaped so
+/// it survives inside the URL fragment.
+pub fn matrix_to_link(target: &str) -> String {
+    format!("https://matrix.to/#/{}", target.replacen('#', "%23", 1))
+}
+
+/// Server name of `author_id` for native authors. Guests are relayed by the
+/// bot or a ghost, so their sender's server says nothing about them.
+pub fn author_server(author_id: &str, is_guest: bool) -> Option<String> {
+    if is_guest {
+        return None;
+    }
+    author_id
+        .split_once(':')
+        .map(|(_, server)| server.to_string())
+}
+
+/// Loose BCP-47 shape check: alphanumeric subtags of 1-8 chars joined by
+/// `-`, starting with a 2-3 or 5-8 letter language subtag.
+pub fn is_valid_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    let primary_ok =
+        matches!(primary.len(), 2 | 3 | 5..=8) && primary.chars().all(|c| c.is_ascii_alphabetic());
+    primary_ok
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// `body_prefix` is prepended to the body so bridges and bots can pick out
+/// relayed messages without understanding the metadata block.
+pub fn build_outbound_event(
+    nickname: &str,
+    content: &str,
+    fingerprint: Option<String>,
+    lang: Option<String>,
+    verified: bool,
+    body_prefix: Option<&str>,
+) -> Value {
+    let prefix = body_prefix.unwrap_or_default();
+    let body_fallback = format!("{}**{}** (Guest): {}", prefix, nickname, content);
     let metadata = CummentsMetadata {
+        version: METADATA_VERSION,
         author_name: nickname.to_string(),
         is_guest: true,
         origin_content: content.to_string(),
         author_fingerprint: fingerprint,
+        lang,
+        verified,
     };
 
-    serde_json::json!({
+    let mut event = serde_json::json!({
         "msgtype": "m.text",
         "body": body_fallback,
-        "com.cumments.v1": metadata
-    })
+    });
+    if let Some(obj) = event.as_object_mut() {
+        obj.insert(
+            metadata_key(),
+            serde_json::to_value(metadata).unwrap_or_default(),
+        );
+    }
+    event
 }
 
+pub fn build_reply_relation(parent_id: &str, thread_root: Option<&str>) -> Value {
+    match thread_root {
+        Some(root) => serde_json::json!({
+            "rel_type": "m.thread",
+            "event_id": root,
+            "is_falling_back": root == parent_id,
+            "m.in_reply_to": { "event_id": parent_id }
+        }),
+        None => serde_json::json!({ "m.in_reply_to": { "event_id": parent_id } }),
+    }
+}
+
+/// How to name a native Matrix sender that has no display name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NativeNameFallback {
+    Mxid,
+    Localpart,
+    Fixed(String),
+}
+
+pub fn native_author_name(
+    sender_id: &str,
+    display_name: Option<&str>,
+    fallback: &NativeNameFallback,
+) -> String {
+    if let Some(name) = display_name.map(str::trim).filter(|n| !n.is_empty()) {
+        return name.to_string();
+    }
+    match fallback {
+        NativeNameFallback::Mxid => sender_id.to_string(),
+        NativeNameFallback::Localpart => sender_id
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or(sender_id)
+            .to_string(),
+        NativeNameFallback::Fixed(name) => name.clone(),
+    }
+}
+
+/// Classifies a sender against the relay's account. Ghosts share the bot's
+/// server and have localparts of the form `{bot_localpart}_{fingerprint}`.
+pub fn classify_sender(sender_id: &str, bot_id: &str) -> OriginKind {
+    if sender_id == bot_id {
+        return OriginKind::Bot;
+    }
+    let Some((bot_local, bot_server)) = bot_id.trim_start_matches('@').split_once(':') else {
+        return OriginKind::Native;
+    };
+    match sender_id.trim_start_matches('@').split_once(':') {
+        Some((local, server))
+            if server == bot_server
+                && local
+                    .strip_prefix(bot_local)
+                    .is_some_and(|rest| rest.starts_with('_')) =>
+        {
+            OriginKind::Ghost
+        }
+        _ => OriginKind::Native,
+    }
+}
+
+/// Author and content of a comment event, as read by `extract_comment_data`.
+#[derive(Debug)]
+pub struct CommentData {
+    pub author_name: String,
+    pub is_guest: bool,
+    pub content: String,
+    pub author_fingerprint: Option<String>,
+    pub lang: Option<String>,
+    /// From the metadata block; always false for legacy and native events.
+    pub verified: bool,
+    /// `Fallback` when a guest comment had to be parsed from its body.
+    pub origin: CommentOrigin,
+}
+
+/// `native_name` is used for senders that aren't relaying through cumments;
+/// resolve it with `native_author_name`. Metadata is only read from the bot
+/// and its ghosts: any room member can attach it, and a native user's claim
+/// to be a verified guest with some other guest's fingerprint is a forgery.
 pub fn extract_comment_data(
     content_json: &Value,
     sender_id: &str,
     bot_id: &str,
-) -> (String, bool, String, Option<String>) {
-    if let Some(metadata_val) = content_json.get("com.cumments.v1") {
-        if let Ok(meta) = serde_json::from_value::<CummentsMetadata>(metadata_val.clone()) {
-            return (
-                meta.author_name,
-                meta.is_guest,
-                meta.origin_content,
-                meta.author_fingerprint,
-            );
-        }
+    native_name: &str,
+) -> CommentData {
+    let relayed = classify_sender(sender_id, bot_id) != OriginKind::Native;
+    if let Some(meta) = find_metadata(content_json).filter(|_| relayed) {
+        return CommentData {
+            author_name: meta.author_name,
+            is_guest: meta.is_guest,
+            content: meta.origin_content,
+            author_fingerprint: meta.author_fingerprint,
+            lang: meta.lang,
+            verified: meta.verified,
+            origin: CommentOrigin::from_guest(meta.is_guest),
+        };
     }
 
     let body = content_json
         .get("body")
         .and_then(|v| v.as_str())
         .unwrap_or("");
+    let plain = |author_name: String, is_guest: bool, content: &str| CommentData {
+        author_name,
+        is_guest,
+        content: content.to_string(),
+        author_fingerprint: None,
+        lang: None,
+        verified: false,
+        origin: if is_guest {
+            CommentOrigin::Fallback
+        } else {
+            CommentOrigin::Native
+        },
+    };
 
     if sender_id == bot_id {
-        let parts: Vec<&str> = body.splitn(2, " (Guest): ").collect();
-        if parts.len() == 2 {
-            let nick = parts[0]
-                .trim_start_matches("**")
-                .trim_end_matches("**")
-                .to_string();
-            return (nick, true, parts[1].to_string(), None);
+        if let Some((nick, content)) = parse_guest_body(body) {
+            return plain(nick, true, content);
+        }
+        return plain("Bot".to_string(), false, body);
+    }
+
+    plain(native_name.to_string(), false, body)
+}
+
+const GUEST_MARKER: &str = "** (Guest): ";
+
+/// Splits a relayed guest body, `{prefix}**{nickname}** (Guest): {content}`.
+/// The first marker after the opening `**` wins, so a nickname that itself
+/// contains `** (Guest): ` is cut short there; that's why fallback comments
+/// are never trusted as their claimed author.
+fn parse_guest_body(body: &str) -> Option<(String, &str)> {
+    let start = body.find("**")? + 2;
+    let (nick, content) = body[start..].split_once(GUEST_MARKER)?;
+    Some((nick.to_string(), content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MXID: &str = "@alice:example.org";
+
+    #[test]
+    fn test_classify_sender() {
+        let bot = "@cumments:example.org";
+        assert_eq!(classify_sender(bot, bot), OriginKind::Bot);
+        assert_eq!(
+            classify_sender("@cumments_ab12:example.org", bot),
+            OriginKind::Ghost
+        );
+        assert_eq!(
+            classify_sender("@cumments_ab12:other.org", bot),
+            OriginKind::Native
+        );
+        assert_eq!(
+            classify_sender("@cummentsfan:example.org", bot),
+            OriginKind::Native
+        );
+        assert_eq!(classify_sender(MXID, bot), OriginKind::Native);
+    }
+
+    #[test]
+    fn test_native_name_prefers_display_name() {
+        let name = native_author_name(MXID, Some(" Alice "), &NativeNameFallback::Mxid);
+        assert_eq!(name, "Alice");
+    }
+
+    #[test]
+    fn test_native_name_fallback_mxid() {
+        assert_eq!(
+            native_author_name(MXID, None, &NativeNameFallback::Mxid),
+            MXID
+        );
+        assert_eq!(
+            native_author_name(MXID, Some("  "), &NativeNameFallback::Mxid),
+            MXID
+        );
+    }
+
+    #[test]
+    fn test_native_name_fallback_localpart() {
+        let name = native_author_name(MXID, None, &NativeNameFallback::Localpart);
+        assert_eq!(name, "alice");
+    }
+
+    #[test]
+    fn test_native_name_fallback_fixed() {
+        let fallback = NativeNameFallback::Fixed("Matrix User".to_string());
+        assert_eq!(native_author_name(MXID, None, &fallback), "Matrix User");
+    }
+
+    #[test]
+    fn test_guest_name_unaffected_by_fallback() {
+        let event = build_outbound_event("Bob", "hi", None, None, false, None);
+        let data = extract_comment_data(&event, "@bot:example.org", "@bot:example.org", "ignored");
+        assert_eq!(data.author_name, "Bob");
+        assert!(data.is_guest);
+    }
+
+    #[test]
+    fn test_verified_round_trips() {
+        let event = build_outbound_event("Bob", "hi", None, None, true, None);
+        assert!(extract_comment_data(&event, "@bot:x", "@bot:x", "ignored").verified);
+
+        // Older events have no `verified` key.
+        let mut legacy = build_outbound_event("Bob", "hi", None, None, false, None);
+        assert!(legacy[metadata_key()].get("verified").is_none());
+        legacy[metadata_key()]["lang"] = Value::Null;
+        assert!(!extract_comment_data(&legacy, "@bot:x", "@bot:x", "ignored").verified);
+    }
+
+    #[test]
+    fn test_language_tags() {
+        for tag in ["en", "zh-Hans", "de-CH-1996", "sgn-ASE", "abcde"] {
+            assert!(is_valid_language_tag(tag), "{tag}");
+        }
+        for tag in [
+            "",
+            "e",
+            "englishes",
+            "en-",
+            "en_US",
+            "1en",
+            "en-toolongsub",
+            "zh Hans",
+        ] {
+            assert!(!is_valid_language_tag(tag), "{tag}");
         }
-        return ("Bot".to_string(), false, body.to_string(), None);
+
+        let event = build_outbound_event("Bob", "hi", None, Some("zh-Hans".into()), false, None);
+        let data = extract_comment_data(&event, "@bot:x", "@bot:x", "ignored");
+        assert_eq!(data.lang.as_deref(), Some("zh-Hans"));
+    }
+
+    #[test]
+    fn test_fallback_tolerates_body_prefix() {
+        let mut event = build_outbound_event("Bob", "hi", None, None, false, Some("[cumments] "));
+        assert!(event["body"]
+            .as_str()
+            .unwrap()
+            .starts_with("[cumments] **Bob**"));
+
+        // Parse as a client that dropped the metadata block would.
+        event.as_object_mut().unwrap().remove(&metadata_key());
+        let data = extract_comment_data(&event, "@bot:x", "@bot:x", "ignored");
+        assert_eq!(data.author_name, "Bob");
+        assert_eq!(data.content, "hi");
     }
 
-    (sender_id.to_string(), false, body.to_string(), None)
+    #[test]
+    fn test_alias_round_trip() {
+        let site_id = SiteId::new("blog.example").unwrap();
+        let slugs = [
+            "hello",
+            "with_underscore",
+            "a--b",
+            "2024/01/post",
+            "trailing-",
+            "-",
+        ];
+        for template in [
+            DEFAULT_ALIAS_TEMPLATE,
+            "comments-{site_id}__{slug}",
+            "{slug}=-{site_id}.c",
+        ] {
+            let scheme = AliasScheme::new(template).unwrap();
+            for slug in slugs {
+                let alias = scheme.format(&site_id, slug, "example.org");
+                let localpart = alias.strip_suffix(":example.org").unwrap();
+                assert_eq!(
+                    scheme.parse(localpart),
+                    Some((site_id.clone(), slug.to_string())),
+                    "{} via {}",
+                    slug,
+                    template
+                );
+            }
+        }
+
+        assert_eq!(
+            AliasScheme::default().format(&site_id, "hello", "x"),
+            "#blog.example_hello:x"
+        );
+        assert!(AliasScheme::default().parse("other").is_none());
+        assert!(AliasScheme::new("{site_id}{slug}").is_err());
+        // Site IDs may hold `--` or `.`, so these can't tell where one ends.
+        assert!(AliasScheme::new("{site_id}--{slug}").is_err());
+        assert!(AliasScheme::new("{slug}.{site_id}").is_err());
+        assert!(AliasScheme::new("{slug}").is_err());
+        assert!(AliasScheme::new("{site_id}_{slug}_{slug}").is_err());
+    }
+
+    #[test]
+    fn test_author_server_only_for_native() {
+        assert_eq!(
+            author_server("@alice:matrix.org", false).as_deref(),
+            Some("matrix.org")
+        );
+        assert_eq!(
+            author_server("@alice:example.com:8448", false).as_deref(),
+            Some("example.com:8448")
+        );
+        assert_eq!(author_server("@bot:example.com", true), None);
+    }
+
+    #[test]
+    fn test_fallback_survives_adversarial_nicknames() {
+        let nicks = ["**Bob**", "a (Guest): b", "<Eve> & \"co\""];
+        for nick in nicks {
+            let mut event = build_outbound_event(nick, "hi **there**", None, None, false, None);
+            event.as_object_mut().unwrap().remove(&metadata_key());
+
+            let data = extract_comment_data(&event, "@bot:x", "@bot:x", "ignored");
+            assert_eq!(data.author_name, nick);
+            assert_eq!(data.content, "hi **there**");
+            assert!(data.is_guest);
+            assert_eq!(data.origin, CommentOrigin::Fallback);
+        }
+
+        // A nickname faking the marker is split there, but stays untrusted.
+        let mut event = build_outbound_event("x** (Guest): y", "hi", None, None, false, None);
+        event.as_object_mut().unwrap().remove(&metadata_key());
+        let data = extract_comment_data(&event, "@bot:x", "@bot:x", "ignored");
+        assert_eq!(data.author_name, "x");
+        assert_eq!(data.origin, CommentOrigin::Fallback);
+        assert!(!data.verified && data.author_fingerprint.is_none());
+
+        let event = build_outbound_event("Bob", "hi", None, None, false, None);
+        let data = extract_comment_data(&event, "@bot:x", "@bot:x", "ignored");
+        assert_eq!(data.origin, CommentOrigin::Web);
+    }
+
+    #[test]
+    fn test_native_sender_cannot_claim_guest_metadata() {
+        let event = build_outbound_event("Bob", "hi", Some("abc123".to_string()), None, true, None);
+        let data = extract_comment_data(&event, "@mallory:x", "@bot:x", "Mallory");
+        assert_eq!(data.author_name, "Mallory");
+        assert!(!data.is_guest);
+        assert!(!data.verified);
+        assert!(data.author_fingerprint.is_none());
+        assert!(matches!(data.origin, CommentOrigin::Native));
+
+        // Ghosts relay for the bot, so theirs is honoured.
+        let data = extract_comment_data(&event, "@bot_abc123:x", "@bot:x", "ignored");
+        assert!(data.is_guest && data.verified);
+        assert_eq!(data.author_fingerprint.as_deref(), Some("abc123"));
+    }
 }
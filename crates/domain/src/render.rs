@@ -0,0 +1,59 @@
+use pulldown_cmark::{escape::escape_html, html, Event, Options, Parser, Tag};
+
+const OPTIONS: Options = Options::ENABLE_TABLES.union(Options::ENABLE_STRIKETHROUGH);
+
+fn is_safe_url(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains('/') => {
+            matches!(
+                scheme.to_ascii_lowercase().as_str(),
+                "http" | "https" | "mailto"
+            )
+        }
+        _ => true,
+    }
+}
+
+/// Renders comment markdown to HTML. Raw HTML in the input is escaped rather
+/// than passed through, links are limited to http(s)/mailto and images are
+/// reduced to their alt text, so the output is safe to embed as-is.
+pub fn render_markdown(content: &str) -> String {
+    let events = Parser::new_ext(content, OPTIONS).filter_map(|event| match event {
+        Event::SoftBreak => Some(Event::HardBreak),
+        Event::Html(raw) => Some(Event::Text(raw)),
+        Event::Start(Tag::Image(..)) | Event::End(Tag::Image(..)) => None,
+        Event::Start(Tag::Link(_, ref dest, _)) | Event::End(Tag::Link(_, ref dest, _))
+            if !is_safe_url(dest) =>
+        {
+            None
+        }
+        other => Some(other),
+    });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+pub fn escape(text: &str) -> String {
+    let mut out = String::new();
+    let _ = escape_html(&mut out, text);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_escapes_raw_html_and_unsafe_links() {
+        let html = render_markdown("**hi** <script>x</script> [a](javascript:alert(1))");
+        assert!(html.contains("<strong>hi</strong>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("<a"));
+
+        let html = render_markdown("[ok](https://example.org)");
+        assert!(html.contains(r#"<a href="https://example.org">ok</a>"#));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const HOUR_SECS: u64 = 3600;
+
+/// `(second, submissions)` pairs, oldest first.
+type Buckets = VecDeque<(u64, u32)>;
+
+/// Per-site count of submitted comments over the last minute and hour, kept
+/// in one-second buckets so spikes show up as they happen.
+#[derive(Clone)]
+pub struct ActivityCounter {
+    start: Instant,
+    sites: Arc<Mutex<HashMap<String, Buckets>>>,
+}
+
+/// Submissions in the trailing windows.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Rates {
+    pub last_minute: u32,
+    pub last_hour: u32,
+}
+
+impl ActivityCounter {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            sites: Arc::default(),
+        }
+    }
+
+    pub fn record(&self, site_id: &str) {
+        self.record_at(site_id, self.now());
+    }
+
+    pub fn rates(&self, site_id: &str) -> Rates {
+        self.rates_at(site_id, self.now())
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn record_at(&self, site_id: &str, now: u64) {
+        let mut sites = self.sites.lock().unwrap();
+        // Drop sites that have gone quiet so the map doesn't grow unbounded.
+        sites.retain(|_, buckets| {
+            prune(buckets, now);
+            !buckets.is_empty()
+        });
+
+        let buckets = sites.entry(site_id.to_string()).or_default();
+        match buckets.back_mut() {
+            Some((sec, count)) if *sec == now => *count += 1,
+            _ => buckets.push_back((now, 1)),
+        }
+    }
+
+    fn rates_at(&self, site_id: &str, now: u64) -> Rates {
+        let mut sites = self.sites.lock().unwrap();
+        let Some(buckets) = sites.get_mut(site_id) else {
+            return Rates::default();
+        };
+        prune(buckets, now);
+
+        let mut rates = Rates::default();
+        for &(sec, count) in buckets.iter() {
+            rates.last_hour += count;
+            if now - sec < 60 {
+                rates.last_minute += count;
+            }
+        }
+        rates
+    }
+}
+
+impl Default for ActivityCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn prune(buckets: &mut Buckets, now: u64) {
+    while buckets
+        .front()
+        .is_some_and(|&(sec, _)| now - sec >= HOUR_SECS)
+    {
+        buckets.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_slide() {
+        let counter = ActivityCounter::new();
+        counter.record_at("blog", 0);
+        counter.record_at("blog", 0);
+        counter.record_at("blog", 100);
+        counter.record_at("other", 100);
+
+        assert_eq!(
+            counter.rates_at("blog", 120),
+            Rates {
+                last_minute: 1,
+                last_hour: 3
+            }
+        );
+        assert_eq!(
+            counter.rates_at("blog", HOUR_SECS + 50),
+            Rates {
+                last_minute: 0,
+                last_hour: 1
+            }
+        );
+        assert_eq!(counter.rates_at("missing", 0), Rates::default());
+    }
+}
@@ -0,0 +1,23 @@
+use domain::{NewAuditEntry, SiteId};
+use storage::Db;
+
+/// Everything done through the admin API is logged as this actor: there is
+/// one admin token, so there's no one more specific to name.
+pub const ADMIN_ACTOR: &str = "admin";
+
+pub fn admin_entry(action: &'static str, site_id: &SiteId) -> NewAuditEntry {
+    NewAuditEntry::new(ADMIN_ACTOR, action, site_id.clone())
+}
+
+/// Stores `entry` in the background, so the admin request doesn't wait on
+/// the write. A failure is only logged. Driver commands are recorded once
+/// they succeed or are still running at the timeout, never when refused or
+/// failed.
+pub fn record(db: &Db, entry: NewAuditEntry) {
+    let db = db.clone();
+    tokio::spawn(async move {
+        if let Err(e) = db.insert_audit(&entry).await {
+            tracing::error!("Failed to write audit entry {:?}: {:?}", entry, e);
+        }
+    });
+}
@@ -1,5 +1,8 @@
+use config::builder::{ConfigBuilder, DefaultState};
 use config::ConfigError;
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::net::IpAddr;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
@@ -7,6 +10,10 @@ pub struct Settings {
     pub database: DatabaseSettings,
     pub matrix: MatrixSettings,
     pub security: SecuritySettings,
+    pub relay: RelaySettings,
+    pub mirrors: MirrorSettings,
+    pub unfurl: UnfurlSettings,
+    pub notify: NotifySettings,
 }
 
 #[derive(Deserialize, Clone)]
@@ -14,16 +21,162 @@ pub struct ServerSettings {
     pub host: String,
     pub port: u16,
     pub cors_origins: String,
+    /// How long browsers may cache a preflight; `0` leaves it to them.
+    pub cors_max_age_secs: u64,
+    pub cors_allow_credentials: bool,
+    pub max_content_length: usize,
+    pub default_per_page: u32,
+    pub max_per_page: u32,
+    pub preview_rate_limit: u32,
+    pub report_rate_limit: u32,
+    /// Reports per client IP per hour.
+    pub report_ip_rate_limit: u32,
+    /// Challenges per IP per minute; 0 turns the limit off.
+    pub challenge_rate_limit: u32,
+    /// Comma-separated CIDRs that skip the challenge limit.
+    pub challenge_rate_limit_exempt: String,
+    /// Comma-separated CIDRs of reverse proxies whose `X-Forwarded-For` is
+    /// believed when limiting per client IP.
+    pub trusted_proxies: String,
+    pub report_threshold: i64,
+    pub command_timeout_secs: u64,
+    /// Commands waiting for the Matrix driver before requests are refused.
+    pub command_queue_capacity: usize,
+    pub identicons: bool,
+    pub sse_batch_window_ms: u64,
+    /// Buffered events per site before slow SSE clients start lagging.
+    pub sse_channel_capacity: usize,
+    /// Sites that may have live SSE subscribers at once.
+    pub sse_max_sites: usize,
+    /// Open SSE streams per client IP; 0 turns the limit off.
+    pub sse_max_per_ip: usize,
+    /// Open SSE streams in total; 0 turns the limit off.
+    pub sse_max_connections: usize,
+    /// Attach the parent's author and snippet to replies sent over SSE.
+    pub sse_reply_context: bool,
+    pub shutdown_drain_secs: u64,
+    /// `/readyz` reports `lagging` while the latest ingest lag exceeds this;
+    /// 0 = never.
+    pub ingest_lag_alert_secs: u64,
+    pub feed_items: u32,
+    /// Stands in for a blank nickname on web comments; empty keeps it blank.
+    pub default_nickname: String,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct DatabaseSettings {
     pub url: String,
+    pub max_connections: u32,
+    pub busy_timeout_ms: u64,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct SecuritySettings {
     pub identity_salt: String,
+    /// Derive a separate salt for each site from `identity_salt`.
+    pub per_site_salt: bool,
+    /// Like `per_site_salt`, changing this changes every fingerprint.
+    pub fingerprint_rounds: u32,
+    pub pow_difficulty: usize,
+    pub pow_length_step: usize,
+    pub pow_max_difficulty: usize,
+    pub pow_secret: Option<String>,
+    pub admin_token: Option<String>,
+    /// Refuse emails from the embedded list of disposable providers.
+    pub block_disposable_emails: bool,
+    /// Comma-separated extra email domains to refuse.
+    pub blocked_email_domains: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RelaySettings {
+    pub use_threads: bool,
+    pub max_event_bytes: usize,
+    pub strict_capabilities: bool,
+    pub native_name_fallback: NativeNameMode,
+    pub native_name_fixed: String,
+    pub probe_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    /// 0 retries failing requests forever.
+    pub request_retry_secs: u64,
+    pub flag_federated: bool,
+    pub use_spaces: bool,
+    pub body_prefix: Option<String>,
+    pub thread_reply_to: ThreadReplyMode,
+    /// Localpart template for thread room aliases.
+    pub room_alias_template: String,
+    pub empty_nickname_action: ModerationAction,
+    /// Comma-separated, matched case-insensitively.
+    pub banned_words: String,
+    pub banned_words_action: ModerationAction,
+    /// Deepest a reply may nest; also bounds walks up a reply chain.
+    pub max_reply_depth: u32,
+    /// Tries at a send or redaction before it is dead-lettered.
+    pub command_attempts: u32,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Off,
+    Flag,
+    Drop,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadReplyMode {
+    Parent,
+    Root,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NativeNameMode {
+    Mxid,
+    Localpart,
+    Fixed,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MirrorSettings {
+    pub log: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UnfurlSettings {
+    /// Default for the per-site `link_previews` setting.
+    pub enabled: bool,
+    /// Comma-separated host suffixes; empty allows any public host.
+    pub allowed_hosts: String,
+    pub denied_hosts: String,
+    pub timeout_secs: u64,
+    pub max_bytes: usize,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct NotifySettings {
+    /// Email guests who left an address when someone replies to them.
+    pub enabled: bool,
+    pub sender: EmailSenderKind,
+    pub sendmail_path: String,
+    pub from: String,
+    /// Only notify about replies to comments posted after their guest
+    /// confirmed the address.
+    pub verified_only: bool,
+    /// Emails per recipient per hour, confirmation emails included.
+    pub max_per_hour: u32,
+    /// Base URL this server is reached at, for the confirmation and
+    /// unsubscribe links in emails.
+    pub public_url: String,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailSenderKind {
+    /// Logs each email instead of sending it.
+    Log,
+    Sendmail,
 }
 
 #[derive(Deserialize, Clone)]
@@ -33,6 +186,8 @@ pub enum MatrixSettings {
         homeserver_url: String,
         user: String,
         token: String,
+        #[serde(default)]
+        refresh_token: Option<String>,
     },
     #[serde(rename = "appservice")]
     AppService {
@@ -45,29 +200,320 @@ pub enum MatrixSettings {
     },
 }
 
+impl MatrixSettings {
+    /// Server part of the room aliases the driver creates.
+    pub fn server_name(&self) -> &str {
+        match self {
+            MatrixSettings::Bot { user, .. } => user.split_once(':').map_or("", |(_, s)| s),
+            MatrixSettings::AppService { server_name, .. } => server_name,
+        }
+    }
+}
+
+impl SecuritySettings {
+    pub fn identity_salt(&self) -> adapter::IdentitySalt {
+        adapter::IdentitySalt::new(self.identity_salt.clone(), self.per_site_salt)
+    }
+}
+
+fn parse_nets(key: &str, raw: &str) -> Result<Vec<IpNet>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("{}: invalid CIDR {}", key, s))
+        })
+        .collect()
+}
+
+impl ServerSettings {
+    /// Parses `challenge_rate_limit_exempt`; a bare address counts as a
+    /// single-host network.
+    pub fn challenge_exempt_nets(&self) -> Result<Vec<IpNet>, String> {
+        parse_nets(
+            "server.challenge_rate_limit_exempt",
+            &self.challenge_rate_limit_exempt,
+        )
+    }
+
+    /// Parses `trusted_proxies`, like `challenge_exempt_nets`.
+    pub fn trusted_proxy_nets(&self) -> Result<Vec<IpNet>, String> {
+        parse_nets("server.trusted_proxies", &self.trusted_proxies)
+    }
+
+    /// Parses `cors_origins`: `None` for `*`, otherwise the listed origins.
+    pub fn cors_allowed_origins(&self) -> Result<Option<Vec<url::Origin>>, String> {
+        parse_cors_origins(&self.cors_origins)
+    }
+
+    /// The explicitly listed `cors_origins`; empty for `*`.
+    pub fn cors_origin_list(&self) -> Vec<url::Origin> {
+        self.cors_allowed_origins()
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+}
+
+/// `*` alone allows any origin. Anything else must be a comma-separated list
+/// of `scheme://host[:port]` origins, since browsers compare the `Origin`
+/// header against them verbatim and a typo would silently block the site.
+fn parse_cors_origins(raw: &str) -> Result<Option<Vec<url::Origin>>, String> {
+    let entries: Vec<&str> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    match entries.as_slice() {
+        ["*"] => return Ok(None),
+        [] => return Err("server.cors_origins is empty; use * to allow any origin".to_string()),
+        _ => {}
+    }
+    entries
+        .iter()
+        .map(|&s| {
+            let invalid = || format!("server.cors_origins: invalid origin {}", s);
+            let url = url::Url::parse(s).map_err(|_| invalid())?;
+            let origin = url.origin();
+            // Only a bare origin round-trips; a path, query or userinfo doesn't.
+            if !matches!(url.scheme(), "http" | "https")
+                || origin.ascii_serialization() != s.trim_end_matches('/').to_ascii_lowercase()
+            {
+                return Err(invalid());
+            }
+            Ok(origin)
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
-        let run_mode = std::env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
-
-        let s = config::Config::builder()
-            .set_default("server.host", "0.0.0.0")?
-            .set_default("server.port", 3000)?
-            .set_default("server.cors_origins", "*")?
-            .set_default("database.url", "sqlite://data/cumments.db")?
-            .set_default("matrix.mode", "bot")?
-            .set_default("matrix.homeserver_url", "https://matrix.org")?
-            .set_default("security.identity_salt", "change_me_please")?
-            .add_source(config::File::with_name("config").required(false))
-            .add_source(config::File::with_name(&format!("config.{}", run_mode)).required(false))
-            .add_source(
-                config::Environment::with_prefix("CUMMENTS")
-                    .try_parsing(true)
-                    .separator("__")
-                    .prefix_separator("_")
-                    .convert_case(config::Case::Lower),
-            )
-            .build()?;
-
-        s.try_deserialize()
+        let settings: Self = layered(defaults()?)?.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Just the `database` section, for `migrate`, which a deploy may run
+    /// before the Matrix and relay settings are in place.
+    pub fn database() -> Result<DatabaseSettings, ConfigError> {
+        layered(defaults()?)?.get("database")
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let server = &self.server;
+        if server.default_per_page == 0 || server.max_per_page < server.default_per_page {
+            return Err(ConfigError::Message(format!(
+                "server.max_per_page ({}) must be >= server.default_per_page ({}) and both > 0",
+                server.max_per_page, server.default_per_page
+            )));
+        }
+        let origins = server
+            .cors_allowed_origins()
+            .map_err(ConfigError::Message)?;
+        if server.cors_allow_credentials && origins.is_none() {
+            return Err(ConfigError::Message(
+                "server.cors_allow_credentials needs server.cors_origins to list explicit origins; browsers reject credentials with a wildcard".to_string(),
+            ));
+        }
+        server
+            .challenge_exempt_nets()
+            .map_err(ConfigError::Message)?;
+        server.trusted_proxy_nets().map_err(ConfigError::Message)?;
+        if self.notify.enabled && url::Url::parse(&self.notify.public_url).is_err() {
+            return Err(ConfigError::Message(
+                "notify.public_url must be the absolute URL of this server when notify.enabled is set; emails link back to it".to_string(),
+            ));
+        }
+        let rounds = self.security.fingerprint_rounds;
+        if !(1..=adapter::MAX_FINGERPRINT_ROUNDS).contains(&rounds) {
+            return Err(ConfigError::Message(format!(
+                "security.fingerprint_rounds ({}) must be between 1 and {}",
+                rounds,
+                adapter::MAX_FINGERPRINT_ROUNDS
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+    config::Config::builder()
+        .set_default("server.host", "0.0.0.0")?
+        .set_default("server.port", 3000)?
+        .set_default("server.cors_origins", "*")?
+        .set_default("server.cors_max_age_secs", 600)?
+        .set_default("server.cors_allow_credentials", false)?
+        .set_default("server.max_content_length", 5000)?
+        .set_default("server.default_per_page", 20)?
+        .set_default("server.max_per_page", 100)?
+        .set_default("server.preview_rate_limit", 30)?
+        .set_default("server.report_rate_limit", 5)?
+        .set_default("server.report_ip_rate_limit", 20)?
+        .set_default("server.challenge_rate_limit", 60)?
+        .set_default("server.challenge_rate_limit_exempt", "")?
+        .set_default("server.trusted_proxies", "")?
+        .set_default("server.report_threshold", 3)?
+        .set_default("server.command_timeout_secs", 5)?
+        .set_default("server.command_queue_capacity", 100)?
+        .set_default("server.identicons", true)?
+        .set_default("server.sse_batch_window_ms", 0)?
+        .set_default("server.sse_channel_capacity", 100)?
+        .set_default("server.sse_max_sites", 1000)?
+        .set_default("server.sse_max_per_ip", 10)?
+        .set_default("server.sse_max_connections", 10000)?
+        .set_default("server.sse_reply_context", false)?
+        .set_default("server.shutdown_drain_secs", 10)?
+        .set_default("server.ingest_lag_alert_secs", 60)?
+        .set_default("server.feed_items", 20)?
+        .set_default("server.default_nickname", "Anonymous")?
+        .set_default("database.url", "sqlite://data/cumments.db")?
+        .set_default("database.max_connections", 5)?
+        .set_default("database.busy_timeout_ms", 5000)?
+        .set_default("matrix.mode", "bot")?
+        .set_default("matrix.homeserver_url", "https://matrix.org")?
+        .set_default("security.identity_salt", "change_me_please")?
+        .set_default("security.per_site_salt", false)?
+        .set_default("security.fingerprint_rounds", 1)?
+        .set_default("security.pow_difficulty", 4)?
+        .set_default("security.pow_length_step", 0)?
+        .set_default("security.pow_max_difficulty", 6)?
+        .set_default("security.block_disposable_emails", false)?
+        .set_default("security.blocked_email_domains", "")?
+        .set_default("relay.use_threads", false)?
+        .set_default("relay.max_event_bytes", 60000)?
+        .set_default("relay.strict_capabilities", false)?
+        .set_default("relay.native_name_fallback", "mxid")?
+        .set_default("relay.native_name_fixed", "Matrix User")?
+        .set_default("relay.probe_timeout_secs", 10)?
+        .set_default("relay.request_timeout_secs", 30)?
+        .set_default("relay.request_retry_secs", 60)?
+        .set_default("relay.flag_federated", false)?
+        .set_default("relay.use_spaces", true)?
+        .set_default("relay.thread_reply_to", "parent")?
+        .set_default("relay.room_alias_template", "{site_id}_{slug}")?
+        .set_default("relay.empty_nickname_action", "off")?
+        .set_default("relay.banned_words", "")?
+        .set_default("relay.banned_words_action", "flag")?
+        .set_default("relay.max_reply_depth", 64)?
+        .set_default("relay.command_attempts", 3)?
+        .set_default("mirrors.log", false)?
+        .set_default("unfurl.enabled", false)?
+        .set_default("unfurl.allowed_hosts", "")?
+        .set_default("unfurl.denied_hosts", "")?
+        .set_default("unfurl.timeout_secs", 5)?
+        .set_default("unfurl.max_bytes", 524288)?
+        .set_default("notify.enabled", false)?
+        .set_default("notify.sender", "log")?
+        .set_default("notify.sendmail_path", "/usr/sbin/sendmail")?
+        .set_default("notify.from", "cumments@localhost")?
+        .set_default("notify.verified_only", true)?
+        .set_default("notify.max_per_hour", 10)?
+        .set_default("notify.public_url", "")
+}
+
+#[cfg(test)]
+impl Settings {
+    /// The defaults with placeholder bot credentials, ignoring config files
+    /// and the environment.
+    pub(crate) fn for_tests() -> Self {
+        defaults()
+            .and_then(|b| b.set_override("matrix.user", "@bot:x"))
+            .and_then(|b| b.set_override("matrix.token", "t"))
+            .and_then(|b| b.build())
+            .and_then(|c| c.try_deserialize())
+            .unwrap()
+    }
+}
+
+/// `builder` under the config files and `CUMMENTS_*` environment variables.
+fn layered(builder: ConfigBuilder<DefaultState>) -> Result<config::Config, ConfigError> {
+    let run_mode = std::env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+    builder
+        .add_source(config::File::with_name("config").required(false))
+        .add_source(config::File::with_name(&format!("config.{}", run_mode)).required(false))
+        .add_source(
+            config::Environment::with_prefix("CUMMENTS")
+                .try_parsing(true)
+                .separator("__")
+                .prefix_separator("_")
+                .convert_case(config::Case::Lower),
+        )
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(raw: &str) -> Result<Option<Vec<String>>, String> {
+        parse_cors_origins(raw)
+            .map(|list| list.map(|l| l.iter().map(|o| o.ascii_serialization()).collect()))
+    }
+
+    #[test]
+    fn test_cors_origins() {
+        assert_eq!(origins(" * "), Ok(None));
+        assert_eq!(
+            origins("https://Blog.example, http://localhost:8080/").unwrap(),
+            Some(vec![
+                "https://blog.example".to_string(),
+                "http://localhost:8080".to_string()
+            ])
+        );
+
+        for bad in [
+            "",
+            " , ",
+            "*, https://blog.example",
+            "blog.example",
+            "https://blog.example/comments",
+            "https://blog.example?x=1",
+            "https://user@blog.example",
+            "ftp://blog.example",
+            "https://blog.example, not an origin",
+        ] {
+            assert!(origins(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_per_page_bounds() {
+        let mut settings = Settings::for_tests();
+        for (default, max, ok) in [
+            (20, 100, true),
+            (20, 20, true),
+            (20, 10, false),
+            (0, 10, false),
+        ] {
+            settings.server.default_per_page = default;
+            settings.server.max_per_page = max;
+            assert_eq!(settings.validate().is_ok(), ok, "{} / {}", default, max);
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_rounds_bounds() {
+        let mut settings = Settings::for_tests();
+        for (rounds, ok) in [
+            (0, false),
+            (1, true),
+            (adapter::MAX_FINGERPRINT_ROUNDS, true),
+            (adapter::MAX_FINGERPRINT_ROUNDS + 1, false),
+        ] {
+            settings.security.fingerprint_rounds = rounds;
+            assert_eq!(settings.validate().is_ok(), ok, "{}", rounds);
+        }
+    }
+
+    #[test]
+    fn test_database_settings_stand_alone() {
+        // No Matrix credentials, as on a migration-only deploy step.
+        let config = defaults().unwrap().build().unwrap();
+        let database: DatabaseSettings = config.get("database").unwrap();
+        assert_eq!(database.url, "sqlite://data/cumments.db");
+        assert!(config.try_deserialize::<Settings>().is_err());
     }
 }
@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+const DISPOSABLE_DOMAINS: &str = include_str!("disposable_domains.txt");
+
+/// Email domains whose addresses are refused when commenting, so an email
+/// fingerprint can't be rotated for free. A listed domain blocks its
+/// subdomains too.
+pub struct EmailBlocklist {
+    domains: HashSet<String>,
+}
+
+impl EmailBlocklist {
+    /// `extra` is a comma-separated list added to the embedded disposable
+    /// providers (if `disposable` is set).
+    pub fn new(disposable: bool, extra: &str) -> Self {
+        let embedded = DISPOSABLE_DOMAINS
+            .lines()
+            .filter(|_| disposable)
+            .filter(|l| !l.starts_with('#'));
+        let domains = embedded
+            .chain(extra.split(','))
+            .map(normalize_domain)
+            .filter(|d| !d.is_empty())
+            .collect();
+        Self { domains }
+    }
+
+    pub fn is_blocked(&self, email: &str) -> bool {
+        let Some(domain) = email_domain(email) else {
+            return false;
+        };
+        let mut rest = domain.as_str();
+        loop {
+            if self.domains.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// `local@Example.COM.` becomes `example.com`.
+fn email_domain(email: &str) -> Option<String> {
+    let (_, domain) = email.trim().rsplit_once('@')?;
+    Some(normalize_domain(domain))
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_listed_domains_and_subdomains() {
+        let list = EmailBlocklist::new(true, " Spam.Example ,");
+        assert!(list.is_blocked("someone@mailinator.com"));
+        assert!(list.is_blocked("Some.One+tag@MAILINATOR.COM."));
+        assert!(list.is_blocked("x@inbox.mailinator.com"));
+        assert!(list.is_blocked("x@spam.example"));
+        assert!(!list.is_blocked("x@example.com"));
+        assert!(!list.is_blocked("x@notmailinator.com"));
+        assert!(!list.is_blocked("not-an-email"));
+
+        let extra_only = EmailBlocklist::new(false, "spam.example");
+        assert!(!extra_only.is_blocked("x@mailinator.com"));
+        assert!(extra_only.is_blocked("x@spam.example"));
+        assert_eq!(email_domain("A+b@c@X.org."), Some("x.org".to_string()));
+    }
+}
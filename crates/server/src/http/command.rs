@@ -0,0 +1,202 @@
+use axum::{http::StatusCode, Json};
+use domain::{Ack, AckError, AckResult, AppCommand};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc::error::TrySendError, oneshot};
+
+use crate::http::error::ApiError;
+use crate::state::AppState;
+
+/// What a client refused for a full command queue is told to wait; bursts
+/// drain in well under that.
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+/// Queues a command and waits up to `server.command_timeout_secs` for the
+/// Matrix driver to finish it. Returns `None` on timeout: the command is
+/// still in flight, so that is not a failure. A full queue is refused at
+/// once with `ApiError::Busy` rather than left to wait out the timeout.
+pub async fn dispatch_and_wait<T>(
+    state: &AppState,
+    build: impl FnOnce(Ack<T>) -> AppCommand,
+) -> Result<Option<T>, ApiError> {
+    let (ack, mut rx) = Ack::new();
+    enqueue(state, build(ack))?;
+    wait_for_ack(state, &mut rx).await
+}
+
+/// The first half of `dispatch_and_wait`, for callers with something to
+/// undo if the command never gets queued.
+pub fn enqueue(state: &AppState, cmd: AppCommand) -> Result<(), ApiError> {
+    match state.sender.try_send(cmd) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!("Command queue is full, refusing request");
+            Err(ApiError::Busy {
+                retry_after_secs: BUSY_RETRY_AFTER_SECS,
+            })
+        }
+        Err(TrySendError::Closed(_)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Worker closed".to_string(),
+        )
+            .into()),
+    }
+}
+
+/// The second half of `dispatch_and_wait`. On timeout `rx` can still be
+/// awaited for the driver's answer.
+pub async fn wait_for_ack<T>(
+    state: &AppState,
+    rx: &mut oneshot::Receiver<AckResult<T>>,
+) -> Result<Option<T>, ApiError> {
+    let timeout = Duration::from_secs(state.settings.server.command_timeout_secs);
+    match tokio::time::timeout(timeout, rx).await {
+        Ok(Ok(Ok(value))) => Ok(Some(value)),
+        Ok(Ok(Err(e))) => Err(ack_error_response(e)),
+        Ok(Err(_)) => Err((
+            StatusCode::BAD_GATEWAY,
+            "Worker dropped the command".to_string(),
+        )
+            .into()),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Refusals are the client's to fix and keep their message; an upstream
+/// failure is logged and answered without the homeserver's internals.
+fn ack_error_response(e: AckError) -> ApiError {
+    match e {
+        AckError::Rejected(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg).into(),
+        AckError::Unavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg).into(),
+        AckError::Failed(detail) => {
+            tracing::error!("Matrix driver failed a command: {}", detail);
+            (
+                StatusCode::BAD_GATEWAY,
+                "The Matrix homeserver request failed".to_string(),
+            )
+                .into()
+        }
+    }
+}
+
+/// `dispatch_and_wait` for commands with no result: 200 once done, 202 if
+/// still processing at the timeout.
+pub async fn send_cmd_and_wait(
+    state: &AppState,
+    build: impl FnOnce(Ack) -> AppCommand,
+) -> Result<(StatusCode, Json<&'static str>), ApiError> {
+    Ok(sent_or_processing(dispatch_and_wait(state, build).await?))
+}
+
+/// `send_cmd_and_wait` for a command on a claimed target. The claim is held
+/// until the driver answers, even when the request returns 202 first, so a
+/// retry can't race a command that is still in flight.
+pub async fn send_claimed_cmd_and_wait(
+    state: &AppState,
+    claim: InFlightGuard,
+    build: impl FnOnce(Ack) -> AppCommand,
+) -> Result<(StatusCode, Json<&'static str>), ApiError> {
+    let (ack, mut rx) = Ack::new();
+    enqueue(state, build(ack))?;
+    let done = wait_for_ack(state, &mut rx).await?;
+    if done.is_none() {
+        release_on_ack(claim, rx);
+    }
+    Ok(sent_or_processing(done))
+}
+
+fn release_on_ack<T: Send + 'static>(claim: InFlightGuard, rx: oneshot::Receiver<T>) {
+    tokio::spawn(async move {
+        let _ = rx.await;
+        drop(claim);
+    });
+}
+
+pub fn sent_or_processing(done: Option<()>) -> (StatusCode, Json<&'static str>) {
+    match done {
+        Some(()) => (StatusCode::OK, Json("Sent")),
+        None => (StatusCode::ACCEPTED, Json("Processing")),
+    }
+}
+
+/// IDs with a command in flight, so a second request for the same target can
+/// be refused instead of racing the first.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashSet<String>>>);
+
+impl InFlight {
+    /// Claims `id` until the returned guard is dropped; `None` if taken.
+    pub fn claim(&self, id: &str) -> Option<InFlightGuard> {
+        if !self.0.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(InFlightGuard {
+            set: self.clone(),
+            id: id.to_string(),
+        })
+    }
+}
+
+pub struct InFlightGuard {
+    set: InFlight,
+    id: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.set.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_claim_released_on_drop() {
+        let set = InFlight::default();
+        let guard = set.claim("$a").unwrap();
+        assert!(set.claim("$a").is_none());
+        assert!(set.claim("$b").is_some());
+        drop(guard);
+        assert!(set.claim("$a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_claim_held_until_ack() {
+        let set = InFlight::default();
+        let (tx, rx) = oneshot::channel::<AckResult<()>>();
+        release_on_ack(set.claim("$a").unwrap(), rx);
+        tokio::task::yield_now().await;
+        assert!(set.claim("$a").is_none());
+
+        tx.send(Ok(())).unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(set.claim("$a").is_some());
+    }
+
+    #[test]
+    fn test_ack_errors_map_to_statuses() {
+        let status = |e| match ack_error_response(e) {
+            ApiError::Plain(status, msg) => (status, msg),
+            _ => panic!("expected a plain error"),
+        };
+        assert_eq!(
+            status(AckError::Rejected("Comment too large".to_string())),
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Comment too large".to_string()
+            )
+        );
+        assert_eq!(
+            status(AckError::Unavailable("shutting down".to_string())).0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let (code, msg) = status(AckError::Failed("M_UNKNOWN: db down".to_string()));
+        assert_eq!(code, StatusCode::BAD_GATEWAY);
+        assert!(!msg.contains("M_UNKNOWN"));
+    }
+}
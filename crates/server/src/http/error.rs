@@ -0,0 +1,97 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::pow::PowError;
+
+/// A handler error: most are a status and a plain-text message, but
+/// failures a client should react to differently carry a `code` in a JSON
+/// body shaped like `BodyError`.
+pub enum ApiError {
+    Plain(StatusCode, String),
+    Coded(StatusCode, CodedError),
+    /// 503 `server_busy` with a `Retry-After`: the command queue is full.
+    Busy {
+        retry_after_secs: u64,
+    },
+}
+
+/// `{ "error": { "code", "message" } }`.
+#[derive(Serialize, ToSchema)]
+pub struct CodedError {
+    pub error: CodedErrorDetail,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CodedErrorDetail {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl CodedError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        CodedError {
+            error: CodedErrorDetail {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+}
+
+impl ApiError {
+    pub fn coded(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Coded(status, CodedError::new(code, message))
+    }
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        ApiError::Plain(status, message)
+    }
+}
+
+/// 403 with `challenge_invalid`, `challenge_expired`, `challenge_spent` or
+/// `insufficient_work`.
+impl From<PowError> for ApiError {
+    fn from(e: PowError) -> Self {
+        ApiError::coded(StatusCode::FORBIDDEN, e.code(), e.message())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::Plain(status, message) => (status, message).into_response(),
+            ApiError::Coded(status, body) => (status, Json(body)).into_response(),
+            ApiError::Busy { retry_after_secs } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, retry_after_secs.to_string())],
+                Json(CodedError::new(
+                    "server_busy",
+                    "Server is busy, try again shortly",
+                )),
+            )
+                .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_busy_asks_to_retry() {
+        let response = ApiError::Busy {
+            retry_after_secs: 1,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+}
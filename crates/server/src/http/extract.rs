@@ -0,0 +1,343 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Path, Request},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        request::Parts,
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use domain::SiteId;
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use utoipa::ToSchema;
+
+use crate::state::AppState;
+
+/// The `:site_id` path segment, trimmed, lowercased and validated once so
+/// every handler sees the same normalized `SiteId`.
+pub struct ValidatedSiteId(pub SiteId);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ValidatedSiteId {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        #[derive(Deserialize)]
+        struct Params {
+            site_id: String,
+        }
+
+        let Path(params) = Path::<Params>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+        SiteId::new(params.site_id.trim().to_ascii_lowercase())
+            .map(Self)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))
+    }
+}
+
+/// Route layer for the `/api/:site_id` group: rejects a bad site id before
+/// any handler runs. Handlers still take `ValidatedSiteId` for the value.
+pub async fn require_site_id(_: ValidatedSiteId, req: Request, next: Next) -> Response {
+    next.run(req).await
+}
+
+/// The address per-client limits are keyed on: the peer's, or the one a
+/// trusted proxy (`server.trusted_proxies`) forwarded for it.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let forwarded = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(Self(client_ip(
+            peer.ip(),
+            &forwarded,
+            &state.trusted_proxies,
+        )))
+    }
+}
+
+/// Walks `X-Forwarded-For` from the right, past trusted proxies, to the
+/// first hop that isn't one. Hops further left were written by the client,
+/// so they're never believed; a malformed hop stops the walk where it is.
+fn client_ip(peer: IpAddr, forwarded_for: &str, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    if !is_trusted(&client) {
+        return client;
+    }
+    for hop in forwarded_for
+        .rsplit(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+    {
+        let Ok(ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// The `:slug` segment of site-scoped routes; pair with `ValidatedSiteId`.
+#[derive(Deserialize)]
+pub struct SlugPath {
+    pub slug: String,
+}
+
+/// Guards admin routes behind `security.admin_token`, sent as a bearer token.
+/// The admin API is disabled entirely when no token is configured.
+pub struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = state.settings.security.admin_token.as_deref() else {
+            return Err((StatusCode::FORBIDDEN, "Admin API is disabled".to_string()));
+        };
+
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        if !provided.is_some_and(|p| token_matches(p, expected)) {
+            return Err((StatusCode::UNAUTHORIZED, "Invalid admin token".to_string()));
+        }
+        Ok(AdminAuth)
+    }
+}
+
+/// Compares MACs of both tokens in constant time, so neither the content
+/// nor the length of the admin token leaks through response timing.
+fn token_matches(provided: &str, expected: &str) -> bool {
+    let mac = |token: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"cumments-admin-token")
+            .expect("HMAC accepts any key length");
+        mac.update(token.as_bytes());
+        mac
+    };
+    mac(provided)
+        .verify_slice(&mac(expected).finalize().into_bytes())
+        .is_ok()
+}
+
+/// A JSON body whose rejections say which field failed, as
+/// `{ "error": { "code": "invalid_body", "message", "fields": { path: reason } } }`.
+/// An empty body is read as `{}`, so optional bodies need no `Option`
+/// wrapper and required fields are reported as missing.
+pub struct ValidJson<T>(pub T);
+
+/// The JSON body of a `ValidJson` rejection.
+#[derive(Serialize, ToSchema)]
+pub struct BodyError {
+    pub error: BodyErrorDetail,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BodyErrorDetail {
+    /// Always `invalid_body`.
+    pub code: &'static str,
+    pub message: String,
+    /// Field path, e.g. `author.name`, mapped to why it was rejected.
+    #[schema(value_type = HashMap<String, String>)]
+    pub fields: Map<String, Value>,
+}
+
+pub struct BodyRejection {
+    status: StatusCode,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl BodyRejection {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            fields: Map::new(),
+        }
+    }
+}
+
+impl IntoResponse for BodyRejection {
+    fn into_response(self) -> Response {
+        let body = BodyError {
+            error: BodyErrorDetail {
+                code: "invalid_body",
+                message: self.message,
+                fields: self.fields,
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = BodyRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|mime| {
+                let mime = mime.trim();
+                mime == "application/json" || mime.ends_with("+json")
+            })
+            .unwrap_or(false);
+
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| BodyRejection::new(e.status(), e.body_text()))?;
+        let bytes: &[u8] = if bytes.is_empty() {
+            b"{}"
+        } else if is_json {
+            &bytes
+        } else {
+            return Err(BodyRejection::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected `Content-Type: application/json`",
+            ));
+        };
+
+        let de = &mut serde_json::Deserializer::from_slice(bytes);
+        serde_path_to_error::deserialize(de)
+            .map(Self)
+            .map_err(field_rejection)
+    }
+}
+
+fn field_rejection(err: serde_path_to_error::Error<serde_json::Error>) -> BodyRejection {
+    let inner = err.inner();
+    if !inner.is_data() {
+        return BodyRejection::new(StatusCode::BAD_REQUEST, inner.to_string());
+    }
+
+    let reason = inner.to_string();
+    let reason = reason
+        .rsplit_once(" at line ")
+        .map_or(reason.as_str(), |(r, _)| r)
+        .to_string();
+
+    // A missing field fails at its parent, so name it from the message.
+    let path = err.path().to_string();
+    let field = match reason
+        .strip_prefix("missing field `")
+        .and_then(|r| r.strip_suffix('`'))
+    {
+        Some(name) if path == "." => name.to_string(),
+        Some(name) => format!("{}.{}", path, name),
+        None => path,
+    };
+
+    let mut rejection =
+        BodyRejection::new(StatusCode::UNPROCESSABLE_ENTITY, "Invalid request body");
+    rejection.fields.insert(field, Value::String(reason));
+    rejection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Body {
+        name: String,
+        count: u32,
+    }
+
+    fn reject(json: &str) -> BodyRejection {
+        let de = &mut serde_json::Deserializer::from_str(json);
+        field_rejection(serde_path_to_error::deserialize::<_, Body>(de).unwrap_err())
+    }
+
+    #[test]
+    fn test_field_rejection_names_the_field() {
+        let missing = reject(r#"{"name": "a"}"#);
+        assert_eq!(missing.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(missing.fields["count"], "missing field `count`");
+
+        let wrong_type = reject(r#"{"name": "a", "count": "x"}"#);
+        assert!(wrong_type.fields.contains_key("count"));
+
+        let syntax = reject(r#"{"name": "#);
+        assert_eq!(syntax.status, StatusCode::BAD_REQUEST);
+        assert!(syntax.fields.is_empty());
+    }
+
+    #[test]
+    fn test_admin_token_comparison() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cre", "s3cret"));
+        assert!(!token_matches("s3cret ", "s3cret"));
+        assert!(!token_matches("", "s3cret"));
+    }
+
+    #[test]
+    fn test_client_ip_behind_trusted_proxies() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        // An untrusted peer can't speak for anyone else.
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), "198.51.100.1", &trusted),
+            ip("203.0.113.7")
+        );
+        // Past the trusted hops, but not into what the client wrote itself.
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), "1.2.3.4, 198.51.100.1, 10.0.0.2", &trusted),
+            ip("198.51.100.1")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), "", &trusted), ip("10.0.0.1"));
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), "1.2.3.4, garbage", &trusted),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), "198.51.100.1", &[]),
+            ip("10.0.0.1")
+        );
+    }
+}
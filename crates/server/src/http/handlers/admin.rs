@@ -0,0 +1,1058 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use domain::{
+    Ack, AppCommand, AuditEntry, Comment, DeadLetter, DeletionKind, IngestEvent, OriginKind,
+    PurgeReplies, ThreadOrder, ThreadSummary,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::audit::{self, admin_entry};
+use crate::http::command::{
+    dispatch_and_wait, enqueue, send_claimed_cmd_and_wait, sent_or_processing, wait_for_ack,
+};
+use crate::http::error::ApiError;
+use crate::http::extract::{AdminAuth, SlugPath, ValidJson, ValidatedSiteId};
+use crate::http::handlers::comments::{is_reserved_slug, parse_post_url};
+use crate::http::pagination::Pager;
+use crate::state::AppState;
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/settings",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "`effective`, `overrides` and `defaults` settings", body = Object),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn get_site_settings(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let effective = state
+        .site_config
+        .get(&site_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let overrides = state
+        .site_config
+        .overrides(&site_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(serde_json::json!({
+        "effective": effective,
+        "overrides": overrides,
+        "defaults": state.site_config.defaults(),
+    })))
+}
+
+/// Applies a partial update: each key maps to its new value, or `null` to
+/// drop the override and fall back to the file config.
+#[utoipa::path(
+    put,
+    path = "/api/{site_id}/admin/settings",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body(content = Object, description = "Setting names mapped to new values, or `null` to reset"),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Settings after the update, as from GET", body = Object),
+        (status = 400, description = "Invalid site ID or setting", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn update_site_settings(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Json(changes): Json<HashMap<String, Value>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let changes: Vec<(String, Option<String>)> = changes
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Null => None,
+                Value::String(s) => Some(s),
+                other => Some(other.to_string()),
+            };
+            (key, value)
+        })
+        .collect();
+
+    for (key, value) in &changes {
+        state
+            .site_config
+            .validate(key, value.as_deref())
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+
+    for (key, value) in &changes {
+        state
+            .site_config
+            .set(&site_id, key, value.as_deref())
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    let mut entry = admin_entry("update_settings", &site_id);
+    entry.detail =
+        Some(serde_json::json!(changes.iter().cloned().collect::<HashMap<_, _>>()).to_string());
+    audit::record(&state.db, entry);
+
+    get_site_settings(AdminAuth, State(state), ValidatedSiteId(site_id)).await
+}
+
+#[derive(Deserialize)]
+pub struct CommentPath {
+    comment_id: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeleteCommentRequest {
+    reason: Option<String>,
+}
+
+/// Redacts a comment on Matrix. As soon as the homeserver accepts the
+/// redaction the local copy is soft-deleted and streams get an
+/// `admin_removed` deletion, without waiting for sync to echo it. A refusal,
+/// e.g. the bot lacking redact power, answers 422; a comment that is already
+/// gone 410, one whose redaction is still in flight 409.
+#[utoipa::path(
+    delete,
+    path = "/api/{site_id}/admin/comments/{comment_id}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body(content = DeleteCommentRequest, description = "Optional"),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("comment_id" = String, Path, description = "Event ID of the comment")
+    ),
+    responses(
+        (status = 200, description = "Redacted on Matrix and marked removed here", body = String, content_type = "application/json", example = json!("Sent")),
+        (status = 202, description = "Still running at the command timeout; the comment is marked removed once the redaction goes through", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 500, description = "The Matrix worker has stopped", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 404, description = "No such comment on this site", body = String),
+        (status = 409, description = "A redaction is already in flight", body = String),
+        (status = 410, description = "The comment was already removed", body = String),
+        (status = 415, description = "Body is not JSON", body = BodyError),
+        (status = 422, description = "A field is missing or invalid (`BodyError`), or the Matrix driver refused the redaction, e.g. the bot lacks power in the room (plain text)", body = BodyError),
+        (status = 503, description = "The command queue is full (`server_busy`); retry after `Retry-After` seconds; or the relay is shutting down", body = CodedError)
+    )
+)]
+pub async fn delete_comment(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(CommentPath { comment_id }): Path<CommentPath>,
+    ValidJson(payload): ValidJson<DeleteCommentRequest>,
+) -> Result<(StatusCode, Json<&'static str>), ApiError> {
+    let comment = state
+        .db
+        .get_comment(&comment_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let comment = match comment {
+        Some(c) if c.site_id == site_id && c.is_redacted => {
+            return Err((StatusCode::GONE, "Comment was already removed".to_string()).into())
+        }
+        Some(c) if c.site_id == site_id => c,
+        _ => return Err((StatusCode::NOT_FOUND, "Comment not found".to_string()).into()),
+    };
+
+    let Some(claim) = state.redactions.claim(&comment_id) else {
+        return Err((
+            StatusCode::CONFLICT,
+            "Comment is already being removed".to_string(),
+        )
+            .into());
+    };
+    let mut entry = admin_entry("redact_comment", &site_id);
+    entry.post_slug = Some(comment.post_slug);
+    entry.comment_id = Some(comment_id.clone());
+    entry.reason = payload.reason.clone();
+    let sent = send_claimed_cmd_and_wait(&state, claim, |ack| AppCommand::RedactComment {
+        site_id,
+        comment_id,
+        reason: payload.reason,
+        ack,
+    })
+    .await?;
+    audit::record(&state.db, entry);
+    Ok(sent)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/dead-letters",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Commands the Matrix driver failed to carry out", body = [DeadLetter]),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_dead_letters(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Json<Vec<DeadLetter>>, (StatusCode, String)> {
+    let letters = state
+        .db
+        .list_dead_letters(site_id.as_str())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(letters))
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterPath {
+    id: i64,
+}
+
+/// Removes a dead letter and re-enqueues its command. If it fails again the
+/// driver records a new entry.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/dead-letters/{id}/retry",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("id" = i64, Path, description = "Dead letter ID")
+    ),
+    responses(
+        (status = 200, description = "Carried out by the Matrix driver", body = String, content_type = "application/json", example = json!("Sent")),
+        (status = 202, description = "Still running at the command timeout", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 500, description = "The Matrix worker has stopped", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 404, description = "No such dead letter", body = String),
+        (status = 422, description = "The dead letter can't be retried, e.g. a send stored before guest secrets were dropped from dead letters, or the Matrix driver refused it again", body = String),
+        (status = 503, description = "The command queue is full (`server_busy`); the dead letter is kept, retry after `Retry-After` seconds; or the relay is shutting down", body = CodedError)
+    )
+)]
+pub async fn retry_dead_letter(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(DeadLetterPath { id }): Path<DeadLetterPath>,
+) -> Result<(StatusCode, Json<&'static str>), ApiError> {
+    let letter = state
+        .db
+        .take_dead_letter(site_id.as_str(), id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Dead letter not found".to_string()))?;
+    // Taken first so a concurrent retry can't queue it too; put back unless
+    // the driver actually has it.
+    let (ack, mut rx) = Ack::new();
+    let queued = match serde_json::from_str::<AppCommand>(&letter.command) {
+        Ok(cmd) => enqueue(&state, cmd.with_ack(ack)),
+        Err(e) => Err(ApiError::from((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Dead letter can't be retried: {}", e),
+        ))),
+    };
+    if let Err(e) = queued {
+        if let Err(restore) = state.db.restore_dead_letter(&letter).await {
+            tracing::error!("Failed to restore dead letter {}: {:?}", id, restore);
+        }
+        return Err(e);
+    }
+
+    let done = wait_for_ack(&state, &mut rx).await?;
+    // The command carries a reader's comment text, which doesn't belong in
+    // the audit log, so only its ID is kept.
+    let mut entry = admin_entry("retry_dead_letter", &site_id);
+    entry.detail = Some(format!("dead letter {}", id));
+    audit::record(&state.db, entry);
+    Ok(sent_or_processing(done))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PurgeRequest {
+    /// Only purge comments redacted at least this many days ago.
+    older_than_days: u32,
+    #[serde(default)]
+    replies: PurgeReplies,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PurgeReport {
+    purged: u64,
+}
+
+/// Hard-deletes the site's redacted comments, which otherwise stay around
+/// as placeholders. Replies survive, re-parented or made top-level. Each
+/// purged comment goes out to live clients as an `erased` deletion.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/purge-redacted",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = PurgeRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Number of comments purged", body = PurgeReport),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 415, description = "Body is not JSON", body = BodyError),
+        (status = 422, description = "A field is missing or invalid", body = BodyError),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn purge_redacted(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    ValidJson(payload): ValidJson<PurgeRequest>,
+) -> Result<Json<PurgeReport>, (StatusCode, String)> {
+    let before =
+        chrono::Utc::now().naive_utc() - chrono::Duration::days(payload.older_than_days.into());
+    let erased = state
+        .db
+        .purge_redacted(site_id.as_str(), before, payload.replies)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let purged = erased.len() as u64;
+    for (comment_id, post_slug) in erased {
+        let _ = state.tx_ingest.send(IngestEvent::CommentDeleted {
+            site_id: site_id.clone(),
+            post_slug,
+            comment_id,
+            kind: DeletionKind::Erased,
+        });
+    }
+    tracing::info!("Purged {} redacted comments from {}", purged, site_id);
+    let mut entry = admin_entry("purge_redacted", &site_id);
+    entry.detail = Some(
+        serde_json::json!({
+            "older_than_days": payload.older_than_days,
+            "replies": payload.replies,
+            "purged": purged,
+        })
+        .to_string(),
+    );
+    audit::record(&state.db, entry);
+    Ok(Json(PurgeReport { purged }))
+}
+
+/// Re-reads a thread's recent Matrix timeline and re-upserts its comments,
+/// for repairing rows missed while the relay was down. Returns the counts, or
+/// 202 if the driver is still working at the command timeout.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/resync/{slug}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "Rows touched by the resync", body = ResyncReport),
+        (status = 202, description = "Still running at the command timeout", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 422, description = "The Matrix homeserver refused the command", body = String),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 503, description = "The command queue is full (`server_busy`); retry after `Retry-After` seconds; or the relay is shutting down", body = CodedError)
+    )
+)]
+pub async fn resync_room(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<Response, ApiError> {
+    let mut entry = admin_entry("resync_room", &site_id);
+    entry.post_slug = Some(slug.clone());
+    let report = dispatch_and_wait(&state, |ack| AppCommand::ResyncRoom {
+        site_id,
+        post_slug: slug,
+        ack,
+    })
+    .await?;
+    audit::record(&state.db, entry);
+    Ok(match report {
+        Some(report) => Json(report).into_response(),
+        None => (StatusCode::ACCEPTED, Json("Processing")).into_response(),
+    })
+}
+
+/// Finds or creates a thread's room, and the site space, without posting a
+/// comment, so operators can set topics or invite moderators up front.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/rooms/{slug}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "The thread's room", body = ProvisionedRoom),
+        (status = 202, description = "Still running at the command timeout", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 422, description = "The Matrix homeserver refused the command", body = String),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 503, description = "The command queue is full (`server_busy`); retry after `Retry-After` seconds; or the relay is shutting down", body = CodedError)
+    )
+)]
+pub async fn ensure_room(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<Response, ApiError> {
+    let mut entry = admin_entry("ensure_room", &site_id);
+    entry.post_slug = Some(slug.clone());
+    let room = dispatch_and_wait(&state, |ack| AppCommand::EnsureRoom {
+        site_id,
+        post_slug: slug,
+        ack,
+    })
+    .await?;
+    audit::record(&state.db, entry);
+    Ok(match room {
+        Some(room) => Json(room).into_response(),
+        None => (StatusCode::ACCEPTED, Json("Processing")).into_response(),
+    })
+}
+
+/// Re-links the site's thread rooms that are missing from its space, e.g.
+/// because linking failed when they were created.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/space-links",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Rooms checked, re-linked and failed", body = SpaceLinkReport),
+        (status = 202, description = "Still running at the command timeout", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 422, description = "The Matrix driver refused the command, e.g. spaces are disabled", body = String),
+        (status = 503, description = "The command queue is full (`server_busy`); retry after `Retry-After` seconds; or the relay is shutting down", body = CodedError)
+    )
+)]
+pub async fn link_space(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Response, ApiError> {
+    let entry = admin_entry("link_space", &site_id);
+    let report = dispatch_and_wait(&state, |ack| AppCommand::LinkSpace { site_id, ack }).await?;
+    audit::record(&state.db, entry);
+    Ok(match report {
+        Some(report) => Json(report).into_response(),
+        None => (StatusCode::ACCEPTED, Json("Processing")).into_response(),
+    })
+}
+
+/// Live comment counts broken down by the kind of account that sent them:
+/// the relay bot, an appservice ghost, or a native Matrix user.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/stats",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "`total`, `by_origin_kind`, `federated` and `flagged` counts", body = Object),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn get_stats(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let counts = state
+        .db
+        .count_comments_by_origin_kind(site_id.as_str())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut by_origin_kind = serde_json::Map::new();
+    for kind in [OriginKind::Bot, OriginKind::Ghost, OriginKind::Native] {
+        by_origin_kind.insert(kind.as_str().to_string(), 0.into());
+    }
+    let mut total = 0;
+    for (kind, count) in counts {
+        by_origin_kind.insert(kind.as_str().to_string(), count.into());
+        total += count;
+    }
+    let (federated, flagged) = state
+        .db
+        .count_federated_and_flagged(site_id.as_str())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "by_origin_kind": by_origin_kind,
+        "federated": federated,
+        "flagged": flagged,
+    })))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SiteCommentsQuery {
+    #[serde(default)]
+    flagged: bool,
+    #[serde(default)]
+    federated: bool,
+    #[serde(default)]
+    reported: bool,
+    limit: Option<u32>,
+}
+
+/// Newest comments across the whole site, optionally only flagged, federated
+/// or reader-reported ones.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/comments",
+    tag = "admin",
+    security(("admin_token" = [])),
+    operation_id = "list_site_comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        SiteCommentsQuery
+    ),
+    responses(
+        (status = 200, description = "Newest matching comments across the site", body = [Comment]),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_comments(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Query(query): Query<SiteCommentsQuery>,
+) -> Result<Json<Vec<Comment>>, (StatusCode, String)> {
+    let pager = Pager::new(None, query.limit, &state.settings.server);
+    let comments = state
+        .db
+        .list_site_comments(
+            site_id.as_str(),
+            query.flagged,
+            query.federated,
+            query.reported,
+            pager.limit(),
+        )
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(comments))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ThreadsQuery {
+    #[serde(default)]
+    order: ThreadOrder,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+/// The site's threads with comment counts and last activity, for dashboards.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/threads",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ThreadsQuery
+    ),
+    responses(
+        (status = 200, description = "Threads with comment counts and last activity", body = [ThreadSummary]),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_threads(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Query(query): Query<ThreadsQuery>,
+) -> Result<Json<Vec<ThreadSummary>>, (StatusCode, String)> {
+    let pager = Pager::new(query.page, query.per_page, &state.settings.server);
+    let threads = state
+        .db
+        .list_threads(site_id.as_str(), query.order, pager.limit(), pager.offset())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(threads))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PostUrlRequest {
+    /// Absolute http(s) URL, or `null` to forget it.
+    url: Option<String>,
+}
+
+/// Overrides the post URL recorded for a thread, whoever set it first.
+/// Clearing it lets the next comment that names one record it again.
+#[utoipa::path(
+    put,
+    path = "/api/{site_id}/admin/threads/{slug}/url",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = PostUrlRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "The thread's post URL after the update", body = PostUrlRequest),
+        (status = 400, description = "Invalid site ID or URL", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn set_post_url(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+    ValidJson(payload): ValidJson<PostUrlRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let url = payload
+        .url
+        .as_deref()
+        .map(parse_post_url)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .db
+        .set_post_url(site_id.as_str(), &slug, url.as_deref())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut entry = admin_entry("set_post_url", &site_id);
+    entry.post_slug = Some(slug);
+    entry.detail = url.clone();
+    audit::record(&state.db, entry);
+    Ok(Json(serde_json::json!({ "url": url })))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ActivityQuery {
+    hours: Option<u32>,
+}
+
+/// Live submission rates from memory plus a stored per-hour history, for an
+/// operator dashboard. `hours` defaults to 24 and is capped at a week.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/activity",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ActivityQuery
+    ),
+    responses(
+        (status = 200, description = "`last_minute` and `last_hour` rates plus `hourly` counts", body = Object),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn get_activity(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Query(query): Query<ActivityQuery>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let hours = query.hours.unwrap_or(24).clamp(1, 24 * 7);
+    let since = chrono::Utc::now().naive_utc() - chrono::Duration::hours(hours as i64);
+    let hourly = state
+        .db
+        .count_comments_by_hour(site_id.as_str(), since)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let rates = state.activity.rates(site_id.as_str());
+
+    Ok(Json(serde_json::json!({
+        "last_minute": rates.last_minute,
+        "last_hour": rates.last_hour,
+        "hourly": hourly
+            .into_iter()
+            .map(|(hour, count)| serde_json::json!({ "hour": hour, "count": count }))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+/// A site's slug allowlist. It only restricts posting while the site's
+/// `slug_allowlist` setting is on.
+#[derive(Serialize, ToSchema)]
+pub struct SlugAllowlist {
+    enabled: bool,
+    slugs: Vec<String>,
+}
+
+async fn slug_allowlist(
+    state: &AppState,
+    site_id: &domain::SiteId,
+) -> Result<Json<SlugAllowlist>, (StatusCode, String)> {
+    let internal = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let enabled = state
+        .site_config
+        .get(site_id)
+        .await
+        .map_err(internal)?
+        .slug_allowlist;
+    let slugs = state
+        .db
+        .list_allowed_slugs(site_id.as_str())
+        .await
+        .map_err(internal)?;
+    Ok(Json(SlugAllowlist { enabled, slugs }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/admin/slugs",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "The allowlist and whether it is enforced", body = SlugAllowlist),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_allowed_slugs(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Json<SlugAllowlist>, (StatusCode, String)> {
+    slug_allowlist(&state, &site_id).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddSlugsRequest {
+    slugs: Vec<String>,
+}
+
+/// Adds slugs to the allowlist; ones already on it are left alone.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/admin/slugs",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = AddSlugsRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "The allowlist after the update", body = SlugAllowlist),
+        (status = 400, description = "Invalid site ID, or an empty or reserved slug", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn add_allowed_slugs(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    ValidJson(payload): ValidJson<AddSlugsRequest>,
+) -> Result<Json<SlugAllowlist>, (StatusCode, String)> {
+    if payload.slugs.iter().any(|s| s.trim().is_empty()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Slugs must not be empty".to_string(),
+        ));
+    }
+    if let Some(slug) = payload.slugs.iter().find(|s| is_reserved_slug(s)) {
+        return Err((StatusCode::BAD_REQUEST, format!("Reserved slug: {}", slug)));
+    }
+    state
+        .db
+        .add_allowed_slugs(site_id.as_str(), &payload.slugs)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut entry = admin_entry("allow_slugs", &site_id);
+    entry.detail = Some(serde_json::json!(payload.slugs).to_string());
+    audit::record(&state.db, entry);
+    slug_allowlist(&state, &site_id).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/{site_id}/admin/slugs/{slug}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "The allowlist after the removal", body = SlugAllowlist),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 404, description = "The slug was not on the allowlist", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn remove_allowed_slug(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<Json<SlugAllowlist>, (StatusCode, String)> {
+    let removed = state
+        .db
+        .remove_allowed_slug(site_id.as_str(), &slug)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !removed {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{} is not on the allowlist", slug),
+        ));
+    }
+    let mut entry = admin_entry("disallow_slug", &site_id);
+    entry.post_slug = Some(slug);
+    audit::record(&state.db, entry);
+    slug_allowlist(&state, &site_id).await
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only this site's entries.
+    site_id: Option<String>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+/// Moderation and admin actions across all sites, newest first: what the
+/// admin API did and comments room members removed from Matrix clients.
+#[utoipa::path(
+    get,
+    path = "/api/admin/audit",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Audit log entries, newest first", body = [AuditEntry]),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_audit(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, (StatusCode, String)> {
+    let pager = Pager::new(query.page, query.per_page, &state.settings.server);
+    let entries = state
+        .db
+        .list_audit(query.site_id.as_deref(), pager.limit(), pager.offset())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{AckError, ProvisionedRoom, SiteId, SpaceLinkReport};
+
+    #[tokio::test]
+    async fn test_link_space_reports_driver_result() {
+        let (state, mut rx) = AppState::for_tests().await;
+        let driver = tokio::spawn(async move {
+            let mut answers = [
+                Ok(SpaceLinkReport {
+                    checked: 3,
+                    linked: 1,
+                    failed: 1,
+                }),
+                Err(AckError::Rejected("Spaces are disabled".to_string())),
+            ]
+            .into_iter();
+            while let Some(cmd) = rx.recv().await {
+                let AppCommand::LinkSpace { site_id, ack } = cmd else {
+                    panic!("unexpected command");
+                };
+                assert_eq!(site_id.as_str(), "blog");
+                ack.resolve(answers.next().unwrap());
+            }
+        });
+        let link = || {
+            let site_id = SiteId::new_unchecked("blog".to_string());
+            link_space(AdminAuth, State(state.clone()), ValidatedSiteId(site_id))
+        };
+
+        let Ok(response) = link().await else {
+            panic!("the report was refused");
+        };
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({"checked": 3, "linked": 1, "failed": 1})
+        );
+
+        let Err(refused) = link().await else {
+            panic!("a refusal was reported as success");
+        };
+        assert_eq!(
+            refused.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        driver.abort();
+    }
+
+    #[tokio::test]
+    async fn test_ensure_room_returns_and_audits_the_room() {
+        let (state, mut rx) = AppState::for_tests().await;
+        let driver = tokio::spawn(async move {
+            let Some(AppCommand::EnsureRoom {
+                site_id,
+                post_slug,
+                ack,
+            }) = rx.recv().await
+            else {
+                panic!("expected EnsureRoom");
+            };
+            ack.resolve(Ok(ProvisionedRoom {
+                room_id: "!r:x".to_string(),
+                room_alias: format!("#{}_{}:x", site_id, post_slug),
+            }));
+        });
+
+        let Ok(response) = ensure_room(
+            AdminAuth,
+            State(state.clone()),
+            ValidatedSiteId(SiteId::new_unchecked("blog".to_string())),
+            Path(SlugPath {
+                slug: "hello".to_string(),
+            }),
+        )
+        .await
+        else {
+            panic!("provisioning was refused");
+        };
+        driver.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let room: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            room,
+            serde_json::json!({"room_id": "!r:x", "room_alias": "#blog_hello:x"})
+        );
+
+        // The audit entry is written in the background.
+        let audited = async {
+            loop {
+                let entries = state.db.list_audit(Some("blog"), 10, 0).await.unwrap();
+                if let Some(entry) = entries.into_iter().next() {
+                    return entry;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        let entry = tokio::time::timeout(std::time::Duration::from_secs(5), audited)
+            .await
+            .unwrap();
+        assert_eq!(entry.action, "ensure_room");
+        assert_eq!(entry.post_slug.as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_reserved_slug_cannot_be_allowed() {
+        let (state, _rx) = AppState::for_tests().await;
+        let add = |slugs: &[&str]| {
+            let request = AddSlugsRequest {
+                slugs: slugs.iter().map(|s| s.to_string()).collect(),
+            };
+            add_allowed_slugs(
+                AdminAuth,
+                State(state.clone()),
+                ValidatedSiteId(SiteId::new_unchecked("blog".to_string())),
+                ValidJson(request),
+            )
+        };
+        let Err((status, _)) = add(&["hello", "batch"]).await else {
+            panic!("a reserved slug was allowed");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(state
+            .db
+            .list_allowed_slugs("blog")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let Json(list) = add(&["hello"]).await.unwrap();
+        assert_eq!(list.slugs, ["hello"]);
+    }
+}
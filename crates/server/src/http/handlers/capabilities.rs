@@ -0,0 +1,117 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::{MatrixSettings, Settings};
+use crate::http::extract::ValidatedSiteId;
+use crate::pow::{ALGORITHM, CHALLENGE_TTL_SECS};
+use crate::site_config::SiteConfig;
+use crate::state::AppState;
+
+/// What a widget needs to know to talk to this deployment. Everything here
+/// is public; secrets never leave the config.
+#[derive(Serialize, ToSchema)]
+pub struct Capabilities {
+    /// Server version.
+    pub version: &'static str,
+    /// How the relay talks to Matrix.
+    pub matrix_mode: MatrixMode,
+    pub pow: PowParams,
+    /// Longest accepted comment, in chars.
+    pub max_content_length: usize,
+    /// Comment bodies are rendered as Markdown.
+    pub markdown: bool,
+    /// Replies are relayed as Matrix threads.
+    pub threads: bool,
+    /// Reactions are not relayed yet, so this is always `false`.
+    pub reactions: bool,
+    /// New comments may get link preview cards.
+    pub link_previews: bool,
+    /// `/api/identicon/:seed` serves avatars.
+    pub identicons: bool,
+    /// Comments are only accepted on allowlisted slugs.
+    pub slug_allowlist: bool,
+    /// Live events carry the parent of each reply.
+    pub sse_reply_context: bool,
+    pub default_per_page: u32,
+    pub max_per_page: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MatrixMode {
+    /// One bot account relays every guest comment.
+    Bot,
+    /// An appservice gives each guest a ghost user, so redactions and edits
+    /// are attributed to them.
+    AppService,
+}
+
+/// Parameters for solving `/api/challenge`.
+#[derive(Serialize, ToSchema)]
+pub struct PowParams {
+    /// See `ChallengeResponse::algorithm`.
+    pub algorithm: &'static str,
+    /// Base difficulty for this site.
+    pub difficulty: usize,
+    /// One extra digit per this many chars of content; 0 means no scaling.
+    pub length_step: usize,
+    /// Cap for length-scaled difficulty.
+    pub max_difficulty: usize,
+    /// How long a challenge stays valid.
+    pub challenge_ttl_secs: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/config",
+    tag = "comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Public capabilities of this deployment for the site", body = Capabilities),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn get_capabilities(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Json<Capabilities>, (StatusCode, String)> {
+    let site = state
+        .site_config
+        .get(&site_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(capabilities(&state.settings, &site)))
+}
+
+fn capabilities(settings: &Settings, site: &SiteConfig) -> Capabilities {
+    let server = &settings.server;
+    let security = &settings.security;
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        matrix_mode: match settings.matrix {
+            MatrixSettings::Bot { .. } => MatrixMode::Bot,
+            MatrixSettings::AppService { .. } => MatrixMode::AppService,
+        },
+        pow: PowParams {
+            algorithm: ALGORITHM,
+            difficulty: site.pow_difficulty,
+            length_step: security.pow_length_step,
+            max_difficulty: security.pow_max_difficulty.max(site.pow_difficulty),
+            challenge_ttl_secs: CHALLENGE_TTL_SECS,
+        },
+        max_content_length: site.max_content_length,
+        markdown: true,
+        threads: settings.relay.use_threads,
+        reactions: false,
+        link_previews: site.link_previews,
+        identicons: server.identicons,
+        slug_allowlist: site.slug_allowlist,
+        sse_reply_context: server.sse_reply_context,
+        default_per_page: server.default_per_page,
+        max_per_page: server.max_per_page,
+    }
+}
@@ -1,7 +1,107 @@
+use crate::http::extract::ClientIp;
+use crate::pow;
 use crate::state::AppState;
-use axum::{extract::State, Json};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use domain::SiteId;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-pub async fn get_challenge(State(state): State<AppState>) -> Json<serde_json::Value> {
-    let secret = state.pow.generate_challenge();
-    Json(serde_json::json!({ "secret": secret, "difficulty": 4 }))
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChallengeQuery {
+    pub site_id: Option<String>,
+    /// Length in chars of the comment about to be posted. Longer comments
+    /// get harder challenges when `security.pow_length_step` is set.
+    pub content_length: Option<usize>,
+}
+
+/// Self-describing challenge. Solvers should dispatch on `algorithm` and
+/// read `params`; `secret` and `difficulty` repeat them for clients written
+/// against the original `{ secret, difficulty }` shape.
+#[derive(Serialize, ToSchema)]
+pub struct ChallengeResponse {
+    /// Envelope format version.
+    pub version: u32,
+    /// `sha256-leading-zero-hex`: find a nonce such that
+    /// `sha256(challenge + nonce)` starts with `params.difficulty` zero hex
+    /// digits.
+    pub algorithm: &'static str,
+    pub params: ChallengeParams,
+    /// Signed challenge; post it back as `challenge|nonce`.
+    pub challenge: String,
+    /// Deprecated: same as `challenge`.
+    pub secret: String,
+    /// Deprecated: same as `params.difficulty`.
+    pub difficulty: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChallengeParams {
+    /// Leading zero hex digits needed.
+    pub difficulty: usize,
+}
+
+const ENVELOPE_VERSION: u32 = 1;
+
+#[utoipa::path(
+    get,
+    path = "/api/challenge",
+    tag = "comments",
+    params(
+        ChallengeQuery
+    ),
+    responses(
+        (status = 200, description = "A proof-of-work challenge to solve before posting", body = ChallengeResponse),
+        (status = 429, description = "Too many challenges; wait `Retry-After` seconds", body = String)
+    )
+)]
+pub async fn get_challenge(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Query(query): Query<ChallengeQuery>,
+) -> Result<Json<ChallengeResponse>, Response> {
+    let exempt = state.challenge_exempt.iter().any(|net| net.contains(&ip));
+    if state.settings.server.challenge_rate_limit > 0 && !exempt {
+        if let Err(wait) = state.challenge_limiter.hit(ip) {
+            // Round up so a client that waits exactly this long gets through.
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, secs.to_string())],
+                "Too many challenge requests".to_string(),
+            )
+                .into_response());
+        }
+    }
+
+    let mut difficulty = state.site_config.defaults().pow_difficulty;
+    if let Some(site_id) = query.site_id.and_then(|s| SiteId::new(s).ok()) {
+        match state.site_config.get(&site_id).await {
+            Ok(c) => difficulty = c.pow_difficulty,
+            Err(e) => tracing::error!("Failed to load site config for {}: {:?}", site_id, e),
+        }
+    }
+
+    let security = &state.settings.security;
+    let difficulty = pow::scaled_difficulty(
+        difficulty,
+        query.content_length.unwrap_or(0),
+        security.pow_length_step,
+        security.pow_max_difficulty,
+    );
+
+    let secret = state.pow.generate_challenge(difficulty);
+    Ok(Json(ChallengeResponse {
+        version: ENVELOPE_VERSION,
+        algorithm: pow::ALGORITHM,
+        params: ChallengeParams { difficulty },
+        challenge: secret.clone(),
+        secret,
+        difficulty,
+    }))
 }
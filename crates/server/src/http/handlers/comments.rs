@@ -1,14 +1,25 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use domain::{AppCommand, SiteId};
+use chrono::{DateTime, Utc};
+use domain::{protocol, AppCommand, Comment, CommentEntry, CommentScope, IngestEvent, SiteId};
 use matrix_sdk::ruma::EventId;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
+use crate::http::command::send_cmd_and_wait;
+use crate::http::error::ApiError;
+use crate::http::extract::{ClientIp, SlugPath, ValidJson, ValidatedSiteId};
+use crate::http::pagination::Pager;
+use crate::identicon;
+use crate::notify;
+use crate::pow;
 use crate::state::AppState;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct CreateCommentRequest {
     pub post_slug: String,
     pub content: String,
@@ -18,67 +29,1102 @@ pub struct CreateCommentRequest {
 
     pub challenge_response: String,
     pub reply_to: Option<String>,
+    /// BCP-47 language tag, e.g. `en` or `zh-Hans`.
+    pub lang: Option<String>,
+    /// Absolute URL of the post. Recorded for the thread unless one is
+    /// already known, and only if it is on one of `server.cors_origins`.
+    pub post_url: Option<String>,
+    /// Whether to email `email` about replies, when the server sends reply
+    /// notifications. Defaults to `true`. The first time, a confirmation
+    /// link is mailed instead; after that, the latest comment's choice wins.
+    pub notify_replies: Option<bool>,
+}
+
+const MAX_POST_URL_LEN: usize = 2048;
+
+/// Slugs a static route under `/comments/` would shadow, so their threads
+/// could never be listed.
+const RESERVED_SLUGS: &[&str] = &["batch"];
+
+pub(crate) fn is_reserved_slug(slug: &str) -> bool {
+    RESERVED_SLUGS.contains(&slug)
+}
+
+/// Accepts absolute http(s) URLs with a host, normalized.
+pub(crate) fn parse_post_url(raw: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid post_url: {}", raw);
+    if raw.len() > MAX_POST_URL_LEN {
+        return Err(invalid());
+    }
+    let url = url::Url::parse(raw.trim()).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        return Err(invalid());
+    }
+    Ok(url.into())
+}
+
+/// Whether `url`, as returned by `parse_post_url`, lives on one of `origins`.
+fn is_allowed_post_url(origins: &[url::Origin], url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| origins.contains(&url.origin()))
+}
+
+/// A blank nickname would leave the comment with no visible author, so it
+/// becomes `default` first; ingest-time rules still see the result.
+fn nickname_or_default(nickname: String, default: &str) -> String {
+    if nickname.trim().is_empty() {
+        default.to_string()
+    } else {
+        nickname
+    }
+}
+
+/// Derives the guest's fingerprint off the async runtime; with many rounds
+/// it is CPU work that would otherwise stall other requests.
+async fn guest_fingerprint(
+    state: &AppState,
+    email: Option<String>,
+    guest_token: String,
+    salt: String,
+) -> Result<String, (axum::http::StatusCode, String)> {
+    let rounds = state.settings.security.fingerprint_rounds;
+    tokio::task::spawn_blocking(move || {
+        adapter::compute_user_fingerprint(email.as_deref(), &guest_token, &salt, rounds)
+    })
+    .await
+    .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    /// Only list comments that aren't replies; fetch each one's replies
+    /// from `/{id}/replies` as needed, guided by `reply_count`.
+    #[serde(default)]
+    pub top_level_only: bool,
+    /// Comma-separated comment fields to return, e.g. `author_name,content`;
+    /// `id` is always included. Omit for every field.
+    pub fields: Option<String>,
 }
 
+/// Every field a listed comment serializes to, i.e. `CommentEntry`'s.
+const COMMENT_FIELDS: &[&str] = &[
+    "id",
+    "site_id",
+    "post_slug",
+    "author_id",
+    "author_name",
+    "is_guest",
+    "origin",
+    "origin_kind",
+    "verified",
+    "is_federated",
+    "author_server",
+    "flagged",
+    "is_redacted",
+    "author_fingerprint",
+    "content",
+    "created_at",
+    "reply_to",
+    "updated_at",
+    "lang",
+    "reply_count",
+    "latest_reply_at",
+    "avatar_url",
+    "link_preview",
+];
+
+/// The requested fields plus `id`, or `None` for all of them.
+fn parse_fields(raw: Option<&str>) -> Result<Option<Vec<&str>>, String> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+    let mut fields = vec!["id"];
+    for field in raw.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        if !COMMENT_FIELDS.contains(&field) {
+            return Err(format!("Unknown field: {}", field));
+        }
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// Serializes the page, trimming each comment to `fields` when given.
+fn project_page(
+    page: PaginatedResponse,
+    fields: Option<&[&str]>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut value = serde_json::to_value(page)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let (Some(fields), Some(comments)) = (fields, value["comments"].as_array_mut()) {
+        for comment in comments.iter_mut().filter_map(|c| c.as_object_mut()) {
+            comment.retain(|key, _| fields.contains(&key.as_str()));
+        }
+    }
+    Ok(Json(value))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PaginationMeta {
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+    pub total_pages: i64,
+    /// Set once the Matrix room exists; `matrix_to_link` falls back to the
+    /// alias until then.
+    pub room_id: Option<String>,
+    pub room_alias: String,
+    pub matrix_to_link: String,
+    /// Where the post lives, once a client or an admin has said.
+    pub post_url: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PaginatedResponse {
+    pub comments: Vec<CommentEntry>,
+    pub meta: PaginationMeta,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CommentCount {
+    pub count: i64,
+}
+
+/// `{ "count": N }` of visible comments, for embeds that only show a badge.
+/// Clients may revalidate with `If-None-Match` between short cache windows.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/count",
+    tag = "comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "Visible comments in the thread", body = CommentCount),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn count_comments(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let count = state
+        .db
+        .count_comments(site_id.as_str(), &slug)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let etag = format!("W/\"{}\"", count);
+    let cache = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "public, max-age=30".to_string()),
+    ];
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, cache).into_response());
+    }
+    Ok((cache, Json(CommentCount { count })).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}",
+    tag = "comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug"),
+        ListQuery
+    ),
+    responses(
+        (status = 200, description = "A page of the thread, oldest first", body = PaginatedResponse),
+        (status = 400, description = "Invalid site ID or an unknown field in `fields`", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
 pub async fn list_comments(
     State(state): State<AppState>,
-    Path((site_id_str, slug)): Path<(String, String)>,
-) -> Result<Json<Vec<domain::Comment>>, (axum::http::StatusCode, String)> {
-    if SiteId::new(&site_id_str).is_err() {
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let fields = parse_fields(query.fields.as_deref()).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let scope = if query.top_level_only {
+        CommentScope::TopLevel
+    } else {
+        CommentScope::All
+    };
+    let page = thread_page(&state, &site_id, &slug, &query, scope).await?;
+    project_page(page, fields.as_deref())
+}
+
+/// Direct replies to one comment, paginated like the thread itself.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/{id}/replies",
+    tag = "comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug"),
+        ("id" = String, Path, description = "Event ID of the parent comment"),
+        ListQuery
+    ),
+    responses(
+        (status = 200, description = "A page of the comment's direct replies, oldest first", body = PaginatedResponse),
+        (status = 400, description = "Invalid site ID or an unknown field in `fields`", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_replies(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(CommentPath { slug, id }): Path<CommentPath>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let fields = parse_fields(query.fields.as_deref()).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let page = thread_page(
+        &state,
+        &site_id,
+        &slug,
+        &query,
+        CommentScope::RepliesTo(&id),
+    )
+    .await?;
+    project_page(page, fields.as_deref())
+}
+
+const MAX_BATCH_IDS: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+pub struct BatchRequest {
+    /// Event IDs of the comments to fetch; at most 100.
+    pub ids: Vec<String>,
+}
+
+/// Current state of specific comments, for clients refreshing a local cache
+/// after a reconnect. Redacted comments come back as placeholders; IDs that
+/// don't exist on this site are left out.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/comments/batch",
+    tag = "comments",
+    request_body = BatchRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "The comments found, oldest first", body = [CommentEntry]),
+        (status = 400, description = "Invalid site ID or too many IDs", body = String),
+        (status = 415, description = "Body is not JSON", body = BodyError),
+        (status = 422, description = "A field is missing or invalid", body = BodyError),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn batch_comments(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    ValidJson(payload): ValidJson<BatchRequest>,
+) -> Result<Json<Vec<CommentEntry>>, (axum::http::StatusCode, String)> {
+    if payload.ids.len() > MAX_BATCH_IDS {
         return Err((
             axum::http::StatusCode::BAD_REQUEST,
-            "Invalid Site ID format".to_string(),
+            format!("Too many ids (max {})", MAX_BATCH_IDS),
         ));
     }
+    let mut comments = state
+        .db
+        .get_comments(site_id.as_str(), &payload.ids)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    fill_avatars(&state, &mut comments);
+    Ok(Json(comments))
+}
+
+fn fill_avatars(state: &AppState, comments: &mut [CommentEntry]) {
+    if !state.settings.server.identicons {
+        return;
+    }
+    for entry in comments.iter_mut().filter(|e| e.comment.is_guest) {
+        entry.avatar_url = entry
+            .comment
+            .author_fingerprint
+            .as_deref()
+            .map(identicon::url_for);
+    }
+}
 
+async fn thread_page(
+    state: &AppState,
+    site_id: &SiteId,
+    slug: &str,
+    query: &ListQuery,
+    scope: CommentScope<'_>,
+) -> Result<PaginatedResponse, (axum::http::StatusCode, String)> {
+    let pager = Pager::new(query.page, query.per_page, &state.settings.server);
+    let mut comments = state
+        .db
+        .list_comments(site_id.as_str(), slug, scope, pager.limit(), pager.offset())
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    fill_avatars(state, &mut comments);
+    let total = state
+        .db
+        .count_thread_comments(site_id.as_str(), slug, scope)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let room_id = state
+        .db
+        .get_room_id(site_id.as_str(), slug)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let room_alias = state
+        .room_alias
+        .format(site_id, slug, state.settings.matrix.server_name());
+    let matrix_to_link = protocol::matrix_to_link(room_id.as_deref().unwrap_or(&room_alias));
+    let post_url = state
+        .db
+        .get_post_url(site_id.as_str(), slug)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(PaginatedResponse {
+        comments,
+        meta: PaginationMeta {
+            page: pager.page,
+            per_page: pager.per_page,
+            total,
+            total_pages: pager.total_pages(total),
+            room_id,
+            room_alias,
+            matrix_to_link,
+            post_url,
+        },
+    })
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SinceQuery {
+    pub ts: DateTime<Utc>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/since",
+    tag = "comments",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug"),
+        SinceQuery
+    ),
+    responses(
+        (status = 200, description = "Comments created, edited or deleted after `ts`", body = [Comment]),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn list_comments_since(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+    Query(query): Query<SinceQuery>,
+) -> Result<Json<Vec<Comment>>, (axum::http::StatusCode, String)> {
     let comments = state
         .db
-        .list_comments(&site_id_str, &slug)
+        .list_comments_changed_since(site_id.as_str(), &slug, query.ts.naive_utc())
         .await
         .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(Json(comments))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/comments",
+    tag = "comments",
+    request_body = CreateCommentRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Carried out by the Matrix driver", body = String, content_type = "application/json", example = json!("Sent")),
+        (status = 202, description = "Still running at the command timeout", body = String, content_type = "application/json", example = json!("Processing")),
+        (status = 502, description = "The Matrix homeserver failed or couldn't be reached", body = String),
+        (status = 500, description = "The Matrix worker has stopped", body = String),
+        (status = 503, description = "The Matrix driver is still starting up or shutting down, or the command queue is full (`server_busy`, retry after `Retry-After` seconds)", body = String),
+        (status = 400, description = "Invalid site ID, `reply_to`, `lang`, `post_url`, a reserved slug, a blocked email domain, over-long content or a reply nested too deep", body = String),
+        (status = 403, description = "Proof-of-work refused; `code` is `challenge_invalid`, `challenge_expired` or `challenge_spent` (fetch a new challenge) or `insufficient_work`", body = CodedError),
+        (status = 404, description = "The site only allows listed slugs and this one isn't", body = String),
+        (status = 415, description = "Body is not JSON", body = BodyError),
+        (status = 422, description = "A field is missing or invalid (`BodyError`), or the Matrix driver refused the comment, e.g. too large to send or an encrypted room (plain text)", body = BodyError)
+    )
+)]
 pub async fn post_comment(
     State(state): State<AppState>,
-    Path(site_id_str): Path<String>,
-    Json(payload): Json<CreateCommentRequest>,
-) -> Result<Json<&'static str>, (axum::http::StatusCode, String)> {
-    let site_id = SiteId::new(site_id_str).map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e))?;
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    ValidJson(payload): ValidJson<CreateCommentRequest>,
+) -> Result<(axum::http::StatusCode, Json<&'static str>), ApiError> {
+    if is_reserved_slug(&payload.post_slug) {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            format!("Reserved post slug: {}", payload.post_slug),
+        )
+            .into());
+    }
+
+    // Refuse up front rather than queue behind a driver that is still logging in.
+    if !state.driver_ready.is_ready() {
+        return Err((
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Server is starting up, try again shortly".to_string(),
+        )
+            .into());
+    }
 
     if let Some(ref reply_id) = payload.reply_to {
         if EventId::parse(reply_id).is_err() {
             return Err((
                 axum::http::StatusCode::BAD_REQUEST,
                 format!("Invalid reply_to ID format: {}", reply_id),
-            ));
+            )
+                .into());
+        }
+        let max_depth = state.settings.relay.max_reply_depth.max(1);
+        let ancestors = state
+            .db
+            .get_ancestors(reply_id, max_depth)
+            .await
+            .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if ancestors.len() >= max_depth as usize {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                format!("Replies can't nest deeper than {} levels", max_depth),
+            )
+                .into());
         }
     }
 
-    let parts: Vec<&str> = payload.challenge_response.split('|').collect();
-    if parts.len() != 2 || !state.pow.verify(parts[0], parts[1]) {
+    if let Some(ref lang) = payload.lang {
+        if !protocol::is_valid_language_tag(lang) {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                format!("Invalid lang tag: {}", lang),
+            )
+                .into());
+        }
+    }
+
+    if let Some(ref email) = payload.email {
+        if state.email_blocklist.is_blocked(email) {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "Email addresses from this domain are not accepted".to_string(),
+            )
+                .into());
+        }
+    }
+
+    // Anyone can post, so a reader-supplied link is only kept when it points
+    // at one of the site origins the server allows; others are left to
+    // `admin::set_post_url`.
+    let post_url = payload
+        .post_url
+        .as_deref()
+        .map(parse_post_url)
+        .transpose()
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e))?
+        .filter(|url| is_allowed_post_url(&state.settings.server.cors_origin_list(), url));
+
+    let site_config = state
+        .site_config
+        .get(&site_id)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Checked before anything reaches the driver, so no room is created.
+    if site_config.slug_allowlist {
+        let allowed = state
+            .db
+            .is_slug_allowed(site_id.as_str(), &payload.post_slug)
+            .await
+            .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if !allowed {
+            return Err((
+                axum::http::StatusCode::NOT_FOUND,
+                format!("Unknown post: {}", payload.post_slug),
+            )
+                .into());
+        }
+    }
+
+    if payload.content.chars().count() > site_config.max_content_length {
         return Err((
-            axum::http::StatusCode::FORBIDDEN,
-            "Invalid PoW Challenge".to_string(),
-        ));
+            axum::http::StatusCode::BAD_REQUEST,
+            format!(
+                "Comment is too long (max {} chars)",
+                site_config.max_content_length
+            ),
+        )
+            .into());
     }
 
-    let cmd = AppCommand::SendComment {
+    // Judge by the real length, so a client can't fetch a cheap challenge
+    // with a small `content_length` hint and then post a long comment.
+    let security = &state.settings.security;
+    let required = pow::scaled_difficulty(
+        site_config.pow_difficulty,
+        payload.content.chars().count(),
+        security.pow_length_step,
+        security.pow_max_difficulty,
+    );
+    state
+        .pow
+        .verify_response(&payload.challenge_response, required)?;
+
+    state.activity.record(site_id.as_str());
+    let thread = (site_id.clone(), payload.post_slug.clone());
+    let salt = state.settings.security.identity_salt();
+    let salt = salt.for_site(&site_id);
+    let fingerprint = guest_fingerprint(
+        &state,
+        payload.email.clone(),
+        payload.guest_token.clone(),
+        salt.to_string(),
+    )
+    .await?;
+    // Keyed by the comment's author fingerprint, which is all a later reply
+    // knows about its parent's author. The fingerprint only proves the guest
+    // typed the address; the token that confirmed it proves they own it.
+    let subscription = match (&state.notifier, payload.email) {
+        (Some(_), Some(email)) => {
+            let owner = notify::subscription_owner(&payload.guest_token, &salt);
+            let confirmed = state
+                .db
+                .get_reply_subscription(site_id.as_str(), &fingerprint)
+                .await
+                .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .is_some_and(|s| s.confirmed_owner.as_deref() == Some(owner.as_str()));
+            Some((email, owner, confirmed))
+        }
+        _ => None,
+    };
+    let verified = subscription
+        .as_ref()
+        .is_some_and(|(_, _, confirmed)| *confirmed);
+    let author_fingerprint = fingerprint.clone();
+    let sent = send_cmd_and_wait(&state, |ack| AppCommand::SendComment {
         site_id,
         post_slug: payload.post_slug,
         content: payload.content,
-        nickname: payload.nickname,
-        email: payload.email,
-        guest_token: payload.guest_token,
+        nickname: nickname_or_default(payload.nickname, &state.settings.server.default_nickname),
+        author_fingerprint,
         reply_to: payload.reply_to,
+        lang: payload.lang,
+        verified,
+        ack,
+    })
+    .await?;
+
+    let (site_id, slug) = thread;
+    // Only comments that made it to the driver get to name the post.
+    if let Some(url) = post_url {
+        if let Err(e) = state
+            .db
+            .record_post_url(site_id.as_str(), &slug, &url)
+            .await
+        {
+            tracing::warn!(
+                "Failed to record post URL for {}/{}: {:?}",
+                site_id,
+                slug,
+                e
+            );
+        }
+    }
+    if let Some((email, owner, confirmed)) = subscription {
+        let wanted = payload.notify_replies.unwrap_or(true);
+        if let Err(e) = update_reply_subscription(
+            &state,
+            site_id,
+            &fingerprint,
+            email,
+            &owner,
+            confirmed,
+            wanted,
+        )
+        .await
+        {
+            tracing::warn!("Failed to record reply notification choice: {:?}", e);
+        }
+    }
+    Ok(sent)
+}
+
+/// The guest who confirmed the address turns emails on or off by posting.
+/// Anyone else asking for them, including that guest from a new browser,
+/// gets a confirmation link mailed to the address instead.
+async fn update_reply_subscription(
+    state: &AppState,
+    site_id: SiteId,
+    fingerprint: &str,
+    email: String,
+    owner: &str,
+    confirmed: bool,
+    wanted: bool,
+) -> anyhow::Result<()> {
+    if confirmed {
+        state
+            .db
+            .set_reply_opt_out(site_id.as_str(), fingerprint, owner, !wanted)
+            .await?;
+        return Ok(());
+    }
+    let Some(notifier) = state.notifier.clone().filter(|_| wanted) else {
+        return Ok(());
     };
+    let confirm_token = notify::link_token();
+    state
+        .db
+        .request_reply_subscription(
+            site_id.as_str(),
+            fingerprint,
+            &email,
+            owner,
+            &confirm_token,
+            &notify::link_token(),
+        )
+        .await?;
+    tokio::spawn(async move {
+        if let Err(e) = notifier
+            .send_confirmation(&site_id, &email, &confirm_token)
+            .await
+        {
+            tracing::warn!("Confirmation email for {} failed: {:?}", site_id, e);
+        }
+    });
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct CommentPath {
+    slug: String,
+    id: String,
+}
+
+/// Identifies the reporter the same way a post identifies its author.
+#[derive(Deserialize, ToSchema)]
+pub struct ReportCommentRequest {
+    pub guest_token: String,
+    pub email: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Lets a reader report a comment. Each reader counts once per comment; at
+/// `server.report_threshold` distinct reports the comment is flagged and
+/// admin streams get a `flag_comment` event.
+#[utoipa::path(
+    post,
+    path = "/api/{site_id}/comments/{slug}/{id}/report",
+    tag = "comments",
+    request_body = ReportCommentRequest,
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug"),
+        ("id" = String, Path, description = "Event ID of the comment")
+    ),
+    responses(
+        (status = 200, description = "Report recorded", body = String, content_type = "application/json", example = json!("Reported")),
+        (status = 404, description = "No such comment in this thread", body = String),
+        (status = 410, description = "The comment was already removed", body = String),
+        (status = 429, description = "Too many reports from this reader or address", body = String),
+        (status = 415, description = "Body is not JSON", body = BodyError),
+        (status = 422, description = "A field is missing or invalid", body = BodyError)
+    )
+)]
+pub async fn report_comment(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(CommentPath { slug, id }): Path<CommentPath>,
+    ValidJson(payload): ValidJson<ReportCommentRequest>,
+) -> Result<(axum::http::StatusCode, Json<&'static str>), (axum::http::StatusCode, String)> {
+    // Guest tokens cost nothing to mint, so the address bounds how many
+    // readers one client can pass for.
+    if !state.report_ip_limiter.check(ip) {
+        return Err((
+            axum::http::StatusCode::TOO_MANY_REQUESTS,
+            "Too many reports, slow down".to_string(),
+        ));
+    }
+
+    let comment = state
+        .db
+        .get_comment(&id)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .filter(|c| c.site_id == site_id && c.post_slug == slug)
+        .ok_or_else(|| {
+            (
+                axum::http::StatusCode::NOT_FOUND,
+                "Comment not found".to_string(),
+            )
+        })?;
+    if comment.is_redacted {
+        return Err((
+            axum::http::StatusCode::GONE,
+            "Comment was already removed".to_string(),
+        ));
+    }
 
-    if state.sender.send(cmd).await.is_err() {
+    let fingerprint = guest_fingerprint(
+        &state,
+        payload.email.clone(),
+        payload.guest_token.clone(),
+        state
+            .settings
+            .security
+            .identity_salt()
+            .for_site(&site_id)
+            .into_owned(),
+    )
+    .await?;
+    if !state.report_limiter.check(fingerprint.clone()) {
         return Err((
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            "Worker closed".to_string(),
+            axum::http::StatusCode::TOO_MANY_REQUESTS,
+            "Too many reports, slow down".to_string(),
         ));
     }
-    Ok(Json("Accepted"))
+
+    let reason = payload
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+    let count = state
+        .db
+        .add_report(&id, &fingerprint, reason)
+        .await
+        .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if let Some(count) = count.filter(|&c| c >= state.settings.server.report_threshold) {
+        let newly_flagged = state
+            .db
+            .flag_comment(&id)
+            .await
+            .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if newly_flagged {
+            tracing::info!("Comment {} flagged after {} reports", id, count);
+            let _ = state.tx_ingest.send(IngestEvent::CommentFlagged {
+                site_id,
+                post_slug: slug,
+                comment_id: id,
+                reports: count,
+            });
+        }
+    }
+
+    Ok((axum::http::StatusCode::OK, Json("Reported")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_post_url_validation() {
+        assert_eq!(
+            parse_post_url(" https://Blog.example/posts/a?x=1 ").unwrap(),
+            "https://blog.example/posts/a?x=1"
+        );
+        assert!(parse_post_url("http://blog.example").is_ok());
+        for bad in [
+            "/posts/a",
+            "javascript:alert(1)",
+            "ftp://x.org/a",
+            "https://",
+        ] {
+            assert!(parse_post_url(bad).is_err(), "{bad}");
+        }
+        let long = format!("https://x.org/{}", "a".repeat(MAX_POST_URL_LEN));
+        assert!(parse_post_url(&long).is_err());
+    }
+
+    #[test]
+    fn test_empty_nickname_gets_default() {
+        assert_eq!(nickname_or_default(String::new(), "Anonymous"), "Anonymous");
+        assert_eq!(nickname_or_default(" \t".into(), "Anonymous"), "Anonymous");
+        assert_eq!(nickname_or_default("Ann".into(), "Anonymous"), "Ann");
+        assert_eq!(nickname_or_default(String::new(), ""), "");
+    }
+
+    #[test]
+    fn test_fields_projection() {
+        assert_eq!(parse_fields(None).unwrap(), None);
+        assert_eq!(
+            parse_fields(Some("content, author_name,,content")).unwrap(),
+            Some(vec!["id", "content", "author_name"])
+        );
+        assert!(parse_fields(Some("content,raw_event")).is_err());
+
+        let entry: CommentEntry = serde_json::from_value(serde_json::json!({
+            "id": "$e", "site_id": "blog", "post_slug": "p", "author_id": "@a:x",
+            "author_name": "A", "is_guest": false, "origin": "native",
+            "origin_kind": "native", "verified": false, "is_federated": false,
+            "author_server": "x", "flagged": false, "is_redacted": false,
+            "author_fingerprint": null, "content": "hi",
+            "created_at": "2024-01-01T00:00:00", "reply_to": null,
+            "updated_at": null, "lang": null, "reply_count": 0,
+            "latest_reply_at": null
+        }))
+        .unwrap();
+        let full = serde_json::to_value(&entry).unwrap();
+        let mut keys: Vec<&str> = full
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        let mut known = COMMENT_FIELDS.to_vec();
+        known.retain(|f| !["avatar_url", "link_preview"].contains(f));
+        known.sort();
+        assert_eq!(
+            keys, known,
+            "COMMENT_FIELDS is out of step with CommentEntry"
+        );
+
+        let page = PaginatedResponse {
+            comments: vec![entry],
+            meta: PaginationMeta {
+                page: 1,
+                per_page: 20,
+                total: 1,
+                total_pages: 1,
+                room_id: None,
+                room_alias: "#blog_p:x".into(),
+                matrix_to_link: String::new(),
+                post_url: None,
+            },
+        };
+        let Json(value) = project_page(page, Some(&["id", "content"])).unwrap();
+        assert_eq!(
+            value["comments"][0],
+            serde_json::json!({"id": "$e", "content": "hi"})
+        );
+        assert_eq!(value["meta"]["total"], 1);
+    }
+
+    #[test]
+    fn test_post_url_must_match_an_origin() {
+        let origins: Vec<_> = ["https://blog.example", "http://localhost:8080"]
+            .iter()
+            .map(|o| url::Url::parse(o).unwrap().origin())
+            .collect();
+        assert!(is_allowed_post_url(
+            &origins,
+            "https://blog.example/posts/a"
+        ));
+        assert!(is_allowed_post_url(&origins, "http://localhost:8080/a"));
+        assert!(!is_allowed_post_url(
+            &origins,
+            "http://blog.example/posts/a"
+        ));
+        assert!(!is_allowed_post_url(&origins, "https://evil.example/a"));
+        assert!(!is_allowed_post_url(&origins, "http://localhost:9090/a"));
+        assert!(!is_allowed_post_url(&[], "https://blog.example/a"));
+    }
+
+    #[tokio::test]
+    async fn test_batch_slug_is_reserved() {
+        let (state, mut rx) = AppState::for_tests().await;
+        let request = CreateCommentRequest {
+            post_slug: "batch".to_string(),
+            content: "hi".to_string(),
+            nickname: "Ann".to_string(),
+            email: None,
+            guest_token: "token".to_string(),
+            challenge_response: String::new(),
+            reply_to: None,
+            lang: None,
+            post_url: None,
+            notify_replies: None,
+        };
+        let site = SiteId::new_unchecked("blog".to_string());
+        let err = post_comment(State(state), ValidatedSiteId(site), ValidJson(request))
+            .await
+            .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_batch_comments() {
+        let (state, _rx) = AppState::for_tests().await;
+        let blog = SiteId::new_unchecked("blog".to_string());
+        for id in ["$a", "$b", "$gone"] {
+            let comment = Comment::fixture(id);
+            state
+                .db
+                .upsert_comment("!r:x", "blog", "hello", &comment)
+                .await
+                .unwrap();
+        }
+        state.db.delete_comment("$gone").await.unwrap();
+        let elsewhere = Comment {
+            site_id: SiteId::new_unchecked("other".to_string()),
+            ..Comment::fixture("$other")
+        };
+        state
+            .db
+            .upsert_comment("!o:x", "other", "hello", &elsewhere)
+            .await
+            .unwrap();
+
+        let batch = |ids: &[&str]| {
+            let request = BatchRequest {
+                ids: ids.iter().map(|id| id.to_string()).collect(),
+            };
+            batch_comments(
+                State(state.clone()),
+                ValidatedSiteId(blog.clone()),
+                ValidJson(request),
+            )
+        };
+        let Json(found) = batch(&["$b", "$gone", "$other", "$missing"]).await.unwrap();
+        let mut found: Vec<_> = found
+            .into_iter()
+            .map(|e| (e.comment.id, e.comment.is_redacted, e.comment.content))
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                ("$b".to_string(), false, "hi".to_string()),
+                ("$gone".to_string(), true, String::new()),
+            ]
+        );
+
+        let ids = vec!["$a"; MAX_BATCH_IDS + 1];
+        let (status, _) = batch(&ids).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(batch(&ids[1..]).await.is_ok());
+    }
+
+    /// The first page of `blog`/`hello`, as the list endpoint returns it.
+    async fn first_page(state: &AppState, per_page: Option<u32>) -> serde_json::Value {
+        let query = ListQuery {
+            page: None,
+            per_page,
+            top_level_only: false,
+            fields: None,
+        };
+        let Json(page) = list_comments(
+            State(state.clone()),
+            ValidatedSiteId(SiteId::new_unchecked("blog".to_string())),
+            Path(SlugPath {
+                slug: "hello".to_string(),
+            }),
+            Query(query),
+        )
+        .await
+        .unwrap();
+        page
+    }
+
+    #[tokio::test]
+    async fn test_per_page_bounds_come_from_settings() {
+        let (mut state, _rx) = AppState::for_tests().await;
+        let mut settings = (*state.settings).clone();
+        settings.server.default_per_page = 2;
+        settings.server.max_per_page = 3;
+        state.settings = Arc::new(settings);
+        for i in 0..5 {
+            let comment = Comment::fixture(&format!("${}", i));
+            state
+                .db
+                .upsert_comment("!r:x", "blog", "hello", &comment)
+                .await
+                .unwrap();
+        }
+
+        let page = first_page(&state, None).await;
+        assert_eq!(page["comments"].as_array().unwrap().len(), 2);
+        assert_eq!(page["meta"]["per_page"], 2);
+        assert_eq!(page["meta"]["total"], 5);
+        assert_eq!(page["meta"]["total_pages"], 3);
+
+        let page = first_page(&state, Some(50)).await;
+        assert_eq!(page["comments"].as_array().unwrap().len(), 3);
+        assert_eq!(page["meta"]["per_page"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_meta_links_the_room_once_it_exists() {
+        let (state, _rx) = AppState::for_tests().await;
+        let meta = first_page(&state, None).await["meta"].take();
+        assert_eq!(meta["room_id"], serde_json::Value::Null);
+        assert_eq!(meta["room_alias"], "#blog_hello:x");
+        assert_eq!(
+            meta["matrix_to_link"],
+            "https://matrix.to/#/%23blog_hello:x"
+        );
+
+        state.db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        let meta = first_page(&state, None).await["meta"].take();
+        assert_eq!(meta["room_id"], "!r:x");
+        assert_eq!(meta["room_alias"], "#blog_hello:x");
+        assert_eq!(meta["matrix_to_link"], "https://matrix.to/#/!r:x");
+    }
+
+    #[tokio::test]
+    async fn test_count_skips_redacted_and_revalidates() {
+        let (state, _rx) = AppState::for_tests().await;
+        for id in ["$a", "$b", "$gone"] {
+            let comment = Comment::fixture(id);
+            state
+                .db
+                .upsert_comment("!r:x", "blog", "hello", &comment)
+                .await
+                .unwrap();
+        }
+        state.db.delete_comment("$gone").await.unwrap();
+        let count = |if_none_match: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(tag) = if_none_match {
+                headers.insert(header::IF_NONE_MATCH, tag.parse().unwrap());
+            }
+            count_comments(
+                State(state.clone()),
+                ValidatedSiteId(SiteId::new_unchecked("blog".to_string())),
+                Path(SlugPath {
+                    slug: "hello".to_string(),
+                }),
+                headers,
+            )
+        };
+
+        let response = count(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "W/\"2\"");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], br#"{"count":2}"#);
+
+        let fresh = count(Some("W/\"1\", W/\"2\"")).await.unwrap();
+        assert_eq!(fresh.status(), StatusCode::NOT_MODIFIED);
+        let stale = count(Some("W/\"3\"")).await.unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+    }
 }
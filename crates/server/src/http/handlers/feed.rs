@@ -0,0 +1,250 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::NaiveDateTime;
+use domain::{protocol, render, CommentEntry, CommentScope, SiteId};
+use std::fmt::Write;
+
+use crate::http::extract::{SlugPath, ValidatedSiteId};
+use crate::state::AppState;
+
+/// What a feed is about: the thread's room and where to find it on Matrix.
+struct FeedMeta {
+    title: String,
+    /// Stable feed ID: the room's matrix.to link.
+    id: String,
+    /// The post itself when its URL is known, else the room.
+    link: String,
+    /// `room_id` or alias, prefixed to event IDs for per-comment permalinks.
+    room: String,
+}
+
+impl FeedMeta {
+    fn permalink(&self, event_id: &str) -> String {
+        protocol::matrix_to_link(&format!("{}/{}", self.room, event_id))
+    }
+}
+
+/// Newest visible comments of a thread, newest first, plus feed metadata.
+async fn load_feed(
+    state: &AppState,
+    site_id: &SiteId,
+    slug: &str,
+) -> Result<(FeedMeta, Vec<CommentEntry>), (StatusCode, String)> {
+    let internal = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let limit = state.settings.server.feed_items as i64;
+    let total = state
+        .db
+        .count_thread_comments(site_id.as_str(), slug, CommentScope::All)
+        .await
+        .map_err(internal)?;
+    let mut comments = state
+        .db
+        .list_comments(
+            site_id.as_str(),
+            slug,
+            CommentScope::All,
+            limit,
+            (total - limit).max(0),
+        )
+        .await
+        .map_err(internal)?;
+    comments.retain(|e| !e.comment.is_redacted);
+    comments.reverse();
+
+    let room_id = state
+        .db
+        .get_room_id(site_id.as_str(), slug)
+        .await
+        .map_err(internal)?;
+    let room = room_id.unwrap_or_else(|| {
+        state
+            .room_alias
+            .format(site_id, slug, state.settings.matrix.server_name())
+    });
+    let post_url = state
+        .db
+        .get_post_url(site_id.as_str(), slug)
+        .await
+        .map_err(internal)?;
+    let id = protocol::matrix_to_link(&room);
+    let meta = FeedMeta {
+        title: format!("Comments on {}", slug),
+        link: post_url.unwrap_or_else(|| id.clone()),
+        id,
+        room,
+    };
+    Ok((meta, comments))
+}
+
+fn rfc3339(ts: NaiveDateTime) -> String {
+    ts.and_utc().to_rfc3339()
+}
+
+fn render_atom(meta: &FeedMeta, comments: &[CommentEntry]) -> String {
+    let updated = comments
+        .first()
+        .map(|e| e.comment.updated_at.unwrap_or(e.comment.created_at))
+        .unwrap_or_default();
+    let link = render::escape(&meta.link);
+
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    let _ = writeln!(out, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    let _ = writeln!(out, "  <id>{}</id>", render::escape(&meta.id));
+    let _ = writeln!(out, "  <title>{}</title>", render::escape(&meta.title));
+    let _ = writeln!(out, r#"  <link href="{}"/>"#, link);
+    let _ = writeln!(out, "  <updated>{}</updated>", rfc3339(updated));
+    for entry in comments {
+        let c = &entry.comment;
+        let permalink = render::escape(&meta.permalink(&c.id));
+        let author = render::escape(&c.author_name);
+        // The rendered HTML is escaped once more to travel as text.
+        let content = render::escape(&render::render_markdown(&c.content));
+
+        let _ = writeln!(out, "  <entry>");
+        let _ = writeln!(out, "    <id>{}</id>", permalink);
+        let _ = writeln!(out, "    <title>{}</title>", author);
+        let _ = writeln!(out, r#"    <link href="{}"/>"#, permalink);
+        let _ = writeln!(out, "    <author><name>{}</name></author>", author);
+        let _ = writeln!(out, "    <published>{}</published>", rfc3339(c.created_at));
+        let _ = writeln!(
+            out,
+            "    <updated>{}</updated>",
+            rfc3339(c.updated_at.unwrap_or(c.created_at))
+        );
+        let _ = writeln!(out, r#"    <content type="html">{}</content>"#, content);
+        let _ = writeln!(out, "  </entry>");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn render_json_feed(meta: &FeedMeta, comments: &[CommentEntry]) -> serde_json::Value {
+    let items: Vec<_> = comments
+        .iter()
+        .map(|entry| {
+            let c = &entry.comment;
+            let mut item = serde_json::json!({
+                "id": c.id,
+                "url": meta.permalink(&c.id),
+                "content_text": c.content,
+                "content_html": render::render_markdown(&c.content),
+                "date_published": rfc3339(c.created_at),
+                "authors": [{ "name": c.author_name }],
+            });
+            if let Some(updated) = c.updated_at {
+                item["date_modified"] = rfc3339(updated).into();
+            }
+            if let Some(lang) = &c.lang {
+                item["language"] = lang.clone().into();
+            }
+            item
+        })
+        .collect();
+
+    serde_json::json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": meta.title,
+        "home_page_url": meta.link,
+        "items": items,
+    })
+}
+
+/// Atom feed of a thread's most recent comments.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/feed.xml",
+    tag = "feeds",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "Atom feed of recent comments", body = String, content_type = "application/atom+xml"),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn atom_feed(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (meta, comments) = load_feed(&state, &site_id, &slug).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        render_atom(&meta, &comments),
+    ))
+}
+
+/// JSON Feed 1.1 of a thread's most recent comments.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/feed.json",
+    tag = "feeds",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "JSON Feed 1.1 of recent comments", body = Object, content_type = "application/feed+json"),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 500, description = "Storage error", body = String)
+    )
+)]
+pub async fn json_feed(
+    State(state): State<AppState>,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (meta, comments) = load_feed(&state, &site_id, &slug).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/feed+json")],
+        render_json_feed(&meta, &comments).to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{Comment, CommentOrigin, OriginKind};
+
+    #[test]
+    fn test_atom_escapes_content() {
+        let meta = FeedMeta {
+            title: "Comments on hello".to_string(),
+            id: protocol::matrix_to_link("!r:x"),
+            link: "https://blog.example/hello?a=1&b=2".to_string(),
+            room: "!r:x".to_string(),
+        };
+        let entry = CommentEntry {
+            comment: Comment {
+                author_id: "@bot:x".to_string(),
+                author_name: "<Eve & co>".to_string(),
+                is_guest: true,
+                origin: CommentOrigin::Web,
+                origin_kind: OriginKind::Bot,
+                verified: false,
+                content: "**hi** <script>".to_string(),
+                created_at: NaiveDateTime::default(),
+                ..Comment::fixture("$e")
+            },
+            reply_count: 0,
+            latest_reply_at: None,
+            avatar_url: None,
+            link_preview: None,
+        };
+
+        let xml = render_atom(&meta, &[entry]);
+        assert!(xml.contains("<name>&lt;Eve &amp; co&gt;</name>"));
+        assert!(xml.contains("&lt;strong&gt;hi&lt;/strong&gt; &amp;lt;script&amp;gt;"));
+        assert!(!xml.contains("<script>"));
+        assert!(xml.contains("<id>https://matrix.to/#/!r:x/$e</id>"));
+        assert!(xml.contains("<id>https://matrix.to/#/!r:x</id>"));
+        assert!(xml.contains(r#"<link href="https://blog.example/hello?a=1&amp;b=2"/>"#));
+    }
+}
@@ -0,0 +1,60 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::state::AppState;
+
+/// A lag sample older than this says nothing about sync today; quiet sites
+/// shouldn't stay unhealthy because of one slow comment long ago.
+const LAG_SAMPLE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    /// The Matrix driver is logged in and has synced.
+    pub ready: bool,
+    /// How long after it was sent the latest synced comment was ingested.
+    /// Bot mode only.
+    pub ingest_lag_ms: Option<u64>,
+    /// Seconds since that comment was ingested.
+    pub ingest_lag_age_secs: Option<u64>,
+    /// A recent ingest lag exceeded `server.ingest_lag_alert_secs`.
+    pub lagging: bool,
+}
+
+/// For load balancers and monitoring: 503 while the driver is starting.
+/// Ingest lag is reported for alerting but doesn't fail the probe: the lag
+/// of a comment also counts how long its sender's homeserver sat on it, and
+/// restarting this instance wouldn't help with either.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready to relay comments", body = Readiness),
+        (status = 503, description = "Driver not ready", body = Readiness)
+    )
+)]
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let ready = state.driver_ready.is_ready();
+    let sample = state.driver_ready.ingest_lag();
+    let threshold = state.settings.server.ingest_lag_alert_secs;
+    let lagging = sample.is_some_and(|s| {
+        threshold > 0 && s.at.elapsed() < LAG_SAMPLE_TTL && s.lag > Duration::from_secs(threshold)
+    });
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            ingest_lag_ms: sample.map(|s| s.lag.as_millis() as u64),
+            ingest_lag_age_secs: sample.map(|s| s.at.elapsed().as_secs()),
+            lagging,
+        }),
+    )
+}
@@ -0,0 +1,43 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::identicon;
+use crate::state::AppState;
+
+#[utoipa::path(
+    get,
+    path = "/api/identicon/{seed}",
+    tag = "comments",
+    params(
+        ("seed" = String, Path, description = "Author fingerprint, optionally with `.svg`")
+    ),
+    responses(
+        (status = 200, description = "SVG identicon", body = String, content_type = "image/svg+xml"),
+        (status = 400, description = "Invalid seed", body = String),
+        (status = 404, description = "Identicons are disabled", body = String)
+    )
+)]
+pub async fn get_identicon(
+    State(state): State<AppState>,
+    Path(seed): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !state.settings.server.identicons {
+        return Err((StatusCode::NOT_FOUND, "Identicons are disabled".to_string()));
+    }
+
+    let seed = seed.strip_suffix(".svg").unwrap_or(&seed);
+    if seed.is_empty() || seed.len() > 128 {
+        return Err((StatusCode::BAD_REQUEST, "Invalid seed".to_string()));
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        identicon::render_svg(seed),
+    ))
+}
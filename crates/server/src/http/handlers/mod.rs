@@ -1,3 +1,10 @@
+pub mod admin;
+pub mod capabilities;
 pub mod challenge;
 pub mod comments;
+pub mod feed;
+pub mod health;
+pub mod identicon;
+pub mod notifications;
+pub mod preview;
 pub mod sse;
@@ -0,0 +1,117 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+    Form,
+};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::state::AppState;
+
+#[derive(Deserialize, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+pub struct LinkQuery {
+    /// The token from the emailed link.
+    token: String,
+}
+
+fn link_result(found: anyhow::Result<bool>, done: &str) -> Result<String, (StatusCode, String)> {
+    match found {
+        Ok(true) => Ok(done.to_string()),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "This link is invalid or was already used".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Opened from the email sent when a guest asks for reply notifications.
+/// Only shows a button that posts the token back, so a mail scanner or link
+/// prefetcher opening the link doesn't confirm on the guest's behalf.
+#[utoipa::path(
+    get,
+    path = "/api/notifications/confirm",
+    tag = "comments",
+    params(LinkQuery),
+    responses(
+        (status = 200, description = "Page with a form that confirms the subscription", body = String, content_type = "text/html")
+    )
+)]
+pub async fn confirm_page(Query(LinkQuery { token }): Query<LinkQuery>) -> Html<String> {
+    Html(confirm_form(&token))
+}
+
+fn confirm_form(token: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="robots" content="noindex"><title>Reply notifications</title></head>
+<body>
+<form method="post" action="confirm">
+<input type="hidden" name="token" value="{}">
+<button type="submit">Turn on reply notifications</button>
+</form>
+</body>
+</html>
+"#,
+        domain::render::escape(token)
+    )
+}
+
+/// Submitted by the button on the confirmation page.
+/// Comments the guest posts from then on, from the same browser, count as
+/// `verified`.
+#[utoipa::path(
+    post,
+    path = "/api/notifications/confirm",
+    tag = "comments",
+    request_body(content = LinkQuery, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Reply notifications confirmed", body = String),
+        (status = 404, description = "Unknown or already used link", body = String)
+    )
+)]
+pub async fn confirm(
+    State(state): State<AppState>,
+    Form(LinkQuery { token }): Form<LinkQuery>,
+) -> Result<String, (StatusCode, String)> {
+    link_result(
+        state.db.confirm_reply_subscription(&token).await,
+        "Reply notifications are on. You can close this page.",
+    )
+}
+
+/// Opened from the link in every reply notification.
+#[utoipa::path(
+    get,
+    path = "/api/notifications/unsubscribe",
+    tag = "comments",
+    params(LinkQuery),
+    responses(
+        (status = 200, description = "Reply notifications stopped", body = String),
+        (status = 404, description = "Unknown link", body = String)
+    )
+)]
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    Query(LinkQuery { token }): Query<LinkQuery>,
+) -> Result<String, (StatusCode, String)> {
+    link_result(
+        state.db.unsubscribe_replies(&token).await,
+        "You won't get reply notifications any more.",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirm_page_posts_the_token() {
+        let page = confirm_form("a\"><script>");
+        assert!(page.contains(r#"<form method="post" action="confirm">"#));
+        assert!(page.contains(r#"value="a&quot;&gt;&lt;script&gt;""#));
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::http::extract::ClientIp;
+use crate::state::AppState;
+
+#[derive(Deserialize, ToSchema)]
+pub struct PreviewRequest {
+    pub content: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PreviewResponse {
+    pub html: String,
+}
+
+/// Renders content as the site shows it, with the same sanitized Markdown
+/// as the feeds' `content_html`. Matrix clients see the plain-text body
+/// instead, since relayed events carry no HTML. Writes nothing, so no PoW,
+/// but it is rate-limited.
+#[utoipa::path(
+    post,
+    path = "/api/preview",
+    tag = "comments",
+    request_body = PreviewRequest,
+    responses(
+        (status = 200, description = "Content rendered as sanitized HTML for the site; Matrix clients get the plain text", body = PreviewResponse),
+        (status = 400, description = "Content is too long", body = String),
+        (status = 429, description = "Too many preview requests", body = String)
+    )
+)]
+pub async fn preview(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<PreviewRequest>,
+) -> Result<Json<PreviewResponse>, (StatusCode, String)> {
+    if !state.preview_limiter.check(ip) {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many preview requests".to_string(),
+        ));
+    }
+
+    if payload.content.chars().count() > state.settings.server.max_content_length {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Comment is too long (max {} chars)",
+                state.settings.server.max_content_length
+            ),
+        ));
+    }
+
+    Ok(Json(PreviewResponse {
+        html: domain::render::render_markdown(&payload.content),
+    }))
+}
@@ -1,69 +1,285 @@
 use axum::{
     extract::{Path, State},
+    http::StatusCode,
     response::sse::{Event, KeepAlive, Sse},
 };
-use domain::IngestEvent;
+use domain::{IngestEvent, SiteId};
 use futures::stream::Stream;
+use serde_json::Value;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::time::Duration;
 use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 
+use crate::http::extract::{AdminAuth, ClientIp, SlugPath, ValidatedSiteId};
+use crate::sse_hub::SseLimit;
 use crate::state::AppState;
 
+/// Events coalesced into one `batch` frame per window.
+const MAX_BATCH: usize = 100;
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, axum::Error>> + Send>>;
+
+/// Maps an ingest event to its SSE event name and payload, if it belongs to
+/// this thread, or to any thread of the site when `slug` is `None`.
+fn to_frame(
+    event: IngestEvent,
+    site_id: &SiteId,
+    slug: Option<&str>,
+) -> Option<(&'static str, Value)> {
+    let matches = |event_site_id: &SiteId, event_slug: &str| {
+        event_site_id == site_id && slug.is_none_or(|s| s == event_slug)
+    };
+    match event {
+        IngestEvent::CommentSaved {
+            site_id: event_site_id,
+            post_slug: event_slug,
+            comment,
+            reply_context,
+        } if matches(&event_site_id, &event_slug) => {
+            let event_type = if comment.updated_at.is_some() {
+                "update_comment"
+            } else {
+                "new_comment"
+            };
+            let mut data = serde_json::to_value(comment).ok()?;
+            if let (Some(context), Some(obj)) = (reply_context, data.as_object_mut()) {
+                obj.insert(
+                    "reply_context".to_string(),
+                    serde_json::to_value(context).ok()?,
+                );
+            }
+            Some((event_type, data))
+        }
+        IngestEvent::CommentDeleted {
+            site_id: event_site_id,
+            post_slug: event_slug,
+            comment_id,
+            kind,
+        } if matches(&event_site_id, &event_slug) => {
+            let data = match slug {
+                Some(_) => serde_json::json!({ "id": comment_id, "kind": kind }),
+                None => serde_json::json!({
+                    "id": comment_id,
+                    "post_slug": event_slug,
+                    "kind": kind,
+                }),
+            };
+            Some(("delete_comment", data))
+        }
+        IngestEvent::CommentFlagged {
+            site_id: event_site_id,
+            post_slug,
+            comment_id,
+            reports,
+        } if slug.is_none() && &event_site_id == site_id => Some((
+            "flag_comment",
+            serde_json::json!({ "id": comment_id, "post_slug": post_slug, "reports": reports }),
+        )),
+        _ => None,
+    }
+}
+
+/// A lone event in a window goes out as-is; a burst becomes one `batch`
+/// frame holding `{ event, data }` entries in order.
+fn coalesce<S>(frames: S, window: Duration) -> impl Stream<Item = (&'static str, Value)>
+where
+    S: Stream<Item = (&'static str, Value)>,
+{
+    frames.chunks_timeout(MAX_BATCH, window).map(|mut chunk| {
+        if chunk.len() == 1 {
+            return chunk.remove(0);
+        }
+        let batch: Vec<Value> = chunk
+            .into_iter()
+            .map(|(event, data)| serde_json::json!({ "event": event, "data": data }))
+            .collect();
+        ("batch", Value::Array(batch))
+    })
+}
+
+fn to_event(event_type: &str, data: Value) -> Result<Event, axum::Error> {
+    Event::default()
+        .event(event_type)
+        .json_data(data)
+        .map_err(|e| {
+            tracing::error!("SSE serialization error: {}", e);
+            axum::Error::new(e)
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/comments/{slug}/sse",
+    tag = "events",
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores"),
+        ("slug" = String, Path, description = "Post slug")
+    ),
+    responses(
+        (status = 200, description = "Event stream of `new_comment`, `update_comment`, `delete_comment` and `batch` events", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 429, description = "Too many open streams from this client or in total", body = String),
+        (status = 503, description = "Too many sites are streaming", body = String)
+    )
+)]
 pub async fn sse_handler(
     State(state): State<AppState>,
-    Path((site_id_str, slug)): Path<(String, String)>,
-) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
-    let rx = state.tx_ingest.subscribe();
-
-    tracing::info!("SSE Connected: site={} slug={}", site_id_str, slug);
-
-    let stream = BroadcastStream::new(rx).filter_map(move |result| match result {
-        Ok(event) => match event {
-            IngestEvent::CommentSaved {
-                site_id: event_site_id,
-                post_slug: event_slug,
-                comment,
-            } => {
-                if event_site_id.as_str() == site_id_str && event_slug == slug {
-                    let event_type = if comment.updated_at.is_some() {
-                        "update_comment"
-                    } else {
-                        "new_comment"
-                    };
-                    Some(
-                        Event::default()
-                            .event(event_type)
-                            .json_data(comment)
-                            .map_err(|e| {
-                                tracing::error!("SSE serialization error: {}", e);
-                                axum::Error::new(e)
-                            }),
-                    )
-                } else {
-                    None
-                }
-            }
-            IngestEvent::CommentDeleted {
-                site_id: event_site_id,
-                post_slug: event_slug,
-                comment_id,
-            } => {
-                if event_site_id.as_str() == site_id_str && event_slug == slug {
-                    Some(
-                        Event::default()
-                            .event("delete_comment")
-                            .json_data(serde_json::json!({ "id": comment_id }))
-                            .map_err(|e| {
-                                tracing::error!("SSE serialization error: {}", e);
-                                axum::Error::new(e)
-                            }),
-                    )
-                } else {
-                    None
-                }
-            }
-        },
-        Err(_) => None,
+    ClientIp(ip): ClientIp,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+    Path(SlugPath { slug }): Path<SlugPath>,
+) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    tracing::info!("SSE Connected: site={} slug={}", site_id, slug);
+    event_stream(&state, ip, site_id, Some(slug))
+}
+
+/// Every event for the site, across all threads. Admin-only, since it exposes
+/// all activity; comment payloads already carry `post_slug`.
+#[utoipa::path(
+    get,
+    path = "/api/{site_id}/sse",
+    tag = "events",
+    security(("admin_token" = [])),
+    params(
+        ("site_id" = String, Path, description = "Site ID; lowercase, no underscores")
+    ),
+    responses(
+        (status = 200, description = "Event stream of every thread of the site, plus `flag_comment`", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid site ID", body = String),
+        (status = 401, description = "Missing or wrong admin token", body = String),
+        (status = 403, description = "Admin API is disabled", body = String),
+        (status = 429, description = "Too many open streams from this client or in total", body = String),
+        (status = 503, description = "Too many sites are streaming", body = String)
+    )
+)]
+pub async fn site_sse_handler(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    ValidatedSiteId(site_id): ValidatedSiteId,
+) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    tracing::info!("SSE Connected: site={} (all slugs)", site_id);
+    event_stream(&state, ip, site_id, None)
+}
+
+fn event_stream(
+    state: &AppState,
+    ip: IpAddr,
+    site_id: SiteId,
+    slug: Option<String>,
+) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    let guard = state.sse_connections.open(ip).map_err(|limit| {
+        let message = match limit {
+            SseLimit::PerIp => "Too many open streams from this address",
+            SseLimit::Total => "Too many open streams",
+        };
+        (StatusCode::TOO_MANY_REQUESTS, message.to_string())
+    })?;
+    let rx = state.sse.subscribe(&site_id).ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many sites streaming".to_string(),
+        )
+    })?;
+
+    // The guard lives as long as the stream, which axum drops when the
+    // client goes away, cleanly or not.
+    let frames = BroadcastStream::new(rx).filter_map(move |result| {
+        let _ = &guard;
+        result
+            .ok()
+            .and_then(|e| to_frame(e, &site_id, slug.as_deref()))
     });
 
-    Sse::new(stream).keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(15)))
+    let window = state.settings.server.sse_batch_window_ms;
+    let stream: EventStream = if window == 0 {
+        Box::pin(frames.map(|(event_type, data)| to_event(event_type, data)))
+    } else {
+        Box::pin(
+            coalesce(frames, Duration::from_millis(window))
+                .map(|(event_type, data)| to_event(event_type, data)),
+        )
+    };
+
+    // Ended on shutdown, or the server would wait on it forever.
+    let stream: EventStream = Box::pin(state.sse.until_closed(stream));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    #[tokio::test]
+    async fn test_bursts_are_coalesced() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let frames = coalesce(UnboundedReceiverStream::new(rx), Duration::from_millis(20));
+        tokio::pin!(frames);
+
+        tx.send(("new_comment", json!({ "id": "$a" }))).unwrap();
+        let lone = frames.next().await.unwrap();
+        assert_eq!(lone, ("new_comment", json!({ "id": "$a" })));
+
+        tx.send(("new_comment", json!({ "id": "$b" }))).unwrap();
+        tx.send(("delete_comment", json!({ "id": "$a" }))).unwrap();
+        drop(tx);
+        let burst = frames.next().await.unwrap();
+        assert_eq!(
+            burst,
+            (
+                "batch",
+                json!([
+                    { "event": "new_comment", "data": { "id": "$b" } },
+                    { "event": "delete_comment", "data": { "id": "$a" } },
+                ])
+            )
+        );
+        assert!(frames.next().await.is_none());
+    }
+
+    #[test]
+    fn test_site_stream_spans_threads() {
+        let blog = SiteId::new_unchecked("blog".to_string());
+        let saved = |site: &str, slug: &str| IngestEvent::CommentSaved {
+            site_id: SiteId::new_unchecked(site.to_string()),
+            post_slug: slug.to_string(),
+            comment: Box::new(domain::Comment::fixture("$c")),
+            reply_context: None,
+        };
+        let name = |event, slug| to_frame(event, &blog, slug).map(|(name, _)| name);
+
+        assert_eq!(
+            name(saved("blog", "hello"), Some("hello")),
+            Some("new_comment")
+        );
+        assert_eq!(name(saved("blog", "other"), Some("hello")), None);
+        assert_eq!(name(saved("blog", "other"), None), Some("new_comment"));
+        assert_eq!(name(saved("docs", "hello"), None), None);
+
+        let flagged = || IngestEvent::CommentFlagged {
+            site_id: blog.clone(),
+            post_slug: "hello".to_string(),
+            comment_id: "$c".to_string(),
+            reports: 3,
+        };
+        assert_eq!(name(flagged(), Some("hello")), None);
+        assert_eq!(name(flagged(), None), Some("flag_comment"));
+
+        let deleted = || IngestEvent::CommentDeleted {
+            site_id: blog.clone(),
+            post_slug: "hello".to_string(),
+            comment_id: "$c".to_string(),
+            kind: domain::DeletionKind::Redacted,
+        };
+        let (_, thread) = to_frame(deleted(), &blog, Some("hello")).unwrap();
+        assert_eq!(thread, json!({ "id": "$c", "kind": "redacted" }));
+        let (_, site) = to_frame(deleted(), &blog, None).unwrap();
+        assert_eq!(
+            site,
+            json!({ "id": "$c", "post_slug": "hello", "kind": "redacted" })
+        );
+    }
 }
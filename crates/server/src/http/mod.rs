@@ -1,2 +1,7 @@
+pub mod command;
+pub mod error;
+pub mod extract;
 pub mod handlers;
+pub mod openapi;
+pub mod pagination;
 pub mod router;
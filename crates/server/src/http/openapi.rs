@@ -0,0 +1,162 @@
+use axum::Json;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::error::{CodedError, CodedErrorDetail};
+use super::extract::{BodyError, BodyErrorDetail};
+use super::handlers::{
+    admin, capabilities, challenge, comments, feed, health, identicon, notifications, preview, sse,
+};
+
+/// The HTTP API as OpenAPI 3. Handler errors not listed as `BodyError` are
+/// plain-text messages.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "cumments", description = "Matrix-backed comments for static sites"),
+    paths(
+        comments::list_comments,
+        comments::list_replies,
+        comments::batch_comments,
+        comments::list_comments_since,
+        comments::count_comments,
+        comments::post_comment,
+        comments::report_comment,
+        feed::atom_feed,
+        feed::json_feed,
+        sse::sse_handler,
+        sse::site_sse_handler,
+        challenge::get_challenge,
+        capabilities::get_capabilities,
+        preview::preview,
+        identicon::get_identicon,
+        notifications::confirm_page,
+        notifications::confirm,
+        notifications::unsubscribe,
+        health::readyz,
+        admin::get_site_settings,
+        admin::update_site_settings,
+        admin::delete_comment,
+        admin::list_dead_letters,
+        admin::retry_dead_letter,
+        admin::resync_room,
+        admin::ensure_room,
+        admin::link_space,
+        admin::purge_redacted,
+        admin::get_stats,
+        admin::list_comments,
+        admin::list_threads,
+        admin::set_post_url,
+        admin::get_activity,
+        admin::list_allowed_slugs,
+        admin::add_allowed_slugs,
+        admin::remove_allowed_slug,
+        admin::list_audit,
+    ),
+    components(schemas(
+        domain::Comment,
+        domain::CommentEntry,
+        domain::LinkPreview,
+        domain::CommentOrigin,
+        domain::OriginKind,
+        domain::SiteId,
+        domain::ThreadSummary,
+        domain::ThreadOrder,
+        domain::DeadLetter,
+        domain::AuditEntry,
+        domain::DeletionKind,
+        domain::PurgeReplies,
+        domain::ResyncReport,
+        domain::ProvisionedRoom,
+        domain::SpaceLinkReport,
+        comments::CreateCommentRequest,
+        comments::BatchRequest,
+        comments::PaginatedResponse,
+        comments::PaginationMeta,
+        comments::CommentCount,
+        comments::ReportCommentRequest,
+        challenge::ChallengeResponse,
+        challenge::ChallengeParams,
+        health::Readiness,
+        capabilities::Capabilities,
+        capabilities::PowParams,
+        capabilities::MatrixMode,
+        preview::PreviewRequest,
+        preview::PreviewResponse,
+        notifications::LinkQuery,
+        admin::DeleteCommentRequest,
+        admin::SlugAllowlist,
+        admin::AddSlugsRequest,
+        admin::PostUrlRequest,
+        admin::PurgeRequest,
+        admin::PurgeReport,
+        BodyError,
+        BodyErrorDetail,
+        CodedError,
+        CodedErrorDetail,
+    )),
+    modifiers(&AdminToken),
+    tags(
+        (name = "comments", description = "Reading and posting comments"),
+        (name = "feeds", description = "Syndication feeds of a thread"),
+        (name = "events", description = "Server-sent event streams"),
+        (name = "admin", description = "Moderation and operations; needs `security.admin_token`"),
+        (name = "health", description = "Readiness probes"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Admin routes take `security.admin_token` as a bearer token.
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn collect_refs(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    refs.push(r.clone());
+                }
+                map.values().for_each(|v| collect_refs(v, refs));
+            }
+            Value::Array(items) => items.iter().for_each(|v| collect_refs(v, refs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_every_schema_ref_resolves() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        assert!(doc["paths"]["/api/{site_id}/comments/{slug}"]["get"].is_object());
+        assert!(doc["paths"]["/api/{site_id}/admin/stats"]["get"]["security"].is_array());
+
+        let mut refs = Vec::new();
+        collect_refs(&doc, &mut refs);
+        assert!(!refs.is_empty());
+        for r in refs {
+            let name = r.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                doc["components"]["schemas"][name].is_object(),
+                "{} is not registered",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::config::ServerSettings;
+
+/// A page requested as `page`/`per_page`, clamped to the server's limits.
+/// Every paginated endpoint goes through this so they agree on defaults,
+/// offsets and page counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pager {
+    /// 1-based.
+    pub page: u32,
+    pub per_page: u32,
+}
+
+impl Pager {
+    pub fn new(page: Option<u32>, per_page: Option<u32>, server: &ServerSettings) -> Self {
+        Self::with_limits(page, per_page, server.default_per_page, server.max_per_page)
+    }
+
+    fn with_limits(page: Option<u32>, per_page: Option<u32>, default: u32, max: u32) -> Self {
+        Self {
+            page: page.unwrap_or(1).max(1),
+            per_page: per_page.unwrap_or(default).clamp(1, max),
+        }
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.per_page as i64
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page as i64 - 1) * self.per_page as i64
+    }
+
+    pub fn total_pages(&self, total: i64) -> i64 {
+        (total + self.limit() - 1) / self.limit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pager_clamps_and_counts() {
+        let pager = Pager::with_limits(None, None, 20, 50);
+        assert_eq!((pager.page, pager.per_page, pager.offset()), (1, 20, 0));
+
+        let pager = Pager::with_limits(Some(0), Some(500), 20, 50);
+        assert_eq!((pager.page, pager.per_page), (1, 50));
+
+        let pager = Pager::with_limits(Some(3), Some(0), 20, 50);
+        assert_eq!((pager.per_page, pager.offset()), (1, 2));
+
+        let pager = Pager::with_limits(Some(2), Some(10), 20, 50);
+        assert_eq!(pager.offset(), 10);
+        assert_eq!(pager.total_pages(0), 0);
+        assert_eq!(pager.total_pages(10), 1);
+        assert_eq!(pager.total_pages(11), 2);
+    }
+}
@@ -1,46 +1,112 @@
-use super::handlers::{challenge, comments, sse};
+use super::extract::require_site_id;
+use super::handlers::{
+    admin, capabilities, challenge, comments, feed, health, identicon, notifications, preview, sse,
+};
+use super::openapi;
+use crate::config::ServerSettings;
 use crate::state::AppState;
 use axum::{
     http::{HeaderValue, Method},
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-use tower_http::cors::{Any, CorsLayer};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, Any, CorsLayer};
 
-pub fn build_router(state: AppState, allowed_origins: &str) -> Router {
-    let cors = if allowed_origins == "*" {
-        CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST])
-            .allow_origin(Any)
-            .allow_headers(Any)
+/// Credentialed requests can't use wildcards, so with credentials on the
+/// request's headers are mirrored instead. Config validation has already
+/// refused unparsable origins, and credentials with a wildcard.
+fn cors_layer(server: &ServerSettings) -> CorsLayer {
+    let mut cors =
+        CorsLayer::new().allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]);
+    if server.cors_max_age_secs > 0 {
+        cors = cors.max_age(Duration::from_secs(server.cors_max_age_secs));
+    }
+    cors = if server.cors_allow_credentials {
+        cors.allow_headers(AllowHeaders::mirror_request())
+            .allow_credentials(true)
     } else {
-        let origins: Vec<HeaderValue> = allowed_origins
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .filter_map(|s| s.parse::<HeaderValue>().ok())
-            .collect();
+        cors.allow_headers(Any)
+    };
 
-        if origins.is_empty() {
-            tracing::warn!("CORS config is invalid or empty, falling back to allow ANY.");
-            CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST])
-                .allow_origin(Any)
-                .allow_headers(Any)
-        } else {
+    match server.cors_allowed_origins() {
+        Ok(None) if !server.cors_allow_credentials => cors.allow_origin(Any),
+        Ok(Some(origins)) => {
+            let origins: Vec<HeaderValue> = origins
+                .iter()
+                .filter_map(|o| o.ascii_serialization().parse().ok())
+                .collect();
             tracing::info!("CORS enabled for origins: {:?}", origins);
-            CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST])
-                .allow_origin(origins)
-                .allow_headers(Any)
+            cors.allow_origin(origins)
         }
-    };
+        // Unreachable past config validation; fail closed regardless.
+        _ => cors.allow_origin(Vec::<HeaderValue>::new()),
+    }
+}
+
+pub fn build_router(state: AppState) -> Router {
+    let cors = cors_layer(&state.settings.server);
 
     Router::new()
-        .route("/api/:site_id/comments/:slug", get(comments::list_comments))
-        .route("/api/:site_id/comments", post(comments::post_comment))
-        .route("/api/:site_id/comments/:slug/sse", get(sse::sse_handler))
+        .nest("/api/:site_id", site_routes())
         .route("/api/challenge", get(challenge::get_challenge))
+        .route("/api/preview", post(preview::preview))
+        .route("/api/identicon/:seed", get(identicon::get_identicon))
+        .route(
+            "/api/notifications/confirm",
+            get(notifications::confirm_page).post(notifications::confirm),
+        )
+        .route(
+            "/api/notifications/unsubscribe",
+            get(notifications::unsubscribe),
+        )
+        .route("/api/admin/audit", get(admin::list_audit))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/readyz", get(health::readyz))
         .layer(cors)
         .with_state(state)
 }
+
+/// Everything under `/api/:site_id`. The site id is validated once for the
+/// whole group, so a new endpoint added here can't skip it.
+fn site_routes() -> Router<AppState> {
+    Router::new()
+        .route("/config", get(capabilities::get_capabilities))
+        .route("/comments/:slug", get(comments::list_comments))
+        .route("/comments/:slug/since", get(comments::list_comments_since))
+        .route("/comments/:slug/count", get(comments::count_comments))
+        .route("/comments/:slug/feed.xml", get(feed::atom_feed))
+        .route("/comments/:slug/feed.json", get(feed::json_feed))
+        .route("/comments", post(comments::post_comment))
+        .route("/comments/batch", post(comments::batch_comments))
+        .route("/comments/:slug/sse", get(sse::sse_handler))
+        .route("/comments/:slug/:id/report", post(comments::report_comment))
+        .route("/comments/:slug/:id/replies", get(comments::list_replies))
+        .route("/sse", get(sse::site_sse_handler))
+        .route(
+            "/admin/settings",
+            get(admin::get_site_settings).put(admin::update_site_settings),
+        )
+        .route("/admin/comments/:comment_id", delete(admin::delete_comment))
+        .route("/admin/dead-letters", get(admin::list_dead_letters))
+        .route(
+            "/admin/dead-letters/:id/retry",
+            post(admin::retry_dead_letter),
+        )
+        .route("/admin/resync/:slug", post(admin::resync_room))
+        .route("/admin/rooms/:slug", post(admin::ensure_room))
+        .route("/admin/space-links", post(admin::link_space))
+        .route("/admin/purge-redacted", post(admin::purge_redacted))
+        .route("/admin/stats", get(admin::get_stats))
+        .route("/admin/comments", get(admin::list_comments))
+        .route("/admin/threads", get(admin::list_threads))
+        .route("/admin/threads/:slug/url", put(admin::set_post_url))
+        .route("/admin/activity", get(admin::get_activity))
+        .route(
+            "/admin/slugs",
+            get(admin::list_allowed_slugs).post(admin::add_allowed_slugs),
+        )
+        .route("/admin/slugs/:slug", delete(admin::remove_allowed_slug))
+        .route_layer(middleware::from_fn(require_site_id))
+}
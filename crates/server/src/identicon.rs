@@ -0,0 +1,46 @@
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+const GRID: usize = 5;
+const CELL: usize = 10;
+
+/// Renders a 5x5 horizontally mirrored identicon. The colour and pattern are
+/// taken from the SHA-256 of `seed`, so the same seed always looks the same.
+pub fn render_svg(seed: &str) -> String {
+    let hash = Sha256::digest(seed.as_bytes());
+    let hue = u16::from_be_bytes([hash[0], hash[1]]) % 360;
+    let size = GRID * CELL;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {size} {size}"><rect width="{size}" height="{size}" fill="hsl({hue},20%,94%)"/><g fill="hsl({hue},55%,50%)">"#
+    );
+
+    let half = GRID.div_ceil(2);
+    for row in 0..GRID {
+        for col in 0..half {
+            let bit = row * half + col;
+            if hash[2 + bit / 8] >> (bit % 8) & 1 == 0 {
+                continue;
+            }
+            for x in [col, GRID - 1 - col] {
+                let _ = write!(
+                    svg,
+                    r#"<rect x="{}" y="{}" width="{CELL}" height="{CELL}"/>"#,
+                    x * CELL,
+                    row * CELL
+                );
+                if x == GRID - 1 - x {
+                    break;
+                }
+            }
+        }
+    }
+
+    svg.push_str("</g></svg>");
+    svg
+}
+
+/// Relative URL served by the identicon route.
+pub fn url_for(seed: &str) -> String {
+    format!("/api/identicon/{}.svg", seed)
+}
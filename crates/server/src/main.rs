@@ -1,36 +1,132 @@
+mod activity;
+mod audit;
 mod config;
+mod email_domains;
 mod http;
+mod identicon;
+mod notify;
 mod pow;
+mod rate_limit;
+mod site_config;
+mod sse_hub;
 mod state;
+mod unfurl;
 
 use anyhow::Context;
+use domain::protocol::{AliasScheme, NativeNameFallback};
 use dotenvy::dotenv;
 use tokio::sync::{broadcast, mpsc};
 use tracing::info;
 
+use activity::ActivityCounter;
 use config::Settings;
+use email_domains::EmailBlocklist;
 use http::router::build_router;
+use notify::ReplyNotifier;
 use pow::PowGuard;
+use rate_limit::RateLimiter;
+use site_config::{SiteConfig, SiteConfigStore};
+use sse_hub::{SiteChannels, SseConnections};
 use state::AppState;
-use storage::Db;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{Db, DbOptions, MigrationState};
+use unfurl::Unfurler;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     tracing_subscriber::fmt::init();
 
+    // Migrations only need the database, so they don't load, or require,
+    // the rest of the configuration.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => {}
+        ["migrate"] => {
+            let database = Settings::database().context("Failed to load configuration")?;
+            Db::migrate_only(&database.url, db_options(&database)).await?;
+            info!("Migrations applied");
+            return Ok(());
+        }
+        ["migrate", "status"] => {
+            let database = Settings::database().context("Failed to load configuration")?;
+            return migration_status(&database.url).await;
+        }
+        _ => anyhow::bail!("Usage: cumments-server [migrate [status]]"),
+    }
+
     let settings = Settings::new().context("Failed to load configuration")?;
+    let db_options = db_options(&settings.database);
 
-    let db = Db::new(&settings.database.url).await?;
+    let db = Db::with_options(&settings.database.url, db_options).await?;
 
-    let (tx_cmd, rx_cmd) = mpsc::channel(100);
+    let (tx_cmd, rx_cmd) = mpsc::channel(settings.server.command_queue_capacity.max(1));
     let (tx_ingest, _rx_ingest) = broadcast::channel(100);
 
-    let matrix_config = match settings.matrix {
+    let site_config = SiteConfigStore::new(
+        db.clone(),
+        SiteConfig {
+            pow_difficulty: settings.security.pow_difficulty,
+            max_content_length: settings.server.max_content_length,
+            link_previews: settings.unfurl.enabled,
+            slug_allowlist: false,
+        },
+    );
+
+    let room_alias = AliasScheme::new(&settings.relay.room_alias_template)
+        .map_err(anyhow::Error::msg)
+        .context("Invalid relay.room_alias_template")?;
+    let relay = adapter::RelayConfig {
+        use_threads: settings.relay.use_threads,
+        max_event_bytes: settings.relay.max_event_bytes,
+        strict_capabilities: settings.relay.strict_capabilities,
+        probe_timeout: Duration::from_secs(settings.relay.probe_timeout_secs),
+        request_timeout: Duration::from_secs(settings.relay.request_timeout_secs.max(1)),
+        request_retry: (settings.relay.request_retry_secs > 0)
+            .then(|| Duration::from_secs(settings.relay.request_retry_secs)),
+        flag_federated: settings.relay.flag_federated,
+        use_spaces: settings.relay.use_spaces,
+        body_prefix: settings.relay.body_prefix.clone().filter(|p| !p.is_empty()),
+        thread_reply_to: match settings.relay.thread_reply_to {
+            config::ThreadReplyMode::Parent => adapter::ThreadReplyTo::Parent,
+            config::ThreadReplyMode::Root => adapter::ThreadReplyTo::Root,
+        },
+        room_alias: room_alias.clone(),
+        max_reply_depth: settings.relay.max_reply_depth.max(1),
+        command_attempts: settings.relay.command_attempts.max(1),
+        moderation: adapter::ModerationRules {
+            empty_nickname: rule_action(settings.relay.empty_nickname_action),
+            banned_words: settings
+                .relay
+                .banned_words
+                .split(',')
+                .map(|w| w.trim().to_lowercase())
+                .filter(|w| !w.is_empty())
+                .collect(),
+            banned_words_action: rule_action(settings.relay.banned_words_action),
+        },
+        native_name_fallback: match settings.relay.native_name_fallback {
+            config::NativeNameMode::Mxid => NativeNameFallback::Mxid,
+            config::NativeNameMode::Localpart => NativeNameFallback::Localpart,
+            config::NativeNameMode::Fixed => {
+                NativeNameFallback::Fixed(settings.relay.native_name_fixed.clone())
+            }
+        },
+    };
+
+    let matrix_config = match settings.matrix.clone() {
         config::MatrixSettings::Bot {
             homeserver_url,
             user,
             token,
+            refresh_token,
         } => {
             use matrix_sdk::ruma::UserId;
             let user_id = UserId::parse(&user)
@@ -40,7 +136,8 @@ async fn main() -> anyhow::Result<()> {
                 homeserver_url,
                 user_id,
                 access_token: token,
-                identity_salt: settings.security.identity_salt.clone(),
+                refresh_token,
+                relay,
             })
         }
         config::MatrixSettings::AppService {
@@ -57,29 +154,114 @@ async fn main() -> anyhow::Result<()> {
             hs_token,
             bot_localpart,
             listen_port,
-            identity_salt: settings.security.identity_salt.clone(),
+            relay,
         }),
     };
 
+    let mut mirrors = Vec::new();
+    if settings.mirrors.log {
+        mirrors.push(adapter::MirrorConfig::Log);
+    }
+
     let db_for_worker = db.clone();
     let tx_ingest_for_worker = tx_ingest.clone();
 
-    tokio::spawn(async move {
-        if let Err(e) =
-            adapter::start(matrix_config, db_for_worker, rx_cmd, tx_ingest_for_worker).await
+    let drain_timeout = Duration::from_secs(settings.server.shutdown_drain_secs);
+    let driver_ready = adapter::Readiness::new();
+    let ready_for_worker = driver_ready.clone();
+
+    let worker = tokio::spawn(async move {
+        if let Err(e) = adapter::start(
+            matrix_config,
+            mirrors,
+            db_for_worker,
+            rx_cmd,
+            tx_ingest_for_worker,
+            drain_timeout,
+            ready_for_worker,
+        )
+        .await
         {
             tracing::error!("Matrix worker crashed: {:?}", e);
         }
     });
 
+    let mut sse_hub = SiteChannels::new(
+        settings.server.sse_channel_capacity,
+        settings.server.sse_max_sites,
+    );
+    if settings.server.sse_reply_context {
+        sse_hub = sse_hub.with_reply_context(db.clone());
+    }
+    sse_hub.spawn_router(&tx_ingest);
+    Unfurler::new(&settings.unfurl, db.clone(), site_config.clone())?.spawn(&tx_ingest);
+    let notifier = settings
+        .notify
+        .enabled
+        .then(|| ReplyNotifier::new(&settings.notify, db.clone()));
+    if let Some(ref notifier) = notifier {
+        notifier.clone().spawn(&tx_ingest);
+    }
+
     let state = AppState {
         db,
         sender: tx_cmd,
         tx_ingest,
-        pow: PowGuard::new(),
+        pow: match settings.security.pow_secret.as_deref() {
+            Some(secret) => PowGuard::new(secret.as_bytes()),
+            None => {
+                tracing::warn!(
+                    "security.pow_secret is not set; challenges will not survive a restart"
+                );
+                PowGuard::new(&rand::random::<[u8; 32]>())
+            }
+        },
+        settings: Arc::new(settings.clone()),
+        site_config,
+        driver_ready,
+        preview_limiter: RateLimiter::new(
+            settings.server.preview_rate_limit,
+            Duration::from_secs(60),
+        ),
+        email_blocklist: Arc::new(EmailBlocklist::new(
+            settings.security.block_disposable_emails,
+            &settings.security.blocked_email_domains,
+        )),
+        challenge_limiter: RateLimiter::new(
+            settings.server.challenge_rate_limit,
+            Duration::from_secs(60),
+        ),
+        challenge_exempt: settings
+            .server
+            .challenge_exempt_nets()
+            .map_err(anyhow::Error::msg)?
+            .into(),
+        trusted_proxies: settings
+            .server
+            .trusted_proxy_nets()
+            .map_err(anyhow::Error::msg)?
+            .into(),
+        report_limiter: RateLimiter::new(
+            settings.server.report_rate_limit,
+            Duration::from_secs(60),
+        ),
+        report_ip_limiter: RateLimiter::new(
+            settings.server.report_ip_rate_limit,
+            Duration::from_secs(3600),
+        ),
+        activity: ActivityCounter::new(),
+        redactions: Default::default(),
+        sse: sse_hub,
+        sse_connections: SseConnections::new(
+            settings.server.sse_max_per_ip,
+            settings.server.sse_max_connections,
+        ),
+        notifier,
+        room_alias,
     };
 
-    let app = build_router(state, &settings.server.cors_origins);
+    let sse_closing = state.sse.clone();
+    let app = build_router(state);
 
     let addr = format!("{}:{}", settings.server.host, settings.server.port);
     info!("Server listening on {}", addr);
@@ -88,13 +270,45 @@ async fn main() -> anyhow::Result<()> {
         .await
         .with_context(|| format!("Failed to bind to address: {}", addr))?;
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        sse_closing.close();
+    })
+    .await?;
+
+    // The router, and with it every command sender, is gone now, so the
+    // worker flushes its queue and exits. It enforces `drain_timeout` itself;
+    // the margin only covers dead-lettering the remainder.
+    info!("HTTP stopped, draining queued commands...");
+    if tokio::time::timeout(drain_timeout + Duration::from_secs(5), worker)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Matrix worker did not stop in time");
+    }
 
     Ok(())
 }
 
+fn db_options(database: &config::DatabaseSettings) -> DbOptions {
+    DbOptions {
+        max_connections: database.max_connections,
+        busy_timeout: Duration::from_millis(database.busy_timeout_ms),
+    }
+}
+
+fn rule_action(action: config::ModerationAction) -> adapter::RuleAction {
+    match action {
+        config::ModerationAction::Off => adapter::RuleAction::Off,
+        config::ModerationAction::Flag => adapter::RuleAction::Flag,
+        config::ModerationAction::Drop => adapter::RuleAction::Drop,
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -122,3 +336,25 @@ async fn shutdown_signal() {
         },
     }
 }
+
+/// Prints each migration's state and fails unless all are applied, so a
+/// deploy can be gated on a clean schema.
+async fn migration_status(db_url: &str) -> anyhow::Result<()> {
+    let statuses = Db::migration_status(db_url).await?;
+    for m in &statuses {
+        println!(
+            "{:>4}  {:<8}  {}",
+            m.version,
+            format!("{:?}", m.state).to_lowercase(),
+            m.description
+        );
+    }
+    let unclean = statuses
+        .iter()
+        .filter(|m| m.state != MigrationState::Applied)
+        .count();
+    if unclean > 0 {
+        anyhow::bail!("{} migration(s) not cleanly applied", unclean);
+    }
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use domain::{Comment, IngestEvent, SiteId};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::Db;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+use crate::config::{EmailSenderKind, NotifySettings};
+use crate::rate_limit::RateLimiter;
+
+/// Longest excerpt of the reply quoted in an email.
+const MAX_EXCERPT_CHARS: usize = 500;
+
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()>;
+}
+
+/// Logs emails instead of sending them, for trying the feature out.
+pub struct LogSender;
+
+#[async_trait]
+impl EmailSender for LogSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        tracing::info!("Email to {}: {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
+
+/// Hands each email to a local `sendmail`-compatible program.
+pub struct SendmailSender {
+    path: String,
+    from: String,
+}
+
+#[async_trait]
+impl EmailSender for SendmailSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            header_value(&self.from),
+            header_value(to),
+            header_value(subject),
+            body
+        );
+        let mut child = tokio::process::Command::new(&self.path)
+            .args(["-i", "--", to])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.path))?;
+        let mut stdin = child.stdin.take().context("sendmail has no stdin")?;
+        stdin.write_all(message.as_bytes()).await?;
+        drop(stdin);
+        let status = child.wait().await?;
+        if !status.success() {
+            anyhow::bail!("{} exited with {}", self.path, status);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps user-supplied text from starting new headers.
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Identifies the guest token behind a subscription without storing it, so
+/// only the guest who confirmed an address can change it by posting.
+pub fn subscription_owner(guest_token: &str, salt: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(b"reply-subscription:");
+    mac.update(guest_token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// A fresh secret for a confirmation or unsubscribe link.
+pub fn link_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Emails guests when someone replies to one of their comments. Runs off
+/// the ingest broadcast, so it sees replies from the web and from Matrix
+/// alike, and never holds up posting. Only addresses confirmed through a
+/// mailed link get these emails, and each one carries an unsubscribe link.
+#[derive(Clone)]
+pub struct ReplyNotifier {
+    db: Db,
+    sender: Arc<dyn EmailSender>,
+    verified_only: bool,
+    limiter: RateLimiter<String>,
+    /// Where the links in emails point, without a trailing slash.
+    public_url: String,
+}
+
+impl ReplyNotifier {
+    pub fn new(settings: &NotifySettings, db: Db) -> Self {
+        let sender: Arc<dyn EmailSender> = match settings.sender {
+            EmailSenderKind::Log => Arc::new(LogSender),
+            EmailSenderKind::Sendmail => Arc::new(SendmailSender {
+                path: settings.sendmail_path.clone(),
+                from: settings.from.clone(),
+            }),
+        };
+        Self {
+            db,
+            sender,
+            verified_only: settings.verified_only,
+            limiter: RateLimiter::new(settings.max_per_hour, Duration::from_secs(3600)),
+            public_url: settings.public_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Mails the link that confirms `email` for reply notifications. Counts
+    /// against the recipient's hourly limit like any other email, so posting
+    /// someone's address over and over can't flood them.
+    pub async fn send_confirmation(
+        &self,
+        site_id: &SiteId,
+        email: &str,
+        confirm_token: &str,
+    ) -> anyhow::Result<()> {
+        if !self.limiter.check(email.to_string()) {
+            tracing::debug!("Confirmation email for {} rate limited", site_id);
+            return Ok(());
+        }
+        let link = format!(
+            "{}/api/notifications/confirm?token={}",
+            self.public_url, confirm_token
+        );
+        let body = format!(
+            "Someone left this address with a comment on {} and asked to be emailed \
+             about replies.\n\nTo confirm, open:\n{}\n\nIf that wasn't you, ignore this \
+             email and you won't hear from us again.\n",
+            site_id, link
+        );
+        self.sender
+            .send(email, "Confirm reply notifications", &body)
+            .await
+    }
+
+    /// Handles saved comments until the ingest channel closes.
+    pub fn spawn(self, tx_ingest: &broadcast::Sender<IngestEvent>) {
+        let mut rx = tx_ingest.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(IngestEvent::CommentSaved { comment, .. }) => {
+                        let notifier = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = notifier.handle(&comment).await {
+                                tracing::warn!(
+                                    "Reply notification for {} failed: {:?}",
+                                    comment.id,
+                                    e
+                                );
+                            }
+                        });
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Reply notifications lagged, {} events skipped", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn handle(&self, reply: &Comment) -> anyhow::Result<()> {
+        // Edits are saved again under the same ID; only new replies count.
+        if reply.is_redacted || reply.updated_at.is_some() {
+            return Ok(());
+        }
+        let Some(ref parent_id) = reply.reply_to else {
+            return Ok(());
+        };
+        let site_id = reply.site_id.as_str();
+        let Some(parent) = self
+            .db
+            .get_comments(site_id, std::slice::from_ref(parent_id))
+            .await?
+            .into_iter()
+            .next()
+        else {
+            return Ok(());
+        };
+        let parent = parent.comment;
+        let Some(ref fingerprint) = parent.author_fingerprint else {
+            return Ok(());
+        };
+        if !notifiable(&parent, reply, self.verified_only) {
+            return Ok(());
+        }
+        let Some((email, unsubscribe_token)) =
+            self.db.get_reply_recipient(site_id, fingerprint).await?
+        else {
+            return Ok(());
+        };
+        if !self.db.claim_reply_notification(&reply.id).await? {
+            return Ok(());
+        }
+        if !self.limiter.check(email.clone()) {
+            tracing::debug!("Reply notification for {} rate limited", reply.id);
+            return Ok(());
+        }
+
+        let post_url = self.db.get_post_url(site_id, &reply.post_slug).await?;
+        let unsubscribe = format!(
+            "{}/api/notifications/unsubscribe?token={}",
+            self.public_url, unsubscribe_token
+        );
+        let (subject, body) = compose(reply, post_url.as_deref(), &unsubscribe);
+        self.sender.send(&email, &subject, &body).await
+    }
+}
+
+/// Whether the parent's author should hear about this reply at all. A
+/// comment is `verified` when its guest had confirmed the address from the
+/// same browser, so with `verified_only` a stranger who typed in that
+/// address can't have replies to their comments mailed to its owner.
+fn notifiable(parent: &Comment, reply: &Comment, verified_only: bool) -> bool {
+    parent.is_guest
+        && !parent.is_redacted
+        && (parent.verified || !verified_only)
+        && reply.author_fingerprint != parent.author_fingerprint
+}
+
+/// The subject stays fixed; the author's name and words only appear quoted
+/// in the body.
+fn compose(reply: &Comment, post_url: Option<&str>, unsubscribe: &str) -> (String, String) {
+    let subject = "New reply to your comment".to_string();
+    let mut excerpt: String = reply.content.chars().take(MAX_EXCERPT_CHARS).collect();
+    if excerpt.len() < reply.content.len() {
+        excerpt.push('…');
+    }
+    let mut body = format!(
+        "{} replied to your comment on \"{}\":\n\n{}\n",
+        reply.author_name, reply.post_slug, excerpt
+    );
+    if let Some(url) = post_url {
+        body.push_str(&format!("\n{}\n", url));
+    }
+    body.push_str(&format!("\nTo stop these emails, open:\n{}\n", unsubscribe));
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(fingerprint: &str) -> Comment {
+        Comment {
+            author_id: "@bot:x".to_string(),
+            author_name: "Bea".to_string(),
+            is_guest: true,
+            origin: domain::CommentOrigin::Web,
+            origin_kind: domain::OriginKind::Bot,
+            verified: false,
+            author_fingerprint: Some(fingerprint.to_string()),
+            content: "Nice post".to_string(),
+            ..Comment::fixture("$c")
+        }
+    }
+
+    #[test]
+    fn test_who_gets_notified() {
+        let parent = comment("aaa");
+        let reply = comment("bbb");
+        assert!(!notifiable(&parent, &reply, true));
+        assert!(notifiable(&parent, &reply, false));
+        assert!(!notifiable(&parent, &comment("aaa"), false), "self reply");
+
+        let mut native = parent.clone();
+        native.is_guest = false;
+        assert!(!notifiable(&native, &reply, false));
+
+        let mut verified = parent.clone();
+        verified.verified = true;
+        assert!(notifiable(&verified, &reply, true));
+    }
+
+    #[test]
+    fn test_email_text() {
+        assert_eq!(header_value("Hi\r\nBcc: x@y"), "Hi  Bcc: x@y");
+        let (subject, body) = compose(
+            &comment("bbb"),
+            Some("https://blog.example/hello"),
+            "https://c.example/api/notifications/unsubscribe?token=u1",
+        );
+        assert_eq!(subject, "New reply to your comment");
+        assert!(body.contains("Bea replied"));
+        assert!(body.contains("Nice post"));
+        assert!(body.contains("https://blog.example/hello"));
+        assert!(body.contains("unsubscribe?token=u1"));
+    }
+
+    #[test]
+    fn test_subscription_owner_is_keyed() {
+        let owner = subscription_owner("tok", "salt");
+        assert_eq!(owner, subscription_owner("tok", "salt"));
+        assert_ne!(owner, subscription_owner("tok", "other"));
+        assert_ne!(owner, subscription_owner("tok2", "salt"));
+        assert_ne!(link_token(), link_token());
+    }
+}
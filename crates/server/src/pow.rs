@@ -1,76 +1,291 @@
+use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Find a nonce such that `sha256(secret + nonce)` starts with `difficulty`
+/// zero hex digits.
+pub const ALGORITHM: &str = "sha256-leading-zero-hex";
+
+/// How long an issued challenge stays valid, in seconds.
+pub(crate) const CHALLENGE_TTL_SECS: u64 = 300;
+
+/// Spent challenges are pruned at most this often, in seconds.
+const SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// Why a proof-of-work was refused, so a client knows whether to fetch a
+/// new challenge or keep mining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowError {
+    /// Not a challenge this server issued, or not `{challenge}|{nonce}`.
+    Invalid,
+    /// Issued too long ago; fetch a new one.
+    Expired,
+    /// The nonce doesn't meet the required difficulty.
+    InsufficientWork,
+    /// Already used for another comment; fetch a new one.
+    Spent,
+}
+
+impl PowError {
+    pub fn code(self) -> &'static str {
+        match self {
+            PowError::Invalid => "challenge_invalid",
+            PowError::Expired => "challenge_expired",
+            PowError::InsufficientWork => "insufficient_work",
+            PowError::Spent => "challenge_spent",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            PowError::Invalid => "Invalid PoW challenge",
+            PowError::Expired => "PoW challenge expired, fetch a new one",
+            PowError::InsufficientWork => "PoW nonce does not meet the required difficulty",
+            PowError::Spent => "PoW challenge was already used, fetch a new one",
+        }
+    }
+}
+
+/// Signed challenges: each one is `{issued_at}.{difficulty}.{nonce}.{mac}`
+/// signed with the server key, so issuing stores nothing and a challenge
+/// stays valid across restarts and on any instance sharing the key.
+///
+/// Only solved challenges are remembered, by MAC, until they expire, so each
+/// pays for one comment. That memory is per process: a restart forgets it,
+/// and instances sharing the key don't share it.
 #[derive(Clone)]
 pub struct PowGuard {
-    secrets: Arc<Mutex<HashMap<String, SystemTime>>>,
+    key: Arc<Vec<u8>>,
+    spent: Arc<Mutex<Spent>>,
+}
+
+#[derive(Default)]
+struct Spent {
+    /// MAC of each spent challenge, with when it was issued.
+    macs: HashMap<Vec<u8>, u64>,
+    swept_at: u64,
 }
 
 impl PowGuard {
-    pub fn new() -> Self {
+    pub fn new(key: &[u8]) -> Self {
         Self {
-            secrets: Arc::new(Mutex::new(HashMap::new())),
+            key: Arc::new(key.to_vec()),
+            spent: Arc::default(),
         }
     }
 
-    pub fn generate_challenge(&self) -> String {
-        let secret = format!("{:x}", rand::random::<u128>());
-        let mut map = self.secrets.lock().unwrap();
-        map.insert(secret.clone(), SystemTime::now() + Duration::from_secs(300));
-        secret
+    pub fn generate_challenge(&self, difficulty: usize) -> String {
+        self.sign(
+            now_secs(),
+            difficulty,
+            &format!("{:x}", rand::random::<u128>()),
+        )
     }
 
-    pub fn verify(&self, secret: &str, nonce: &str) -> bool {
-        {
-            let mut map = self.secrets.lock().unwrap();
-            if let Some(expiry) = map.remove(secret) {
-                if SystemTime::now() > expiry {
-                    return false;
-                }
-            } else {
-                return false;
-            }
+    /// Checks the nonce against the difficulty the challenge was issued with,
+    /// raised to `min_difficulty` so an easier challenge can't be reused on a
+    /// site that demands more work.
+    /// A challenge that passes is spent and refused from then on.
+    pub fn verify(&self, secret: &str, nonce: &str, min_difficulty: usize) -> Result<(), PowError> {
+        let (issued_at, difficulty, mac) = self.check_signature(secret).ok_or(PowError::Invalid)?;
+        let now = now_secs();
+        if issued_at > now || now - issued_at > CHALLENGE_TTL_SECS {
+            return Err(PowError::Expired);
         }
+        let difficulty = difficulty.max(min_difficulty);
 
         let input = format!("{}{}", secret, nonce);
         let mut hasher = Sha256::new();
         hasher.update(input);
         let result = hex::encode(hasher.finalize());
 
-        result.starts_with("0000")
+        if !result.starts_with(&"0".repeat(difficulty)) {
+            return Err(PowError::InsufficientWork);
+        }
+        self.spend(mac, issued_at, now)
+    }
+
+    fn spend(&self, mac: Vec<u8>, issued_at: u64, now: u64) -> Result<(), PowError> {
+        let mut spent = self.spent.lock().unwrap();
+        if now - spent.swept_at >= SWEEP_INTERVAL_SECS {
+            // Expired challenges are refused anyway, so they needn't be kept.
+            spent
+                .macs
+                .retain(|_, issued| now - *issued <= CHALLENGE_TTL_SECS);
+            spent.swept_at = now;
+        }
+        if spent.macs.insert(mac, issued_at).is_some() {
+            return Err(PowError::Spent);
+        }
+        Ok(())
+    }
+
+    /// Verifies a `{challenge}|{nonce}` response as posted with a comment.
+    pub fn verify_response(&self, response: &str, min_difficulty: usize) -> Result<(), PowError> {
+        let (secret, nonce) = response.split_once('|').ok_or(PowError::Invalid)?;
+        if nonce.contains('|') {
+            return Err(PowError::Invalid);
+        }
+        self.verify(secret, nonce, min_difficulty)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn sign(&self, issued_at: u64, difficulty: usize, nonce: &str) -> String {
+        let payload = format!("{}.{}.{}", issued_at, difficulty, nonce);
+        let tag = hex::encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, tag)
+    }
+
+    /// Returns `(issued_at, difficulty, mac)` if the token carries a valid
+    /// MAC.
+    fn check_signature(&self, secret: &str) -> Option<(u64, usize, Vec<u8>)> {
+        let (payload, tag) = secret.rsplit_once('.')?;
+        let tag = hex::decode(tag).ok()?;
+        self.mac(payload).verify_slice(&tag).ok()?;
+
+        let mut fields = payload.splitn(3, '.');
+        let issued_at = fields.next()?.parse().ok()?;
+        let difficulty = fields.next()?.parse().ok()?;
+        Some((issued_at, difficulty, tag))
     }
 }
 
+/// Difficulty for a comment of `content_len` chars: `base`, plus one per full
+/// `step` chars, capped at `max` (but never below `base`). A `step` of 0
+/// turns scaling off.
+pub fn scaled_difficulty(base: usize, content_len: usize, step: usize, max: usize) -> usize {
+    if step == 0 {
+        return base;
+    }
+    (base + content_len / step).min(max.max(base))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_pow_flow() {
-        let guard = PowGuard::new();
-
-        let secret = guard.generate_challenge();
-        assert!(!secret.is_empty());
-
-        let difficulty = 4;
+    fn solve(secret: &str, difficulty: usize) -> String {
         let prefix = "0".repeat(difficulty);
         let mut nonce = 0;
         loop {
             let input = format!("{}{}", secret, nonce);
             let hash = hex::encode(sha2::Sha256::digest(input));
             if hash.starts_with(&prefix) {
-                break;
+                return nonce.to_string();
             }
             nonce += 1;
         }
+    }
+
+    #[test]
+    fn test_pow_flow() {
+        let guard = PowGuard::new(b"test-key");
+
+        let difficulty = 4;
+        let secret = guard.generate_challenge(difficulty);
+        assert!(!secret.is_empty());
+
+        let nonce_str = solve(&secret, difficulty);
+        assert_eq!(guard.verify(&secret, &nonce_str, difficulty), Ok(()));
+
+        assert_eq!(
+            guard.verify(&secret, "999999999999", difficulty),
+            Err(PowError::InsufficientWork)
+        );
+    }
 
-        let nonce_str = nonce.to_string();
-        assert!(guard.verify(&secret, &nonce_str));
+    #[test]
+    fn test_challenge_is_spent_once() {
+        let guard = PowGuard::new(b"test-key");
+        let secret = guard.generate_challenge(1);
+        let nonce = solve(&secret, 1);
+
+        // A failed attempt doesn't spend it.
+        assert_eq!(
+            guard.verify(&secret, "999999999999", 64),
+            Err(PowError::InsufficientWork)
+        );
+        assert_eq!(guard.verify(&secret, &nonce, 1), Ok(()));
+        assert_eq!(guard.verify(&secret, &nonce, 1), Err(PowError::Spent));
+        assert_eq!(
+            guard.clone().verify(&secret, &nonce, 1),
+            Err(PowError::Spent)
+        );
 
-        assert!(!guard.verify(&secret, "999999999999"));
+        // Expired entries are pruned; the challenge is refused as expired.
+        let now = now_secs();
+        guard.spent.lock().unwrap().swept_at = 0;
+        guard
+            .spend(vec![1], now, now + CHALLENGE_TTL_SECS + 1)
+            .unwrap();
+        assert_eq!(guard.spent.lock().unwrap().macs.len(), 1);
+    }
+
+    #[test]
+    fn test_challenge_survives_restart() {
+        let secret = PowGuard::new(b"test-key").generate_challenge(2);
+        let nonce = solve(&secret, 2);
+
+        assert!(PowGuard::new(b"test-key")
+            .verify(&secret, &nonce, 2)
+            .is_ok());
+        assert_eq!(
+            PowGuard::new(b"other-key").verify(&secret, &nonce, 2),
+            Err(PowError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_rejects_tampered_or_expired() {
+        let guard = PowGuard::new(b"test-key");
 
-        assert!(!guard.verify(&secret, &nonce_str));
+        // Lowering the difficulty breaks the signature.
+        let secret = guard.generate_challenge(4);
+        let forged = secret.replacen(".4.", ".0.", 1);
+        assert_eq!(guard.verify(&forged, "0", 0), Err(PowError::Invalid));
+
+        let stale = guard.sign(now_secs() - CHALLENGE_TTL_SECS - 1, 1, "abc");
+        let nonce = solve(&stale, 1);
+        assert_eq!(guard.verify(&stale, &nonce, 1), Err(PowError::Expired));
+    }
+
+    #[test]
+    fn test_verify_response_format() {
+        let guard = PowGuard::new(b"test-key");
+        let secret = guard.generate_challenge(1);
+        let nonce = solve(&secret, 1);
+
+        let response = format!("{}|{}", secret, nonce);
+        assert_eq!(guard.verify_response(&secret, 1), Err(PowError::Invalid));
+        assert!(guard.verify_response(&response, 1).is_ok());
+        assert_eq!(
+            guard.verify_response(&format!("{}|x", response), 1),
+            Err(PowError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_scaled_difficulty() {
+        assert_eq!(scaled_difficulty(4, 5000, 0, 8), 4);
+        assert_eq!(scaled_difficulty(4, 999, 1000, 8), 4);
+        assert_eq!(scaled_difficulty(4, 2500, 1000, 8), 6);
+        assert_eq!(scaled_difficulty(4, 50_000, 1000, 8), 8);
+        assert_eq!(scaled_difficulty(4, 50_000, 1000, 2), 4);
     }
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Fixed-window limiter, keyed by client IP unless stated otherwise.
+#[derive(Clone)]
+pub struct RateLimiter<K = IpAddr> {
+    max: u32,
+    window: Duration,
+    hits: Arc<Mutex<Hits<K>>>,
+}
+
+struct Hits<K> {
+    /// Start of each key's window, and its hits so far.
+    by_key: HashMap<K, (Instant, u32)>,
+    swept_at: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Arc::new(Mutex::new(Hits {
+                by_key: HashMap::new(),
+                swept_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Records a hit and returns whether it is within the limit.
+    pub fn check(&self, key: K) -> bool {
+        self.hit(key).is_ok()
+    }
+
+    /// Records a hit; over the limit, returns how long until the key's
+    /// window resets.
+    pub fn hit(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        // Expired keys are dropped once per window rather than on every hit,
+        // which would scan the whole map each time.
+        if now.duration_since(hits.swept_at) >= self.window {
+            let window = self.window;
+            hits.by_key
+                .retain(|_, (start, _)| now.duration_since(*start) < window);
+            hits.swept_at = now;
+        }
+
+        let (start, count) = hits.by_key.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count <= self.max {
+            Ok(())
+        } else {
+            Err(self.window - now.duration_since(*start))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_reports_wait_once_over_limit() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.hit("a").is_ok());
+        assert!(limiter.hit("a").is_ok());
+        let wait = limiter.hit("a").unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        assert!(limiter.check("b"));
+    }
+
+    #[test]
+    fn test_expired_windows_reset_and_are_swept() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(20));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        std::thread::sleep(Duration::from_millis(30));
+
+        // The first hit after the window sweeps every stale key.
+        assert!(limiter.check("b"));
+        assert_eq!(limiter.hits.lock().unwrap().by_key.len(), 1);
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+    }
+}
@@ -0,0 +1,135 @@
+use domain::SiteId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use storage::Db;
+
+/// Effective tuning for one site: the file config with any `site_settings`
+/// overrides from the database applied on top.
+#[derive(Clone, Serialize)]
+pub struct SiteConfig {
+    pub pow_difficulty: usize,
+    pub max_content_length: usize,
+    /// Fetch preview cards for links in new comments.
+    pub link_previews: bool,
+    /// Only accept comments on slugs in the site's allowlist.
+    pub slug_allowlist: bool,
+}
+
+impl SiteConfig {
+    pub const KEYS: &'static [&'static str] = &[
+        "pow_difficulty",
+        "max_content_length",
+        "link_previews",
+        "slug_allowlist",
+    ];
+
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parse = |v: &str| {
+            v.parse::<usize>()
+                .map_err(|_| format!("Invalid value for {}: {}", key, v))
+        };
+        let parse_bool = |v: &str| {
+            v.parse::<bool>()
+                .map_err(|_| format!("Invalid value for {}: {}", key, v))
+        };
+        match key {
+            "pow_difficulty" => {
+                let d = parse(value)?;
+                if !(1..=8).contains(&d) {
+                    return Err("pow_difficulty must be between 1 and 8".to_string());
+                }
+                self.pow_difficulty = d;
+            }
+            "max_content_length" => self.max_content_length = parse(value)?,
+            "link_previews" => self.link_previews = parse_bool(value)?,
+            "slug_allowlist" => self.slug_allowlist = parse_bool(value)?,
+            _ => return Err(format!("Unknown site setting: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SiteConfigStore {
+    db: Db,
+    defaults: SiteConfig,
+    cache: Arc<RwLock<HashMap<String, SiteConfig>>>,
+    /// Bumped by every write. A read that started before a write must not
+    /// cache what it loaded, or the stale config would outlive the change.
+    generation: Arc<AtomicU64>,
+}
+
+impl SiteConfigStore {
+    pub fn new(db: Db, defaults: SiteConfig) -> Self {
+        Self {
+            db,
+            defaults,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+            generation: Arc::default(),
+        }
+    }
+
+    pub fn defaults(&self) -> &SiteConfig {
+        &self.defaults
+    }
+
+    pub async fn get(&self, site_id: &SiteId) -> anyhow::Result<SiteConfig> {
+        if let Some(c) = self.cache.read().unwrap().get(site_id.as_str()) {
+            return Ok(c.clone());
+        }
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let mut config = self.defaults.clone();
+        for (key, value) in self.db.list_site_settings(site_id.as_str()).await? {
+            if let Err(e) = config.apply(&key, &value) {
+                tracing::warn!("Ignoring site setting for {}: {}", site_id, e);
+            }
+        }
+
+        // Checked under the lock `set` invalidates with, so a write can't
+        // land between the check and the insert.
+        let mut cache = self.cache.write().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            cache.insert(site_id.as_str().to_string(), config.clone());
+        }
+        Ok(config)
+    }
+
+    pub async fn overrides(&self, site_id: &SiteId) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self
+            .db
+            .list_site_settings(site_id.as_str())
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    pub fn validate(&self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match value {
+            Some(v) => self.defaults.clone().apply(key, v),
+            None if SiteConfig::KEYS.contains(&key) => Ok(()),
+            None => Err(format!("Unknown site setting: {}", key)),
+        }
+    }
+
+    /// Stores an override, or removes it when `value` is `None` so the file
+    /// default applies again. Callers validate first.
+    pub async fn set(
+        &self,
+        site_id: &SiteId,
+        key: &str,
+        value: Option<&str>,
+    ) -> anyhow::Result<()> {
+        match value {
+            Some(v) => self.db.set_site_setting(site_id.as_str(), key, v).await?,
+            None => self.db.delete_site_setting(site_id.as_str(), key).await?,
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        cache.remove(site_id.as_str());
+        Ok(())
+    }
+}
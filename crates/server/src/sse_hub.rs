@@ -0,0 +1,457 @@
+use domain::{IngestEvent, ReplyContext, SiteId};
+use futures::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use storage::Db;
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Characters of the parent's content kept in a reply context.
+const SNIPPET_CHARS: usize = 100;
+
+/// How long a reply waits for its parent's lookup before it goes out
+/// without context.
+const REPLY_CONTEXT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Per-site broadcast channels for SSE subscribers, so a busy site filling
+/// its buffer only makes its own clients lag. Shards are created on first
+/// subscribe and dropped once their last subscriber leaves.
+#[derive(Clone)]
+pub struct SiteChannels {
+    shards: Arc<Mutex<HashMap<SiteId, broadcast::Sender<IngestEvent>>>>,
+    capacity: usize,
+    max_sites: usize,
+    /// Set to attach the parent's author and snippet to replies.
+    reply_context: Option<Db>,
+    /// Flipped on shutdown to end every open stream.
+    closing: Arc<watch::Sender<bool>>,
+}
+
+impl SiteChannels {
+    pub fn new(capacity: usize, max_sites: usize) -> Self {
+        Self {
+            shards: Arc::default(),
+            capacity,
+            max_sites,
+            reply_context: None,
+            closing: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Looks up the parent of each reply once, as it is routed, so every
+    /// subscriber gets it without a query of its own.
+    pub fn with_reply_context(mut self, db: Db) -> Self {
+        self.reply_context = Some(db);
+        self
+    }
+
+    /// Ends every stream wrapped in `until_closed`. SSE clients never hang up
+    /// on their own, so graceful shutdown would otherwise wait on them.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// `stream`, cut short once `close` is called.
+    pub fn until_closed<S: Stream>(&self, stream: S) -> impl Stream<Item = S::Item> {
+        let mut closing = self.closing.subscribe();
+        stream.take_until(async move {
+            let _ = closing.wait_for(|closed| *closed).await;
+        })
+    }
+
+    /// `None` when `max_sites` other sites already have live subscribers.
+    pub fn subscribe(&self, site_id: &SiteId) -> Option<broadcast::Receiver<IngestEvent>> {
+        let mut shards = self.shards.lock().unwrap();
+        if let Some(tx) = shards.get(site_id) {
+            return Some(tx.subscribe());
+        }
+
+        shards.retain(|_, tx| tx.receiver_count() > 0);
+        if shards.len() >= self.max_sites {
+            return None;
+        }
+        let (tx, rx) = broadcast::channel(self.capacity);
+        shards.insert(site_id.clone(), tx);
+        Some(rx)
+    }
+
+    /// Sends to the event's site shard; dropped if nobody is listening.
+    /// Shards left without receivers are reclaimed on the next subscribe.
+    pub fn publish(&self, event: IngestEvent) {
+        let shards = self.shards.lock().unwrap();
+        if let Some(tx) = shards.get(event.site_id()) {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Routes everything sent on `tx_ingest` to the site shards until the
+    /// channel closes. The loop does no I/O, so it keeps up with the drivers
+    /// and the shared channel's buffer stays short. With reply context on,
+    /// each listened-to site gets a queue of its own, worked through in
+    /// order, so a slow parent lookup delays only that site and never lets
+    /// a later event overtake the reply.
+    pub fn spawn_router(&self, tx_ingest: &broadcast::Sender<IngestEvent>) {
+        let hub = self.clone();
+        let mut rx = tx_ingest.subscribe();
+        tokio::spawn(async move {
+            let mut queues: HashMap<SiteId, mpsc::Sender<IngestEvent>> = HashMap::new();
+            loop {
+                match rx.recv().await {
+                    Ok(event) if hub.reply_context.is_some() => hub.enqueue(&mut queues, event),
+                    Ok(event) => hub.publish(event),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("SSE router lagged, {} events dropped", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Hands the event to its site's queue, starting one if needed. A site
+    /// nobody listens on loses its queue, since publishing would drop the
+    /// event anyway, and queues of other such sites are swept whenever a new
+    /// one starts. A full queue drops the event, as a lagging shard would.
+    fn enqueue(&self, queues: &mut HashMap<SiteId, mpsc::Sender<IngestEvent>>, event: IngestEvent) {
+        let site_id = event.site_id().clone();
+        if !self.listening(&site_id) {
+            queues.remove(&site_id);
+            return;
+        }
+        if !queues.contains_key(&site_id) {
+            queues.retain(|site, _| self.listening(site));
+        }
+        let queue = queues.entry(site_id).or_insert_with(|| {
+            let (tx, mut rx) = mpsc::channel(self.capacity);
+            let hub = self.clone();
+            tokio::spawn(async move {
+                while let Some(mut event) = rx.recv().await {
+                    hub.attach_reply_context(&mut event).await;
+                    hub.publish(event);
+                }
+            });
+            tx
+        });
+        if let Err(mpsc::error::TrySendError::Full(event)) = queue.try_send(event) {
+            tracing::warn!(
+                "SSE queue for site {} is full, event dropped",
+                event.site_id()
+            );
+        }
+    }
+
+    fn listening(&self, site_id: &SiteId) -> bool {
+        self.shards
+            .lock()
+            .unwrap()
+            .get(site_id)
+            .is_some_and(|tx| tx.receiver_count() > 0)
+    }
+
+    /// Whether the event is a reply whose parent should be looked up:
+    /// context is enabled and someone is listening on the site.
+    fn wants_reply_context(&self, event: &IngestEvent) -> bool {
+        let IngestEvent::CommentSaved {
+            site_id, comment, ..
+        } = event
+        else {
+            return false;
+        };
+        self.reply_context.is_some() && comment.reply_to.is_some() && self.listening(site_id)
+    }
+
+    /// Leaves the event as is when it doesn't want context, or the parent
+    /// is unknown or took too long to look up.
+    async fn attach_reply_context(&self, event: &mut IngestEvent) {
+        if !self.wants_reply_context(event) {
+            return;
+        }
+        let (
+            Some(db),
+            IngestEvent::CommentSaved {
+                comment,
+                reply_context,
+                ..
+            },
+        ) = (&self.reply_context, event)
+        else {
+            return;
+        };
+        let Some(parent_id) = comment.reply_to.as_deref() else {
+            return;
+        };
+
+        match tokio::time::timeout(REPLY_CONTEXT_TIMEOUT, db.get_comment(parent_id)).await {
+            Ok(Ok(Some(parent))) => *reply_context = Some(to_reply_context(&parent)),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => tracing::warn!("Reply context for {} failed: {:?}", parent_id, e),
+            Err(_) => tracing::warn!("Reply context for {} timed out", parent_id),
+        }
+    }
+}
+
+/// Open SSE streams, counted per client IP and in total. 0 turns a limit off.
+#[derive(Clone)]
+pub struct SseConnections {
+    open: Arc<Mutex<Counts>>,
+    max_per_ip: usize,
+    max_total: usize,
+}
+
+#[derive(Default)]
+struct Counts {
+    by_ip: HashMap<IpAddr, usize>,
+    total: usize,
+}
+
+/// Why a stream was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SseLimit {
+    PerIp,
+    Total,
+}
+
+impl SseConnections {
+    pub fn new(max_per_ip: usize, max_total: usize) -> Self {
+        Self {
+            open: Arc::default(),
+            max_per_ip,
+            max_total,
+        }
+    }
+
+    /// Counts a stream from `ip` until the returned guard is dropped, which
+    /// happens when the response stream is, however the client went away.
+    pub fn open(&self, ip: IpAddr) -> Result<SseConnectionGuard, SseLimit> {
+        let mut open = self.open.lock().unwrap();
+        if self.max_total > 0 && open.total >= self.max_total {
+            return Err(SseLimit::Total);
+        }
+        let count = open.by_ip.entry(ip).or_default();
+        if self.max_per_ip > 0 && *count >= self.max_per_ip {
+            return Err(SseLimit::PerIp);
+        }
+        *count += 1;
+        open.total += 1;
+        Ok(SseConnectionGuard {
+            connections: self.clone(),
+            ip,
+        })
+    }
+}
+
+pub struct SseConnectionGuard {
+    connections: SseConnections,
+    ip: IpAddr,
+}
+
+impl Drop for SseConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn to_reply_context(parent: &domain::Comment) -> ReplyContext {
+    if parent.is_redacted {
+        return ReplyContext {
+            id: parent.id.clone(),
+            author_name: None,
+            snippet: None,
+            is_redacted: true,
+        };
+    }
+    let content = parent
+        .content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let snippet = match content.char_indices().nth(SNIPPET_CHARS) {
+        Some((cut, _)) => format!("{}…", &content[..cut]),
+        None => content,
+    };
+    ReplyContext {
+        id: parent.id.clone(),
+        author_name: Some(parent.author_name.clone()),
+        snippet: Some(snippet),
+        is_redacted: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::DeletionKind;
+
+    fn deleted(site: &str, id: &str) -> IngestEvent {
+        IngestEvent::CommentDeleted {
+            site_id: SiteId::new_unchecked(site.to_string()),
+            post_slug: "hello".to_string(),
+            comment_id: id.to_string(),
+            kind: DeletionKind::Redacted,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_busy_site_does_not_lag_others() {
+        let hub = SiteChannels::new(2, 10);
+        let busy = SiteId::new_unchecked("busy".to_string());
+        let quiet = SiteId::new_unchecked("quiet".to_string());
+        let mut rx_busy = hub.subscribe(&busy).unwrap();
+        let mut rx_quiet = hub.subscribe(&quiet).unwrap();
+
+        for i in 0..5 {
+            hub.publish(deleted("busy", &i.to_string()));
+        }
+        hub.publish(deleted("quiet", "q"));
+
+        assert!(matches!(
+            rx_busy.recv().await,
+            Err(broadcast::error::RecvError::Lagged(_))
+        ));
+        assert!(matches!(
+            rx_quiet.recv().await,
+            Ok(IngestEvent::CommentDeleted { ref comment_id, .. }) if comment_id == "q"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_shards_are_reclaimed() {
+        let hub = SiteChannels::new(8, 1);
+        let a = SiteId::new_unchecked("a".to_string());
+        let b = SiteId::new_unchecked("b".to_string());
+
+        let rx_a = hub.subscribe(&a).unwrap();
+        assert!(hub.subscribe(&b).is_none());
+        drop(rx_a);
+        assert!(hub.subscribe(&b).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_replies_carry_parent_context() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let hub = SiteChannels::new(8, 10).with_reply_context(db.clone());
+        let site = SiteId::new_unchecked("blog".to_string());
+        let mut rx = hub.subscribe(&site).unwrap();
+
+        let comment = |id: &str, reply_to: Option<&str>| domain::Comment {
+            author_name: "Alice".to_string(),
+            content: "first\nline ".repeat(20),
+            reply_to: reply_to.map(str::to_string),
+            ..domain::Comment::fixture(id)
+        };
+        db.upsert_comment("!r:x", "blog", "hello", &comment("$p", None))
+            .await
+            .unwrap();
+
+        // Through the router, which looks parents up off its own loop but
+        // publishes each site's events in the order they arrived.
+        let (tx_ingest, _) = broadcast::channel(8);
+        hub.spawn_router(&tx_ingest);
+        for parent in ["$p", "$missing"] {
+            tx_ingest
+                .send(IngestEvent::CommentSaved {
+                    site_id: site.clone(),
+                    post_slug: "hello".to_string(),
+                    comment: Box::new(comment("$r", Some(parent))),
+                    reply_context: None,
+                })
+                .unwrap();
+        }
+        tx_ingest.send(deleted("blog", "$r")).unwrap();
+
+        let Ok(IngestEvent::CommentSaved { reply_context, .. }) = rx.recv().await else {
+            panic!("expected a saved comment");
+        };
+        let context = reply_context.unwrap();
+        assert_eq!(context.author_name.as_deref(), Some("Alice"));
+        let snippet = context.snippet.unwrap();
+        assert!(snippet.starts_with("first line first"));
+        assert_eq!(snippet.chars().count(), SNIPPET_CHARS + 1);
+
+        let Ok(IngestEvent::CommentSaved { reply_context, .. }) = rx.recv().await else {
+            panic!("expected a saved comment");
+        };
+        assert!(reply_context.is_none());
+        assert!(matches!(
+            rx.recv().await,
+            Ok(IngestEvent::CommentDeleted { ref comment_id, .. }) if comment_id == "$r"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_does_not_block_shutdown() {
+        use axum::response::sse::{Event, Sse};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let hub = SiteChannels::new(8, 10);
+        let site = SiteId::new_unchecked("blog".to_string());
+        let app = axum::Router::new().route(
+            "/sse",
+            axum::routing::get({
+                let hub = hub.clone();
+                move || async move {
+                    let rx = hub.subscribe(&site).unwrap();
+                    let events = tokio_stream::wrappers::BroadcastStream::new(rx)
+                        .map(|_| Ok::<_, std::convert::Infallible>(Event::default()));
+                    Sse::new(hub.until_closed(events))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let hub = hub.clone();
+            async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async move {
+                        let _ = signal.await;
+                        hub.close();
+                    })
+                    .await
+            }
+        });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /sse HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut head = [0u8; 15];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"HTTP/1.1 200 OK");
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("an open stream held up shutdown")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sse_connections_are_capped() {
+        let connections = SseConnections::new(2, 3);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = connections.open(a).unwrap();
+        let _second = connections.open(a).unwrap();
+        assert_eq!(connections.open(a).err(), Some(SseLimit::PerIp));
+        let _third = connections.open(b).unwrap();
+        assert_eq!(connections.open(b).err(), Some(SseLimit::Total));
+
+        drop(first);
+        let _again = connections.open(a).unwrap();
+        assert_eq!(connections.open(b).err(), Some(SseLimit::Total));
+        assert_eq!(connections.open.lock().unwrap().by_ip[&a], 2);
+    }
+}
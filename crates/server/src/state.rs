@@ -1,8 +1,18 @@
 use axum::extract::FromRef;
-use domain::{AppCommand, IngestEvent};
+use domain::{protocol::AliasScheme, AppCommand, IngestEvent};
+use ipnet::IpNet;
+use std::sync::Arc;
 use tokio::sync::{broadcast, mpsc};
 
+use crate::activity::ActivityCounter;
+use crate::config::Settings;
+use crate::email_domains::EmailBlocklist;
+use crate::http::command::InFlight;
+use crate::notify::ReplyNotifier;
 use crate::pow::PowGuard;
+use crate::rate_limit::RateLimiter;
+use crate::site_config::SiteConfigStore;
+use crate::sse_hub::{SiteChannels, SseConnections};
 use storage::Db;
 
 #[derive(Clone)]
@@ -11,6 +21,29 @@ pub struct AppState {
     pub sender: mpsc::Sender<AppCommand>,
     pub tx_ingest: broadcast::Sender<IngestEvent>,
     pub pow: PowGuard,
+    pub settings: Arc<Settings>,
+    pub site_config: SiteConfigStore,
+    pub email_blocklist: Arc<EmailBlocklist>,
+    pub preview_limiter: RateLimiter,
+    pub challenge_limiter: RateLimiter,
+    /// Networks that skip `challenge_limiter`.
+    pub challenge_exempt: Arc<[IpNet]>,
+    /// Proxies whose `X-Forwarded-For` names the client; see `ClientIp`.
+    pub trusted_proxies: Arc<[IpNet]>,
+    /// Keyed by reporter fingerprint.
+    pub report_limiter: RateLimiter<String>,
+    /// Reports per client IP, whatever identities they claim.
+    pub report_ip_limiter: RateLimiter,
+    pub activity: ActivityCounter,
+    /// Comments with a redaction on its way to Matrix.
+    pub redactions: InFlight,
+    pub sse: SiteChannels,
+    pub sse_connections: SseConnections,
+    /// Set when reply notifications are enabled.
+    pub notifier: Option<ReplyNotifier>,
+    pub room_alias: AliasScheme,
+    /// Set once the Matrix driver can relay comments.
+    pub driver_ready: adapter::Readiness,
 }
 
 impl FromRef<AppState> for Db {
@@ -18,3 +51,49 @@ impl FromRef<AppState> for Db {
         state.db.clone()
     }
 }
+
+#[cfg(test)]
+impl AppState {
+    /// Default settings over an in-memory database, with the driver not yet
+    /// ready. Commands land in the returned queue.
+    pub(crate) async fn for_tests() -> (Self, mpsc::Receiver<AppCommand>) {
+        use crate::site_config::SiteConfig;
+        use std::time::Duration;
+
+        let settings = Settings::for_tests();
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (sender, rx) = mpsc::channel(8);
+        let minute = Duration::from_secs(60);
+        let state = AppState {
+            site_config: SiteConfigStore::new(
+                db.clone(),
+                SiteConfig {
+                    pow_difficulty: settings.security.pow_difficulty,
+                    max_content_length: settings.server.max_content_length,
+                    link_previews: false,
+                    slug_allowlist: false,
+                },
+            ),
+            db,
+            sender,
+            tx_ingest: broadcast::channel(8).0,
+            pow: PowGuard::new(b"test"),
+            email_blocklist: Arc::new(EmailBlocklist::new(false, "")),
+            preview_limiter: RateLimiter::new(30, minute),
+            challenge_limiter: RateLimiter::new(60, minute),
+            challenge_exempt: Arc::new([]),
+            trusted_proxies: Arc::new([]),
+            report_limiter: RateLimiter::new(5, minute),
+            report_ip_limiter: RateLimiter::new(20, minute),
+            activity: ActivityCounter::new(),
+            redactions: Default::default(),
+            sse: SiteChannels::new(8, 10),
+            sse_connections: SseConnections::new(10, 100),
+            notifier: None,
+            room_alias: AliasScheme::default(),
+            driver_ready: adapter::Readiness::new(),
+            settings: Arc::new(settings),
+        };
+        (state, rx)
+    }
+}
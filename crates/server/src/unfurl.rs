@@ -0,0 +1,457 @@
+use anyhow::Context;
+use domain::{Comment, IngestEvent, LinkPreview};
+use hyper_reqwest::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use storage::Db;
+use tokio::sync::{broadcast, Semaphore};
+
+use crate::config::UnfurlSettings;
+use crate::site_config::SiteConfigStore;
+
+/// How long a fetched preview, or a failed fetch, is reused for.
+const CACHE_TTL_HOURS: i64 = 24;
+const MAX_REDIRECTS: usize = 3;
+const MAX_CONCURRENT_FETCHES: usize = 4;
+/// Longest title or description kept from a page.
+const MAX_FIELD_CHARS: usize = 300;
+
+/// Fetches OpenGraph cards for links in newly saved comments, for sites that
+/// enable `link_previews`. Runs off the ingest broadcast, so posting never
+/// waits on a third-party site.
+#[derive(Clone)]
+pub struct Unfurler {
+    client: reqwest::Client,
+    db: Db,
+    site_config: SiteConfigStore,
+    hosts: Arc<HostRules>,
+    max_bytes: usize,
+    permits: Arc<Semaphore>,
+}
+
+/// Which hosts may be fetched, by name. Addresses are checked separately.
+struct HostRules {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl HostRules {
+    fn new(settings: &UnfurlSettings) -> Self {
+        let split = |list: &str| {
+            list.split(',')
+                .map(|h| h.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect()
+        };
+        Self {
+            allowed: split(&settings.allowed_hosts),
+            denied: split(&settings.denied_hosts),
+        }
+    }
+
+    /// Entries match the host itself and its subdomains.
+    fn permits(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let matches = |rule: &String| {
+            host == *rule
+                || host
+                    .strip_suffix(rule.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        };
+        !self.denied.iter().any(matches)
+            && (self.allowed.is_empty() || self.allowed.iter().any(matches))
+    }
+}
+
+/// Resolves names like the system resolver but refuses any that point at a
+/// private or otherwise non-public address. Living in the connector, the
+/// check also covers redirects and can't be raced by a second DNS answer.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addrs.is_empty() || !addrs.iter().all(|a| is_public(a.ip())) {
+                return Err(format!("{} resolves to a non-public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let seg = v6.segments();
+            let first = seg[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10.
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // NAT64 64:ff9b::/96 and 6to4 2002::/16 embed an IPv4
+                // address, which may well be a private one.
+                || seg[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                || first == 0x2002)
+        }
+    }
+}
+
+impl Unfurler {
+    pub fn new(
+        settings: &UnfurlSettings,
+        db: Db,
+        site_config: SiteConfigStore,
+    ) -> anyhow::Result<Self> {
+        let hosts = Arc::new(HostRules::new(settings));
+        let redirect_hosts = hosts.clone();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .user_agent(concat!("cumments/", env!("CARGO_PKG_VERSION")))
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS
+                    || check_url(attempt.url(), &redirect_hosts).is_err()
+                {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .context("Failed to build link preview client")?;
+
+        Ok(Self {
+            client,
+            db,
+            site_config,
+            hosts,
+            max_bytes: settings.max_bytes,
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_FETCHES)),
+        })
+    }
+
+    /// Handles saved comments until the ingest channel closes.
+    pub fn spawn(self, tx_ingest: &broadcast::Sender<IngestEvent>) {
+        let mut rx = tx_ingest.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(IngestEvent::CommentSaved { comment, .. }) => {
+                        let unfurler = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = unfurler.handle(&comment).await {
+                                tracing::warn!("Link preview for {} failed: {:?}", comment.id, e);
+                            }
+                        });
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Link previews lagged, {} events skipped", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn handle(&self, comment: &Comment) -> anyhow::Result<()> {
+        if comment.is_redacted || !self.site_config.get(&comment.site_id).await?.link_previews {
+            return Ok(());
+        }
+
+        let preview = match first_url(&comment.content) {
+            Some(url) if check_url(&url, &self.hosts).is_ok() => self.preview_for(&url).await?,
+            // An edit may have removed the link.
+            _ if comment.updated_at.is_some() => None,
+            _ => return Ok(()),
+        };
+        let json = preview.map(|p| serde_json::to_string(&p)).transpose()?;
+        self.db
+            .set_comment_link_preview(&comment.id, json.as_deref())
+            .await
+    }
+
+    async fn preview_for(&self, url: &Url) -> anyhow::Result<Option<LinkPreview>> {
+        let fresh_since = chrono::Utc::now().naive_utc() - chrono::Duration::hours(CACHE_TTL_HOURS);
+        if let Some(cached) = self.db.get_link_preview(url.as_str(), fresh_since).await? {
+            return Ok(cached.and_then(|json| serde_json::from_str(&json).ok()));
+        }
+
+        let preview = {
+            let _permit = self.permits.acquire().await?;
+            match self.fetch(url).await {
+                Ok(preview) => preview,
+                Err(e) => {
+                    tracing::debug!("Could not unfurl {}: {:?}", url, e);
+                    None
+                }
+            }
+        };
+        let json = preview.as_ref().map(serde_json::to_string).transpose()?;
+        self.db
+            .put_link_preview(url.as_str(), json.as_deref())
+            .await?;
+        Ok(preview)
+    }
+
+    /// Reads at most `max_bytes` of an HTML page and parses its card.
+    async fn fetch(&self, url: &Url) -> anyhow::Result<Option<LinkPreview>> {
+        let mut resp = self
+            .client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let is_html = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+        if !is_html {
+            return Ok(None);
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            let room = self.max_bytes.saturating_sub(body.len());
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if body.len() >= self.max_bytes {
+                break;
+            }
+        }
+        let base = resp.url().clone();
+        Ok(parse_open_graph(&String::from_utf8_lossy(&body), &base))
+    }
+}
+
+/// Refuses URLs a preview must never be fetched from: non-HTTP schemes,
+/// hosts outside the allow/deny lists and literal non-public addresses.
+fn check_url(url: &Url, hosts: &HostRules) -> Result<(), &'static str> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("unsupported scheme");
+    }
+    let host = match url.host() {
+        Some(url::Host::Domain(d)) => d.to_string(),
+        Some(url::Host::Ipv4(ip)) if is_public(ip.into()) => ip.to_string(),
+        Some(url::Host::Ipv6(ip)) if is_public(ip.into()) => ip.to_string(),
+        _ => return Err("non-public host"),
+    };
+    if !hosts.permits(&host) {
+        return Err("host not permitted");
+    }
+    Ok(())
+}
+
+/// The first `http(s)://` link in markdown or plain text.
+fn first_url(content: &str) -> Option<Url> {
+    let start = match (content.find("https://"), content.find("http://")) {
+        (Some(a), Some(b)) => a.min(b),
+        (a, b) => a.or(b)?,
+    };
+    let rest = &content[start..];
+    let end = rest
+        .find(|c: char| c.is_whitespace() || matches!(c, ')' | '>' | '"' | '\'' | ']'))
+        .unwrap_or(rest.len());
+    let candidate = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?']);
+    Url::parse(candidate).ok()
+}
+
+/// Pulls `og:` tags, falling back to `<title>` and `description`. Only the
+/// page head matters, so a light scan over `<meta>` tags does.
+fn parse_open_graph(html: &str, base: &Url) -> Option<LinkPreview> {
+    let mut og_title = None;
+    let mut og_description = None;
+    let mut description = None;
+    let mut image = None;
+
+    for tag in html.split('<').skip(1) {
+        let Some(attrs) = tag
+            .strip_prefix("meta")
+            .or_else(|| tag.strip_prefix("META"))
+        else {
+            continue;
+        };
+        let attrs = attrs.split('>').next().unwrap_or_default();
+        let key = attr(attrs, "property").or_else(|| attr(attrs, "name"));
+        let Some(content) = attr(attrs, "content") else {
+            continue;
+        };
+        match key.map(|k| k.to_ascii_lowercase()).as_deref() {
+            Some("og:title") => og_title = og_title.or(Some(content)),
+            Some("og:description") => og_description = og_description.or(Some(content)),
+            Some("description") => description = description.or(Some(content)),
+            Some("og:image") => image = image.or(Some(content)),
+            _ => {}
+        }
+    }
+
+    let title = og_title.or_else(|| {
+        let lower = html.to_ascii_lowercase();
+        let start = lower.find("<title")?;
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = open_end + lower[open_end..].find("</title")?;
+        Some(html[open_end..close].to_string())
+    });
+    let preview = LinkPreview {
+        url: base.to_string(),
+        title: title.map(|t| clean(&t)).filter(|t| !t.is_empty()),
+        description: og_description
+            .or(description)
+            .map(|d| clean(&d))
+            .filter(|d| !d.is_empty()),
+        image: image
+            .and_then(|i| base.join(&unescape(&i)).ok())
+            .filter(|u| matches!(u.scheme(), "http" | "https"))
+            .map(String::from),
+    };
+    (preview.title.is_some() || preview.description.is_some()).then_some(preview)
+}
+
+/// Value of `name="..."` (or single-quoted) within a tag's attributes.
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    let lower = attrs.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let at = from + found;
+        from = at + name.len();
+        let preceded_ok = at == 0 || lower.as_bytes()[at - 1].is_ascii_whitespace();
+        let rest = attrs[from..].trim_start();
+        let Some(rest) = rest.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        if !preceded_ok {
+            continue;
+        }
+        let quote = rest.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let value = &rest[1..];
+        return Some(value[..value.find(quote)?].to_string());
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Unescapes, collapses whitespace and caps the length of a text field.
+fn clean(text: &str) -> String {
+    let text = unescape(text);
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match collapsed.char_indices().nth(MAX_FIELD_CHARS) {
+        Some((cut, _)) => format!("{}…", &collapsed[..cut]),
+        None => collapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_open_graph() {
+        let html = r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="Hello &amp; welcome">
+            <META name="description" content='Plain description'>
+            <meta content="/img/card.png" property="og:image" />
+            </head></html>"#;
+        let base = Url::parse("https://example.com/post").unwrap();
+
+        let preview = parse_open_graph(html, &base).unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Hello & welcome"));
+        assert_eq!(preview.description.as_deref(), Some("Plain description"));
+        assert_eq!(
+            preview.image.as_deref(),
+            Some("https://example.com/img/card.png")
+        );
+
+        let bare = parse_open_graph("<title> Just a\n title </title>", &base).unwrap();
+        assert_eq!(bare.title.as_deref(), Some("Just a title"));
+        assert!(parse_open_graph("<p>nothing</p>", &base).is_none());
+    }
+
+    #[test]
+    fn test_url_checks() {
+        let hosts = HostRules {
+            allowed: vec![],
+            denied: vec!["evil.com".to_string()],
+        };
+        let check = |u: &str| check_url(&Url::parse(u).unwrap(), &hosts);
+
+        assert!(check("https://example.com/a").is_ok());
+        assert!(check("https://8.8.8.8/").is_ok());
+        assert!(check("ftp://example.com/").is_err());
+        assert!(check("http://127.0.0.1:8080/").is_err());
+        assert!(check("http://10.0.0.5/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://[::ffff:192.168.0.1]/").is_err());
+        assert!(check("http://[64:ff9b::a00:1]/").is_err());
+        assert!(check("http://[2002:a00:1::]/").is_err());
+        assert!(check("http://[2606:4700::1111]/").is_ok());
+        assert!(check("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check("https://sub.evil.com/").is_err());
+        assert!(check("https://notevil.com/").is_ok());
+
+        let only = HostRules {
+            allowed: vec!["github.com".to_string()],
+            denied: vec![],
+        };
+        assert!(only.permits("gist.github.com"));
+        assert!(!only.permits("example.com"));
+    }
+
+    #[test]
+    fn test_first_url() {
+        assert_eq!(
+            first_url("see [this](https://example.com/a_b?x=1). ok")
+                .unwrap()
+                .as_str(),
+            "https://example.com/a_b?x=1"
+        );
+        assert_eq!(
+            first_url("link: http://example.com/page.")
+                .unwrap()
+                .as_str(),
+            "http://example.com/page"
+        );
+        assert_eq!(
+            first_url("first http://a.example/ then https://b.example/")
+                .unwrap()
+                .as_str(),
+            "http://a.example/"
+        );
+        assert!(first_url("no links here").is_none());
+    }
+}
@@ -1,41 +1,269 @@
-use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePoolOptions, Pool, Sqlite};
-use std::{fs, path::Path};
+use sqlx::{
+    migrate::{MigrateDatabase, Migrator},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    Pool, Sqlite,
+};
+use std::{fs, path::Path, str::FromStr, time::Duration};
 
 mod models;
 mod repo;
 
+static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
 #[derive(Clone)]
 pub struct Db {
     pub(crate) pool: Pool<Sqlite>,
 }
 
+/// Connection tuning. Every connection runs in WAL mode with
+/// `synchronous = NORMAL`: readers never block the writer, and a commit is
+/// durable against application crashes but may be lost on power failure or
+/// an OS crash. Writers wait up to `busy_timeout` for the lock instead of
+/// failing with "database is locked".
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    pub max_connections: u32,
+    pub busy_timeout: Duration,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 5,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Where one migration stands in a database, compared with this build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the file has changed since: the schema may have drifted.
+    Modified,
+    /// Started but never finished; needs fixing by hand.
+    Failed,
+    /// Applied by a newer build that this one doesn't know about.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
 impl Db {
+    /// Opens the database and applies any pending migrations.
     pub async fn new(db_url: &str) -> anyhow::Result<Self> {
-        if db_url.starts_with("sqlite://") && !db_url.contains(":memory:") {
-            let path_str = db_url.trim_start_matches("sqlite://");
-            let path = Path::new(path_str);
-            if let Some(parent) = path.parent() {
-                if !parent.as_os_str().is_empty() && !parent.exists() {
-                    fs::create_dir_all(parent)?;
+        Self::with_options(db_url, DbOptions::default()).await
+    }
+
+    pub async fn with_options(db_url: &str, options: DbOptions) -> anyhow::Result<Self> {
+        let pool = connect(db_url, &options).await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    /// Applies pending migrations and closes the database again, for
+    /// migrating ahead of a deploy.
+    pub async fn migrate_only(db_url: &str, options: DbOptions) -> anyhow::Result<()> {
+        let pool = connect(db_url, &options).await?;
+        MIGRATOR.run(&pool).await?;
+        pool.close().await;
+        Ok(())
+    }
+
+    /// Every migration known to this build or recorded in the database,
+    /// by version. Nothing is written, and a missing database is reported
+    /// as entirely pending.
+    pub async fn migration_status(db_url: &str) -> anyhow::Result<Vec<MigrationStatus>> {
+        let mut applied: Vec<(i64, Vec<u8>, bool, String)> = Vec::new();
+        if Sqlite::database_exists(db_url).await.unwrap_or(false) {
+            let connect = SqliteConnectOptions::from_str(db_url)?.read_only(true);
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_with(connect)
+                .await?;
+            let has_table: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master \
+                 WHERE type = 'table' AND name = '_sqlx_migrations')",
+            )
+            .fetch_one(&pool)
+            .await?;
+            if has_table {
+                applied = sqlx::query_as(
+                    "SELECT version, checksum, success, description FROM _sqlx_migrations",
+                )
+                .fetch_all(&pool)
+                .await?;
+            }
+            pool.close().await;
+        }
+
+        let mut statuses: Vec<MigrationStatus> = MIGRATOR
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+            .map(|m| {
+                let state = match applied.iter().find(|a| a.0 == m.version) {
+                    None => MigrationState::Pending,
+                    Some((_, _, false, _)) => MigrationState::Failed,
+                    Some((_, checksum, true, _)) if *checksum != *m.checksum => {
+                        MigrationState::Modified
+                    }
+                    Some(_) => MigrationState::Applied,
+                };
+                MigrationStatus {
+                    version: m.version,
+                    description: m.description.to_string(),
+                    state,
                 }
+            })
+            .collect();
+        for (version, _, _, description) in applied {
+            if !statuses.iter().any(|s| s.version == version) {
+                statuses.push(MigrationStatus {
+                    version,
+                    description,
+                    state: MigrationState::Unknown,
+                });
+            }
+        }
+        statuses.sort_by_key(|s| s.version);
+        Ok(statuses)
+    }
+}
+
+async fn connect(db_url: &str, options: &DbOptions) -> anyhow::Result<Pool<Sqlite>> {
+    if db_url.starts_with("sqlite://") && !db_url.contains(":memory:") {
+        let path_str = db_url.trim_start_matches("sqlite://");
+        let path = Path::new(path_str);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent)?;
             }
         }
+    }
+
+    if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
+        Sqlite::create_database(db_url).await?;
+    }
+
+    let connect = SqliteConnectOptions::from_str(db_url)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(options.busy_timeout)
+        .foreign_keys(true);
+
+    // Every connection to `:memory:` opens its own empty database, so an
+    // in-memory pool must be pinned to a single long-lived connection.
+    let pool = if db_url.contains(":memory:") {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(connect)
+            .await?
+    } else {
+        SqlitePoolOptions::new()
+            .max_connections(options.max_connections.max(1))
+            .connect_with(connect)
+            .await?
+    };
+
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrent_reads_and_writes() {
+        let path = std::env::temp_dir().join(format!(
+            "cumments-test-{}-{}.db",
+            std::process::id(),
+            unique_suffix()
+        ));
+        let url = format!("sqlite://{}", path.display());
+        let db = Db::new(&url).await.unwrap();
 
-        if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
-            Sqlite::create_database(db_url).await?;
+        let mut tasks = Vec::new();
+        for worker in 0..8 {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("k{}_{}", worker, i);
+                    db.set_meta(&key, "v").await?;
+                    db.ensure_room(&format!("!r{}_{}:x", worker, i), "blog", &key)
+                        .await?;
+                    db.get_meta(&key).await?;
+                    db.get_room_id("blog", &key).await?;
+                }
+                anyhow::Ok(())
+            }));
         }
 
-        let pool = SqlitePoolOptions::new().connect(db_url).await?;
+        let all = async {
+            for task in tasks {
+                task.await.unwrap().unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), all)
+            .await
+            .expect("concurrent access deadlocked");
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("db-wal"));
+        let _ = fs::remove_file(path.with_extension("db-shm"));
+    }
+
+    #[tokio::test]
+    async fn test_migration_status() {
+        let path = std::env::temp_dir().join(format!(
+            "cumments-migrate-{}-{}.db",
+            std::process::id(),
+            unique_suffix()
+        ));
+        let url = format!("sqlite://{}", path.display());
+
+        let before = Db::migration_status(&url).await.unwrap();
+        assert!(!before.is_empty());
+        assert!(before.iter().all(|m| m.state == MigrationState::Pending));
+        assert!(!path.exists(), "status must not create the database");
 
-        sqlx::query("PRAGMA journal_mode = WAL;")
+        Db::migrate_only(&url, DbOptions::default()).await.unwrap();
+        let after = Db::migration_status(&url).await.unwrap();
+        assert_eq!(after.len(), before.len());
+        assert!(after.iter().all(|m| m.state == MigrationState::Applied));
+
+        let pool = SqlitePoolOptions::new().connect(&url).await.unwrap();
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = x'00' WHERE version = 1")
             .execute(&pool)
-            .await?;
-        sqlx::query("PRAGMA synchronous = NORMAL;")
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (9999, 'from the future', 1, x'00', 0)")
             .execute(&pool)
-            .await?;
+            .await
+            .unwrap();
+        pool.close().await;
 
-        sqlx::migrate!("../../migrations").run(&pool).await?;
+        let drifted = Db::migration_status(&url).await.unwrap();
+        assert_eq!(drifted[0].state, MigrationState::Modified);
+        let last = drifted.last().unwrap();
+        assert_eq!((last.version, last.state), (9999, MigrationState::Unknown));
 
-        Ok(Self { pool })
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("db-wal"));
+        let _ = fs::remove_file(path.with_extension("db-shm"));
+    }
+
+    fn unique_suffix() -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
     }
 }
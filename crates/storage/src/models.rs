@@ -1,5 +1,5 @@
 use chrono::NaiveDateTime;
-use domain::{Comment, SiteId};
+use domain::{protocol, Comment, CommentEntry, CommentOrigin, OriginKind, SiteId};
 use sqlx::FromRow;
 
 #[derive(FromRow)]
@@ -8,18 +8,25 @@ pub struct SqlComment {
     pub author_id: String,
     pub author_name: String,
     pub is_guest: bool,
+    pub origin: String,
+    pub origin_kind: String,
+    pub verified: bool,
+    pub is_federated: bool,
+    pub flagged: bool,
     pub is_redacted: bool,
     pub author_fingerprint: Option<String>,
     pub content: String,
     pub created_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
     pub reply_to: Option<String>,
+    pub lang: Option<String>,
     pub site_id: String,
     pub post_slug: String,
 }
 
 impl From<SqlComment> for Comment {
     fn from(sql: SqlComment) -> Self {
+        let author_server = protocol::author_server(&sql.author_id, sql.is_guest);
         Comment {
             id: sql.id,
             site_id: SiteId::new_unchecked(sql.site_id),
@@ -27,12 +34,79 @@ impl From<SqlComment> for Comment {
             author_id: sql.author_id,
             author_name: sql.author_name,
             is_guest: sql.is_guest,
+            origin: CommentOrigin::parse(&sql.origin),
+            origin_kind: OriginKind::parse(&sql.origin_kind),
+            verified: sql.verified,
+            is_federated: sql.is_federated,
+            author_server,
+            flagged: sql.flagged,
             is_redacted: sql.is_redacted,
             author_fingerprint: sql.author_fingerprint,
             content: sql.content,
             created_at: sql.created_at,
             updated_at: sql.updated_at,
             reply_to: sql.reply_to,
+            lang: sql.lang,
+        }
+    }
+}
+
+#[derive(FromRow)]
+pub struct SqlCommentEntry {
+    pub id: String,
+    pub author_id: String,
+    pub author_name: String,
+    pub is_guest: bool,
+    pub origin: String,
+    pub origin_kind: String,
+    pub verified: bool,
+    pub is_federated: bool,
+    pub flagged: bool,
+    pub is_redacted: bool,
+    pub author_fingerprint: Option<String>,
+    pub content: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub reply_to: Option<String>,
+    pub lang: Option<String>,
+    pub site_id: String,
+    pub post_slug: String,
+    pub reply_count: i64,
+    pub latest_reply_at: Option<NaiveDateTime>,
+    pub link_preview: Option<String>,
+}
+
+impl From<SqlCommentEntry> for CommentEntry {
+    fn from(sql: SqlCommentEntry) -> Self {
+        let author_server = protocol::author_server(&sql.author_id, sql.is_guest);
+        CommentEntry {
+            comment: Comment {
+                id: sql.id,
+                site_id: SiteId::new_unchecked(sql.site_id),
+                post_slug: sql.post_slug,
+                author_id: sql.author_id,
+                author_name: sql.author_name,
+                is_guest: sql.is_guest,
+                origin: CommentOrigin::parse(&sql.origin),
+                origin_kind: OriginKind::parse(&sql.origin_kind),
+                verified: sql.verified,
+                is_federated: sql.is_federated,
+                author_server,
+                flagged: sql.flagged,
+                is_redacted: sql.is_redacted,
+                author_fingerprint: sql.author_fingerprint,
+                content: sql.content,
+                created_at: sql.created_at,
+                updated_at: sql.updated_at,
+                reply_to: sql.reply_to,
+                lang: sql.lang,
+            },
+            reply_count: sql.reply_count,
+            latest_reply_at: sql.latest_reply_at,
+            link_preview: sql
+                .link_preview
+                .and_then(|json| serde_json::from_str(&json).ok()),
+            avatar_url: None,
         }
     }
 }
@@ -0,0 +1,75 @@
+use crate::Db;
+
+impl Db {
+    pub async fn list_allowed_slugs(&self, site_id: &str) -> anyhow::Result<Vec<String>> {
+        let slugs = sqlx::query_scalar!(
+            "SELECT slug FROM allowed_slugs WHERE site_id = ? ORDER BY slug",
+            site_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(slugs)
+    }
+
+    pub async fn is_slug_allowed(&self, site_id: &str, slug: &str) -> anyhow::Result<bool> {
+        let found = sqlx::query_scalar!(
+            r#"SELECT 1 as "found!: i64" FROM allowed_slugs WHERE site_id = ? AND slug = ?"#,
+            site_id,
+            slug
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    /// Adds slugs to a site's allowlist, ignoring ones already on it. Returns
+    /// how many were new.
+    pub async fn add_allowed_slugs(&self, site_id: &str, slugs: &[String]) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut added = 0;
+        for slug in slugs {
+            added += sqlx::query!(
+                "INSERT INTO allowed_slugs (site_id, slug) VALUES (?, ?) ON CONFLICT DO NOTHING",
+                site_id,
+                slug
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    /// Returns whether the slug was on the allowlist.
+    pub async fn remove_allowed_slug(&self, site_id: &str, slug: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM allowed_slugs WHERE site_id = ? AND slug = ?",
+            site_id,
+            slug
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_allowlist_is_per_site() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let slugs = ["a".to_string(), "b".to_string()];
+        assert_eq!(db.add_allowed_slugs("blog", &slugs).await.unwrap(), 2);
+        assert_eq!(db.add_allowed_slugs("blog", &slugs[..1]).await.unwrap(), 0);
+
+        assert!(db.is_slug_allowed("blog", "a").await.unwrap());
+        assert!(!db.is_slug_allowed("other", "a").await.unwrap());
+
+        assert!(db.remove_allowed_slug("blog", "a").await.unwrap());
+        assert!(!db.remove_allowed_slug("blog", "a").await.unwrap());
+        assert_eq!(db.list_allowed_slugs("blog").await.unwrap(), ["b"]);
+    }
+}
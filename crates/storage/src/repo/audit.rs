@@ -0,0 +1,89 @@
+use crate::Db;
+use chrono::NaiveDateTime;
+use domain::{AuditEntry, NewAuditEntry, SiteId};
+
+impl Db {
+    pub async fn insert_audit(&self, entry: &NewAuditEntry) -> anyhow::Result<()> {
+        let site_id = entry.site_id.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_log (actor, action, site_id, post_slug, comment_id, reason, detail)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+            entry.actor,
+            entry.action,
+            site_id,
+            entry.post_slug,
+            entry.comment_id,
+            entry.reason,
+            entry.detail
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Newest first, optionally for one site.
+    pub async fn list_audit(
+        &self,
+        site_id: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id as "id!", actor, action, site_id, post_slug, comment_id, reason, detail,
+                   created_at as "created_at: NaiveDateTime"
+            FROM audit_log
+            WHERE ?1 IS NULL OR site_id = ?1
+            ORDER BY id DESC
+            LIMIT ?2 OFFSET ?3
+            "#,
+            site_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| AuditEntry {
+                id: r.id,
+                actor: r.actor,
+                action: r.action,
+                site_id: SiteId::new_unchecked(r.site_id),
+                post_slug: r.post_slug,
+                comment_id: r.comment_id,
+                reason: r.reason,
+                detail: r.detail,
+                created_at: r.created_at,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_newest_first_per_site() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        for (site, action) in [("blog", "a"), ("docs", "b"), ("blog", "c")] {
+            let mut entry =
+                NewAuditEntry::new("admin", action, SiteId::new_unchecked(site.to_string()));
+            entry.reason = Some("spam".to_string());
+            db.insert_audit(&entry).await.unwrap();
+        }
+
+        let all = db.list_audit(None, 10, 0).await.unwrap();
+        let actions: Vec<_> = all.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, ["c", "b", "a"]);
+
+        let blog = db.list_audit(Some("blog"), 1, 1).await.unwrap();
+        assert_eq!(blog.len(), 1);
+        assert_eq!(blog[0].action, "a");
+        assert_eq!(blog[0].reason.as_deref(), Some("spam"));
+    }
+}
@@ -1,5 +1,9 @@
-use crate::{models::SqlComment, Db};
-use domain::{Comment, SiteId};
+use crate::{
+    models::{SqlComment, SqlCommentEntry},
+    Db,
+};
+use chrono::NaiveDateTime;
+use domain::{Comment, CommentEntry, CommentScope, OriginKind, PurgeReplies, SiteId};
 
 impl Db {
     pub async fn upsert_comment(
@@ -27,15 +31,16 @@ impl Db {
             r#"
             INSERT INTO comments (
                 id, room_id, author_id, author_name,
-                is_guest, is_redacted,
-                author_fingerprint,
-                content, created_at, updated_at, reply_to
+                is_guest, origin, origin_kind, verified, is_federated, flagged,
+                is_redacted, author_fingerprint,
+                content, created_at, updated_at, reply_to, lang
             )
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(id) DO UPDATE SET
                 content = excluded.content,
                 is_redacted = excluded.is_redacted,
-                updated_at = excluded.updated_at
+                updated_at = excluded.updated_at,
+                flagged = comments.flagged OR excluded.flagged
             "#,
         )
         .bind(&c.id)
@@ -43,12 +48,30 @@ impl Db {
         .bind(&c.author_id)
         .bind(&c.author_name)
         .bind(c.is_guest)
+        .bind(c.origin.as_str())
+        .bind(c.origin_kind.as_str())
+        .bind(c.verified)
+        .bind(c.is_federated)
+        .bind(c.flagged)
         .bind(c.is_redacted)
         .bind(&c.author_fingerprint)
         .bind(&c.content)
         .bind(c.created_at)
         .bind(c.updated_at)
         .bind(&c.reply_to)
+        .bind(&c.lang)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE rooms SET last_comment_at = ?
+            WHERE room_id = ? AND (last_comment_at IS NULL OR last_comment_at < ?)
+            "#,
+        )
+        .bind(c.created_at)
+        .bind(room_id)
+        .bind(c.created_at)
         .execute(&mut *tx)
         .await?;
 
@@ -56,6 +79,8 @@ impl Db {
         Ok(())
     }
 
+    /// Soft-deletes a comment. Returns `None` if it is unknown or was already
+    /// redacted, so an echoed redaction doesn't announce the deletion twice.
     pub async fn delete_comment(&self, id: &str) -> anyhow::Result<Option<(SiteId, String)>> {
         let mut tx = self.pool.begin().await?;
 
@@ -63,8 +88,8 @@ impl Db {
             r#"
             SELECT r.site_id, r.post_slug
             FROM comments c
-            JOIN rooms r ON c.room_id = r.room_id
-            WHERE c.id = ?
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE c.id = ? AND c.is_redacted = FALSE
             "#,
             id
         )
@@ -75,10 +100,11 @@ impl Db {
             sqlx::query(
                 r#"
                 UPDATE comments
-                SET content = '', author_name = '[Deleted]', is_redacted = TRUE
+                SET content = '', author_name = '[Deleted]', is_redacted = TRUE, updated_at = ?
                 WHERE id = ?
                 "#,
             )
+            .bind(chrono::Utc::now().naive_utc())
             .bind(id)
             .execute(&mut *tx)
             .await?;
@@ -90,7 +116,327 @@ impl Db {
         }
     }
 
-    pub async fn list_comments(&self, site_id: &str, slug: &str) -> anyhow::Result<Vec<Comment>> {
+    /// Hard-deletes a site's redacted comments that were redacted before
+    /// `before`, with their reports, and returns each one's ID and post slug.
+    /// Replies are kept and moved according to `replies`.
+    pub async fn purge_redacted(
+        &self,
+        site_id: &str,
+        before: NaiveDateTime,
+        replies: PurgeReplies,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        // Redaction sets `updated_at`, so it dates the redaction.
+        const PURGEABLE: &str = r#"
+            SELECT c.id FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.is_redacted = TRUE
+              AND COALESCE(c.updated_at, c.created_at) < ?
+        "#;
+        let mut tx = self.pool.begin().await?;
+
+        if replies == PurgeReplies::Reparent {
+            // One level per pass, so a reply under a chain of purged
+            // comments climbs to the nearest survivor. Passes are capped in
+            // case of a reply cycle; anything left is orphaned below.
+            for _ in 0..MAX_REPARENT_PASSES {
+                let moved = sqlx::query(&format!(
+                    r#"
+                    UPDATE comments
+                    SET reply_to = (SELECT p.reply_to FROM comments p WHERE p.id = comments.reply_to)
+                    WHERE reply_to IN ({PURGEABLE})
+                    "#
+                ))
+                .bind(site_id)
+                .bind(before)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                if moved == 0 {
+                    break;
+                }
+            }
+        }
+        sqlx::query(&format!(
+            "UPDATE comments SET reply_to = NULL WHERE reply_to IN ({PURGEABLE})"
+        ))
+        .bind(site_id)
+        .bind(before)
+        .execute(&mut *tx)
+        .await?;
+
+        // Listed after the writes above, which took the write lock, so
+        // nothing can join the set before it is deleted.
+        let purged: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT c.id, r.post_slug FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.is_redacted = TRUE
+              AND COALESCE(c.updated_at, c.created_at) < ?
+            "#,
+        )
+        .bind(site_id)
+        .bind(before)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query(&format!("DELETE FROM comments WHERE id IN ({PURGEABLE})"))
+            .bind(site_id)
+            .bind(before)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(purged)
+    }
+
+    pub async fn get_comment(&self, id: &str) -> anyhow::Result<Option<Comment>> {
+        let row = sqlx::query_as!(
+            SqlComment,
+            r#"
+            SELECT
+                c.id as "id!",
+                c.author_id as "author_id!",
+                c.author_name as "author_name!",
+                c.is_guest,
+                c.origin,
+                c.origin_kind,
+                c.verified,
+                c.is_federated,
+                c.flagged,
+                c.is_redacted,
+                c.author_fingerprint,
+                c.content as "content!",
+                c.created_at,
+                c.updated_at,
+                c.reply_to,
+                c.lang,
+                r.site_id as "site_id!",
+                r.post_slug as "post_slug!"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE c.id = ?
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(Comment::from))
+    }
+
+    /// Returns `(room_id, site_id, author_id)` for a stored comment.
+    pub async fn get_comment_origin(
+        &self,
+        id: &str,
+    ) -> anyhow::Result<Option<(String, SiteId, String)>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT c.room_id, r.site_id, c.author_id
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE c.id = ?
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| (r.room_id, SiteId::new_unchecked(r.site_id), r.author_id)))
+    }
+
+    /// The stored ancestors of a comment, nearest first, in one query. At
+    /// most `max + 1` are returned, so a result longer than `max` means the
+    /// chain goes on past the limit.
+    pub async fn get_ancestors(&self, id: &str, max: u32) -> anyhow::Result<Vec<String>> {
+        let ancestors = sqlx::query_scalar(
+            r#"
+            WITH RECURSIVE chain(id, reply_to, depth) AS (
+                SELECT id, reply_to, 0 FROM comments WHERE id = ?1
+                UNION ALL
+                SELECT c.id, c.reply_to, chain.depth + 1
+                FROM comments c
+                JOIN chain ON c.id = chain.reply_to
+                WHERE chain.depth <= ?2
+            )
+            SELECT id FROM chain WHERE depth > 0 ORDER BY depth
+            "#,
+        )
+        .bind(id)
+        .bind(max)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ancestors)
+    }
+
+    pub async fn count_thread_comments(
+        &self,
+        site_id: &str,
+        slug: &str,
+        scope: CommentScope<'_>,
+    ) -> anyhow::Result<i64> {
+        let (top_level, parent) = scope_binds(scope);
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "total!: i64"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND r.post_slug = ?
+              AND (? = FALSE OR c.reply_to IS NULL
+                   OR NOT EXISTS (SELECT 1 FROM comments p WHERE p.id = c.reply_to))
+              AND (? IS NULL OR c.reply_to = ?)
+            "#,
+            site_id,
+            slug,
+            top_level,
+            parent,
+            parent
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.total)
+    }
+
+    /// Visible comments in a thread, for count badges.
+    pub async fn count_comments(&self, site_id: &str, slug: &str) -> anyhow::Result<i64> {
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "total!: i64"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND r.post_slug = ? AND c.is_redacted = FALSE
+            "#,
+            site_id,
+            slug
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.total)
+    }
+
+    pub async fn list_comments(
+        &self,
+        site_id: &str,
+        slug: &str,
+        scope: CommentScope<'_>,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<CommentEntry>> {
+        let (top_level, parent) = scope_binds(scope);
+        let rows = sqlx::query_as!(
+            SqlCommentEntry,
+            r#"
+            SELECT
+                c.id as "id!",
+                c.author_id as "author_id!",
+                c.author_name as "author_name!",
+                c.is_guest,
+                c.origin,
+                c.origin_kind,
+                c.verified,
+                c.is_federated,
+                c.flagged,
+                c.is_redacted,
+                c.author_fingerprint,
+                c.content as "content!",
+                c.created_at,
+                c.updated_at,
+                c.reply_to,
+                c.lang,
+                c.link_preview,
+                r.site_id as "site_id!",
+                r.post_slug as "post_slug!",
+                (
+                    SELECT COUNT(*) FROM comments x
+                    WHERE x.reply_to = c.id AND x.is_redacted = FALSE
+                ) as "reply_count!: i64",
+                (
+                    SELECT MAX(x.created_at) FROM comments x
+                    WHERE x.reply_to = c.id AND x.is_redacted = FALSE
+                ) as "latest_reply_at: NaiveDateTime"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND r.post_slug = ?
+              AND (? = FALSE OR c.reply_to IS NULL
+                   OR NOT EXISTS (SELECT 1 FROM comments p WHERE p.id = c.reply_to))
+              AND (? IS NULL OR c.reply_to = ?)
+            ORDER BY c.created_at ASC
+            LIMIT ? OFFSET ?
+            "#,
+            site_id,
+            slug,
+            top_level,
+            parent,
+            parent,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(CommentEntry::from).collect())
+    }
+
+    /// The listed comments of a site in their current state, redacted ones
+    /// included. Unknown IDs are skipped; order follows `created_at`.
+    pub async fn get_comments(
+        &self,
+        site_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Vec<CommentEntry>> {
+        let ids = serde_json::to_string(ids)?;
+        let rows = sqlx::query_as!(
+            SqlCommentEntry,
+            r#"
+            SELECT
+                c.id as "id!",
+                c.author_id as "author_id!",
+                c.author_name as "author_name!",
+                c.is_guest,
+                c.origin,
+                c.origin_kind,
+                c.verified,
+                c.is_federated,
+                c.flagged,
+                c.is_redacted,
+                c.author_fingerprint,
+                c.content as "content!",
+                c.created_at,
+                c.updated_at,
+                c.reply_to,
+                c.lang,
+                c.link_preview,
+                r.site_id as "site_id!",
+                r.post_slug as "post_slug!",
+                (
+                    SELECT COUNT(*) FROM comments x
+                    WHERE x.reply_to = c.id AND x.is_redacted = FALSE
+                ) as "reply_count!: i64",
+                (
+                    SELECT MAX(x.created_at) FROM comments x
+                    WHERE x.reply_to = c.id AND x.is_redacted = FALSE
+                ) as "latest_reply_at: NaiveDateTime"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.id IN (SELECT value FROM json_each(?))
+            ORDER BY c.created_at ASC
+            "#,
+            site_id,
+            ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(CommentEntry::from).collect())
+    }
+
+    pub async fn list_comments_changed_since(
+        &self,
+        site_id: &str,
+        slug: &str,
+        since: NaiveDateTime,
+    ) -> anyhow::Result<Vec<Comment>> {
         let rows = sqlx::query_as!(
             SqlComment,
             r#"
@@ -99,21 +445,153 @@ impl Db {
                 c.author_id as "author_id!",
                 c.author_name as "author_name!",
                 c.is_guest,
+                c.origin,
+                c.origin_kind,
+                c.verified,
+                c.is_federated,
+                c.flagged,
                 c.is_redacted,
                 c.author_fingerprint,
                 c.content as "content!",
                 c.created_at,
                 c.updated_at,
                 c.reply_to,
+                c.lang,
                 r.site_id as "site_id!",
                 r.post_slug as "post_slug!"
             FROM comments c
-            JOIN rooms r ON c.room_id = r.room_id
+            JOIN thread_rooms r ON c.room_id = r.room_id
             WHERE r.site_id = ? AND r.post_slug = ?
+              AND (c.created_at > ? OR c.updated_at > ?)
             ORDER BY c.created_at ASC
             "#,
             site_id,
-            slug
+            slug,
+            since,
+            since
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Comment::from).collect())
+    }
+
+    /// Live comment counts for a site, grouped by the kind of sending account.
+    pub async fn count_comments_by_origin_kind(
+        &self,
+        site_id: &str,
+    ) -> anyhow::Result<Vec<(OriginKind, i64)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT c.origin_kind, COUNT(*) as "count!: i64"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.is_redacted = FALSE
+            GROUP BY c.origin_kind
+            "#,
+            site_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (OriginKind::parse(&row.origin_kind), row.count))
+            .collect())
+    }
+
+    /// Comments created per hour since `since`, oldest first; hours without
+    /// comments are omitted. Deleted comments still count, since spam that
+    /// was cleaned up is exactly what a trend graph should show.
+    pub async fn count_comments_by_hour(
+        &self,
+        site_id: &str,
+        since: NaiveDateTime,
+    ) -> anyhow::Result<Vec<(NaiveDateTime, i64)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                strftime('%Y-%m-%d %H:00:00', c.created_at) as "hour!: NaiveDateTime",
+                COUNT(*) as "count!: i64"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.created_at >= ?
+            GROUP BY 1
+            ORDER BY 1
+            "#,
+            site_id,
+            since
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| (r.hour, r.count)).collect())
+    }
+
+    /// Live `(federated, flagged)` comment counts for a site.
+    pub async fn count_federated_and_flagged(&self, site_id: &str) -> anyhow::Result<(i64, i64)> {
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                COALESCE(SUM(c.is_federated), 0) as "federated!: i64",
+                COALESCE(SUM(c.flagged), 0) as "flagged!: i64"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.is_redacted = FALSE
+            "#,
+            site_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((row.federated, row.flagged))
+    }
+
+    /// Newest live comments across a site, for moderation views.
+    pub async fn list_site_comments(
+        &self,
+        site_id: &str,
+        flagged_only: bool,
+        federated_only: bool,
+        reported_only: bool,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Comment>> {
+        let rows = sqlx::query_as!(
+            SqlComment,
+            r#"
+            SELECT
+                c.id as "id!",
+                c.author_id as "author_id!",
+                c.author_name as "author_name!",
+                c.is_guest,
+                c.origin,
+                c.origin_kind,
+                c.verified,
+                c.is_federated,
+                c.flagged,
+                c.is_redacted,
+                c.author_fingerprint,
+                c.content as "content!",
+                c.created_at,
+                c.updated_at,
+                c.reply_to,
+                c.lang,
+                r.site_id as "site_id!",
+                r.post_slug as "post_slug!"
+            FROM comments c
+            JOIN thread_rooms r ON c.room_id = r.room_id
+            WHERE r.site_id = ? AND c.is_redacted = FALSE
+              AND (? = FALSE OR c.flagged = TRUE)
+              AND (? = FALSE OR c.is_federated = TRUE)
+              AND (? = FALSE OR EXISTS (SELECT 1 FROM reports x WHERE x.comment_id = c.id))
+            ORDER BY c.created_at DESC
+            LIMIT ?
+            "#,
+            site_id,
+            flagged_only,
+            federated_only,
+            reported_only,
+            limit
         )
         .fetch_all(&self.pool)
         .await?;
@@ -121,3 +599,313 @@ impl Db {
         Ok(rows.into_iter().map(Comment::from).collect())
     }
 }
+
+/// Deepest chain of purged comments `purge_redacted` re-parents across.
+const MAX_REPARENT_PASSES: usize = 64;
+
+/// `(top_level_only, parent)` for the scope filter shared by the thread
+/// listing queries.
+fn scope_binds(scope: CommentScope<'_>) -> (bool, Option<&str>) {
+    match scope {
+        CommentScope::All => (false, None),
+        CommentScope::TopLevel => (true, None),
+        CommentScope::RepliesTo(id) => (false, Some(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn insert(db: &Db, id: &str, reply_to: Option<&str>) {
+        sqlx::query(
+            r#"
+            INSERT INTO comments (id, room_id, author_id, author_name, content, created_at, reply_to)
+            VALUES (?, '!r:x', '@a:x', 'A', 'hi', CURRENT_TIMESTAMP, ?)
+            "#,
+        )
+        .bind(id)
+        .bind(reply_to)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scope_filters_thread_listing() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert(&db, "$a", None).await;
+        insert(&db, "$b", None).await;
+        insert(&db, "$a1", Some("$a")).await;
+        insert(&db, "$a2", Some("$a")).await;
+        insert(&db, "$a1x", Some("$a1")).await;
+
+        let ids = |entries: Vec<CommentEntry>| {
+            let mut ids: Vec<_> = entries.into_iter().map(|e| e.comment.id).collect();
+            ids.sort();
+            ids
+        };
+        let all = db
+            .list_comments("blog", "hello", CommentScope::All, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 5);
+
+        let top = db
+            .list_comments("blog", "hello", CommentScope::TopLevel, 10, 0)
+            .await
+            .unwrap();
+        let a = top.iter().find(|e| e.comment.id == "$a").unwrap();
+        assert_eq!(a.reply_count, 2);
+        assert_eq!(ids(top), ["$a", "$b"]);
+
+        let replies = db
+            .list_comments("blog", "hello", CommentScope::RepliesTo("$a"), 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(ids(replies), ["$a1", "$a2"]);
+
+        let count = |scope| db.count_thread_comments("blog", "hello", scope);
+        assert_eq!(count(CommentScope::TopLevel).await.unwrap(), 2);
+        assert_eq!(count(CommentScope::RepliesTo("$a1")).await.unwrap(), 1);
+
+        let wanted = ["$a2", "$missing", "$b"].map(String::from);
+        let batch = db.get_comments("blog", &wanted).await.unwrap();
+        assert_eq!(ids(batch), ["$a2", "$b"]);
+        assert!(db.get_comments("other", &wanted).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_purge_redacted_keeps_replies() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert(&db, "$root", None).await;
+        insert(&db, "$mid", Some("$root")).await;
+        insert(&db, "$low", Some("$mid")).await;
+        insert(&db, "$leaf", Some("$low")).await;
+        insert(&db, "$top", None).await;
+        insert(&db, "$under_top", Some("$top")).await;
+        for id in ["$mid", "$low", "$top"] {
+            db.delete_comment(id).await.unwrap();
+        }
+        let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(1);
+        let parent = |id: &'static str| {
+            let db = db.clone();
+            async move { db.get_comment(id).await.unwrap().unwrap().reply_to }
+        };
+
+        let earlier = later - chrono::Duration::days(1);
+        let none = db.purge_redacted("blog", earlier, PurgeReplies::Reparent);
+        assert!(none.await.unwrap().is_empty());
+        assert!(db
+            .purge_redacted("other", later, PurgeReplies::Reparent)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut purged = db
+            .purge_redacted("blog", later, PurgeReplies::Reparent)
+            .await
+            .unwrap();
+        purged.sort();
+        let hello = |id: &str| (id.to_string(), "hello".to_string());
+        assert_eq!(purged, [hello("$low"), hello("$mid"), hello("$top")]);
+        assert!(db.get_comment("$mid").await.unwrap().is_none());
+        assert_eq!(parent("$leaf").await.as_deref(), Some("$root"));
+        assert_eq!(parent("$under_top").await, None);
+
+        insert(&db, "$gone", Some("$root")).await;
+        insert(&db, "$child", Some("$gone")).await;
+        db.delete_comment("$gone").await.unwrap();
+        let purged = db
+            .purge_redacted("blog", later, PurgeReplies::Orphan)
+            .await
+            .unwrap();
+        assert_eq!(purged, [hello("$gone")]);
+        assert_eq!(parent("$child").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lang_is_stored() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let tagged = Comment {
+            lang: Some("zh-Hans".to_string()),
+            ..Comment::fixture("$zh")
+        };
+        for c in [tagged, Comment::fixture("$plain")] {
+            db.upsert_comment("!r:x", "blog", "hello", &c)
+                .await
+                .unwrap();
+        }
+        let lang = |id: &'static str| {
+            let db = db.clone();
+            async move { db.get_comment(id).await.unwrap().unwrap().lang }
+        };
+        assert_eq!(lang("$zh").await.as_deref(), Some("zh-Hans"));
+        assert_eq!(lang("$plain").await, None);
+        let listed = db
+            .list_comments("blog", "hello", CommentScope::All, 10, 0)
+            .await
+            .unwrap();
+        assert!(listed
+            .iter()
+            .any(|e| e.comment.lang.as_deref() == Some("zh-Hans")));
+    }
+
+    #[tokio::test]
+    async fn test_reply_stats_skip_redacted_replies() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let at = |minute| {
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(12, minute, 0)
+                .unwrap()
+        };
+        let reply = |id: &str, minute| Comment {
+            reply_to: Some("$root".to_string()),
+            created_at: at(minute),
+            ..Comment::fixture(id)
+        };
+        let comments = [
+            Comment {
+                created_at: at(0),
+                ..Comment::fixture("$root")
+            },
+            reply("$early", 1),
+            reply("$kept", 2),
+            reply("$latest", 3),
+        ];
+        for c in &comments {
+            db.upsert_comment("!r:x", "blog", "hello", c).await.unwrap();
+        }
+        db.delete_comment("$latest").await.unwrap();
+
+        let top = db
+            .list_comments("blog", "hello", CommentScope::TopLevel, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].reply_count, 2);
+        assert_eq!(top[0].latest_reply_at, Some(at(2)));
+        let leaf = db
+            .get_comments("blog", &["$kept".to_string()])
+            .await
+            .unwrap();
+        assert_eq!((leaf[0].reply_count, leaf[0].latest_reply_at), (0, None));
+    }
+
+    #[tokio::test]
+    async fn test_changed_since_includes_edits_and_deletions() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let old = now - chrono::Duration::days(2);
+        let comments = [
+            Comment {
+                created_at: old,
+                ..Comment::fixture("$old")
+            },
+            Comment {
+                created_at: old,
+                updated_at: Some(now),
+                ..Comment::fixture("$edited")
+            },
+            Comment {
+                created_at: old,
+                ..Comment::fixture("$deleted")
+            },
+            Comment::fixture("$new"),
+        ];
+        for c in &comments {
+            db.upsert_comment("!r:x", "blog", "hello", c).await.unwrap();
+        }
+        db.upsert_comment("!o:x", "blog", "other", &Comment::fixture("$elsewhere"))
+            .await
+            .unwrap();
+        db.delete_comment("$deleted").await.unwrap();
+
+        let since = now - chrono::Duration::hours(1);
+        let mut changed: Vec<_> = db
+            .list_comments_changed_since("blog", "hello", since)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.is_redacted))
+            .collect();
+        changed.sort();
+        let expected = [("$deleted", true), ("$edited", false), ("$new", false)];
+        assert_eq!(changed, expected.map(|(id, gone)| (id.to_string(), gone)));
+        assert!(db
+            .list_comments_changed_since("other-site", "hello", since)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_replies_are_top_level() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert(&db, "$root", None).await;
+        insert(&db, "$reply", Some("$root")).await;
+        // Its parent never made it here, e.g. sent before the bot joined.
+        insert(&db, "$stray", Some("$unknown")).await;
+
+        let mut top: Vec<_> = db
+            .list_comments("blog", "hello", CommentScope::TopLevel, 10, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.comment.id)
+            .collect();
+        top.sort();
+        assert_eq!(top, ["$root", "$stray"]);
+        let count = db.count_thread_comments("blog", "hello", CommentScope::TopLevel);
+        assert_eq!(count.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ancestors_are_bounded() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert(&db, "$0", None).await;
+        for i in 1..6 {
+            insert(&db, &format!("${}", i), Some(&format!("${}", i - 1))).await;
+        }
+
+        assert_eq!(
+            db.get_ancestors("$3", 10).await.unwrap(),
+            ["$2", "$1", "$0"]
+        );
+        assert!(db.get_ancestors("$0", 10).await.unwrap().is_empty());
+        assert!(db.get_ancestors("$missing", 10).await.unwrap().is_empty());
+        // One past the limit shows the chain is longer than allowed.
+        assert_eq!(db.get_ancestors("$5", 2).await.unwrap(), ["$4", "$3", "$2"]);
+    }
+
+    #[tokio::test]
+    async fn test_edits_can_flag_but_not_unflag() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert(&db, "$a", None).await;
+        let mut edit = db.get_comment("$a").await.unwrap().unwrap();
+        assert!(!edit.flagged);
+
+        edit.content = "now with a banned word".to_string();
+        edit.flagged = true;
+        db.upsert_comment("!r:x", "blog", "hello", &edit)
+            .await
+            .unwrap();
+        assert!(db.get_comment("$a").await.unwrap().unwrap().flagged);
+
+        // A clean edit doesn't clear a flag a rule or reports set earlier.
+        edit.content = "all clean".to_string();
+        edit.flagged = false;
+        db.upsert_comment("!r:x", "blog", "hello", &edit)
+            .await
+            .unwrap();
+        let stored = db.get_comment("$a").await.unwrap().unwrap();
+        assert_eq!(stored.content, "all clean");
+        assert!(stored.flagged);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::Db;
+use chrono::NaiveDateTime;
+use domain::{DeadLetter, SiteId};
+
+impl Db {
+    pub async fn insert_dead_letter(
+        &self,
+        site_id: &str,
+        command: &str,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("INSERT INTO dead_letters (site_id, command, error) VALUES (?, ?, ?)")
+            .bind(site_id)
+            .bind(command)
+            .bind(error)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_dead_letters(&self, site_id: &str) -> anyhow::Result<Vec<DeadLetter>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id as "id!", site_id, command, error, created_at as "created_at: NaiveDateTime"
+            FROM dead_letters
+            WHERE site_id = ?
+            ORDER BY id ASC
+            "#,
+            site_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| DeadLetter {
+                id: r.id,
+                site_id: SiteId::new_unchecked(r.site_id),
+                command: r.command,
+                error: r.error,
+                created_at: r.created_at,
+            })
+            .collect())
+    }
+
+    /// Removes a dead letter and returns it, so a concurrent retry of the
+    /// same one finds nothing.
+    pub async fn take_dead_letter(
+        &self,
+        site_id: &str,
+        id: i64,
+    ) -> anyhow::Result<Option<DeadLetter>> {
+        let row = sqlx::query!(
+            r#"
+            DELETE FROM dead_letters WHERE id = ? AND site_id = ?
+            RETURNING id as "id!", site_id, command, error, created_at as "created_at: NaiveDateTime"
+            "#,
+            id,
+            site_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| DeadLetter {
+            id: r.id,
+            site_id: SiteId::new_unchecked(r.site_id),
+            command: r.command,
+            error: r.error,
+            created_at: r.created_at,
+        }))
+    }
+
+    /// Puts back a dead letter taken for a retry that never got queued,
+    /// under its original ID.
+    pub async fn restore_dead_letter(&self, letter: &DeadLetter) -> anyhow::Result<()> {
+        let site_id = letter.site_id.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO dead_letters (id, site_id, command, error, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+            letter.id,
+            site_id,
+            letter.command,
+            letter.error,
+            letter.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_restored_dead_letter_keeps_its_id() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.insert_dead_letter("blog", "{}", "boom").await.unwrap();
+        let id = db.list_dead_letters("blog").await.unwrap()[0].id;
+
+        assert!(db.take_dead_letter("other", id).await.unwrap().is_none());
+        let letter = db.take_dead_letter("blog", id).await.unwrap().unwrap();
+        assert!(db.take_dead_letter("blog", id).await.unwrap().is_none());
+
+        db.restore_dead_letter(&letter).await.unwrap();
+        let listed = db.list_dead_letters("blog").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, id);
+        assert_eq!(listed[0].error, "boom");
+    }
+}
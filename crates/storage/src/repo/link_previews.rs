@@ -0,0 +1,52 @@
+use crate::Db;
+use chrono::NaiveDateTime;
+
+impl Db {
+    /// The cached preview JSON for `url` if fetched after `fresh_since`. The
+    /// inner `None` is a cached miss.
+    pub async fn get_link_preview(
+        &self,
+        url: &str,
+        fresh_since: NaiveDateTime,
+    ) -> anyhow::Result<Option<Option<String>>> {
+        let row = sqlx::query!(
+            "SELECT preview FROM link_previews WHERE url = ? AND fetched_at > ?",
+            url,
+            fresh_since
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.preview))
+    }
+
+    pub async fn put_link_preview(&self, url: &str, preview: Option<&str>) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO link_previews (url, preview, fetched_at)
+            VALUES (?, ?, CURRENT_TIMESTAMP)
+            ON CONFLICT(url) DO UPDATE SET
+                preview = excluded.preview,
+                fetched_at = excluded.fetched_at
+            "#,
+        )
+        .bind(url)
+        .bind(preview)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_comment_link_preview(
+        &self,
+        comment_id: &str,
+        preview: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE comments SET link_preview = ? WHERE id = ?")
+            .bind(preview)
+            .bind(comment_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
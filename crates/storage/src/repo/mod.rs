@@ -1,3 +1,11 @@
+mod allowed_slugs;
+mod audit;
 mod comments;
+mod dead_letters;
+mod link_previews;
 mod meta;
+mod notifications;
+mod post_urls;
+mod reports;
 mod rooms;
+mod settings;
@@ -0,0 +1,211 @@
+use crate::Db;
+use domain::ReplySubscription;
+
+impl Db {
+    pub async fn get_reply_subscription(
+        &self,
+        site_id: &str,
+        fingerprint: &str,
+    ) -> anyhow::Result<Option<ReplySubscription>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT email, opted_out, confirmed_owner, unsubscribe_token
+            FROM reply_subscriptions
+            WHERE site_id = ? AND fingerprint = ?
+            "#,
+            site_id,
+            fingerprint
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| ReplySubscription {
+            email: r.email,
+            opted_out: r.opted_out,
+            confirmed_owner: r.confirmed_owner,
+            unsubscribe_token: r.unsubscribe_token,
+        }))
+    }
+
+    /// Where to email a guest about replies: only a confirmed address that
+    /// hasn't opted out. Returns the address and its unsubscribe token.
+    pub async fn get_reply_recipient(
+        &self,
+        site_id: &str,
+        fingerprint: &str,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT email, unsubscribe_token as "unsubscribe_token!"
+            FROM reply_subscriptions
+            WHERE site_id = ? AND fingerprint = ? AND NOT opted_out
+                AND confirmed_owner IS NOT NULL AND unsubscribe_token IS NOT NULL
+            "#,
+            site_id,
+            fingerprint
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| (r.email, r.unsubscribe_token)))
+    }
+
+    /// Records that `owner` asked for reply emails to `email`, pending the
+    /// confirmation link carrying `confirm_token`. An address confirmed by
+    /// someone else stays confirmed, and keeps its unsubscribe token.
+    pub async fn request_reply_subscription(
+        &self,
+        site_id: &str,
+        fingerprint: &str,
+        email: &str,
+        owner: &str,
+        confirm_token: &str,
+        unsubscribe_token: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO reply_subscriptions
+                (site_id, fingerprint, email, pending_owner, confirm_token, unsubscribe_token)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(site_id, fingerprint) DO UPDATE SET
+                pending_owner = excluded.pending_owner,
+                confirm_token = excluded.confirm_token,
+                unsubscribe_token = COALESCE(unsubscribe_token, excluded.unsubscribe_token),
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            site_id,
+            fingerprint,
+            email,
+            owner,
+            confirm_token,
+            unsubscribe_token
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Turns reply emails on or off for the guest who confirmed the address.
+    /// Returns `false`, changing nothing, for anyone else.
+    pub async fn set_reply_opt_out(
+        &self,
+        site_id: &str,
+        fingerprint: &str,
+        owner: &str,
+        opted_out: bool,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE reply_subscriptions
+            SET opted_out = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE site_id = ? AND fingerprint = ? AND confirmed_owner = ?
+            "#,
+            opted_out,
+            site_id,
+            fingerprint,
+            owner
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Confirms the address a confirmation link was sent to, and turns its
+    /// emails on. `false` if the token is unknown or already used.
+    pub async fn confirm_reply_subscription(&self, confirm_token: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE reply_subscriptions
+            SET confirmed_owner = pending_owner, pending_owner = NULL, confirm_token = NULL,
+                opted_out = FALSE, updated_at = CURRENT_TIMESTAMP
+            WHERE confirm_token = ?
+            "#,
+            confirm_token
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Stops reply emails for the address an unsubscribe link was sent to.
+    pub async fn unsubscribe_replies(&self, unsubscribe_token: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE reply_subscriptions
+            SET opted_out = TRUE, updated_at = CURRENT_TIMESTAMP
+            WHERE unsubscribe_token = ?
+            "#,
+            unsubscribe_token
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Marks a reply as notified; `false` if it already was.
+    pub async fn claim_reply_notification(&self, comment_id: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "INSERT OR IGNORE INTO reply_notifications (comment_id) VALUES (?)",
+            comment_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_emails_wait_for_confirmation() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.request_reply_subscription("blog", "fp", "a@x.org", "alice", "c1", "u1")
+            .await
+            .unwrap();
+        assert_eq!(db.get_reply_recipient("blog", "fp").await.unwrap(), None);
+        // Only the confirmed owner may opt in or out by posting.
+        assert!(!db
+            .set_reply_opt_out("blog", "fp", "alice", false)
+            .await
+            .unwrap());
+
+        assert!(db.confirm_reply_subscription("c1").await.unwrap());
+        assert!(!db.confirm_reply_subscription("c1").await.unwrap());
+        assert_eq!(
+            db.get_reply_recipient("blog", "fp").await.unwrap(),
+            Some(("a@x.org".to_string(), "u1".to_string()))
+        );
+        assert_eq!(db.get_reply_recipient("docs", "fp").await.unwrap(), None);
+
+        // Someone else entering the address leaves it confirmed as it was.
+        db.request_reply_subscription("blog", "fp", "a@x.org", "mallory", "c2", "u2")
+            .await
+            .unwrap();
+        let sub = db
+            .get_reply_subscription("blog", "fp")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sub.confirmed_owner.as_deref(), Some("alice"));
+        assert_eq!(sub.unsubscribe_token.as_deref(), Some("u1"));
+        assert!(!db
+            .set_reply_opt_out("blog", "fp", "mallory", true)
+            .await
+            .unwrap());
+
+        assert!(db.unsubscribe_replies("u1").await.unwrap());
+        assert_eq!(db.get_reply_recipient("blog", "fp").await.unwrap(), None);
+        assert!(db
+            .set_reply_opt_out("blog", "fp", "alice", false)
+            .await
+            .unwrap());
+        assert!(db
+            .get_reply_recipient("blog", "fp")
+            .await
+            .unwrap()
+            .is_some());
+
+        assert!(db.claim_reply_notification("$r").await.unwrap());
+        assert!(!db.claim_reply_notification("$r").await.unwrap());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::Db;
+
+impl Db {
+    pub async fn get_post_url(&self, site_id: &str, slug: &str) -> anyhow::Result<Option<String>> {
+        let url = sqlx::query_scalar!(
+            "SELECT url FROM post_urls WHERE site_id = ? AND post_slug = ?",
+            site_id,
+            slug
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(url)
+    }
+
+    /// Records a thread's post URL unless one is already known: the first
+    /// writer wins. Returns whether it was stored.
+    pub async fn record_post_url(
+        &self,
+        site_id: &str,
+        slug: &str,
+        url: &str,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO post_urls (site_id, post_slug, url)
+            VALUES (?, ?, ?)
+            ON CONFLICT(site_id, post_slug) DO NOTHING
+            "#,
+            site_id,
+            slug,
+            url
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Replaces a thread's post URL, or forgets it with `None` so the next
+    /// recorded one sticks.
+    pub async fn set_post_url(
+        &self,
+        site_id: &str,
+        slug: &str,
+        url: Option<&str>,
+    ) -> anyhow::Result<()> {
+        match url {
+            Some(url) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO post_urls (site_id, post_slug, url)
+                    VALUES (?, ?, ?)
+                    ON CONFLICT(site_id, post_slug) DO UPDATE SET
+                        url = excluded.url,
+                        updated_at = CURRENT_TIMESTAMP
+                    "#,
+                    site_id,
+                    slug,
+                    url
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM post_urls WHERE site_id = ? AND post_slug = ?",
+                    site_id,
+                    slug
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_first_writer_wins_until_overridden() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        assert!(db
+            .record_post_url("blog", "a", "https://x.org/a")
+            .await
+            .unwrap());
+        assert!(!db
+            .record_post_url("blog", "a", "https://evil.org")
+            .await
+            .unwrap());
+        assert_eq!(
+            db.get_post_url("blog", "a").await.unwrap().as_deref(),
+            Some("https://x.org/a")
+        );
+
+        db.set_post_url("blog", "a", Some("https://x.org/b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_post_url("blog", "a").await.unwrap().as_deref(),
+            Some("https://x.org/b")
+        );
+        db.set_post_url("blog", "a", None).await.unwrap();
+        assert!(db
+            .record_post_url("blog", "a", "https://x.org/c")
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::Db;
+
+impl Db {
+    /// Records a reader's report and returns the comment's report count, or
+    /// `None` if this fingerprint had already reported it.
+    pub async fn add_report(
+        &self,
+        comment_id: &str,
+        reporter_fingerprint: &str,
+        reason: Option<&str>,
+    ) -> anyhow::Result<Option<i64>> {
+        let mut tx = self.pool.begin().await?;
+
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO reports (comment_id, reporter_fingerprint, reason)
+            VALUES (?, ?, ?)
+            ON CONFLICT(comment_id, reporter_fingerprint) DO NOTHING
+            "#,
+            comment_id,
+            reporter_fingerprint,
+            reason
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            return Ok(None);
+        }
+
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!: i64" FROM reports WHERE comment_id = ?"#,
+            comment_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(count))
+    }
+
+    /// Marks a comment for moderator review. Returns whether it was newly
+    /// flagged.
+    pub async fn flag_comment(&self, comment_id: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE comments SET flagged = TRUE WHERE id = ? AND flagged = FALSE",
+            comment_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_duplicate_reports_are_ignored() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        sqlx::query(
+            r#"
+            INSERT INTO comments (id, room_id, author_id, author_name, content, created_at)
+            VALUES ('$a', '!r:x', '@a:x', 'A', 'hi', CURRENT_TIMESTAMP)
+            "#,
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        assert_eq!(db.add_report("$a", "fp1", None).await.unwrap(), Some(1));
+        assert_eq!(
+            db.add_report("$a", "fp1", Some("again")).await.unwrap(),
+            None
+        );
+        assert_eq!(db.add_report("$a", "fp2", None).await.unwrap(), Some(2));
+
+        assert!(db.flag_comment("$a").await.unwrap());
+        assert!(!db.flag_comment("$a").await.unwrap());
+    }
+}
@@ -1,31 +1,130 @@
 use crate::Db;
-use domain::SiteId;
+use chrono::NaiveDateTime;
+use domain::{SiteId, ThreadOrder, ThreadSummary};
 
 impl Db {
+    /// Maps `room_id` to a thread. A thread holds one room, so if it is
+    /// already mapped to another one (its alias was recreated after the old
+    /// room became unusable), the room the caller just resolved wins: the
+    /// old room is recorded in `retired_rooms`. Its comments stay where they
+    /// were posted and are still listed with the thread.
     pub async fn ensure_room(
         &self,
         room_id: &str,
         site_id: &str,
         slug: &str,
     ) -> anyhow::Result<()> {
-        sqlx::query(
+        let mut tx = self.pool.begin().await?;
+
+        // Write first, so the transaction takes the write lock up front
+        // instead of failing to upgrade from a read.
+        let inserted = sqlx::query!(
+            "INSERT OR IGNORE INTO rooms (room_id, site_id, post_slug) VALUES (?, ?, ?)",
+            room_id,
+            site_id,
+            slug
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+
+        // A retired room stays retired, or late events from it would flip
+        // the thread back.
+        let known = sqlx::query_scalar!(
             r#"
-            INSERT INTO rooms (room_id, site_id, post_slug)
-            VALUES (?, ?, ?)
-            ON CONFLICT(room_id) DO NOTHING
+            SELECT 1 FROM rooms WHERE room_id = ?1
+            UNION ALL
+            SELECT 1 FROM retired_rooms WHERE room_id = ?1
             "#,
+            room_id
         )
-        .bind(room_id)
-        .bind(site_id)
-        .bind(slug)
-        .execute(&self.pool)
+        .fetch_optional(&mut *tx)
         .await?;
+        if inserted || known.is_some() {
+            tx.commit().await?;
+            return Ok(());
+        }
+
+        let current = sqlx::query_scalar!(
+            r#"SELECT room_id as "room_id!" FROM rooms WHERE site_id = ? AND post_slug = ?"#,
+            site_id,
+            slug
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(old) = current {
+            tracing::warn!(
+                "Thread {}/{} moved from room {} to {}; retiring the old room",
+                site_id,
+                slug,
+                old,
+                room_id
+            );
+            sqlx::query!(
+                r#"
+                INSERT OR REPLACE INTO retired_rooms (room_id, site_id, post_slug, replaced_by)
+                VALUES (?, ?, ?, ?)
+                "#,
+                old,
+                site_id,
+                slug,
+                room_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE rooms SET room_id = ? WHERE room_id = ?",
+                room_id,
+                old
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
         Ok(())
     }
 
+    /// Every room a thread has had, current one first, then retired ones
+    /// newest first. For diagnosing threads whose alias was recreated.
+    pub async fn rooms_for(&self, site_id: &str, slug: &str) -> anyhow::Result<Vec<String>> {
+        let rooms = sqlx::query_scalar!(
+            r#"
+            SELECT room_id as "room_id!" FROM (
+                SELECT room_id, 0 as retired, created_at as at
+                FROM rooms WHERE site_id = ?1 AND post_slug = ?2
+                UNION ALL
+                SELECT room_id, 1 as retired, retired_at as at
+                FROM retired_rooms WHERE site_id = ?1 AND post_slug = ?2
+            )
+            ORDER BY retired, at DESC
+            "#,
+            site_id,
+            slug
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    pub async fn get_room_id(&self, site_id: &str, slug: &str) -> anyhow::Result<Option<String>> {
+        let room_id = sqlx::query_scalar!(
+            r#"SELECT room_id as "room_id!" FROM rooms WHERE site_id = ? AND post_slug = ?"#,
+            site_id,
+            slug
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room_id)
+    }
+
+    /// The thread a room holds or, if it was retired, used to hold.
     pub async fn get_room_meta(&self, room_id: &str) -> anyhow::Result<Option<(SiteId, String)>> {
         let row = sqlx::query!(
-            "SELECT site_id, post_slug FROM rooms WHERE room_id = ?",
+            r#"SELECT site_id as "site_id!", post_slug as "post_slug!" FROM thread_rooms WHERE room_id = ?"#,
             room_id
         )
         .fetch_optional(&self.pool)
@@ -33,4 +132,232 @@ impl Db {
 
         Ok(row.map(|r| (SiteId::new_unchecked(r.site_id), r.post_slug)))
     }
+
+    /// Removes a thread's room mapping along with the rooms it retired, and
+    /// every comment in them; a trigger deletes the current room's comments.
+    /// Returns whether the room existed.
+    pub async fn delete_room(&self, room_id: &str) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM comments WHERE room_id IN (
+                SELECT t.room_id FROM retired_rooms t
+                JOIN rooms r ON r.site_id = t.site_id AND r.post_slug = t.post_slug
+                WHERE r.room_id = ?
+            )
+            "#,
+            room_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM retired_rooms WHERE (site_id, post_slug) IN (
+                SELECT site_id, post_slug FROM rooms WHERE room_id = ?
+            )
+            "#,
+            room_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query!("DELETE FROM rooms WHERE room_id = ?", room_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Current room of every thread on a site, oldest first.
+    pub async fn site_rooms(&self, site_id: &str) -> anyhow::Result<Vec<String>> {
+        let rooms = sqlx::query_scalar!(
+            r#"SELECT room_id as "room_id!" FROM rooms WHERE site_id = ? ORDER BY created_at"#,
+            site_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    /// A site's threads with their visible comment counts, one page at a time.
+    pub async fn list_threads(
+        &self,
+        site_id: &str,
+        order: ThreadOrder,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<Vec<ThreadSummary>> {
+        let order = order.as_str();
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                r.room_id as "room_id!",
+                r.post_slug,
+                r.created_at as "created_at: NaiveDateTime",
+                r.last_comment_at as "last_comment_at: NaiveDateTime",
+                COUNT(c.id) as "comment_count!: i64"
+            FROM rooms r
+            LEFT JOIN thread_rooms t ON t.site_id = r.site_id AND t.post_slug = r.post_slug
+            LEFT JOIN comments c ON c.room_id = t.room_id AND c.is_redacted = FALSE
+            WHERE r.site_id = ?
+            GROUP BY r.room_id
+            ORDER BY
+                CASE WHEN ? = 'recent' THEN r.last_comment_at END DESC,
+                CASE WHEN ? = 'comments' THEN COUNT(c.id) END DESC,
+                r.created_at DESC
+            LIMIT ? OFFSET ?
+            "#,
+            site_id,
+            order,
+            order,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| ThreadSummary {
+                room_id: r.room_id,
+                post_slug: r.post_slug,
+                comment_count: r.comment_count,
+                created_at: r.created_at,
+                last_comment_at: r.last_comment_at,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn insert_comment(db: &Db, id: &str, room_id: &str) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO comments (id, room_id, author_id, author_name, content, created_at)
+            VALUES (?, ?, '@a:x', 'A', 'hi', CURRENT_TIMESTAMP)
+            "#,
+        )
+        .bind(id)
+        .bind(room_id)
+        .execute(&db.pool)
+        .await
+        .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_comment_requires_room() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        assert!(insert_comment(&db, "$orphan", "!missing:x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_room_cascades() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!r:x", "blog", "hello").await.unwrap();
+        insert_comment(&db, "$a", "!r:x").await.unwrap();
+
+        assert!(db.delete_room("!r:x").await.unwrap());
+        assert!(!db.delete_room("!r:x").await.unwrap());
+        assert!(db.get_comment_origin("$a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_room_takes_retired_rooms() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!old:x", "blog", "hello").await.unwrap();
+        insert_comment(&db, "$a", "!old:x").await.unwrap();
+        db.ensure_room("!new:x", "blog", "hello").await.unwrap();
+        insert_comment(&db, "$b", "!new:x").await.unwrap();
+        db.ensure_room("!other:x", "blog", "other").await.unwrap();
+        insert_comment(&db, "$c", "!other:x").await.unwrap();
+
+        assert!(db.delete_room("!new:x").await.unwrap());
+        assert!(db.rooms_for("blog", "hello").await.unwrap().is_empty());
+        assert_eq!(db.count_comments("blog", "hello").await.unwrap(), 0);
+        assert!(db.get_comment_origin("$a").await.unwrap().is_none());
+        // Other threads are left alone.
+        assert!(db.get_comment_origin("$c").await.unwrap().is_some());
+    }
+
+    fn comment(id: &str, minute: u32) -> domain::Comment {
+        domain::Comment {
+            post_slug: String::new(),
+            created_at: chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(12, minute, 0)
+                .unwrap(),
+            ..domain::Comment::fixture(id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_threads_by_activity() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!quiet:x", "blog", "quiet").await.unwrap();
+        db.upsert_comment("!old:x", "blog", "old", &comment("$1", 1))
+            .await
+            .unwrap();
+        db.upsert_comment("!old:x", "blog", "old", &comment("$2", 2))
+            .await
+            .unwrap();
+        db.upsert_comment("!new:x", "blog", "new", &comment("$3", 5))
+            .await
+            .unwrap();
+        // An older comment arriving late, e.g. on resync, keeps the newer time.
+        db.upsert_comment("!new:x", "blog", "new", &comment("$0", 0))
+            .await
+            .unwrap();
+
+        let threads = db
+            .list_threads("blog", ThreadOrder::Recent, 10, 0)
+            .await
+            .unwrap();
+        let slugs: Vec<_> = threads.iter().map(|t| t.post_slug.as_str()).collect();
+        assert_eq!(slugs, ["new", "old", "quiet"]);
+        assert_eq!(
+            threads[0].last_comment_at,
+            Some(comment("$3", 5).created_at)
+        );
+        assert_eq!(threads[2].comment_count, 0);
+
+        let threads = db
+            .list_threads("blog", ThreadOrder::Comments, 1, 1)
+            .await
+            .unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].comment_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_recreated_room_takes_over_thread() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.ensure_room("!old:x", "blog", "hello").await.unwrap();
+        insert_comment(&db, "$a", "!old:x").await.unwrap();
+
+        db.ensure_room("!new:x", "blog", "hello").await.unwrap();
+        // Seeing the retired room again doesn't flip the thread back.
+        db.ensure_room("!old:x", "blog", "hello").await.unwrap();
+
+        assert_eq!(
+            db.get_room_id("blog", "hello").await.unwrap().as_deref(),
+            Some("!new:x")
+        );
+        // The comment stays in its room but is still part of the thread.
+        insert_comment(&db, "$b", "!new:x").await.unwrap();
+        let (room_id, _, _) = db.get_comment_origin("$a").await.unwrap().unwrap();
+        assert_eq!(room_id, "!old:x");
+        assert_eq!(db.count_comments("blog", "hello").await.unwrap(), 2);
+        let a = db.get_comment("$a").await.unwrap().unwrap();
+        assert_eq!(a.post_slug, "hello");
+        assert_eq!(
+            db.rooms_for("blog", "hello").await.unwrap(),
+            ["!new:x", "!old:x"]
+        );
+        // Only the current room is left to link into the site's space.
+        assert_eq!(db.site_rooms("blog").await.unwrap(), ["!new:x"]);
+    }
 }
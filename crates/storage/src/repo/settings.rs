@@ -0,0 +1,62 @@
+use crate::Db;
+
+impl Db {
+    pub async fn get_site_setting(
+        &self,
+        site_id: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let row = sqlx::query!(
+            "SELECT value FROM site_settings WHERE site_id = ? AND key = ?",
+            site_id,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.value))
+    }
+
+    pub async fn list_site_settings(&self, site_id: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let rows = sqlx::query!(
+            "SELECT key, value FROM site_settings WHERE site_id = ? ORDER BY key",
+            site_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| (r.key, r.value)).collect())
+    }
+
+    pub async fn set_site_setting(
+        &self,
+        site_id: &str,
+        key: &str,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO site_settings (site_id, key, value)
+            VALUES (?, ?, ?)
+            ON CONFLICT(site_id, key) DO UPDATE SET
+                value = excluded.value,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(site_id)
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_site_setting(&self, site_id: &str, key: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM site_settings WHERE site_id = ? AND key = ?")
+            .bind(site_id)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+CREATE TABLE site_settings (
+    site_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (site_id, key)
+);
@@ -0,0 +1 @@
+CREATE INDEX idx_comments_reply_to ON comments(reply_to);
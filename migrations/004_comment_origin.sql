@@ -0,0 +1,6 @@
+ALTER TABLE comments ADD COLUMN origin TEXT NOT NULL DEFAULT 'native';
+ALTER TABLE comments ADD COLUMN verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE comments
+SET origin = CASE WHEN is_guest THEN 'web' ELSE 'native' END,
+    verified = NOT is_guest;
@@ -0,0 +1,11 @@
+ALTER TABLE comments ADD COLUMN origin_kind TEXT NOT NULL DEFAULT 'native';
+
+-- Ghost localparts end in the author's fingerprint; other web comments were
+-- relayed by the bot itself.
+UPDATE comments
+SET origin_kind = CASE
+    WHEN origin = 'native' THEN 'native'
+    WHEN author_fingerprint IS NOT NULL
+         AND author_id LIKE '@%\_' || author_fingerprint || ':%' ESCAPE '\' THEN 'ghost'
+    ELSE 'bot'
+END;
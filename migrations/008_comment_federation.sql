@@ -0,0 +1,2 @@
+ALTER TABLE comments ADD COLUMN is_federated BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE comments ADD COLUMN flagged BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Comments whose room row is gone would violate the room_id foreign key now
+-- that it is enforced on every connection.
+DELETE FROM comments WHERE room_id NOT IN (SELECT room_id FROM rooms);
@@ -0,0 +1,9 @@
+CREATE TABLE reports (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    comment_id TEXT NOT NULL,
+    reporter_fingerprint TEXT NOT NULL,
+    reason TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(comment_id, reporter_fingerprint),
+    FOREIGN KEY(comment_id) REFERENCES comments(id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+ALTER TABLE rooms ADD COLUMN last_comment_at DATETIME;
+
+UPDATE rooms SET last_comment_at = (
+    SELECT MAX(created_at) FROM comments WHERE comments.room_id = rooms.room_id
+);
+
+CREATE INDEX idx_rooms_activity ON rooms(site_id, last_comment_at);
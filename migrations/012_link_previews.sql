@@ -0,0 +1,9 @@
+ALTER TABLE comments ADD COLUMN link_preview TEXT;
+
+-- One row per fetched URL, shared by every comment linking it. A NULL
+-- preview records a fetch that found nothing, so it isn't retried at once.
+CREATE TABLE link_previews (
+    url TEXT PRIMARY KEY,
+    preview TEXT,
+    fetched_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
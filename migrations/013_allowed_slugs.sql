@@ -0,0 +1,6 @@
+CREATE TABLE allowed_slugs (
+    site_id TEXT NOT NULL,
+    slug TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (site_id, slug)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE post_urls (
+    site_id TEXT NOT NULL,
+    post_slug TEXT NOT NULL,
+    url TEXT NOT NULL,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (site_id, post_slug)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    site_id TEXT NOT NULL,
+    post_slug TEXT,
+    comment_id TEXT,
+    reason TEXT,
+    detail TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_audit_log_site ON audit_log (site_id, id);
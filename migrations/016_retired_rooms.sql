@@ -0,0 +1,11 @@
+-- Rooms that used to hold a thread before its alias moved to a new room.
+-- Their comments were moved to the replacement.
+CREATE TABLE retired_rooms (
+    room_id TEXT PRIMARY KEY,
+    site_id TEXT NOT NULL,
+    post_slug TEXT NOT NULL,
+    replaced_by TEXT NOT NULL,
+    retired_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_retired_rooms_thread ON retired_rooms(site_id, post_slug);
@@ -0,0 +1,17 @@
+-- Guests who left an email, keyed like comments' author_fingerprint, so a
+-- reply to any of their comments can be mailed to them. Their latest
+-- comment's choice wins.
+CREATE TABLE reply_subscriptions (
+    site_id TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    email TEXT NOT NULL,
+    opted_out BOOLEAN NOT NULL DEFAULT 0,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (site_id, fingerprint)
+);
+
+-- Replies already notified about, so a resync or restart doesn't mail twice.
+CREATE TABLE reply_notifications (
+    comment_id TEXT PRIMARY KEY,
+    sent_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
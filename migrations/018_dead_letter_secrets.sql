@@ -0,0 +1,6 @@
+-- Queued sends used to carry the guest's email and token. Drop them from
+-- commands already dead-lettered; without the fingerprint they were turned
+-- into, those sends can no longer be retried and are only kept for audit.
+UPDATE dead_letters
+SET command = json_remove(command, '$.SendComment.email', '$.SendComment.guest_token')
+WHERE json_extract(command, '$.SendComment') IS NOT NULL;
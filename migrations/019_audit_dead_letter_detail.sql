@@ -0,0 +1,3 @@
+-- Dead-letter retries used to copy the whole command, guest secrets
+-- included, into the audit log. Keep only that a retry happened.
+UPDATE audit_log SET detail = NULL WHERE action = 'retry_dead_letter';
@@ -0,0 +1,10 @@
+-- Reply emails only go to addresses confirmed from a link mailed to them.
+-- `confirmed_owner` and `pending_owner` identify the guest token that
+-- confirmed or asked to; existing subscriptions start unconfirmed.
+ALTER TABLE reply_subscriptions ADD COLUMN confirmed_owner TEXT;
+ALTER TABLE reply_subscriptions ADD COLUMN pending_owner TEXT;
+ALTER TABLE reply_subscriptions ADD COLUMN confirm_token TEXT;
+ALTER TABLE reply_subscriptions ADD COLUMN unsubscribe_token TEXT;
+
+CREATE UNIQUE INDEX idx_reply_subscriptions_confirm ON reply_subscriptions (confirm_token);
+CREATE UNIQUE INDEX idx_reply_subscriptions_unsubscribe ON reply_subscriptions (unsubscribe_token);
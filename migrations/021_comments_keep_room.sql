@@ -0,0 +1,71 @@
+-- Comments stay in the room they were posted to, even after it is retired,
+-- so they can't reference `rooms` alone any more. The foreign key becomes
+-- triggers that accept current and retired rooms alike.
+PRAGMA defer_foreign_keys = ON;
+
+-- Dropping `comments` cascades to its reports; keep them aside.
+CREATE TABLE reports_keep AS SELECT * FROM reports;
+
+CREATE TABLE comments_new (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+
+    author_id TEXT NOT NULL,
+    author_name TEXT NOT NULL,
+    is_guest BOOLEAN NOT NULL DEFAULT FALSE,
+    author_fingerprint TEXT,
+
+    content TEXT NOT NULL,
+    is_redacted BOOLEAN NOT NULL DEFAULT FALSE,
+
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME,
+
+    reply_to TEXT,
+    origin TEXT NOT NULL DEFAULT 'native',
+    verified BOOLEAN NOT NULL DEFAULT FALSE,
+    lang TEXT,
+    origin_kind TEXT NOT NULL DEFAULT 'native',
+    is_federated BOOLEAN NOT NULL DEFAULT FALSE,
+    flagged BOOLEAN NOT NULL DEFAULT FALSE,
+    link_preview TEXT
+);
+
+INSERT INTO comments_new (
+    id, room_id, author_id, author_name, is_guest, author_fingerprint, content,
+    is_redacted, created_at, updated_at, reply_to, origin, verified, lang,
+    origin_kind, is_federated, flagged, link_preview
+)
+SELECT
+    id, room_id, author_id, author_name, is_guest, author_fingerprint, content,
+    is_redacted, created_at, updated_at, reply_to, origin, verified, lang,
+    origin_kind, is_federated, flagged, link_preview
+FROM comments;
+
+DROP TABLE comments;
+ALTER TABLE comments_new RENAME TO comments;
+
+CREATE INDEX idx_comments_room_time ON comments(room_id, created_at);
+CREATE INDEX idx_comments_reply_to ON comments(reply_to);
+
+INSERT INTO reports SELECT * FROM reports_keep;
+DROP TABLE reports_keep;
+
+-- Every room that has held a thread, current or retired.
+CREATE VIEW thread_rooms AS
+    SELECT room_id, site_id, post_slug FROM rooms
+    UNION ALL
+    SELECT room_id, site_id, post_slug FROM retired_rooms;
+
+CREATE TRIGGER comments_require_room
+BEFORE INSERT ON comments
+WHEN NOT EXISTS (SELECT 1 FROM thread_rooms WHERE room_id = NEW.room_id)
+BEGIN
+    SELECT RAISE(ABORT, 'comment room does not exist');
+END;
+
+CREATE TRIGGER rooms_delete_comments
+AFTER DELETE ON rooms
+BEGIN
+    DELETE FROM comments WHERE room_id = OLD.room_id;
+END;